@@ -0,0 +1,315 @@
+//! Just enough of ACME (RFC 8555) to order a certificate: an ES256 account
+//! key and JWS-signed requests with replay nonces.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use ring::rand::SystemRandom;
+use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+use rustls::pki_types::PrivateKeyDer;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::net::http::{Response, request};
+
+/// How long one request to the CA may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Content type of JWS-signed requests.
+const JOSE: &str = "application/jose+json";
+
+/// The CA's endpoints, from its directory.
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// A registered account, signing every request with its key.
+pub struct Account {
+    key: EcdsaKeyPair,
+    jwk: String,
+    thumbprint: String,
+    kid: Option<String>,
+    directory: Directory,
+    nonce: Option<String>,
+}
+
+impl Account {
+    /// Loads the account key at `key_path`, creating it on first use, and
+    /// registers it with the CA (which finds an existing account by key).
+    pub async fn open(directory_url: &str, key_path: &Path, email: Option<&str>) -> Result<Self> {
+        let key = load_or_create_key(key_path)?;
+
+        let directory = request(directory_url, "GET", None, REQUEST_TIMEOUT).await?;
+        if directory.status() != 200 {
+            bail!("Directory {} replied {}", directory_url, directory.status());
+        }
+        let directory = directory.json()?;
+        let endpoint = |name: &str| {
+            directory[name]
+                .as_str()
+                .map(str::to_string)
+                .with_context(|| format!("Directory lists no {}", name))
+        };
+        let directory = Directory {
+            new_nonce: endpoint("newNonce")?,
+            new_account: endpoint("newAccount")?,
+            new_order: endpoint("newOrder")?,
+        };
+
+        let mut account = Self::new(key, directory);
+
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = email {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let url = account.directory.new_account.clone();
+        let response = account.post(&url, Some(&payload)).await?;
+        let kid = response
+            .header("Location")
+            .context("CA returned no account URL")?;
+        if response.status() == 201 {
+            info!("[ACME] Registered account {}", kid);
+        }
+        account.kid = Some(kid.to_string());
+
+        Ok(account)
+    }
+
+    /// An account for `key`, not yet registered.
+    fn new(key: EcdsaKeyPair, directory: Directory) -> Self {
+        let public = key.public_key().as_ref();
+        let (x, y) = public[1..].split_at(32);
+        // Members in lexicographic order and without whitespace, as the
+        // thumbprint requires.
+        let jwk = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            Base64UrlUnpadded::encode_string(x),
+            Base64UrlUnpadded::encode_string(y)
+        );
+        let thumbprint = thumbprint(&jwk);
+
+        Self {
+            key,
+            jwk,
+            thumbprint,
+            kid: None,
+            directory,
+            nonce: None,
+        }
+    }
+
+    /// The key authorization for a challenge `token`.
+    pub fn key_authorization(&self, token: &str) -> String {
+        format!("{}.{}", token, self.thumbprint)
+    }
+
+    /// Places an order for `domains`, returning its URL and body.
+    pub async fn new_order(&mut self, domains: &[String]) -> Result<(String, Value)> {
+        let identifiers: Vec<Value> = domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let url = self.directory.new_order.clone();
+        let response = self
+            .post(&url, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = response
+            .header("Location")
+            .context("CA returned no order URL")?
+            .to_string();
+        Ok((order_url, response.json()?))
+    }
+
+    /// Sends a signed request to `url`: `payload`, or a POST-as-GET without
+    /// one. Retries once when the CA rejects the nonce.
+    pub async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.fresh_nonce().await?,
+            };
+            let body = self.sign(url, &nonce, payload)?;
+            let response =
+                request(url, "POST", Some((JOSE, body.as_bytes())), REQUEST_TIMEOUT).await?;
+            self.nonce = response.header("Replay-Nonce").map(str::to_string);
+
+            if response.status() < 400 {
+                return Ok(response);
+            }
+            let problem = response.json().unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            bail!(
+                "CA refused request to {} ({}): {}",
+                url,
+                response.status(),
+                problem["detail"].as_str().unwrap_or("no detail")
+            );
+        }
+    }
+
+    async fn fresh_nonce(&self) -> Result<String> {
+        let response = request(&self.directory.new_nonce, "HEAD", None, REQUEST_TIMEOUT).await?;
+        response
+            .header("Replay-Nonce")
+            .map(str::to_string)
+            .context("CA returned no nonce")
+    }
+
+    /// The flattened JWS of `payload` for `url`.
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<String> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = serde_json::from_str(&self.jwk)?,
+        }
+        let protected = Base64UrlUnpadded::encode_string(protected.to_string().as_bytes());
+        let payload = match payload {
+            Some(payload) => Base64UrlUnpadded::encode_string(payload.to_string().as_bytes()),
+            None => String::new(),
+        };
+
+        let signing_input = format!("{}.{}", protected, payload);
+        let signature = self
+            .key
+            .sign(&SystemRandom::new(), signing_input.as_bytes())
+            .map_err(|_| anyhow::anyhow!("Failed to sign ACME request"))?;
+
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": Base64UrlUnpadded::encode_string(signature.as_ref()),
+        })
+        .to_string())
+    }
+}
+
+/// The JWK thumbprint (RFC 7638) of `jwk`, given in its canonical form.
+fn thumbprint(jwk: &str) -> String {
+    Base64UrlUnpadded::encode_string(&Sha256::digest(jwk.as_bytes()))
+}
+
+/// Reads the P-256 account key at `path`, or generates and saves one.
+fn load_or_create_key(path: &Path) -> Result<EcdsaKeyPair> {
+    if !path.exists() {
+        let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+        crate::server::tls::write_private(path, key.serialize_pem().as_bytes())
+            .with_context(|| format!("Failed to save account key to {:?}", path))?;
+        info!("[ACME] Created account key {:?}", path);
+    }
+
+    let pkcs8 = match crate::server::tls::load_key(path)? {
+        PrivateKeyDer::Pkcs8(pkcs8) => pkcs8,
+        _ => bail!("Account key {:?} is not a PKCS#8 key", path),
+    };
+    EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_FIXED_SIGNING,
+        pkcs8.secret_pkcs8_der(),
+        &SystemRandom::new(),
+    )
+    .map_err(|e| anyhow::anyhow!("Account key {:?} is not a P-256 key: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{ECDSA_P256_SHA256_FIXED, UnparsedPublicKey};
+
+    fn account() -> Account {
+        let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &key.serialize_der(),
+            &SystemRandom::new(),
+        )
+        .unwrap();
+        let directory = Directory {
+            new_nonce: String::new(),
+            new_account: String::new(),
+            new_order: String::new(),
+        };
+        Account::new(key, directory)
+    }
+
+    fn decode(value: &Value) -> Vec<u8> {
+        Base64UrlUnpadded::decode_vec(value.as_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn thumbprint_matches_rfc_7638() {
+        let jwk = concat!(
+            r#"{"e":"AQAB","kty":"RSA","n":""#,
+            "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            r#""}"#
+        );
+        assert_eq!(
+            thumbprint(jwk),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+    }
+
+    #[test]
+    fn key_authorization_ends_with_the_thumbprint() {
+        let account = account();
+        assert_eq!(
+            account.key_authorization("token"),
+            format!("token.{}", thumbprint(&account.jwk))
+        );
+    }
+
+    #[test]
+    fn signature_verifies_with_the_account_key() {
+        let mut account = account();
+        let payload = json!({ "termsOfServiceAgreed": true });
+        let public = account.key.public_key().as_ref().to_vec();
+        let public = UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, public);
+
+        // Before registration the key itself goes in the header.
+        let jws: Value = serde_json::from_str(
+            &account
+                .sign("https://ca.test/new-account", "n1", Some(&payload))
+                .unwrap(),
+        )
+        .unwrap();
+        let protected: Value = serde_json::from_slice(&decode(&jws["protected"])).unwrap();
+        assert_eq!(protected["alg"], "ES256");
+        assert_eq!(protected["nonce"], "n1");
+        assert_eq!(protected["url"], "https://ca.test/new-account");
+        assert_eq!(protected["jwk"]["kty"], "EC");
+        assert!(protected.get("kid").is_none());
+        assert_eq!(
+            serde_json::from_slice::<Value>(&decode(&jws["payload"])).unwrap(),
+            payload
+        );
+        let signing_input = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        public
+            .verify(signing_input.as_bytes(), &decode(&jws["signature"]))
+            .unwrap();
+
+        // After it, the account URL does, and POST-as-GET has no payload.
+        account.kid = Some(String::from("https://ca.test/acct/1"));
+        let jws: Value =
+            serde_json::from_str(&account.sign("https://ca.test/order/1", "n2", None).unwrap())
+                .unwrap();
+        let protected: Value = serde_json::from_slice(&decode(&jws["protected"])).unwrap();
+        assert_eq!(protected["kid"], "https://ca.test/acct/1");
+        assert!(protected.get("jwk").is_none());
+        assert_eq!(jws["payload"], "");
+        let signing_input = format!("{}.", jws["protected"].as_str().unwrap());
+        public
+            .verify(signing_input.as_bytes(), &decode(&jws["signature"]))
+            .unwrap();
+    }
+}
@@ -0,0 +1,523 @@
+//! `[acme]`: certificates from Let's Encrypt or another ACME CA, without
+//! certbot.
+//!
+//! On startup a certificate is ordered for the configured domains when the
+//! `cert_path` of `[tuic]` or `[trojan]` is missing or close to expiry, and
+//! a check twice a day renews it `renew_before` days ahead. The chain and
+//! key are written over the sections' files and the servers reload them,
+//! so renewals take effect without a restart.
+//!
+//! HTTP-01 challenges are answered on `http_listen` while an order is
+//! open. TLS-ALPN-01 challenges are answered by the Trojan listener, which
+//! serves the challenge certificate to clients asking for `acme-tls/1`, or
+//! on `tls_alpn_listen` when no server holds that port (first start).
+
+mod client;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use rustls::ServerConfig;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::watch::Receiver;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use crate::config::{AcmeChallenge, AcmeConfig, Config};
+use crate::server::ServerManager;
+use crate::server::tls::{build_certified_key, normalize_name, write_file, write_private};
+use client::Account;
+
+/// ALPN protocol of TLS-ALPN-01 validation connections (RFC 8737).
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// How often the certificate is checked for renewal.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How often, and how many times, a pending authorization or order is
+/// polled before giving up.
+const POLL_INTERVAL: Duration = Duration::from_secs(3);
+const POLL_ATTEMPTS: u32 = 40;
+
+/// How long an HTTP-01 peer has to send its request head.
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// HTTP-01 key authorizations by token.
+static TOKENS: Lazy<DashMap<String, String>> = Lazy::new(DashMap::new);
+
+/// TLS-ALPN-01 challenge certificates by domain.
+static CHALLENGES: Lazy<DashMap<String, Arc<CertifiedKey>>> = Lazy::new(DashMap::new);
+
+/// The config to finish a TLS-ALPN-01 validation handshake with, if
+/// `client_hello` is one for a pending challenge.
+pub fn challenge_config(client_hello: &ClientHello<'_>) -> Option<Arc<ServerConfig>> {
+    if CHALLENGES.is_empty() || !is_validation(client_hello.alpn()) {
+        return None;
+    }
+    let sni = normalize_name(client_hello.server_name()?);
+    if !CHALLENGES.contains_key(&sni) {
+        return None;
+    }
+
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .ok()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(ChallengeResolver));
+    config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+    Some(Arc::new(config))
+}
+
+/// Validation connections offer `acme-tls/1` and nothing else.
+fn is_validation<'a>(alpn: Option<impl Iterator<Item = &'a [u8]>>) -> bool {
+    alpn.is_some_and(|mut protocols| {
+        protocols.next() == Some(ACME_TLS_ALPN) && protocols.next().is_none()
+    })
+}
+
+#[derive(Debug)]
+struct ChallengeResolver;
+
+impl ResolvesServerCert for ChallengeResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let sni = normalize_name(client_hello.server_name()?);
+        CHALLENGES.get(&sni).map(|key| Arc::clone(&key))
+    }
+}
+
+/// The certificate and key files `[acme]` keeps up to date.
+fn targets(config: &Config) -> Vec<(PathBuf, PathBuf)> {
+    config
+        .primary_key_pairs()
+        .into_iter()
+        .map(|(cert_path, key_path)| (PathBuf::from(cert_path), PathBuf::from(key_path)))
+        .collect()
+}
+
+/// Whether any of `targets` is missing or expires within `renew_before`.
+fn due(targets: &[(PathBuf, PathBuf)], renew_before: Duration) -> bool {
+    let horizon = chrono::Utc::now()
+        + chrono::Duration::from_std(renew_before).unwrap_or(chrono::Duration::MAX);
+    targets.iter().any(|(cert_path, key_path)| {
+        !key_path.is_file()
+            || !crate::server::tls::expiry(cert_path).is_ok_and(|not_after| not_after >= horizon)
+    })
+}
+
+/// Orders a certificate before the servers start, if one is due. Without
+/// it the servers could not load their certificates at all.
+pub async fn provision(config: &Config) {
+    let Some(acme) = config.acme() else {
+        return;
+    };
+    let targets = targets(config);
+    if targets.is_empty() || !due(&targets, acme.renew_before()) {
+        return;
+    }
+
+    info!("[ACME] Ordering a certificate for {:?}", acme.domains());
+    if let Err(e) = issue(acme, &targets).await {
+        error!("[ACME] Failed to obtain a certificate: {:#}", e);
+    }
+}
+
+/// Checks twice a day whether the certificate is due and renews it, then
+/// has `servers` load it.
+pub fn spawn(config: Arc<Config>, servers: Arc<ServerManager>, mut shutdown_rx: Receiver<()>) {
+    let Some(acme) = config.acme().cloned() else {
+        return;
+    };
+    let targets = targets(&config);
+    if targets.is_empty() {
+        warn!("[ACME] Neither [tuic] nor [trojan] serves a certificate, nothing to renew");
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+        ticker.tick().await;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = shutdown_rx.changed() => return,
+            }
+            if !due(&targets, acme.renew_before()) {
+                continue;
+            }
+
+            info!("[ACME] Renewing the certificate for {:?}", acme.domains());
+            match issue(&acme, &targets).await {
+                Ok(()) => {
+                    let _ = servers.reload_certificates(None).await;
+                }
+                Err(e) => error!("[ACME] Failed to renew the certificate: {:#}", e),
+            }
+        }
+    });
+}
+
+/// Runs one order to completion and writes the certificate to `targets`.
+async fn issue(acme: &AcmeConfig, targets: &[(PathBuf, PathBuf)]) -> Result<()> {
+    let mut account = Account::open(
+        acme.directory(),
+        Path::new(acme.account_key()),
+        acme.email(),
+    )
+    .await?;
+    let (order_url, order) = account.new_order(acme.domains()).await?;
+
+    let responder = Responder::start(acme).await?;
+    let result = async {
+        let authorizations = order["authorizations"]
+            .as_array()
+            .context("Order lists no authorizations")?;
+        for authorization in authorizations {
+            let url = authorization.as_str().context("Bad authorization URL")?;
+            authorize(&mut account, url, acme.challenge()).await?;
+        }
+
+        let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+        let csr =
+            rcgen::CertificateParams::new(acme.domains().to_vec())?.serialize_request(&key)?;
+        let finalize = order["finalize"]
+            .as_str()
+            .context("Order has no finalize URL")?;
+        account
+            .post(
+                finalize,
+                Some(&json!({ "csr": Base64UrlUnpadded::encode_string(csr.der()) })),
+            )
+            .await?;
+
+        let order = poll(&mut account, &order_url, "order").await?;
+        let certificate = order["certificate"]
+            .as_str()
+            .context("Valid order has no certificate URL")?;
+        let chain = account.post(certificate, None).await?.text();
+        anyhow::Ok((chain, key.serialize_pem()))
+    }
+    .await;
+    responder.stop();
+    let (chain, key) = result?;
+
+    for (cert_path, key_path) in targets {
+        write_private(key_path, key.as_bytes())
+            .with_context(|| format!("Failed to write {:?}", key_path))?;
+        write_file(cert_path, chain.as_bytes())
+            .with_context(|| format!("Failed to write {:?}", cert_path))?;
+        info!("[ACME] Saved certificate to {:?}", cert_path);
+    }
+    Ok(())
+}
+
+/// Answers the `kind` challenge of the authorization at `url` and waits
+/// for the CA to validate it.
+async fn authorize(account: &mut Account, url: &str, kind: AcmeChallenge) -> Result<()> {
+    let authorization = account.post(url, None).await?.json()?;
+    if authorization["status"] == "valid" {
+        return Ok(());
+    }
+    let domain = authorization["identifier"]["value"]
+        .as_str()
+        .context("Authorization has no identifier")?
+        .to_string();
+
+    let wanted = match kind {
+        AcmeChallenge::Http01 => "http-01",
+        AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+    };
+    let challenge = authorization["challenges"]
+        .as_array()
+        .and_then(|challenges| challenges.iter().find(|c| c["type"] == wanted))
+        .with_context(|| format!("CA offers no {} challenge for {}", wanted, domain))?;
+    let token = challenge["token"]
+        .as_str()
+        .context("Challenge has no token")?;
+    let challenge_url = challenge["url"].as_str().context("Challenge has no URL")?;
+    let key_authorization = account.key_authorization(token);
+
+    match kind {
+        AcmeChallenge::Http01 => {
+            TOKENS.insert(token.to_string(), key_authorization);
+        }
+        AcmeChallenge::TlsAlpn01 => {
+            let digest = Sha256::digest(key_authorization.as_bytes());
+            let mut params = rcgen::CertificateParams::new(vec![domain.clone()])?;
+            params
+                .custom_extensions
+                .push(rcgen::CustomExtension::new_acme_identifier(&digest));
+            let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+            let cert = params.self_signed(&key)?;
+            let certified = build_certified_key(
+                vec![CertificateDer::from(cert.der().to_vec())],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+            )?;
+            CHALLENGES.insert(normalize_name(&domain), certified);
+        }
+    }
+
+    debug!("[ACME] Answering {} for {}", wanted, domain);
+    account.post(challenge_url, Some(&json!({}))).await?;
+    poll(account, url, "authorization")
+        .await
+        .with_context(|| format!("Validation of {} failed", domain))?;
+    Ok(())
+}
+
+/// Polls the order or authorization at `url` until it is valid.
+async fn poll(account: &mut Account, url: &str, what: &str) -> Result<Value> {
+    for _ in 0..POLL_ATTEMPTS {
+        let body = account.post(url, None).await?.json()?;
+        match body["status"].as_str() {
+            Some("valid") => return Ok(body),
+            Some("invalid") => {
+                let detail = body["challenges"]
+                    .as_array()
+                    .and_then(|challenges| {
+                        challenges
+                            .iter()
+                            .find_map(|c| c["error"]["detail"].as_str())
+                    })
+                    .or_else(|| body["error"]["detail"].as_str())
+                    .unwrap_or("no detail");
+                bail!("{} is invalid: {}", what, detail);
+            }
+            _ => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+    bail!(
+        "{} still pending after {:?}",
+        what,
+        POLL_INTERVAL * POLL_ATTEMPTS
+    )
+}
+
+/// Listeners answering challenges while an order is open.
+struct Responder {
+    task: Option<JoinHandle<()>>,
+}
+
+impl Responder {
+    async fn start(acme: &AcmeConfig) -> Result<Self> {
+        let task = match acme.challenge() {
+            AcmeChallenge::Http01 => {
+                let listener = TcpListener::bind(acme.http_listen())
+                    .await
+                    .with_context(|| format!("Failed to listen on {}", acme.http_listen()))?;
+                Some(tokio::spawn(serve_http(listener)))
+            }
+            AcmeChallenge::TlsAlpn01 => match TcpListener::bind(acme.tls_alpn_listen()).await {
+                Ok(listener) => Some(tokio::spawn(serve_tls_alpn(listener))),
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                    debug!(
+                        "[ACME] {} is taken, leaving TLS-ALPN-01 to the Trojan listener",
+                        acme.tls_alpn_listen()
+                    );
+                    None
+                }
+                Err(e) => bail!("Failed to listen on {}: {}", acme.tls_alpn_listen(), e),
+            },
+        };
+        Ok(Self { task })
+    }
+
+    fn stop(self) {
+        if let Some(task) = self.task {
+            task.abort();
+        }
+        TOKENS.clear();
+        CHALLENGES.clear();
+    }
+}
+
+/// Serves `/.well-known/acme-challenge/<token>`.
+async fn serve_http(listener: TcpListener) {
+    loop {
+        let Ok((stream, peer)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(async move {
+            if let Err(e) = answer_http(stream).await {
+                debug!("[ACME] HTTP-01 request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Answers one HTTP-01 request with the key authorization of its token,
+/// or 404. A peer that sends no request head within `HTTP_READ_TIMEOUT`
+/// is dropped.
+async fn answer_http<S>(mut stream: S) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = Vec::new();
+    let read = tokio::time::timeout(HTTP_READ_TIMEOUT, async {
+        let mut buf = [0u8; 1024];
+        while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 8 * 1024 {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(false);
+            }
+            head.extend_from_slice(&buf[..n]);
+        }
+        std::io::Result::Ok(true)
+    })
+    .await;
+    match read {
+        Ok(Ok(true)) => {}
+        Ok(Ok(false)) => return Ok(()),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Err(std::io::ErrorKind::TimedOut.into()),
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let key_authorization = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|path| path.strip_prefix("/.well-known/acme-challenge/"))
+        .and_then(|token| TOKENS.get(token).map(|key| key.clone()));
+
+    let response = match key_authorization {
+        Some(body) => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        ),
+        None => {
+            String::from("HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+        }
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Completes TLS-ALPN-01 validation handshakes until stopped.
+async fn serve_tls_alpn(listener: TcpListener) {
+    loop {
+        let Ok((stream, peer)) = listener.accept().await else {
+            continue;
+        };
+        tokio::spawn(async move {
+            let acceptor =
+                tokio_rustls::LazyConfigAcceptor::new(rustls::server::Acceptor::default(), stream);
+            let Ok(start) = acceptor.await else {
+                return;
+            };
+            let Some(config) = challenge_config(&start.client_hello()) else {
+                debug!("[ACME] {} is not validating a pending challenge", peer);
+                return;
+            };
+            if let Ok(mut tls) = start.into_stream(config).await {
+                let _ = tls.shutdown().await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Datelike;
+
+    #[test]
+    fn validation_offers_acme_tls_alone() {
+        let alpn = |protocols: &'static [&'static [u8]]| Some(protocols.iter().copied());
+        assert!(is_validation(alpn(&[b"acme-tls/1"])));
+        assert!(!is_validation(alpn(&[b"acme-tls/1", b"h2"])));
+        assert!(!is_validation(alpn(&[b"h2", b"acme-tls/1"])));
+        assert!(!is_validation(alpn(&[b"http/1.1"])));
+        assert!(!is_validation(alpn(&[])));
+        assert!(!is_validation(None::<std::iter::Empty<&[u8]>>));
+    }
+
+    async fn get(path: &str) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let request = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", path);
+        client.write_all(request.as_bytes()).await.unwrap();
+        answer_http(server).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn answers_pending_tokens_only() {
+        TOKENS.insert(
+            String::from("answers-token"),
+            String::from("answers-token.thumb"),
+        );
+
+        let response = get("/.well-known/acme-challenge/answers-token").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nanswers-token.thumb"));
+
+        let response = get("/.well-known/acme-challenge/unknown").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        let response = get("/answers-token").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        TOKENS.remove("answers-token");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_silent_http_peers() {
+        let (_client, server) = tokio::io::duplex(4096);
+        let started = tokio::time::Instant::now();
+        let e = answer_http(server).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(started.elapsed(), HTTP_READ_TIMEOUT);
+    }
+
+    /// Writes a certificate valid until `days` from now, and its key,
+    /// under `dir`.
+    fn write_cert(dir: &Path, name: &str, days: i64) -> (PathBuf, PathBuf) {
+        let until = chrono::Utc::now() + chrono::Duration::days(days);
+        let mut params = rcgen::CertificateParams::new(vec![String::from("example.com")]).unwrap();
+        params.not_after =
+            rcgen::date_time_ymd(until.year(), until.month() as u8, until.day() as u8);
+        let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let cert = params.self_signed(&key).unwrap();
+
+        let cert_path = dir.join(format!("{}.crt", name));
+        let key_path = dir.join(format!("{}.key", name));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    #[test]
+    fn due_when_missing_or_near_expiry() {
+        let dir = std::env::temp_dir().join(format!("iway-acme-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let renew_before = Duration::from_secs(30 * 24 * 60 * 60);
+
+        let fresh = write_cert(&dir, "fresh", 90);
+        assert!(!due(std::slice::from_ref(&fresh), renew_before));
+
+        let expiring = write_cert(&dir, "expiring", 5);
+        assert!(due(std::slice::from_ref(&expiring), renew_before));
+        assert!(due(&[fresh.clone(), expiring], renew_before));
+
+        std::fs::remove_file(&fresh.1).unwrap();
+        assert!(due(&[fresh], renew_before));
+        assert!(due(
+            &[(dir.join("absent.crt"), dir.join("absent.key"))],
+            renew_before
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,2 +1,4 @@
+#[cfg(feature = "trojan")]
 pub mod trojan;
+#[cfg(feature = "tuic")]
 pub mod tuic;
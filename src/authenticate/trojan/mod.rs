@@ -1,47 +1,56 @@
+use arc_swap::ArcSwap;
 use sha2::{Digest, Sha224};
+use std::sync::Arc;
 
 pub struct TrojanAuthenticationManager {
-    valid_hashes: Vec<String>,
+    valid_hashes: ArcSwap<Vec<(String, Arc<str>)>>,
 }
 
 impl TrojanAuthenticationManager {
-    pub fn new(passwords: Vec<String>) -> Self {
-        let valid_hashes = passwords
-            .into_iter()
-            .map(|pwd| {
-                let mut hasher = Sha224::new();
-                hasher.update(pwd.as_bytes());
-                let hash = format!("{:x}", hasher.finalize());
-                tracing::debug!(
-                    "[Trojan Auth] Computed hash for password '{}': {}",
-                    pwd,
-                    hash
-                );
-                hash
-            })
-            .collect();
-
-        Self { valid_hashes }
+    /// Takes `(identity, password)` pairs.
+    pub fn new(users: Vec<(String, String)>) -> Self {
+        Self {
+            valid_hashes: ArcSwap::from_pointee(hash_users(users)),
+        }
+    }
+
+    /// Swaps in a new user list. Connections that already authenticated
+    /// keep running under the identity they got.
+    pub fn replace(&self, users: Vec<(String, String)>) {
+        self.valid_hashes.store(Arc::new(hash_users(users)));
     }
 
-    pub fn verify_password_hash(&self, received_hash: &str) -> bool {
-        let result = self
-            .valid_hashes
-            .iter()
-            .any(|valid_hash| constant_time_eq(valid_hash.as_bytes(), received_hash.as_bytes()));
-
-        if !result {
-            tracing::warn!(
-                "[Trojan Auth] No matching hash found. Valid hashes: {:?}, Received: {}",
-                self.valid_hashes,
-                received_hash
-            );
+    /// Returns the identity owning `received_hash`, if any.
+    pub fn identify(&self, received_hash: &str) -> Option<Arc<str>> {
+        // Compare against every entry so timing doesn't reveal which matched.
+        let mut result = None;
+        for (valid_hash, identity) in self.valid_hashes.load().iter() {
+            if constant_time_eq(valid_hash.as_bytes(), received_hash.as_bytes()) {
+                result = Some(Arc::clone(identity));
+            }
+        }
+
+        if result.is_none() {
+            tracing::debug!("[Trojan Auth] No user matches the received hash");
         }
 
         result
     }
 }
 
+fn hash_users(users: Vec<(String, String)>) -> Vec<(String, Arc<str>)> {
+    users
+        .into_iter()
+        .map(|(identity, pwd)| {
+            let mut hasher = Sha224::new();
+            hasher.update(pwd.as_bytes());
+            let hash = format!("{:x}", hasher.finalize());
+            tracing::debug!("[Trojan Auth] Loaded password for {}", identity);
+            (hash, Arc::from(identity))
+        })
+        .collect()
+}
+
 fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
     if a.len() != b.len() {
         return false;
@@ -1,26 +1,52 @@
 use anyhow::{Result, anyhow};
-use std::{fmt::Debug, sync::Arc};
+use std::{collections::HashSet, fmt::Debug, sync::Arc};
 
 use dashmap::DashMap;
 use uuid::Uuid;
 
-#[derive(Debug)]
+/// Clones share the user table, so a replaced list reaches all of them.
+#[derive(Debug, Clone)]
 pub struct TuicAuthenticationManager {
     users: Arc<DashMap<Uuid, Arc<[u8]>>>,
+    identities: Arc<DashMap<Uuid, Arc<str>>>,
 }
 
 impl TuicAuthenticationManager {
     pub fn new<I>(user_entries: I) -> Self
     where
-        I: IntoIterator<Item = (Uuid, Arc<[u8]>)>,
+        I: IntoIterator<Item = (Uuid, Arc<[u8]>, Arc<str>)>,
     {
         let users: Arc<DashMap<Uuid, Arc<[u8]>>> = Arc::new(DashMap::new());
+        let identities: Arc<DashMap<Uuid, Arc<str>>> = Arc::new(DashMap::new());
 
-        for (uuid, password_bytes) in user_entries {
+        for (uuid, password_bytes, identity) in user_entries {
             users.insert(uuid, password_bytes);
+            identities.insert(uuid, identity);
         }
 
-        TuicAuthenticationManager { users }
+        TuicAuthenticationManager { users, identities }
+    }
+
+    /// Swaps in a new user list. Users that stay keep working throughout;
+    /// connections that already authenticated are not affected.
+    pub fn replace<I>(&self, user_entries: I)
+    where
+        I: IntoIterator<Item = (Uuid, Arc<[u8]>, Arc<str>)>,
+    {
+        let mut kept = HashSet::new();
+        for (uuid, password_bytes, identity) in user_entries {
+            self.users.insert(uuid, password_bytes);
+            self.identities.insert(uuid, identity);
+            kept.insert(uuid);
+        }
+
+        self.users.retain(|uuid, _| kept.contains(uuid));
+        self.identities.retain(|uuid, _| kept.contains(uuid));
+    }
+
+    /// The identity `uuid` is accounted under.
+    pub fn identity(&self, uuid: &Uuid) -> Option<Arc<str>> {
+        self.identities.get(uuid).map(|value| Arc::clone(&*value))
     }
 
     pub fn password(&self, uuid: &Uuid) -> Result<Arc<[u8]>> {
@@ -1,7 +1,12 @@
 use anyhow::{Context, Result};
+#[cfg(any(feature = "trojan", feature = "snell"))]
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UserConfig {
@@ -19,6 +24,365 @@ impl UserConfig {
     }
 }
 
+/// One person's credentials across protocols, accounted under `name` in
+/// sessions and stats no matter which protocol they connect with.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IdentityConfig {
+    name: String,
+
+    /// TUIC UUID; requires `password`.
+    uuid: Option<String>,
+
+    /// TUIC password.
+    password: Option<String>,
+
+    trojan_password: Option<String>,
+
+    /// TUIC connections this identity may hold at once.
+    max_connections: Option<usize>,
+
+    /// Concurrent TUIC TCP streams across all of the identity's connections.
+    max_streams: Option<usize>,
+
+    /// Groups `[[router.rules]]` can name in `user_group`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    groups: Vec<String>,
+}
+
+impl IdentityConfig {
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn max_streams(&self) -> Option<usize> {
+        self.max_streams
+    }
+
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+}
+
+/// A protocol credential together with the identity it is accounted under.
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+#[derive(Debug, Clone, Copy)]
+pub struct Credential<'a> {
+    pub identity: &'a str,
+    #[cfg(feature = "tuic")]
+    pub uuid: &'a str,
+    pub password: &'a str,
+}
+
+/// An additional certificate chain, e.g. an RSA chain next to the primary
+/// ECDSA one. The chain matching the client's signature algorithms is
+/// served, preferring ECDSA. A chain with `server_names` is only served to
+/// clients asking for one of them (`*.` matches any subdomain), and is
+/// preferred over the others for those clients.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CertificateConfig {
+    cert_path: String,
+    key_path: String,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    server_names: Vec<String>,
+}
+
+impl CertificateConfig {
+    pub fn cert_path(&self) -> &str {
+        &self.cert_path
+    }
+
+    pub fn key_path(&self) -> &str {
+        &self.key_path
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn server_names(&self) -> &[String] {
+        &self.server_names
+    }
+}
+
+/// A `[[trojan.fallbacks]]` entry: where a client that fails Trojan
+/// authentication goes when its ALPN and HTTP path match. Entries are
+/// tried in order; a client none of them match goes to `fallback_addr`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FallbackConfig {
+    /// Negotiated ALPN protocol, e.g. "h2" or "http/1.1"; unset matches
+    /// any, including none. Naming one makes the listener offer it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    alpn: Option<String>,
+
+    /// Prefix of the path in an HTTP/1.x request line; unset matches any.
+    /// HTTP/2 requests carry no request line and never match a path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+
+    dest: String,
+
+    /// PROXY protocol header sent ahead of the client's bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+#[cfg(feature = "trojan")]
+impl FallbackConfig {
+    pub fn alpn(&self) -> Option<&str> {
+        self.alpn.as_deref()
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    pub fn dest(&self) -> &str {
+        &self.dest
+    }
+
+    pub fn proxy_protocol(&self) -> Option<ProxyProtocolVersion> {
+        self.proxy_protocol
+    }
+}
+
+/// `[trojan.upstream]`: another Trojan server that accepted connections
+/// are forwarded to instead of being dialed from here, making this node
+/// the edge hop of a chain.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrojanUpstreamConfig {
+    /// `host:port` of the upstream server.
+    server: String,
+
+    password: String,
+
+    /// Name sent as SNI and checked against the upstream's certificate;
+    /// defaults to the host part of `server`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sni: Option<String>,
+
+    /// PEM file of CA certificates trusted for the upstream instead of the
+    /// system roots, e.g. a self-signed certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_path: Option<String>,
+}
+
+#[cfg(feature = "trojan")]
+impl TrojanUpstreamConfig {
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+
+    pub fn sni(&self) -> Option<&str> {
+        self.sni.as_deref()
+    }
+
+    pub fn ca_path(&self) -> Option<&str> {
+        self.ca_path.as_deref()
+    }
+}
+
+/// `[trojan.reality]`: borrow the identity of another TLS site. Clients
+/// that prove they hold the key get the proxy; everybody else, active
+/// probes included, is spliced to `dest` and sees the real site.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RealityConfig {
+    /// `host:port` of the site whose certificate probers see.
+    dest: String,
+
+    /// SNIs clients may ask for; they should be names `dest` serves.
+    server_names: Vec<String>,
+
+    /// X25519 private key, base64url as printed by `xray x25519`, or hex.
+    private_key: String,
+
+    /// Short IDs clients may present, as up to 16 hex digits each; "" is
+    /// the all-zero ID.
+    short_ids: Vec<String>,
+
+    /// Seconds a client's clock may be off by; unset or 0 skips the check.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_time_diff: Option<u64>,
+}
+
+#[cfg(feature = "trojan")]
+impl RealityConfig {
+    pub fn dest(&self) -> &str {
+        &self.dest
+    }
+
+    pub fn server_names(&self) -> &[String] {
+        &self.server_names
+    }
+
+    pub fn private_key(&self) -> &str {
+        &self.private_key
+    }
+
+    pub fn short_ids(&self) -> &[String] {
+        &self.short_ids
+    }
+
+    pub fn max_time_diff(&self) -> Option<Duration> {
+        self.max_time_diff
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+}
+
+/// `[trojan.udp]`: caps on each UDP association, so one client cannot
+/// saturate the box.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TrojanUdpConfig {
+    /// Largest payload a client frame may declare; a bigger one closes the
+    /// association.
+    #[serde(default = "default_trojan_udp_max_frame_size")]
+    max_frame_size: usize,
+
+    /// Packets per second a client may send; the excess is dropped. Unset
+    /// means no limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_packets_per_sec: Option<u32>,
+
+    /// Bytes an association may relay, both ways together, before it is
+    /// closed. Unset means no limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<u64>,
+}
+
+impl TrojanUdpConfig {
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn max_packets_per_sec(&self) -> Option<u32> {
+        self.max_packets_per_sec.filter(|max| *max > 0)
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes.filter(|max| *max > 0)
+    }
+}
+
+impl Default for TrojanUdpConfig {
+    fn default() -> Self {
+        Self {
+            max_frame_size: default_trojan_udp_max_frame_size(),
+            max_packets_per_sec: None,
+            max_bytes: None,
+        }
+    }
+}
+
+/// `[trojan.sniff]`, `[tuic.sniff]`: read the host name back out of a
+/// CONNECT's first bytes (TLS SNI or HTTP `Host`) and connect to it
+/// instead of the requested address.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct SniffConfig {
+    #[serde(default)]
+    enabled: bool,
+
+    /// Rewrite requests that already name a domain too, not only IP ones.
+    #[serde(default)]
+    override_domains: bool,
+
+    /// Milliseconds to wait for the client's first bytes; protocols where
+    /// the server speaks first are held up this long. Defaults to 300.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout_ms: Option<u64>,
+}
+
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+impl SniffConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn override_domains(&self) -> bool {
+        self.override_domains
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(
+            self.timeout_ms
+                .filter(|ms| *ms > 0)
+                .unwrap_or(DEFAULT_SNIFF_TIMEOUT_MS),
+        )
+    }
+}
+
+/// `[trojan.socket]`: options set on accepted client sockets and on the
+/// connections opened for them.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TcpSocketConfig {
+    /// Disable Nagle's algorithm, so small writes go out at once.
+    #[serde(default = "default_tcp_nodelay")]
+    nodelay: bool,
+
+    /// Seconds of idleness before keepalive probes start, and between
+    /// them. Unset leaves keepalive off.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keepalive: Option<u64>,
+
+    /// TCP Fast Open, accepting and sending data in the SYN. Linux only;
+    /// the kernel must allow it in `net.ipv4.tcp_fastopen`.
+    #[serde(default)]
+    fast_open: bool,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recv_buffer_size: Option<usize>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    send_buffer_size: Option<usize>,
+}
+
+#[cfg(feature = "trojan")]
+impl TcpSocketConfig {
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.keepalive
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn fast_open(&self) -> bool {
+        self.fast_open
+    }
+
+    pub fn recv_buffer_size(&self) -> Option<usize> {
+        self.recv_buffer_size.filter(|size| *size > 0)
+    }
+
+    pub fn send_buffer_size(&self) -> Option<usize> {
+        self.send_buffer_size.filter(|size| *size > 0)
+    }
+}
+
+impl Default for TcpSocketConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: default_tcp_nodelay(),
+            keepalive: None,
+            fast_open: false,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct TrojanConfig {
     #[serde(default = "default_trojan_enabled")]
@@ -27,17 +391,116 @@ pub struct TrojanConfig {
     #[serde(default = "default_server_addr")]
     server_addr: String,
 
+    /// More ports, or `first-last` ranges, bound on the host of
+    /// `server_addr` next to its own port and served alike. A
+    /// `unix:///path` entry adds a Unix socket that takes plaintext Trojan
+    /// from a TLS terminator in front, such as nginx.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    listen: Vec<String>,
+
     #[serde(default = "default_cert_path")]
     cert_path: String,
 
     #[serde(default = "default_key_path")]
     key_path: String,
 
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    certificates: Vec<CertificateConfig>,
+
     #[serde(default)]
     users: Vec<UserConfig>,
 
     #[serde(default = "default_trojan_fallback_addr")]
     fallback_addr: String,
+
+    /// Fallback servers picked by ALPN and request path, ahead of
+    /// `fallback_addr`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fallbacks: Vec<FallbackConfig>,
+
+    /// Seconds a client has from connecting to completing the TLS
+    /// handshake.
+    #[serde(default = "default_trojan_handshake_timeout")]
+    handshake_timeout: u64,
+
+    /// TLS handshakes allowed in flight at once; connections beyond it are
+    /// closed right away. Unset means no limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_handshakes: Option<usize>,
+
+    /// Client certificate verification. The subject CN of a verified
+    /// certificate becomes the user identity.
+    #[serde(default)]
+    client_auth: ClientAuth,
+
+    /// PEM bundle of the CAs client certificates must chain to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_ca_path: Option<String>,
+
+    /// Accept a request from a client with a verified certificate whatever
+    /// password it carries, instead of checking the password as well.
+    #[serde(default)]
+    client_cert_replaces_password: bool,
+
+    /// Expect a PROXY protocol (v1 or v2) header on every connection from
+    /// `proxy_protocol_from`, as sent by HAProxy or nginx `stream` in front
+    /// of the listener, and take the client address from it. Connections
+    /// from there without one are dropped.
+    #[serde(default)]
+    proxy_protocol: bool,
+
+    /// Addresses or CIDR networks of the load balancers whose PROXY headers
+    /// are trusted; other peers are served under their own address.
+    /// Required with `proxy_protocol`. Headers on the Unix socket
+    /// listeners are always trusted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    proxy_protocol_from: Vec<String>,
+
+    /// PROXY protocol header sent ahead of the client's bytes to the
+    /// fallback server and `sni_backend`; unset sends none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fallback_proxy_protocol: Option<ProxyProtocolVersion>,
+
+    /// Hostnames (or `*.domain` wildcards) the proxy answers to; other SNIs
+    /// go to `sni_backend` when it is set.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    server_names: Vec<String>,
+
+    /// TLS server that receives, untouched, connections whose SNI is not in
+    /// `server_names` or that are not TLS at all, so the port can be shared
+    /// with a real website.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sni_backend: Option<String>,
+
+    /// How UDP associations map onto outbound sockets.
+    #[serde(default)]
+    udp_nat: UdpNatMode,
+
+    /// Destinations a symmetric association keeps a socket for at once;
+    /// the least recently used one is dropped to make room.
+    #[serde(default = "default_trojan_udp_nat_max_mappings")]
+    udp_nat_max_mappings: usize,
+
+    #[serde(default)]
+    udp: TrojanUdpConfig,
+
+    #[serde(default)]
+    sniff: SniffConfig,
+
+    #[serde(default)]
+    socket: TcpSocketConfig,
+
+    /// REALITY mode; when set, the certificate files are not used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reality: Option<RealityConfig>,
+
+    /// Upstream Trojan server that CONNECT requests are relayed through.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    upstream: Option<TrojanUpstreamConfig>,
+
+    /// Inbound tag that `[[policies]]` entries refer to; defaults to "trojan".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
 }
 
 impl Default for TrojanConfig {
@@ -45,10 +508,31 @@ impl Default for TrojanConfig {
         Self {
             enabled: false,
             server_addr: DEFAULT_SERVER_ADDR.to_string(),
+            listen: vec![],
             cert_path: DEFAULT_CERT_PATH.to_string(),
             key_path: DEFAULT_KEY_PATH.to_string(),
+            certificates: vec![],
             users: vec![],
             fallback_addr: "127.0.0.1:80".to_string(),
+            fallbacks: vec![],
+            handshake_timeout: default_trojan_handshake_timeout(),
+            max_handshakes: None,
+            client_auth: ClientAuth::default(),
+            client_ca_path: None,
+            client_cert_replaces_password: false,
+            proxy_protocol: false,
+            proxy_protocol_from: vec![],
+            fallback_proxy_protocol: None,
+            server_names: vec![],
+            sni_backend: None,
+            udp_nat: UdpNatMode::default(),
+            udp_nat_max_mappings: default_trojan_udp_nat_max_mappings(),
+            udp: TrojanUdpConfig::default(),
+            sniff: SniffConfig::default(),
+            socket: TcpSocketConfig::default(),
+            reality: None,
+            upstream: None,
+            tag: None,
         }
     }
 }
@@ -63,6 +547,10 @@ impl TrojanConfig {
         &self.server_addr
     }
 
+    pub fn listen(&self) -> &[String] {
+        &self.listen
+    }
+
     pub fn cert_path(&self) -> &str {
         &self.cert_path
     }
@@ -71,6 +559,10 @@ impl TrojanConfig {
         &self.key_path
     }
 
+    pub fn certificates(&self) -> &[CertificateConfig] {
+        &self.certificates
+    }
+
     pub fn users(&self) -> &[UserConfig] {
         &self.users
     }
@@ -78,6 +570,135 @@ impl TrojanConfig {
     pub fn fallback_addr(&self) -> &str {
         &self.fallback_addr
     }
+
+    #[cfg(feature = "trojan")]
+    pub fn fallbacks(&self) -> &[FallbackConfig] {
+        &self.fallbacks
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout.max(1))
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn max_handshakes(&self) -> Option<usize> {
+        self.max_handshakes.map(|max| max.max(1))
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn client_auth(&self) -> ClientAuth {
+        self.client_auth
+    }
+
+    pub fn client_ca_path(&self) -> Option<&str> {
+        self.client_ca_path.as_deref()
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn client_cert_replaces_password(&self) -> bool {
+        self.client_cert_replaces_password
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    /// The `proxy_protocol_from` entries that parse; `validate` reports the
+    /// rest.
+    #[cfg(feature = "trojan")]
+    pub fn proxy_protocol_from(&self) -> Vec<IpNet> {
+        parse_nets(&self.proxy_protocol_from)
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn fallback_proxy_protocol(&self) -> Option<ProxyProtocolVersion> {
+        self.fallback_proxy_protocol
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn server_names(&self) -> &[String] {
+        &self.server_names
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn sni_backend(&self) -> Option<&str> {
+        self.sni_backend.as_deref()
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn udp_nat(&self) -> UdpNatMode {
+        self.udp_nat
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn udp_nat_max_mappings(&self) -> usize {
+        self.udp_nat_max_mappings.max(1)
+    }
+
+    pub fn udp(&self) -> &TrojanUdpConfig {
+        &self.udp
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn sniff(&self) -> &SniffConfig {
+        &self.sniff
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn socket(&self) -> &TcpSocketConfig {
+        &self.socket
+    }
+
+    pub fn reality(&self) -> Option<&RealityConfig> {
+        self.reality.as_ref()
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn upstream(&self) -> Option<&TrojanUpstreamConfig> {
+        self.upstream.as_ref()
+    }
+
+    pub fn tag(&self) -> &str {
+        self.tag.as_deref().unwrap_or("trojan")
+    }
+}
+
+/// NAT behavior of a Trojan UDP association.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UdpNatMode {
+    /// One outbound socket per association; replies from any host reach
+    /// the client, which peer-to-peer traffic relies on.
+    #[default]
+    FullCone,
+    /// One connected socket per destination; only the contacted host can
+    /// answer, and each destination sees a different source port.
+    Symmetric,
+}
+
+/// Whether the Trojan listener asks clients for a certificate.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    #[default]
+    None,
+    /// Clients may present a certificate; those that do must pass
+    /// verification.
+    Optional,
+    /// Handshakes without a valid client certificate fail.
+    Required,
+}
+
+/// PROXY protocol header format written in front of relayed connections.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    /// Human-readable text line.
+    V1,
+    /// Binary header.
+    V2,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -88,14 +709,69 @@ pub struct TuicConfig {
     #[serde(default = "default_server_addr")]
     server_addr: String,
 
+    /// More ports, or `first-last` ranges, bound on the host of
+    /// `server_addr` next to its own port and served alike.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    listen: Vec<String>,
+
     #[serde(default = "default_cert_path")]
     cert_path: String,
 
     #[serde(default = "default_key_path")]
     key_path: String,
 
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    certificates: Vec<CertificateConfig>,
+
     #[serde(default)]
     users: Vec<UserConfig>,
+
+    #[serde(default)]
+    masquerade: MasqueradeConfig,
+
+    /// Seconds between downstream bandwidth reports; unset disables them.
+    bandwidth_report_interval: Option<u64>,
+
+    /// Seconds between samples of each connection's RTT and congestion
+    /// state, shown by `iway ctl paths`; unset disables sampling.
+    path_stats_interval: Option<u64>,
+
+    #[serde(default)]
+    transport: TuicTransportConfig,
+
+    #[serde(default)]
+    sniff: SniffConfig,
+
+    /// Seconds a connection may stay open without a valid Authenticate.
+    #[serde(default = "default_tuic_auth_timeout")]
+    auth_timeout: u64,
+
+    /// Data-plane commands (Packet, Heartbeat, Dissociate) a connection may
+    /// send before authenticating; the connection is closed on the next
+    /// one. Unset lets them all wait for the Authenticate. Keep it high
+    /// enough for 0-RTT clients that send ahead of their Authenticate.
+    max_unauthenticated_commands: Option<u32>,
+
+    /// Accept 0-RTT early data. Early data can be replayed by an attacker,
+    /// so replay-sensitive deployments should turn this off.
+    #[serde(default = "default_tuic_zero_rtt")]
+    zero_rtt: bool,
+
+    /// Endpoints sharing the port through SO_REUSEPORT, each with its own
+    /// accept loop. The kernel pins a client's flow to one of them, so a
+    /// client that changes address mid-connection is not recognised by the
+    /// others.
+    #[serde(default = "default_tuic_listeners")]
+    listeners: usize,
+
+    /// Seconds established connections get to finish on shutdown before
+    /// they are closed; `[shutdown] drain_timeout` caps it.
+    #[serde(default = "default_tuic_drain_timeout")]
+    drain_timeout: u64,
+
+    /// Inbound tag that `[[policies]]` entries refer to; defaults to "tuic".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
 }
 
 impl Default for TuicConfig {
@@ -103,9 +779,22 @@ impl Default for TuicConfig {
         Self {
             enabled: false,
             server_addr: DEFAULT_SERVER_ADDR.to_string(),
+            listen: vec![],
             cert_path: DEFAULT_CERT_PATH.to_string(),
             key_path: DEFAULT_KEY_PATH.to_string(),
+            certificates: vec![],
             users: vec![],
+            masquerade: MasqueradeConfig::default(),
+            bandwidth_report_interval: None,
+            path_stats_interval: None,
+            transport: TuicTransportConfig::default(),
+            sniff: SniffConfig::default(),
+            auth_timeout: default_tuic_auth_timeout(),
+            max_unauthenticated_commands: None,
+            zero_rtt: default_tuic_zero_rtt(),
+            listeners: default_tuic_listeners(),
+            drain_timeout: default_tuic_drain_timeout(),
+            tag: None,
         }
     }
 }
@@ -119,6 +808,10 @@ impl TuicConfig {
         &self.server_addr
     }
 
+    pub fn listen(&self) -> &[String] {
+        &self.listen
+    }
+
     pub fn cert_path(&self) -> &str {
         &self.cert_path
     }
@@ -127,101 +820,4059 @@ impl TuicConfig {
         &self.key_path
     }
 
+    pub fn certificates(&self) -> &[CertificateConfig] {
+        &self.certificates
+    }
+
     pub fn users(&self) -> &[UserConfig] {
         &self.users
     }
-}
 
-// DNS cache configuration removed.
+    #[cfg(feature = "tuic")]
+    pub fn masquerade(&self) -> &MasqueradeConfig {
+        &self.masquerade
+    }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct UdpSessionConfig {
-    #[serde(default = "default_udp_session_timeout")]
-    session_timeout: u64,
+    #[cfg(feature = "tuic")]
+    pub fn bandwidth_report_interval(&self) -> Option<Duration> {
+        self.bandwidth_report_interval
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
 
-    #[serde(default = "default_udp_socket_timeout")]
-    socket_timeout: u64,
+    #[cfg(feature = "tuic")]
+    pub fn path_stats_interval(&self) -> Option<Duration> {
+        self.path_stats_interval
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
 
-    max_sessions: Option<usize>,
+    pub fn transport(&self) -> &TuicTransportConfig {
+        &self.transport
+    }
 
-    max_reassembly_bytes_per_session: Option<usize>,
+    #[cfg(feature = "tuic")]
+    pub fn sniff(&self) -> &SniffConfig {
+        &self.sniff
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn auth_timeout(&self) -> Duration {
+        Duration::from_secs(self.auth_timeout.max(1))
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn max_unauthenticated_commands(&self) -> Option<u32> {
+        self.max_unauthenticated_commands
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn zero_rtt(&self) -> bool {
+        self.zero_rtt
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn listeners(&self) -> usize {
+        self.listeners.max(1)
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+
+    pub fn tag(&self) -> &str {
+        self.tag.as_deref().unwrap_or("tuic")
+    }
 }
 
-impl Default for UdpSessionConfig {
+/// QUIC transport parameters for the TUIC endpoint. Unset keys fall back to
+/// the profile defaults (windows and stream limits) or the built-in values.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TuicTransportConfig {
+    max_concurrent_bidi_streams: Option<u32>,
+
+    max_concurrent_uni_streams: Option<u32>,
+
+    stream_receive_window: Option<u32>,
+
+    receive_window: Option<u32>,
+
+    send_window: Option<u64>,
+
+    /// Seconds; 0 disables keep-alives.
+    keep_alive_interval: Option<u64>,
+
+    /// Seconds.
+    max_idle_timeout: Option<u64>,
+
+    initial_mtu: Option<u16>,
+
+    #[serde(default)]
+    congestion_control: CongestionControl,
+
+    /// Initial congestion window in bytes; the controller's default if unset.
+    initial_window: Option<u64>,
+}
+
+/// Congestion controller used by the TUIC endpoint.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CongestionControl {
+    #[default]
+    Bbr,
+    Cubic,
+    #[serde(alias = "newreno")]
+    NewReno,
+}
+
+impl TuicTransportConfig {
+    pub fn max_concurrent_bidi_streams(&self) -> Option<u32> {
+        self.max_concurrent_bidi_streams
+    }
+
+    pub fn max_concurrent_uni_streams(&self) -> Option<u32> {
+        self.max_concurrent_uni_streams
+    }
+
+    pub fn stream_receive_window(&self) -> Option<u32> {
+        self.stream_receive_window
+    }
+
+    pub fn receive_window(&self) -> Option<u32> {
+        self.receive_window
+    }
+
+    pub fn send_window(&self) -> Option<u64> {
+        self.send_window
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn keep_alive_interval(&self) -> Option<Duration> {
+        match self
+            .keep_alive_interval
+            .unwrap_or(DEFAULT_KEEP_ALIVE_INTERVAL)
+        {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn max_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.max_idle_timeout.unwrap_or(DEFAULT_MAX_IDLE_TIMEOUT))
+    }
+
+    pub fn initial_mtu(&self) -> Option<u16> {
+        self.initial_mtu
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn congestion_control(&self) -> CongestionControl {
+        self.congestion_control
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn initial_window(&self) -> Option<u64> {
+        self.initial_window
+    }
+}
+
+/// Answers QUIC connections that fail TUIC authentication like a plain
+/// HTTP/3 server would, instead of leaving them hanging.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MasqueradeConfig {
+    #[serde(default)]
+    enabled: bool,
+
+    #[serde(default = "default_masquerade_status")]
+    status: u16,
+}
+
+impl Default for MasqueradeConfig {
     fn default() -> Self {
         Self {
-            session_timeout: 30,
-            socket_timeout: 10,
-            max_sessions: None,
-            max_reassembly_bytes_per_session: None,
+            enabled: false,
+            status: default_masquerade_status(),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Default)]
-pub struct Config {
+#[cfg(feature = "tuic")]
+impl MasqueradeConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SnellConfig {
     #[serde(default)]
-    trojan: TrojanConfig,
+    enabled: bool,
+
+    #[serde(default = "default_snell_server_addr")]
+    server_addr: String,
 
     #[serde(default)]
-    tuic: TuicConfig,
+    psk: String,
+
+    /// Seconds a client has from connecting to sending its request.
+    #[serde(default = "default_snell_handshake_timeout")]
+    handshake_timeout: u64,
+
+    /// Expect a PROXY protocol (v1 or v2) header on every connection from
+    /// `proxy_protocol_from` and take the client address from it.
     #[serde(default)]
-    udp_session: UdpSessionConfig,
+    proxy_protocol: bool,
+
+    /// Addresses or CIDR networks of the load balancers whose PROXY headers
+    /// are trusted. Required with `proxy_protocol`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    proxy_protocol_from: Vec<String>,
+
+    /// Inbound tag that `[[policies]]` entries refer to; defaults to "snell".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
 }
 
-const DEFAULT_SERVER_ADDR: &str = "[::]:443";
-const DEFAULT_CERT_PATH: &str = "server.crt";
-const DEFAULT_KEY_PATH: &str = "server.key";
+impl Default for SnellConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            server_addr: default_snell_server_addr(),
+            psk: String::new(),
+            handshake_timeout: default_snell_handshake_timeout(),
+            proxy_protocol: false,
+            proxy_protocol_from: vec![],
+            tag: None,
+        }
+    }
+}
 
-fn default_server_addr() -> String {
-    String::from(DEFAULT_SERVER_ADDR)
+impl SnellConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn server_addr(&self) -> &str {
+        &self.server_addr
+    }
+
+    pub fn psk(&self) -> &str {
+        &self.psk
+    }
+
+    #[cfg(feature = "snell")]
+    pub fn handshake_timeout(&self) -> Duration {
+        Duration::from_secs(self.handshake_timeout.max(1))
+    }
+
+    #[cfg(feature = "snell")]
+    pub fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    /// The `proxy_protocol_from` entries that parse; `validate` reports the
+    /// rest.
+    #[cfg(feature = "snell")]
+    pub fn proxy_protocol_from(&self) -> Vec<IpNet> {
+        parse_nets(&self.proxy_protocol_from)
+    }
+
+    pub fn tag(&self) -> &str {
+        self.tag.as_deref().unwrap_or("snell")
+    }
 }
 
-fn default_cert_path() -> String {
-    String::from(DEFAULT_CERT_PATH)
+/// Local admin channel used by `iway ctl`: a unix socket, or a named pipe
+/// on Windows.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ControlConfig {
+    #[serde(default = "default_control_enabled")]
+    enabled: bool,
+
+    #[serde(default = "default_control_path")]
+    path: String,
 }
 
-fn default_key_path() -> String {
-    String::from(DEFAULT_KEY_PATH)
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_control_enabled(),
+            path: default_control_path(),
+        }
+    }
 }
 
-fn default_udp_session_timeout() -> u64 {
-    30
+impl ControlConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    #[cfg(feature = "control")]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
 }
 
-fn default_udp_socket_timeout() -> u64 {
-    10
+/// What a `[[schedule]]` entry does when its cron expression fires.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScheduledAction {
+    /// Gracefully restart the server(s); established connections survive.
+    Restart,
+    /// Re-read certificate files without touching the listener.
+    ReloadCerts,
+    /// Log a summary of sessions and UDP associations.
+    StatsRollup,
 }
 
-fn default_trojan_enabled() -> bool {
-    false
+/// A recurring maintenance job. `cron` uses six fields with seconds first,
+/// e.g. `"0 0 4 * * *"` for 04:00 local time every day.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScheduleConfig {
+    cron: String,
+
+    action: ScheduledAction,
+
+    /// Server to act on (`tuic`, `trojan`, `snell`, `control`); all when unset.
+    server: Option<String>,
 }
 
-fn default_tuic_enabled() -> bool {
-    false
+impl ScheduleConfig {
+    pub fn cron(&self) -> &str {
+        &self.cron
+    }
+
+    pub fn action(&self) -> ScheduledAction {
+        self.action
+    }
+
+    pub fn server(&self) -> Option<&str> {
+        self.server.as_deref()
+    }
 }
 
-fn default_trojan_fallback_addr() -> String {
-    String::from("127.0.0.1:80")
+/// A `[[reverse]]` entry: a public port whose connections are forwarded
+/// back through a TUIC client that registered to serve `name`, exposing a
+/// service on the client's side.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReverseConfig {
+    name: String,
+
+    /// Address the public port listens on.
+    listen: String,
+
+    /// Users that may serve the tunnel.
+    users: Vec<String>,
 }
 
-impl Config {
-    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let content = fs::read_to_string(path).context("Failed to read config file")?;
-        toml::from_str(&content).context("Failed to parse config file")
+impl ReverseConfig {
+    pub fn name(&self) -> &str {
+        &self.name
     }
 
-    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-        let content = toml::to_string_pretty(self).context("Failed to serialize config")?;
-        fs::write(path, content).context("Failed to write config file")?;
-        Ok(())
+    pub fn listen(&self) -> &str {
+        &self.listen
     }
 
-    pub fn trojan(&self) -> &TrojanConfig {
-        &self.trojan
+    pub fn users(&self) -> &[String] {
+        &self.users
     }
+}
 
-    pub fn tuic(&self) -> &TuicConfig {
-        &self.tuic
+/// A `[[hooks]]` entry: a URL the events it names are POSTed to as JSON,
+/// retried with backoff while it fails.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HookConfig {
+    /// `http://` or `https://` endpoint.
+    url: String,
+
+    /// Events to send; all of them when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    events: Vec<HookEvent>,
+
+    /// Extra request headers, e.g. `Authorization`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    headers: BTreeMap<String, String>,
+
+    /// Attempts after the first before an event is dropped.
+    #[serde(default = "default_hook_retries")]
+    retries: u32,
+
+    /// Seconds each attempt may take.
+    #[serde(default = "default_hook_timeout")]
+    timeout: u64,
+
+    /// Days before a certificate expires that `cert_expiring` is sent.
+    #[serde(default = "default_hook_cert_expiry_days")]
+    cert_expiry_days: i64,
+}
+
+impl HookConfig {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn wants(&self, event: HookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.max(1))
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn cert_expiry_days(&self) -> i64 {
+        self.cert_expiry_days
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HookEvent {
+    /// A user authenticated.
+    Connected,
+    AuthFailed,
+    QuotaExceeded,
+    /// An address was banned by `[banlist]`.
+    Banned,
+    /// A served certificate is within `cert_expiry_days` of expiring.
+    CertExpiring,
+}
+
+/// Egress rules for connections arriving on the inbound tagged `inbound`,
+/// or, with `user` set instead, for one identity on every inbound. A
+/// connection has to pass both its inbound's and its user's policy.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PolicyConfig {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    inbound: String,
+
+    user: Option<String>,
+
+    /// Destinations to refuse: CIDR blocks, domain suffixes, or one of
+    /// `private`, `loopback` and `link-local`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    block: Vec<String>,
+
+    /// Destination ports to refuse regardless of address.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    block_ports: Vec<u16>,
+
+    /// Relay UDP for clients on this inbound.
+    #[serde(default = "default_policy_allow_udp")]
+    allow_udp: bool,
+
+    /// Concurrent client sessions accepted on this inbound. Ignored on a
+    /// user policy; see `max_connections` in `[[users]]`.
+    max_connections: Option<usize>,
+}
+
+impl PolicyConfig {
+    pub fn inbound(&self) -> &str {
+        &self.inbound
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn block(&self) -> &[String] {
+        &self.block
+    }
+
+    pub fn block_ports(&self) -> &[u16] {
+        &self.block_ports
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn allow_udp(&self) -> bool {
+        self.allow_udp
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+}
+
+/// `[router]`: ordered rules picking how each outbound connect leaves:
+/// dialed from here (`direct`), refused (`block`), or through a named
+/// `[[outbounds]]` entry. The first matching rule wins.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RouterConfig {
+    /// Outbound for connects no rule matches. Unset leaves them to the
+    /// inbound: through its `upstream` if it has one, direct otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rules: Vec<RouteRuleConfig>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rule_sets: Vec<RuleSetConfig>,
+}
+
+impl RouterConfig {
+    pub fn default_outbound(&self) -> Option<&str> {
+        self.default.as_deref()
+    }
+
+    pub fn rules(&self) -> &[RouteRuleConfig] {
+        &self.rules
+    }
+
+    pub fn rule_sets(&self) -> &[RuleSetConfig] {
+        &self.rule_sets
+    }
+}
+
+/// A `[[router.rule_sets]]` entry: domains read from a file, for rules to
+/// refer to by `name` in their `rule_set`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RuleSetConfig {
+    name: String,
+
+    /// A v2ray `geosite.dat` when `code` is set, a domain list otherwise:
+    /// one entry per line, `domain:`, `full:`, `keyword:` or `regexp:`
+    /// before it, a bare domain meaning `domain:`.
+    path: String,
+
+    /// Category to take from the `geosite.dat`, e.g. `cn` or `google`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+}
+
+impl RuleSetConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn code(&self) -> Option<&str> {
+        self.code.as_deref()
+    }
+}
+
+/// A `[[router.rules]]` entry. Each condition given must hold, and holds
+/// when any of its values does; a rule without conditions matches
+/// everything.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteRuleConfig {
+    /// The target host is one of these domains or below one of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    domain_suffix: Vec<String>,
+
+    /// The target host contains one of these.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    domain_keyword: Vec<String>,
+
+    /// The target host matches one of these regular expressions.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    domain_regex: Vec<String>,
+
+    /// The target host is in one of these `[[router.rule_sets]]`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rule_set: Vec<String>,
+
+    /// An address the target resolved to is in one of these networks.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ip_cidr: Vec<String>,
+
+    /// Target ports, single ("443") or inclusive ranges ("6000-7000").
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    port: Vec<String>,
+
+    /// Names of the authenticated user.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    user: Vec<String>,
+
+    /// Groups of the authenticated user, as its `[[users]]` entry lists.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    user_group: Vec<String>,
+
+    /// Inbound protocols: `tuic`, `trojan` or `snell`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    protocol: Vec<String>,
+
+    /// `direct`, `block`, or the name of an `[[outbounds]]` entry.
+    outbound: String,
+}
+
+impl RouteRuleConfig {
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn domain_suffix(&self) -> &[String] {
+        &self.domain_suffix
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn domain_keyword(&self) -> &[String] {
+        &self.domain_keyword
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn domain_regex(&self) -> &[String] {
+        &self.domain_regex
+    }
+
+    pub fn rule_set(&self) -> &[String] {
+        &self.rule_set
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn ip_cidr(&self) -> &[String] {
+        &self.ip_cidr
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn port(&self) -> &[String] {
+        &self.port
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn user(&self) -> &[String] {
+        &self.user
+    }
+
+    pub fn user_group(&self) -> &[String] {
+        &self.user_group
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn protocol(&self) -> &[String] {
+        &self.protocol
+    }
+
+    pub fn outbound(&self) -> &str {
+        &self.outbound
+    }
+}
+
+/// An `[[outbounds]]` entry: a named way out that routing rules can send
+/// connects through.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutboundConfig {
+    name: String,
+
+    #[serde(flatten)]
+    kind: OutboundKind,
+
+    #[serde(flatten)]
+    bind: BindConfig,
+}
+
+impl OutboundConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> &OutboundKind {
+        &self.kind
+    }
+
+    pub fn bind(&self) -> &BindConfig {
+        &self.bind
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OutboundKind {
+    /// Dialed from this host, e.g. through another uplink than the
+    /// default one.
+    Direct,
+    /// Another Trojan server, configured like `[trojan.upstream]`.
+    Trojan(TrojanUpstreamConfig),
+    Socks5(ProxyUpstreamConfig),
+    /// An HTTP proxy, tunneled through with `CONNECT`.
+    Http(ProxyUpstreamConfig),
+    /// Connects spread over other outbounds.
+    Group(GroupConfig),
+}
+
+/// An outbound group: connects are balanced over `members`, other
+/// `[[outbounds]]` entries, and a member that keeps failing is left out
+/// for a while.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupConfig {
+    members: Vec<String>,
+
+    #[serde(default)]
+    strategy: BalanceStrategy,
+
+    /// Failed connects in a row that eject a member.
+    #[serde(default = "default_group_max_failures")]
+    max_failures: u32,
+
+    /// Seconds an ejected member is left out.
+    #[serde(default = "default_group_eject_duration")]
+    eject_duration: u64,
+}
+
+impl GroupConfig {
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn strategy(&self) -> BalanceStrategy {
+        self.strategy
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn max_failures(&self) -> u32 {
+        self.max_failures.max(1)
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn eject_duration(&self) -> Duration {
+        Duration::from_secs(self.eject_duration.max(1))
+    }
+}
+
+/// How a group picks the member to try first.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BalanceStrategy {
+    /// Each member in turn.
+    #[default]
+    RoundRobin,
+    /// The member that connected fastest lately.
+    LeastRtt,
+}
+
+/// Where an outbound's connections leave from, for multi-homed hosts
+/// that must egress through a particular uplink. For upstreams, this is
+/// the connection to the upstream server.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct BindConfig {
+    /// Interface to send through (`SO_BINDTODEVICE`), Linux only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interface: Option<String>,
+
+    /// Local address to connect from. Targets only reachable over the
+    /// other IP version are refused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_address: Option<IpAddr>,
+}
+
+impl BindConfig {
+    pub fn interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+
+    pub fn source_address(&self) -> Option<IpAddr> {
+        self.source_address
+    }
+}
+
+/// A SOCKS5 or HTTP proxy an `[[outbounds]]` entry forwards to.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProxyUpstreamConfig {
+    /// `host:port` of the proxy.
+    server: String,
+
+    /// Credentials, for proxies that ask for them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+impl ProxyUpstreamConfig {
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    /// Username and password, when a username is set.
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn credentials(&self) -> Option<(&str, &str)> {
+        self.username
+            .as_deref()
+            .map(|username| (username, self.password.as_deref().unwrap_or_default()))
+    }
+}
+
+/// What happens to a client whose source address fails the GeoIP check.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GeoIpAction {
+    /// Drop the connection before the handshake.
+    #[default]
+    Reject,
+    /// Hand Trojan connections to the fallback server untouched; other
+    /// inbounds reject.
+    Fallback,
+}
+
+/// Source-address filtering against MaxMind (mmdb) databases.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct GeoIpConfig {
+    country_database: Option<String>,
+
+    asn_database: Option<String>,
+
+    /// ISO country codes to serve; any other country is refused.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    allow_countries: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    block_countries: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    block_asns: Vec<u32>,
+
+    #[serde(default)]
+    action: GeoIpAction,
+}
+
+impl GeoIpConfig {
+    pub fn country_database(&self) -> Option<&str> {
+        self.country_database.as_deref()
+    }
+
+    pub fn asn_database(&self) -> Option<&str> {
+        self.asn_database.as_deref()
+    }
+
+    pub fn allow_countries(&self) -> &[String] {
+        &self.allow_countries
+    }
+
+    pub fn block_countries(&self) -> &[String] {
+        &self.block_countries
+    }
+
+    pub fn block_asns(&self) -> &[u32] {
+        &self.block_asns
+    }
+
+    pub fn action(&self) -> GeoIpAction {
+        self.action
+    }
+}
+
+/// An operator-supplied WebAssembly module consulted before a session
+/// authenticates and before every outbound connect.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FilterConfig {
+    /// Path to the `.wasm` module.
+    module: String,
+
+    /// Fuel each hook call may burn before it is aborted and the request
+    /// rejected.
+    #[serde(default = "default_filter_fuel")]
+    fuel: u64,
+}
+
+impl FilterConfig {
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+
+    #[cfg_attr(not(feature = "wasm-filters"), allow(dead_code))]
+    pub fn fuel(&self) -> u64 {
+        self.fuel
+    }
+}
+
+/// A Rhai script that decides, per TCP connect, how the request is routed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScriptConfig {
+    /// Path to the script; it must define
+    /// `fn route(user, src, dst, port, host)`.
+    path: String,
+
+    /// Operations one `route` call may run before it is aborted and the
+    /// request blocked.
+    #[serde(default = "default_script_max_operations")]
+    max_operations: u64,
+}
+
+impl ScriptConfig {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    #[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+    pub fn max_operations(&self) -> u64 {
+        self.max_operations
+    }
+}
+
+/// Aggregate budget for everything the proxy sends, towards targets and
+/// back to clients, regardless of per-user limits.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EgressConfig {
+    /// Megabits per second; unset leaves egress unshaped.
+    rate_mbps: Option<u64>,
+
+    /// Bytes that may go out back to back before shaping kicks in.
+    #[serde(default = "default_egress_burst_size")]
+    burst_size: u64,
+}
+
+impl EgressConfig {
+    pub fn bytes_per_sec(&self) -> Option<u64> {
+        self.rate_mbps
+            .filter(|rate| *rate > 0)
+            .map(|rate| rate * 1_000_000 / 8)
+    }
+
+    pub fn burst_size(&self) -> u64 {
+        self.burst_size
+    }
+}
+
+impl Default for EgressConfig {
+    fn default() -> Self {
+        Self {
+            rate_mbps: None,
+            burst_size: default_egress_burst_size(),
+        }
+    }
+}
+
+/// Thresholds that suspend UDP relaying automatically while TCP keeps
+/// working; an operator can also toggle it over the control socket.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UdpGuardConfig {
+    /// Relayed UDP packets per second, in both directions, that trip the
+    /// switch.
+    max_pps: Option<u64>,
+
+    /// Resident memory in MiB that trips the switch.
+    max_memory_mb: Option<u64>,
+
+    /// Seconds UDP stays suspended after the last threshold breach.
+    #[serde(default = "default_udp_guard_cooldown")]
+    cooldown: u64,
+}
+
+impl UdpGuardConfig {
+    pub fn max_pps(&self) -> Option<u64> {
+        self.max_pps.filter(|pps| *pps > 0)
+    }
+
+    pub fn max_memory_bytes(&self) -> Option<u64> {
+        self.max_memory_mb
+            .filter(|mb| *mb > 0)
+            .map(|mb| mb * 1024 * 1024)
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.cooldown)
+    }
+}
+
+impl Default for UdpGuardConfig {
+    fn default() -> Self {
+        Self {
+            max_pps: None,
+            max_memory_mb: None,
+            cooldown: default_udp_guard_cooldown(),
+        }
+    }
+}
+
+/// `[port_policy]`: destination ports every inbound and user may or may
+/// not connect to, on top of the `block_ports` of `[[policies]]`.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PortPolicyConfig {
+    /// Whether ports in neither list are allowed.
+    #[serde(default)]
+    default: PortDefault,
+
+    /// Ports ("443") and ranges ("8000-8999") allowed when the default is
+    /// `deny`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    allow: Vec<String>,
+
+    /// Ports and ranges refused whatever the default, e.g. SMTP.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deny: Vec<String>,
+}
+
+impl PortPolicyConfig {
+    pub fn default_action(&self) -> PortDefault {
+        self.default
+    }
+
+    pub fn allow(&self) -> &[String] {
+        &self.allow
+    }
+
+    pub fn deny(&self) -> &[String] {
+        &self.deny
+    }
+}
+
+/// What happens to a port `[port_policy]` lists nowhere.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PortDefault {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// `[banlist]`: temporary bans of source addresses that keep failing
+/// authentication or the TLS handshake.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BanlistConfig {
+    /// Failures within `window` that get an address banned.
+    #[serde(default = "default_banlist_max_failures")]
+    max_failures: u32,
+
+    /// Seconds failures are counted over.
+    #[serde(default = "default_banlist_window")]
+    window: u64,
+
+    /// Seconds a ban lasts.
+    #[serde(default = "default_banlist_ban_duration")]
+    ban_duration: u64,
+
+    /// Addresses and networks never banned, e.g. a monitoring host.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    exempt: Vec<String>,
+
+    /// JSON file the bans are kept in so they outlast a restart; without
+    /// it they are lost with the process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+}
+
+impl BanlistConfig {
+    pub fn max_failures(&self) -> u32 {
+        self.max_failures.max(1)
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window.max(1))
+    }
+
+    pub fn ban_duration(&self) -> Duration {
+        Duration::from_secs(self.ban_duration.max(1))
+    }
+
+    pub fn exempt(&self) -> &[String] {
+        &self.exempt
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+}
+
+/// `[fingerprints]`: TLS ClientHello fingerprints of scanners and bots,
+/// matched on Trojan connections before the handshake.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct FingerprintConfig {
+    /// JA3 hashes, the MD5 of the JA3 string in hex.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ja3: Vec<String>,
+
+    /// JA4 fingerprints, e.g. "t13d1516h2_8daaf6152771_02713d6af862".
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    ja4: Vec<String>,
+
+    /// What happens to a connection that matches.
+    #[serde(default)]
+    action: FingerprintAction,
+}
+
+impl FingerprintConfig {
+    pub fn ja3(&self) -> &[String] {
+        &self.ja3
+    }
+
+    pub fn ja4(&self) -> &[String] {
+        &self.ja4
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn action(&self) -> FingerprintAction {
+        self.action
+    }
+}
+
+/// What happens to a connection whose ClientHello `[fingerprints]` lists.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FingerprintAction {
+    /// Reset the connection.
+    #[default]
+    Reset,
+    /// Hand it to the fallback, ClientHello and all.
+    Fallback,
+}
+
+/// How long each stage of a shutdown may take, in seconds. Listeners stop
+/// accepting first, then established connections get to finish, then
+/// pending session records are flushed, and finally endpoints are closed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ShutdownConfig {
+    #[serde(default = "default_shutdown_accept_timeout")]
+    accept_timeout: u64,
+
+    /// Once listeners are stopped, authenticated TUIC clients get a
+    /// going-away heartbeat as an early hint and the shutdown waits this
+    /// long before draining, so they open new connections elsewhere while
+    /// streams in flight finish here. Connections left after the drain are
+    /// closed with "server restarting, reconnect" either way. Unset skips
+    /// the hint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    migration_notice: Option<u64>,
+
+    /// Connections still open after this are kicked.
+    #[serde(default = "default_shutdown_drain_timeout")]
+    drain_timeout: u64,
+
+    #[serde(default = "default_shutdown_flush_timeout")]
+    flush_timeout: u64,
+
+    #[serde(default = "default_shutdown_close_timeout")]
+    close_timeout: u64,
+}
+
+impl ShutdownConfig {
+    pub fn accept_timeout(&self) -> Duration {
+        Duration::from_secs(self.accept_timeout)
+    }
+
+    pub fn migration_notice(&self) -> Option<Duration> {
+        self.migration_notice
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+
+    pub fn flush_timeout(&self) -> Duration {
+        Duration::from_secs(self.flush_timeout)
+    }
+
+    pub fn close_timeout(&self) -> Duration {
+        Duration::from_secs(self.close_timeout)
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            accept_timeout: default_shutdown_accept_timeout(),
+            migration_notice: None,
+            drain_timeout: default_shutdown_drain_timeout(),
+            flush_timeout: default_shutdown_flush_timeout(),
+            close_timeout: default_shutdown_close_timeout(),
+        }
+    }
+}
+
+/// Where completed-session records are written.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StatsExportFormat {
+    /// Append rows to a local CSV file.
+    #[default]
+    Csv,
+    /// Insert rows through the ClickHouse HTTP interface.
+    Clickhouse,
+}
+
+/// `[health]`: an HTTP endpoint for load balancer and Kubernetes probes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HealthConfig {
+    /// Address serving `/healthz` and `/readyz`.
+    #[serde(default = "default_health_listen")]
+    listen: String,
+}
+
+impl HealthConfig {
+    pub fn listen(&self) -> &str {
+        &self.listen
+    }
+}
+
+/// `[acme]`: certificates for `domains` obtained from an ACME CA (Let's
+/// Encrypt by default) and renewed before they expire. They are written to
+/// the `cert_path` and `key_path` of `[tuic]` and `[trojan]`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AcmeConfig {
+    domains: Vec<String>,
+
+    /// Contact address the CA sends expiry warnings to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    email: Option<String>,
+
+    /// Directory URL of the CA.
+    #[serde(default = "default_acme_directory")]
+    directory: String,
+
+    #[serde(default)]
+    challenge: AcmeChallenge,
+
+    /// Where HTTP-01 challenges are answered while an order is open.
+    #[serde(default = "default_acme_http_listen")]
+    http_listen: String,
+
+    /// Where TLS-ALPN-01 challenges are answered when no Trojan server
+    /// holds the port already.
+    #[serde(default = "default_acme_tls_alpn_listen")]
+    tls_alpn_listen: String,
+
+    /// PEM file holding the ACME account key, created on first use.
+    #[serde(default = "default_acme_account_key")]
+    account_key: String,
+
+    /// Days before expiry a certificate is renewed.
+    #[serde(default = "default_acme_renew_before")]
+    renew_before: u64,
+}
+
+impl AcmeConfig {
+    pub fn domains(&self) -> &[String] {
+        &self.domains
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn email(&self) -> Option<&str> {
+        self.email.as_deref()
+    }
+
+    pub fn directory(&self) -> &str {
+        &self.directory
+    }
+
+    pub fn challenge(&self) -> AcmeChallenge {
+        self.challenge
+    }
+
+    pub fn http_listen(&self) -> &str {
+        &self.http_listen
+    }
+
+    pub fn tls_alpn_listen(&self) -> &str {
+        &self.tls_alpn_listen
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn account_key(&self) -> &str {
+        &self.account_key
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn renew_before(&self) -> Duration {
+        Duration::from_secs(self.renew_before.max(1) * 24 * 60 * 60)
+    }
+}
+
+/// How the CA checks control of the domains.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AcmeChallenge {
+    /// A token served over plain HTTP on port 80.
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    /// A certificate served for the `acme-tls/1` ALPN on port 443.
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+/// `[tls]`: what the TLS handshakes of `[tuic]` and `[trojan]` offer.
+/// TLS 1.3 only; cipher suites and key exchange groups are listed in order
+/// of preference, by their rustls names.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TlsConfig {
+    #[serde(default = "default_tls_cipher_suites")]
+    cipher_suites: Vec<String>,
+
+    #[serde(default = "default_tls_key_exchange_groups")]
+    key_exchange_groups: Vec<String>,
+
+    #[serde(default)]
+    alpn: TlsAlpnConfig,
+
+    /// Stateless session tickets for Trojan clients; without them sessions
+    /// resume from a per-listener cache.
+    #[serde(default = "default_tls_session_tickets")]
+    session_tickets: bool,
+
+    /// Seconds between ticket key rotations, 60 to 604800; TLS 1.3 caps a
+    /// ticket's lifetime at seven days.
+    #[serde(default = "default_tls_ticket_rotation")]
+    ticket_rotation: u64,
+}
+
+impl TlsConfig {
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn cipher_suites(&self) -> &[String] {
+        &self.cipher_suites
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn key_exchange_groups(&self) -> &[String] {
+        &self.key_exchange_groups
+    }
+
+    pub fn alpn(&self) -> &TlsAlpnConfig {
+        &self.alpn
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn session_tickets(&self) -> bool {
+        self.session_tickets
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn ticket_rotation(&self) -> Duration {
+        Duration::from_secs(
+            self.ticket_rotation
+                .clamp(MIN_TICKET_ROTATION, MAX_TICKET_ROTATION),
+        )
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            cipher_suites: default_tls_cipher_suites(),
+            key_exchange_groups: default_tls_key_exchange_groups(),
+            alpn: TlsAlpnConfig::default(),
+            session_tickets: default_tls_session_tickets(),
+            ticket_rotation: default_tls_ticket_rotation(),
+        }
+    }
+}
+
+/// ALPN protocols each server offers, in order of preference.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TlsAlpnConfig {
+    #[serde(default = "default_tls_alpn_tuic")]
+    tuic: Vec<String>,
+
+    /// Offered ahead of those `[[trojan.fallbacks]]` name. Empty leaves
+    /// ALPN to the fallbacks.
+    #[serde(default)]
+    trojan: Vec<String>,
+}
+
+impl TlsAlpnConfig {
+    pub fn tuic(&self) -> &[String] {
+        &self.tuic
+    }
+
+    pub fn trojan(&self) -> &[String] {
+        &self.trojan
+    }
+}
+
+impl Default for TlsAlpnConfig {
+    fn default() -> Self {
+        Self {
+            tuic: default_tls_alpn_tuic(),
+            trojan: Vec::new(),
+        }
+    }
+}
+
+/// `[self_signed]`: a self-signed certificate generated for `[tuic]` and
+/// `[trojan]` when their `cert_path` does not exist yet, and kept for later
+/// starts. For testing, and for TUIC clients pinning the certificate.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SelfSignedConfig {
+    /// Subject alternative names, DNS names or IP addresses.
+    #[serde(default = "default_self_signed_server_names")]
+    server_names: Vec<String>,
+}
+
+impl SelfSignedConfig {
+    pub fn server_names(&self) -> &[String] {
+        &self.server_names
+    }
+}
+
+/// `[summary]`: a periodic one-line traffic summary in the log.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SummaryConfig {
+    /// Seconds between summaries.
+    #[serde(default = "default_summary_interval")]
+    interval: u64,
+}
+
+impl SummaryConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.max(1))
+    }
+}
+
+/// `[accounting]`: where per-user traffic totals are kept between runs.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccountingConfig {
+    /// JSON file of the totals, rewritten as a whole on each save.
+    #[serde(default = "default_accounting_path")]
+    path: String,
+
+    /// Seconds between saves; the totals are saved at shutdown too.
+    #[serde(default = "default_accounting_flush_interval")]
+    flush_interval: u64,
+}
+
+impl AccountingConfig {
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.flush_interval.max(1))
+    }
+}
+
+/// Batches a record for every closed session and flushes them on an
+/// interval, for offline analytics.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct StatsExportConfig {
+    #[serde(default)]
+    format: StatsExportFormat,
+
+    /// CSV file to append to.
+    #[serde(default = "default_stats_export_path")]
+    path: String,
+
+    /// ClickHouse HTTP endpoint, e.g. `http://127.0.0.1:8123` or an
+    /// `https://` URL; without a port, 80 or 443 is used.
+    #[serde(default = "default_stats_export_url")]
+    url: String,
+
+    #[serde(default = "default_stats_export_table")]
+    table: String,
+
+    user: Option<String>,
+
+    password: Option<String>,
+
+    /// Seconds between flushes.
+    #[serde(default = "default_stats_export_interval")]
+    interval: u64,
+
+    /// Records that trigger an early flush.
+    #[serde(default = "default_stats_export_batch_size")]
+    batch_size: usize,
+}
+
+#[cfg(feature = "metrics")]
+impl StatsExportConfig {
+    pub fn format(&self) -> StatsExportFormat {
+        self.format
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn password(&self) -> Option<&str> {
+        self.password.as_deref()
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.max(1))
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.max(1)
+    }
+}
+
+/// `[dns]`: where destination names are looked up. Without `servers`
+/// the system resolver answers, as it does for names no rule picks
+/// servers for.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DnsConfig {
+    /// Upstreams tried in order: `udp://` and `tcp://` IP addresses,
+    /// `tls://` (DNS over TLS) and `https://` (DNS over HTTPS) URLs, or
+    /// `system`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    servers: Vec<String>,
+
+    /// Seconds an upstream gets to answer before the next is asked.
+    #[serde(default = "default_dns_timeout")]
+    timeout: u64,
+
+    /// Other upstreams for some domains; the first rule naming a domain
+    /// or one of its parents wins.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    rules: Vec<DnsRuleConfig>,
+}
+
+impl DnsConfig {
+    pub fn servers(&self) -> &[String] {
+        &self.servers
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.max(1))
+    }
+
+    pub fn rules(&self) -> &[DnsRuleConfig] {
+        &self.rules
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            timeout: default_dns_timeout(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DnsRuleConfig {
+    domains: Vec<String>,
+    servers: Vec<String>,
+}
+
+impl DnsRuleConfig {
+    pub fn domains(&self) -> &[String] {
+        &self.domains
+    }
+
+    pub fn servers(&self) -> &[String] {
+        &self.servers
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UdpSessionConfig {
+    #[serde(default = "default_udp_session_timeout")]
+    session_timeout: u64,
+
+    #[serde(default = "default_udp_socket_timeout")]
+    socket_timeout: u64,
+
+    max_sessions: Option<usize>,
+
+    max_reassembly_bytes_per_session: Option<usize>,
+}
+
+impl Default for UdpSessionConfig {
+    fn default() -> Self {
+        Self {
+            session_timeout: default_udp_session_timeout(),
+            socket_timeout: default_udp_socket_timeout(),
+            max_sessions: None,
+            max_reassembly_bytes_per_session: None,
+        }
+    }
+}
+
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+impl UdpSessionConfig {
+    /// Idle time after which a UDP association is closed and forgotten.
+    pub fn session_timeout(&self) -> Duration {
+        Duration::from_secs(self.session_timeout.max(1))
+    }
+}
+
+/// Named bundles of tuning defaults for common deployment classes. Any key
+/// set explicitly in the config file wins over the profile.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Small routers with ~256MB of RAM.
+    #[serde(alias = "low-memory")]
+    Router,
+    /// A typical 1G VPS; matches the historical hard-coded values.
+    #[default]
+    Vps,
+    /// Dedicated multi-gigabit relays.
+    #[serde(alias = "high-throughput")]
+    Relay,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProfileDefaults {
+    pub max_worker_threads: Option<usize>,
+    pub relay_buffer_size: usize,
+    #[cfg(feature = "tuic")]
+    pub tuic_max_concurrent_streams: u32,
+    #[cfg(feature = "tuic")]
+    pub tuic_stream_receive_window: u32,
+    #[cfg(feature = "tuic")]
+    pub tuic_receive_window: u32,
+    #[cfg(feature = "tuic")]
+    pub tuic_send_window: u64,
+    pub log_level: &'static str,
+}
+
+impl Profile {
+    pub fn defaults(&self) -> ProfileDefaults {
+        match self {
+            Profile::Router => ProfileDefaults {
+                max_worker_threads: Some(2),
+                relay_buffer_size: 8 * 1024,
+                #[cfg(feature = "tuic")]
+                tuic_max_concurrent_streams: 128,
+                #[cfg(feature = "tuic")]
+                tuic_stream_receive_window: 1 << 18,
+                #[cfg(feature = "tuic")]
+                tuic_receive_window: 1 << 20,
+                #[cfg(feature = "tuic")]
+                tuic_send_window: 1 << 20,
+                log_level: "warn",
+            },
+            Profile::Vps => ProfileDefaults {
+                max_worker_threads: None,
+                relay_buffer_size: 16 * 1024,
+                #[cfg(feature = "tuic")]
+                tuic_max_concurrent_streams: 1024,
+                #[cfg(feature = "tuic")]
+                tuic_stream_receive_window: 1 << 21,
+                #[cfg(feature = "tuic")]
+                tuic_receive_window: 1 << 22,
+                #[cfg(feature = "tuic")]
+                tuic_send_window: 1 << 22,
+                log_level: "info",
+            },
+            Profile::Relay => ProfileDefaults {
+                max_worker_threads: None,
+                relay_buffer_size: 64 * 1024,
+                #[cfg(feature = "tuic")]
+                tuic_max_concurrent_streams: 4096,
+                #[cfg(feature = "tuic")]
+                tuic_stream_receive_window: 1 << 23,
+                #[cfg(feature = "tuic")]
+                tuic_receive_window: 1 << 25,
+                #[cfg(feature = "tuic")]
+                tuic_send_window: 1 << 25,
+                log_level: "info",
+            },
+        }
+    }
+}
+
+/// `[runtime]`: how many threads the async runtime starts.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RuntimeConfig {
+    /// Fixed worker count; overrides `cpu_load_ratio`.
+    worker_threads: Option<usize>,
+
+    /// Most threads kept for blocking work such as file I/O; tokio's own
+    /// limit when unset.
+    max_blocking_threads: Option<usize>,
+
+    /// Workers per CPU, rounded, at least one.
+    cpu_load_ratio: Option<f64>,
+}
+
+impl RuntimeConfig {
+    pub fn worker_threads(&self) -> Option<usize> {
+        self.worker_threads.filter(|&n| n > 0)
+    }
+
+    pub fn max_blocking_threads(&self) -> Option<usize> {
+        self.max_blocking_threads.filter(|&n| n > 0)
+    }
+
+    pub fn cpu_load_ratio(&self) -> f64 {
+        self.cpu_load_ratio
+            .filter(|&ratio| ratio > 0.0 && ratio.is_finite())
+            .unwrap_or(DEFAULT_CPU_LOAD_RATIO)
+    }
+}
+
+/// `[log]`: where log lines go and how they look.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct LogConfig {
+    level: Option<String>,
+
+    #[serde(default)]
+    format: LogFormat,
+
+    /// Log file; defaults to `logs/iway.log` next to the executable.
+    #[serde(flatten)]
+    file: LogFileConfig,
+
+    /// Writes to stdout only, for containers that collect it.
+    #[serde(default)]
+    stdout_only: bool,
+
+    /// `[log.access]`: one JSON line per relayed connection and UDP
+    /// association, in a file of its own.
+    access: Option<LogFileConfig>,
+
+    /// `[log.slow]`: warnings for slow DNS lookups and outbound connects.
+    slow: Option<SlowLogConfig>,
+}
+
+impl LogConfig {
+    pub fn format(&self) -> LogFormat {
+        self.format
+    }
+
+    pub fn file(&self) -> &LogFileConfig {
+        &self.file
+    }
+
+    pub fn stdout_only(&self) -> bool {
+        self.stdout_only
+    }
+
+    pub fn access(&self) -> Option<&LogFileConfig> {
+        self.access.as_ref()
+    }
+
+    pub fn slow(&self) -> Option<&SlowLogConfig> {
+        self.slow.as_ref()
+    }
+}
+
+/// How long a DNS lookup or outbound connect may take before it is
+/// logged as slow, in milliseconds.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SlowLogConfig {
+    #[serde(default = "default_slow_dns_ms")]
+    dns_ms: u64,
+
+    #[serde(default = "default_slow_connect_ms")]
+    connect_ms: u64,
+}
+
+impl SlowLogConfig {
+    pub fn dns(&self) -> Duration {
+        Duration::from_millis(self.dns_ms)
+    }
+
+    pub fn connect(&self) -> Duration {
+        Duration::from_millis(self.connect_ms)
+    }
+}
+
+/// A log file and how it is rotated.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct LogFileConfig {
+    path: Option<String>,
+
+    #[serde(default)]
+    rotation: LogRotation,
+
+    /// Size in MiB at which `size` rotation starts a new file.
+    max_size_mb: Option<u64>,
+
+    /// Rotated files kept besides the current one; unset or 0 keeps all
+    /// for `daily` rotation and the default for `size`.
+    max_files: Option<usize>,
+}
+
+impl LogFileConfig {
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref().filter(|path| !path.is_empty())
+    }
+
+    pub fn rotation(&self) -> LogRotation {
+        self.rotation
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size_mb
+            .filter(|&mb| mb > 0)
+            .unwrap_or(DEFAULT_LOG_MAX_SIZE_MB)
+            * 1024
+            * 1024
+    }
+
+    pub fn max_files(&self) -> Option<usize> {
+        self.max_files.filter(|&n| n > 0)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Multi-line, colored records on the console; one line each in the
+    /// file.
+    #[default]
+    Pretty,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    /// A new file every day, named by its date.
+    #[default]
+    Daily,
+    /// A new file once the current one reaches `max_size_mb`; older ones
+    /// are numbered `.1`, `.2` and so on.
+    Size,
+    /// One file, never rotated.
+    Never,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RelayConfig {
+    /// Bytes read at a time in each direction; the profile's default if
+    /// unset.
+    buffer_size: Option<usize>,
+
+    /// Seconds a relayed TCP connection may go without a byte either way
+    /// before both halves are closed; 0 disables. Defaults to 300.
+    idle_timeout: Option<u64>,
+
+    /// Seconds a relayed TCP connection may live at all; unset or 0 means
+    /// no limit.
+    max_lifetime: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Config {
+    #[serde(default)]
+    config_version: ConfigVersion,
+
+    #[serde(default)]
+    profile: Profile,
+
+    #[serde(default)]
+    runtime: RuntimeConfig,
+
+    #[serde(default)]
+    log: LogConfig,
+
+    #[serde(default)]
+    relay: RelayConfig,
+
+    #[serde(default)]
+    trojan: TrojanConfig,
+
+    #[serde(default)]
+    tuic: TuicConfig,
+
+    #[serde(default)]
+    snell: SnellConfig,
+
+    #[serde(default)]
+    control: ControlConfig,
+
+    #[serde(default)]
+    udp_session: UdpSessionConfig,
+
+    #[serde(default)]
+    dns: DnsConfig,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    schedule: Vec<ScheduleConfig>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    hooks: Vec<HookConfig>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reverse: Vec<ReverseConfig>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    policies: Vec<PolicyConfig>,
+
+    router: Option<RouterConfig>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    outbounds: Vec<OutboundConfig>,
+
+    #[serde(default)]
+    geoip: GeoIpConfig,
+
+    stats_export: Option<StatsExportConfig>,
+
+    accounting: Option<AccountingConfig>,
+
+    summary: Option<SummaryConfig>,
+
+    health: Option<HealthConfig>,
+
+    #[serde(default)]
+    tls: TlsConfig,
+
+    acme: Option<AcmeConfig>,
+
+    self_signed: Option<SelfSignedConfig>,
+
+    filter: Option<FilterConfig>,
+
+    script: Option<ScriptConfig>,
+
+    #[serde(default)]
+    egress: EgressConfig,
+
+    #[serde(default)]
+    udp_guard: UdpGuardConfig,
+
+    #[serde(default)]
+    port_policy: PortPolicyConfig,
+
+    banlist: Option<BanlistConfig>,
+
+    #[serde(default)]
+    fingerprints: FingerprintConfig,
+
+    #[serde(default)]
+    shutdown: ShutdownConfig,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    users: Vec<IdentityConfig>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    inbounds: Vec<InboundConfig>,
+
+    /// The files this config was read from, included ones too.
+    #[serde(skip)]
+    sources: Vec<PathBuf>,
+
+    #[serde(skip)]
+    migrated_from: Option<u32>,
+}
+
+/// Layout version the file is written in; see [`CONFIG_VERSION`].
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct ConfigVersion(u32);
+
+impl Default for ConfigVersion {
+    fn default() -> Self {
+        Self(CONFIG_VERSION)
+    }
+}
+
+/// `[[inbounds]]`: a listener of its own, configured like the section of
+/// its `type` (`[tuic]`, `[trojan]` or `[snell]`), next to those sections.
+/// Entries are always served, whatever their `enabled` says; give each a
+/// distinct `tag`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum InboundConfig {
+    Tuic(Box<TuicConfig>),
+    Trojan(Box<TrojanConfig>),
+    Snell(Box<SnellConfig>),
+}
+
+impl InboundConfig {
+    pub fn protocol(&self) -> &'static str {
+        match self {
+            InboundConfig::Tuic(_) => "tuic",
+            InboundConfig::Trojan(_) => "trojan",
+            InboundConfig::Snell(_) => "snell",
+        }
+    }
+
+    pub fn tag(&self) -> &str {
+        match self {
+            InboundConfig::Tuic(tuic) => tuic.tag(),
+            InboundConfig::Trojan(trojan) => trojan.tag(),
+            InboundConfig::Snell(snell) => snell.tag(),
+        }
+    }
+
+    pub fn server_addr(&self) -> &str {
+        match self {
+            InboundConfig::Tuic(tuic) => tuic.server_addr(),
+            InboundConfig::Trojan(trojan) => trojan.server_addr(),
+            InboundConfig::Snell(snell) => snell.server_addr(),
+        }
+    }
+}
+
+const DEFAULT_SERVER_ADDR: &str = "[::]:443";
+const DEFAULT_CERT_PATH: &str = "server.crt";
+const DEFAULT_KEY_PATH: &str = "server.key";
+#[cfg(feature = "tuic")]
+const DEFAULT_KEEP_ALIVE_INTERVAL: u64 = 10;
+#[cfg(feature = "tuic")]
+const DEFAULT_MAX_IDLE_TIMEOUT: u64 = 30;
+const DEFAULT_RELAY_IDLE_TIMEOUT: u64 = 300;
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+const DEFAULT_SNIFF_TIMEOUT_MS: u64 = 300;
+const DEFAULT_LOG_MAX_SIZE_MB: u64 = 100;
+const DEFAULT_CPU_LOAD_RATIO: f64 = 1.0;
+const DEFAULT_HEALTH_LISTEN: &str = "127.0.0.1:9090";
+#[cfg(feature = "trojan")]
+const MIN_TICKET_ROTATION: u64 = 60;
+/// Longest ticket lifetime TLS 1.3 allows (RFC 8446, section 4.6.1).
+pub const MAX_TICKET_ROTATION: u64 = 7 * 24 * 60 * 60;
+
+fn default_server_addr() -> String {
+    String::from(DEFAULT_SERVER_ADDR)
+}
+
+fn default_cert_path() -> String {
+    String::from(DEFAULT_CERT_PATH)
+}
+
+fn default_key_path() -> String {
+    String::from(DEFAULT_KEY_PATH)
+}
+
+fn default_snell_server_addr() -> String {
+    String::from("[::]:8388")
+}
+
+fn default_snell_handshake_timeout() -> u64 {
+    10
+}
+
+fn default_control_enabled() -> bool {
+    true
+}
+
+#[cfg(windows)]
+fn default_control_path() -> String {
+    String::from(r"\\.\pipe\iway")
+}
+
+/// In the per-user runtime directory, or `/run/iway` for a system
+/// service; never in a directory anyone can create files in.
+#[cfg(not(windows))]
+fn default_control_path() -> String {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => Path::new(&dir)
+            .join("iway.sock")
+            .to_string_lossy()
+            .into_owned(),
+        _ => String::from("/run/iway/iway.sock"),
+    }
+}
+
+fn default_udp_session_timeout() -> u64 {
+    60
+}
+
+fn default_udp_socket_timeout() -> u64 {
+    10
+}
+
+fn default_trojan_enabled() -> bool {
+    false
+}
+
+fn default_tuic_enabled() -> bool {
+    false
+}
+
+fn default_tuic_auth_timeout() -> u64 {
+    3
+}
+
+fn default_tuic_zero_rtt() -> bool {
+    true
+}
+
+fn default_tuic_listeners() -> usize {
+    1
+}
+
+fn default_tuic_drain_timeout() -> u64 {
+    5
+}
+
+fn default_slow_dns_ms() -> u64 {
+    500
+}
+
+fn default_slow_connect_ms() -> u64 {
+    1000
+}
+
+fn default_group_max_failures() -> u32 {
+    3
+}
+
+fn default_group_eject_duration() -> u64 {
+    30
+}
+
+fn default_banlist_max_failures() -> u32 {
+    10
+}
+
+fn default_banlist_window() -> u64 {
+    60
+}
+
+fn default_banlist_ban_duration() -> u64 {
+    600
+}
+
+fn default_hook_retries() -> u32 {
+    5
+}
+
+fn default_hook_timeout() -> u64 {
+    10
+}
+
+fn default_hook_cert_expiry_days() -> i64 {
+    14
+}
+
+fn default_summary_interval() -> u64 {
+    300
+}
+
+fn default_health_listen() -> String {
+    String::from(DEFAULT_HEALTH_LISTEN)
+}
+
+fn default_acme_directory() -> String {
+    String::from("https://acme-v02.api.letsencrypt.org/directory")
+}
+
+fn default_acme_http_listen() -> String {
+    String::from("[::]:80")
+}
+
+fn default_acme_tls_alpn_listen() -> String {
+    String::from("[::]:443")
+}
+
+fn default_acme_account_key() -> String {
+    String::from("acme-account.pem")
+}
+
+fn default_tls_cipher_suites() -> Vec<String> {
+    vec![
+        String::from("TLS13_AES_256_GCM_SHA384"),
+        String::from("TLS13_CHACHA20_POLY1305_SHA256"),
+    ]
+}
+
+fn default_tls_key_exchange_groups() -> Vec<String> {
+    vec![
+        String::from("X25519"),
+        String::from("secp256r1"),
+        String::from("secp384r1"),
+    ]
+}
+
+fn default_dns_timeout() -> u64 {
+    5
+}
+
+fn default_tls_session_tickets() -> bool {
+    true
+}
+
+fn default_tls_ticket_rotation() -> u64 {
+    6 * 60 * 60
+}
+
+fn default_tls_alpn_tuic() -> Vec<String> {
+    vec![String::from("h3")]
+}
+
+fn default_self_signed_server_names() -> Vec<String> {
+    vec![String::from("localhost")]
+}
+
+fn default_acme_renew_before() -> u64 {
+    30
+}
+
+fn default_accounting_path() -> String {
+    "traffic.json".to_string()
+}
+
+fn default_accounting_flush_interval() -> u64 {
+    60
+}
+
+fn default_stats_export_path() -> String {
+    String::from("sessions.csv")
+}
+
+fn default_stats_export_url() -> String {
+    String::from("http://127.0.0.1:8123")
+}
+
+fn default_stats_export_table() -> String {
+    String::from("iway_sessions")
+}
+
+fn default_stats_export_interval() -> u64 {
+    60
+}
+
+fn default_stats_export_batch_size() -> usize {
+    1000
+}
+
+fn default_filter_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_script_max_operations() -> u64 {
+    100_000
+}
+
+fn default_egress_burst_size() -> u64 {
+    4 * 1024 * 1024
+}
+
+fn default_udp_guard_cooldown() -> u64 {
+    60
+}
+
+fn default_shutdown_accept_timeout() -> u64 {
+    5
+}
+
+fn default_shutdown_drain_timeout() -> u64 {
+    30
+}
+
+fn default_shutdown_flush_timeout() -> u64 {
+    10
+}
+
+fn default_shutdown_close_timeout() -> u64 {
+    5
+}
+
+fn default_policy_allow_udp() -> bool {
+    true
+}
+
+fn default_masquerade_status() -> u16 {
+    404
+}
+
+fn default_trojan_fallback_addr() -> String {
+    String::from("127.0.0.1:80")
+}
+
+fn default_trojan_udp_nat_max_mappings() -> usize {
+    256
+}
+
+fn default_tcp_nodelay() -> bool {
+    true
+}
+
+fn default_trojan_udp_max_frame_size() -> usize {
+    // The largest payload an IPv4 UDP datagram can carry.
+    65507
+}
+
+fn default_trojan_handshake_timeout() -> u64 {
+    10
+}
+
+impl Config {
+    /// Reads the config at `path`, with the files its `include` list names
+    /// merged in. `${VAR}` (or `${VAR:-default}`) in a string value is
+    /// replaced by the environment variable, and `IWAY_<SECTION>__<KEY>`
+    /// variables override keys of the files, so secrets can stay out of
+    /// them.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut sources = Vec::new();
+        let table = load_table(path.as_ref(), &mut sources)?;
+
+        let mut root = toml::Value::Table(table);
+        interpolate(&mut root, "")?;
+        apply_env_overrides(&mut root, std::env::vars())?;
+        // After the overrides, which name keys of the layout the file is in.
+        let toml::Value::Table(table) = &mut root else {
+            unreachable!();
+        };
+        let migrated = migrate(table)?;
+
+        let config: Config = root.try_into().context("Failed to parse config file")?;
+        Ok(Config {
+            sources,
+            migrated_from: migrated.map(|(version, _)| version),
+            ..config
+        })
+    }
+
+    /// Rewrites the config file at `path` in the current layout, keeping
+    /// the old one as `<path>.bak`. Returns what changed, or `None` if the
+    /// file was current. Comments are not carried over.
+    pub fn upgrade_file<P: AsRef<Path>>(path: P) -> Result<Option<Vec<String>>> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut table: toml::Table = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        if table.contains_key(INCLUDE_KEY) {
+            // Sections split across files would be moved only in part.
+            anyhow::bail!(
+                "{} includes other files; merge them into it before upgrading",
+                path.display()
+            );
+        }
+
+        let Some((_, changes)) = migrate(&mut table)? else {
+            return Ok(None);
+        };
+
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(".bak");
+        fs::copy(path, &backup).with_context(|| format!("Failed to back up {}", path.display()))?;
+        let content = toml::to_string_pretty(&table).context("Failed to serialize config")?;
+        fs::write(path, content).context("Failed to write config file")?;
+
+        Ok(Some(changes))
+    }
+
+    pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let content = toml::to_string_pretty(self).context("Failed to serialize config")?;
+        fs::write(path, content).context("Failed to write config file")?;
+        Ok(())
+    }
+
+    pub fn profile(&self) -> Profile {
+        self.profile
+    }
+
+    pub fn runtime(&self) -> &RuntimeConfig {
+        &self.runtime
+    }
+
+    pub fn log(&self) -> &LogConfig {
+        &self.log
+    }
+
+    pub fn log_level(&self) -> &str {
+        self.log
+            .level
+            .as_deref()
+            .unwrap_or(self.profile.defaults().log_level)
+    }
+
+    pub fn relay_buffer_size(&self) -> usize {
+        self.relay
+            .buffer_size
+            .unwrap_or(self.profile.defaults().relay_buffer_size)
+    }
+
+    pub fn relay_idle_timeout(&self) -> Option<Duration> {
+        Some(
+            self.relay
+                .idle_timeout
+                .unwrap_or(DEFAULT_RELAY_IDLE_TIMEOUT),
+        )
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+    }
+
+    pub fn relay_max_lifetime(&self) -> Option<Duration> {
+        self.relay
+            .max_lifetime
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn trojan(&self) -> &TrojanConfig {
+        &self.trojan
+    }
+
+    pub fn tuic(&self) -> &TuicConfig {
+        &self.tuic
+    }
+
+    pub fn snell(&self) -> &SnellConfig {
+        &self.snell
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn udp_session(&self) -> &UdpSessionConfig {
+        &self.udp_session
+    }
+
+    pub fn control(&self) -> &ControlConfig {
+        &self.control
+    }
+
+    pub fn schedule(&self) -> &[ScheduleConfig] {
+        &self.schedule
+    }
+
+    pub fn hooks(&self) -> &[HookConfig] {
+        &self.hooks
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn reverse(&self) -> &[ReverseConfig] {
+        &self.reverse
+    }
+
+    pub fn policies(&self) -> &[PolicyConfig] {
+        &self.policies
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn router(&self) -> Option<&RouterConfig> {
+        self.router.as_ref()
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn outbounds(&self) -> &[OutboundConfig] {
+        &self.outbounds
+    }
+
+    pub fn geoip(&self) -> &GeoIpConfig {
+        &self.geoip
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn stats_export(&self) -> Option<&StatsExportConfig> {
+        self.stats_export.as_ref()
+    }
+
+    pub fn accounting(&self) -> Option<&AccountingConfig> {
+        self.accounting.as_ref()
+    }
+
+    pub fn summary(&self) -> Option<&SummaryConfig> {
+        self.summary.as_ref()
+    }
+
+    pub fn health(&self) -> Option<&HealthConfig> {
+        self.health.as_ref()
+    }
+
+    pub fn dns(&self) -> &DnsConfig {
+        &self.dns
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn tls(&self) -> &TlsConfig {
+        &self.tls
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn acme(&self) -> Option<&AcmeConfig> {
+        self.acme.as_ref()
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn self_signed(&self) -> Option<&SelfSignedConfig> {
+        self.self_signed.as_ref()
+    }
+
+    pub fn filter(&self) -> Option<&FilterConfig> {
+        self.filter.as_ref()
+    }
+
+    pub fn script(&self) -> Option<&ScriptConfig> {
+        self.script.as_ref()
+    }
+
+    pub fn egress(&self) -> &EgressConfig {
+        &self.egress
+    }
+
+    pub fn udp_guard(&self) -> &UdpGuardConfig {
+        &self.udp_guard
+    }
+
+    pub fn port_policy(&self) -> &PortPolicyConfig {
+        &self.port_policy
+    }
+
+    pub fn banlist(&self) -> Option<&BanlistConfig> {
+        self.banlist.as_ref()
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn fingerprints(&self) -> &FingerprintConfig {
+        &self.fingerprints
+    }
+
+    pub fn shutdown(&self) -> &ShutdownConfig {
+        &self.shutdown
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn identities(&self) -> &[IdentityConfig] {
+        &self.users
+    }
+
+    pub fn inbounds(&self) -> &[InboundConfig] {
+        &self.inbounds
+    }
+
+    pub fn sources(&self) -> &[PathBuf] {
+        &self.sources
+    }
+
+    /// The layout version the file was in, if it was upgraded as it was
+    /// read.
+    pub fn migrated_from(&self) -> Option<u32> {
+        self.migrated_from
+    }
+
+    /// The config a server for `inbound` is built from: this one, with
+    /// `inbound` enabled in place of the section of its type.
+    pub fn for_inbound(&self, inbound: &InboundConfig) -> Config {
+        let mut config = self.clone();
+        config.inbounds.clear();
+        match inbound {
+            InboundConfig::Tuic(tuic) => {
+                config.tuic = TuicConfig {
+                    enabled: true,
+                    ..tuic.as_ref().clone()
+                }
+            }
+            InboundConfig::Trojan(trojan) => {
+                config.trojan = TrojanConfig {
+                    enabled: true,
+                    ..trojan.as_ref().clone()
+                }
+            }
+            InboundConfig::Snell(snell) => {
+                config.snell = SnellConfig {
+                    enabled: true,
+                    ..snell.as_ref().clone()
+                }
+            }
+        }
+        config
+    }
+
+    /// TUIC credentials from `[[tuic.users]]` (identified by UUID) and from
+    /// `[[users]]` entries that carry a UUID and password.
+    #[cfg(feature = "tuic")]
+    pub fn tuic_credentials(&self) -> Vec<Credential<'_>> {
+        let legacy = self.tuic.users().iter().map(|u| Credential {
+            identity: u.uuid(),
+            uuid: u.uuid(),
+            password: u.password(),
+        });
+
+        let identities = self.users.iter().filter_map(|u| {
+            Some(Credential {
+                identity: &u.name,
+                uuid: u.uuid.as_deref()?,
+                password: u.password.as_deref()?,
+            })
+        });
+
+        legacy.chain(identities).collect()
+    }
+
+    /// Trojan credentials from `[[trojan.users]]` (identified by UUID) and
+    /// from `[[users]]` entries that carry a Trojan password.
+    #[cfg(feature = "trojan")]
+    pub fn trojan_credentials(&self) -> Vec<Credential<'_>> {
+        let legacy = self.trojan.users().iter().map(|u| Credential {
+            identity: u.uuid(),
+            #[cfg(feature = "tuic")]
+            uuid: u.uuid(),
+            password: u.password(),
+        });
+
+        let identities = self.users.iter().filter_map(|u| {
+            Some(Credential {
+                identity: &u.name,
+                #[cfg(feature = "tuic")]
+                uuid: u.uuid.as_deref().unwrap_or_default(),
+                password: u.trojan_password.as_deref()?,
+            })
+        });
+
+        legacy.chain(identities).collect()
+    }
+
+    /// Entries that would merge two users into one: a TUIC UUID or Trojan
+    /// password used twice, or two `[[users]]` sharing a name. Each conflict
+    /// names both places in the config.
+    pub fn credential_conflicts(&self) -> Vec<String> {
+        let mut conflicts = Vec::new();
+
+        let located = |i: usize, user: &IdentityConfig| format!("users[{}] ({})", i, user.name);
+
+        let names = self
+            .users
+            .iter()
+            .enumerate()
+            .map(|(i, u)| (format!("users[{}]", i), u.name.clone()));
+        for (first, second, name) in duplicates(names) {
+            conflicts.push(format!(
+                "user name {:?} is used by both {} and {}",
+                name, first, second
+            ));
+        }
+
+        // UUIDs are compared in canonical form, so case and hyphenation do
+        // not hide a duplicate.
+        let canonical = |uuid: &str| {
+            uuid::Uuid::parse_str(uuid)
+                .map(|u| u.to_string())
+                .unwrap_or_else(|_| uuid.to_string())
+        };
+        let uuids = self
+            .tuic
+            .users()
+            .iter()
+            .enumerate()
+            .map(|(i, u)| (format!("tuic.users[{}]", i), canonical(u.uuid())))
+            .chain(
+                self.users
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, u)| Some((located(i, u), canonical(u.uuid.as_deref()?)))),
+            );
+        for (first, second, uuid) in duplicates(uuids) {
+            conflicts.push(format!(
+                "TUIC UUID {} is used by both {} and {}",
+                uuid, first, second
+            ));
+        }
+
+        // Passwords are secrets, so only their locations are reported.
+        let passwords = self
+            .trojan
+            .users()
+            .iter()
+            .enumerate()
+            .map(|(i, u)| (format!("trojan.users[{}]", i), u.password().to_string()))
+            .chain(
+                self.users
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, u)| Some((located(i, u), u.trojan_password.clone()?))),
+            );
+        for (first, second, _) in duplicates(passwords) {
+            conflicts.push(format!(
+                "Trojan password of {} is reused by {}",
+                first, second
+            ));
+        }
+
+        conflicts
+    }
+
+    /// The certificate files served over TLS, each with where it is
+    /// configured.
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn certificate_paths(&self) -> Vec<(String, &str)> {
+        self.key_pairs()
+            .into_iter()
+            .map(|(at, cert_path, _)| (at, cert_path))
+            .collect()
+    }
+
+    /// Every certificate and key file served over TLS, for watching.
+    pub fn certificate_files(&self) -> Vec<&str> {
+        self.key_pairs()
+            .into_iter()
+            .flat_map(|(_, cert_path, key_path)| [cert_path, key_path])
+            .collect()
+    }
+
+    /// The primary certificate and key files of `[tuic]` and `[trojan]`,
+    /// the ones `[acme]` and `[self_signed]` provide.
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn primary_key_pairs(&self) -> Vec<(&str, &str)> {
+        let mut pairs = Vec::new();
+        for (at, cert_path, key_path) in self.key_pairs() {
+            if (at == "tuic" || at == "trojan") && !pairs.contains(&(cert_path, key_path)) {
+                pairs.push((cert_path, key_path));
+            }
+        }
+        pairs
+    }
+
+    /// The certificate and key files served over TLS, each pair with where
+    /// it is configured.
+    fn key_pairs(&self) -> Vec<(String, &str, &str)> {
+        let tuics = self.sections(
+            ("tuic", &self.tuic, self.tuic.enabled),
+            |inbound| match inbound {
+                InboundConfig::Tuic(tuic) => Some(tuic),
+                _ => None,
+            },
+        );
+        let tuics = tuics
+            .into_iter()
+            .map(|(at, tuic)| (at, tuic.cert_path(), tuic.key_path(), tuic.certificates()));
+
+        let trojans =
+            self.sections(
+                ("trojan", &self.trojan, self.trojan.enabled),
+                |inbound| match inbound {
+                    InboundConfig::Trojan(trojan) => Some(trojan),
+                    _ => None,
+                },
+            );
+        let trojans = trojans
+            .into_iter()
+            .filter(|(_, trojan)| trojan.reality().is_none())
+            .map(|(at, trojan)| {
+                (
+                    at,
+                    trojan.cert_path(),
+                    trojan.key_path(),
+                    trojan.certificates(),
+                )
+            });
+
+        let mut pairs = Vec::new();
+        for (at, cert_path, key_path, certificates) in tuics.chain(trojans) {
+            pairs.push((at.clone(), cert_path, key_path));
+            pairs.extend(certificates.iter().enumerate().map(|(i, certificate)| {
+                (
+                    format!("{}.certificates[{}]", at, i),
+                    certificate.cert_path(),
+                    certificate.key_path(),
+                )
+            }));
+        }
+        pairs
+    }
+
+    /// Whether `[acme]` or `[self_signed]` creates missing primary
+    /// certificates. `[acme]` only does in builds with the trojan feature;
+    /// elsewhere the certificates must be there already.
+    fn issues_certificates(&self) -> bool {
+        (cfg!(feature = "trojan") && self.acme.is_some()) || self.self_signed.is_some()
+    }
+
+    /// Everything that would keep a server from starting or mix up its
+    /// users, all at once and each with where it is: unparseable addresses,
+    /// missing or mismatched certificates, malformed UUIDs, credential
+    /// conflicts, listeners sharing a port and limits that leave nothing
+    /// able to pass.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        // Where each listener is configured, its transport and address.
+        let mut listeners: Vec<(String, &str, SocketAddr)> = Vec::new();
+
+        let tuics = self.sections(
+            ("tuic", &self.tuic, self.tuic.enabled),
+            |inbound| match inbound {
+                InboundConfig::Tuic(tuic) => Some(tuic),
+                _ => None,
+            },
+        );
+        for (at, tuic) in tuics {
+            if let Some(addr) = check_addr(&mut problems, &at, "server_addr", tuic.server_addr()) {
+                match crate::net::util::parse_ports(tuic.listen()) {
+                    Ok(ports) => listeners.extend(
+                        crate::net::util::listen_addrs(addr, &ports)
+                            .into_iter()
+                            .map(|addr| (at.clone(), "UDP", addr)),
+                    ),
+                    Err(e) => problems.push(format!("{}.listen: {:#}", at, e)),
+                }
+            }
+            check_key_pairs(
+                &mut problems,
+                &at,
+                tuic.cert_path(),
+                tuic.key_path(),
+                tuic.certificates(),
+                self.issues_certificates() && at == "tuic",
+            );
+            // Zero windows or stream limits leave connections that
+            // authenticate but can never carry anything.
+            let transport = tuic.transport();
+            for (key, value) in [
+                (
+                    "max_concurrent_bidi_streams",
+                    transport.max_concurrent_bidi_streams().map(u64::from),
+                ),
+                (
+                    "max_concurrent_uni_streams",
+                    transport.max_concurrent_uni_streams().map(u64::from),
+                ),
+                (
+                    "stream_receive_window",
+                    transport.stream_receive_window().map(u64::from),
+                ),
+                ("receive_window", transport.receive_window().map(u64::from)),
+                ("send_window", transport.send_window()),
+            ] {
+                if value == Some(0) {
+                    problems.push(format!("{}.transport.{}: must be at least 1", at, key));
+                }
+            }
+            if let Some(mtu) = transport.initial_mtu()
+                && mtu < 1200
+            {
+                problems.push(format!(
+                    "{}.transport.initial_mtu: {} is below the 1200 bytes QUIC needs",
+                    at, mtu
+                ));
+            }
+        }
+
+        let trojans =
+            self.sections(
+                ("trojan", &self.trojan, self.trojan.enabled),
+                |inbound| match inbound {
+                    InboundConfig::Trojan(trojan) => Some(trojan),
+                    _ => None,
+                },
+            );
+        for (at, trojan) in trojans {
+            if let Some(addr) = check_addr(&mut problems, &at, "server_addr", trojan.server_addr())
+            {
+                match crate::net::util::parse_listen(trojan.listen()) {
+                    Ok((ports, _)) => listeners.extend(
+                        crate::net::util::listen_addrs(addr, &ports)
+                            .into_iter()
+                            .map(|addr| (at.clone(), "TCP", addr)),
+                    ),
+                    Err(e) => problems.push(format!("{}.listen: {:#}", at, e)),
+                }
+            }
+            check_addr(&mut problems, &at, "fallback_addr", trojan.fallback_addr());
+            // REALITY makes up its own certificate.
+            if trojan.reality().is_none() {
+                check_key_pairs(
+                    &mut problems,
+                    &at,
+                    trojan.cert_path(),
+                    trojan.key_path(),
+                    trojan.certificates(),
+                    self.issues_certificates() && at == "trojan",
+                );
+            }
+            if let Some(path) = trojan.client_ca_path()
+                && !Path::new(path).is_file()
+            {
+                problems.push(format!("{}.client_ca_path: {:?} does not exist", at, path));
+            }
+            if trojan.udp().max_frame_size() == 0 {
+                problems.push(format!("{}.udp.max_frame_size: must be at least 1", at));
+            }
+            check_proxy_protocol(
+                &mut problems,
+                &at,
+                trojan.proxy_protocol,
+                &trojan.proxy_protocol_from,
+            );
+        }
+
+        let snells =
+            self.sections(
+                ("snell", &self.snell, self.snell.enabled),
+                |inbound| match inbound {
+                    InboundConfig::Snell(snell) => Some(snell),
+                    _ => None,
+                },
+            );
+        for (at, snell) in snells {
+            if let Some(addr) = check_addr(&mut problems, &at, "server_addr", snell.server_addr()) {
+                listeners.push((at.clone(), "TCP", addr));
+            }
+            if snell.psk().is_empty() {
+                problems.push(format!("{}.psk: must not be empty", at));
+            }
+            check_proxy_protocol(
+                &mut problems,
+                &at,
+                snell.proxy_protocol,
+                &snell.proxy_protocol_from,
+            );
+        }
+
+        for (i, tunnel) in self.reverse.iter().enumerate() {
+            let at = format!("reverse[{}]", i);
+            if let Some(addr) = check_addr(&mut problems, &at, "listen", tunnel.listen()) {
+                listeners.push((at.clone(), "TCP", addr));
+            }
+            if tunnel.name().is_empty() || tunnel.name().len() > 255 {
+                problems.push(format!("{}.name: must be 1 to 255 bytes", at));
+            }
+            if self.reverse[..i]
+                .iter()
+                .any(|other| other.name() == tunnel.name())
+            {
+                problems.push(format!("{}.name: {:?} is taken", at, tunnel.name()));
+            }
+            if tunnel.users().is_empty() {
+                problems.push(format!("{}.users: no user may serve it", at));
+            }
+            if !cfg!(feature = "tuic") {
+                problems.push(format!(
+                    "{}: reverse tunnels need a build with the tuic feature",
+                    at
+                ));
+            }
+        }
+
+        let uuids = self
+            .tuic
+            .users()
+            .iter()
+            .enumerate()
+            .map(|(i, u)| (format!("tuic.users[{}]", i), u.uuid()))
+            .chain(self.users.iter().enumerate().filter_map(|(i, u)| {
+                Some((format!("users[{}] ({})", i, u.name), u.uuid.as_deref()?))
+            }));
+        for (at, uuid) in uuids {
+            if let Err(e) = uuid::Uuid::parse_str(uuid) {
+                problems.push(format!("{}.uuid: {:?} is not a UUID: {}", at, uuid, e));
+            }
+        }
+
+        if let Some(banlist) = &self.banlist {
+            for (i, exempt) in banlist.exempt().iter().enumerate() {
+                if crate::security::banlist::parse_net(exempt).is_none() {
+                    problems.push(format!(
+                        "banlist.exempt[{}]: {:?} is not an address or network",
+                        i, exempt
+                    ));
+                }
+            }
+        }
+
+        for (i, hook) in self.hooks.iter().enumerate() {
+            if let Err(e) = crate::net::endpoint::Endpoint::parse(hook.url()) {
+                problems.push(format!("hooks[{}].url: {:#}", i, e));
+            }
+        }
+
+        problems.extend(self.routing_problems());
+
+        for (key, specs) in [
+            ("allow", self.port_policy.allow()),
+            ("deny", self.port_policy.deny()),
+        ] {
+            for spec in specs {
+                if let Err(e) = crate::net::util::parse_port_range(spec) {
+                    problems.push(format!("port_policy.{}: {:#}", key, e));
+                }
+            }
+        }
+
+        for (i, ja3) in self.fingerprints.ja3().iter().enumerate() {
+            if ja3.len() != 32 || !ja3.bytes().all(|b| b.is_ascii_hexdigit()) {
+                problems.push(format!(
+                    "fingerprints.ja3[{}]: {:?} is not an MD5 hash",
+                    i, ja3
+                ));
+            }
+        }
+        for (i, ja4) in self.fingerprints.ja4().iter().enumerate() {
+            if ja4.split('_').count() != 3 {
+                problems.push(format!(
+                    "fingerprints.ja4[{}]: {:?} is not a JA4 fingerprint",
+                    i, ja4
+                ));
+            }
+        }
+
+        if let Some(health) = &self.health {
+            check_addr(&mut problems, "health", "listen", health.listen());
+        }
+
+        if let Some(acme) = &self.acme {
+            if acme.domains().is_empty() {
+                problems.push(String::from("acme.domains: no domain to certify"));
+            }
+            if !acme.directory().starts_with("https://") {
+                problems.push(format!(
+                    "acme.directory: {:?} is not an https:// URL",
+                    acme.directory()
+                ));
+            }
+            match acme.challenge() {
+                AcmeChallenge::Http01 => {
+                    check_addr(&mut problems, "acme", "http_listen", acme.http_listen());
+                }
+                AcmeChallenge::TlsAlpn01 => {
+                    check_addr(
+                        &mut problems,
+                        "acme",
+                        "tls_alpn_listen",
+                        acme.tls_alpn_listen(),
+                    );
+                }
+            }
+            if !cfg!(feature = "trojan") {
+                problems.push(String::from(
+                    "acme: [acme] needs a build with the trojan feature; this build cannot order certificates",
+                ));
+            }
+        }
+
+        for (i, server) in self.dns.servers().iter().enumerate() {
+            if let Err(e) = crate::resolver::Upstream::parse(server) {
+                problems.push(format!("dns.servers[{}]: {:#}", i, e));
+            }
+        }
+        for (i, rule) in self.dns.rules().iter().enumerate() {
+            if rule.domains().is_empty() {
+                problems.push(format!("dns.rules[{}].domains: no domain to match", i));
+            }
+            if rule.servers().is_empty() {
+                problems.push(format!("dns.rules[{}].servers: no server to ask", i));
+            }
+            for (j, server) in rule.servers().iter().enumerate() {
+                if let Err(e) = crate::resolver::Upstream::parse(server) {
+                    problems.push(format!("dns.rules[{}].servers[{}]: {:#}", i, j, e));
+                }
+            }
+        }
+
+        #[cfg(any(feature = "tuic", feature = "trojan"))]
+        if let Err(e) = crate::server::tls::crypto_provider(&self.tls) {
+            problems.push(format!("tls: {:#}", e));
+        }
+        if self.tls.ticket_rotation > MAX_TICKET_ROTATION {
+            problems.push(format!(
+                "tls.ticket_rotation: {} is above the {} seconds a TLS 1.3 ticket may live",
+                self.tls.ticket_rotation, MAX_TICKET_ROTATION
+            ));
+        }
+        let alpn = self.tls.alpn();
+        for (key, protocols) in [("tuic", alpn.tuic()), ("trojan", alpn.trojan())] {
+            for protocol in protocols {
+                if protocol.is_empty() || protocol.len() > 255 {
+                    problems.push(format!(
+                        "tls.alpn.{}: {:?} is not 1 to 255 bytes long",
+                        key, protocol
+                    ));
+                }
+            }
+        }
+        if alpn.tuic().is_empty() {
+            problems.push(String::from("tls.alpn.tuic: TUIC needs an ALPN protocol"));
+        }
+
+        if let Some(self_signed) = &self.self_signed {
+            if self_signed.server_names().is_empty() {
+                problems.push(String::from("self_signed.server_names: no name to certify"));
+            }
+            if self.acme.is_some() {
+                problems.push(String::from(
+                    "self_signed: [acme] provides the certificates already",
+                ));
+            }
+        }
+
+        // Every relay read would come back empty and close the connection.
+        if self.relay.buffer_size == Some(0) {
+            problems.push(String::from("relay.buffer_size: must be at least 1"));
+        }
+
+        if let Some(ratio) = self.runtime.cpu_load_ratio
+            && !(ratio > 0.0 && ratio.is_finite())
+        {
+            problems.push(format!(
+                "runtime.cpu_load_ratio: {} is not a positive number",
+                ratio
+            ));
+        }
+
+        problems.extend(self.credential_conflicts());
+
+        for (i, (first, transport, a)) in listeners.iter().enumerate() {
+            for (second, other, b) in &listeners[i + 1..] {
+                if transport == other && a.port() == b.port() && overlaps(a.ip(), b.ip()) {
+                    problems.push(format!(
+                        "{} ({}) and {} ({}) both listen on {} port {}",
+                        first,
+                        a,
+                        second,
+                        b,
+                        transport,
+                        a.port()
+                    ));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Rules that do not compile and outbounds that are unknown, duplicated
+    /// or not available in this build.
+    fn routing_problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let mut names = vec!["direct", "block"];
+        for (i, outbound) in self.outbounds.iter().enumerate() {
+            if names.contains(&outbound.name()) {
+                problems.push(format!(
+                    "outbounds[{}].name: {:?} is taken",
+                    i,
+                    outbound.name()
+                ));
+            }
+            names.push(outbound.name());
+            if let OutboundKind::Trojan(_) = outbound.kind()
+                && !cfg!(feature = "trojan")
+            {
+                problems.push(format!(
+                    "outbounds[{}]: Trojan upstreams need a build with the trojan feature",
+                    i
+                ));
+            }
+            if let Err(e) = crate::net::sockopt::Bind::from_config(outbound.bind()) {
+                problems.push(format!("outbounds[{}].interface: {:#}", i, e));
+            }
+        }
+
+        for (i, outbound) in self.outbounds.iter().enumerate() {
+            let OutboundKind::Group(group) = outbound.kind() else {
+                continue;
+            };
+            if group.members().is_empty() {
+                problems.push(format!("outbounds[{}].members: no members", i));
+            }
+            if outbound.bind().interface().is_some() || outbound.bind().source_address().is_some() {
+                problems.push(format!(
+                    "outbounds[{}]: groups are not bound, their members are",
+                    i
+                ));
+            }
+            for member in group.members() {
+                match self.outbounds.iter().find(|o| o.name() == member) {
+                    Some(o) if matches!(o.kind(), OutboundKind::Group(_)) => problems.push(
+                        format!("outbounds[{}].members: {:?} is a group itself", i, member),
+                    ),
+                    Some(_) => {}
+                    None => problems.push(format!(
+                        "outbounds[{}].members: no outbound named {:?}",
+                        i, member
+                    )),
+                }
+            }
+        }
+
+        let Some(router) = &self.router else {
+            return problems;
+        };
+        if let Some(default) = router.default_outbound()
+            && !names.contains(&default)
+        {
+            problems.push(format!("router.default: no outbound named {:?}", default));
+        }
+        let mut sets = Vec::new();
+        for (i, set) in router.rule_sets().iter().enumerate() {
+            if sets.contains(&set.name()) {
+                problems.push(format!(
+                    "router.rule_sets[{}].name: {:?} is taken",
+                    i,
+                    set.name()
+                ));
+            }
+            sets.push(set.name());
+            if !Path::new(set.path()).is_file() {
+                problems.push(format!(
+                    "router.rule_sets[{}].path: {:?} does not exist",
+                    i,
+                    set.path()
+                ));
+            }
+        }
+        for (i, rule) in router.rules().iter().enumerate() {
+            #[cfg(any(feature = "tuic", feature = "trojan"))]
+            if let Err(e) = crate::router::Rule::compile(rule) {
+                problems.push(format!("router.rules[{}]: {:#}", i, e));
+            }
+            for group in rule.user_group() {
+                if !self
+                    .users
+                    .iter()
+                    .any(|identity| identity.groups().contains(group))
+                {
+                    problems.push(format!(
+                        "router.rules[{}].user_group: no user is in {:?}",
+                        i, group
+                    ));
+                }
+            }
+            for name in rule.rule_set() {
+                if !sets.contains(&name.as_str()) {
+                    problems.push(format!(
+                        "router.rules[{}].rule_set: no rule set named {:?}",
+                        i, name
+                    ));
+                }
+            }
+            if !names.contains(&rule.outbound()) {
+                problems.push(format!(
+                    "router.rules[{}].outbound: no outbound named {:?}",
+                    i,
+                    rule.outbound()
+                ));
+            }
+        }
+        problems
+    }
+
+    /// The legacy section, if `enabled`, and every `[[inbounds]]` entry
+    /// `pick` takes, each with its location in the config.
+    fn sections<'a, T>(
+        &'a self,
+        (name, legacy, enabled): (&str, &'a T, bool),
+        pick: impl Fn(&'a InboundConfig) -> Option<&'a Box<T>>,
+    ) -> Vec<(String, &'a T)> {
+        let inbounds = self.inbounds.iter().enumerate().filter_map(|(i, inbound)| {
+            pick(inbound).map(|config| {
+                (
+                    format!("inbounds[{}] ({})", i, inbound.tag()),
+                    config.as_ref(),
+                )
+            })
+        });
+
+        enabled
+            .then(|| (name.to_string(), legacy))
+            .into_iter()
+            .chain(inbounds)
+            .collect()
+    }
+}
+
+/// The addresses and networks in `values` that parse.
+#[cfg(any(feature = "trojan", feature = "snell"))]
+fn parse_nets(values: &[String]) -> Vec<IpNet> {
+    values
+        .iter()
+        .filter_map(|value| crate::security::banlist::parse_net(value))
+        .collect()
+}
+
+/// Notes in `problems` a `proxy_protocol_from` of the section at `at`
+/// that does not parse, or that is empty while `proxy_protocol` is on and
+/// every PROXY header would be ignored.
+fn check_proxy_protocol(problems: &mut Vec<String>, at: &str, enabled: bool, from: &[String]) {
+    if enabled && from.is_empty() {
+        problems.push(format!(
+            "{}.proxy_protocol_from: list the load balancers allowed to send PROXY headers",
+            at
+        ));
+    }
+    for (i, value) in from.iter().enumerate() {
+        if crate::security::banlist::parse_net(value).is_none() {
+            problems.push(format!(
+                "{}.proxy_protocol_from[{}]: {:?} is not an address or network",
+                at, i, value
+            ));
+        }
+    }
+}
+
+/// Parses `value`, the `key` of the section at `at`, noting it in
+/// `problems` if it is not a socket address.
+fn check_addr(problems: &mut Vec<String>, at: &str, key: &str, value: &str) -> Option<SocketAddr> {
+    value
+        .parse()
+        .inspect_err(|e| {
+            problems.push(format!(
+                "{}.{}: invalid address {:?}: {}",
+                at, key, value, e
+            ))
+        })
+        .ok()
+}
+
+/// Notes in `problems` the certificate and key files of the section at
+/// `at` that are missing, unreadable or do not belong together. With
+/// `issued`, the primary pair comes from `[acme]` or `[self_signed]` and
+/// may not exist yet.
+fn check_key_pairs(
+    problems: &mut Vec<String>,
+    at: &str,
+    cert_path: &str,
+    key_path: &str,
+    additional: &[CertificateConfig],
+    issued: bool,
+) {
+    let pairs = std::iter::once((at.to_string(), cert_path, key_path)).chain(
+        additional.iter().enumerate().map(|(i, extra)| {
+            (
+                format!("{}.certificates[{}]", at, i),
+                extra.cert_path(),
+                extra.key_path(),
+            )
+        }),
+    );
+
+    for (i, (at, cert_path, key_path)) in pairs.enumerate() {
+        if i == 0 && issued && !Path::new(cert_path).is_file() {
+            continue;
+        }
+        let missing = [("cert_path", cert_path), ("key_path", key_path)]
+            .into_iter()
+            .filter(|(_, path)| !Path::new(path).is_file())
+            .map(|(key, path)| format!("{}.{}: {:?} does not exist", at, key, path))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            problems.extend(missing);
+            continue;
+        }
+
+        #[cfg(any(feature = "tuic", feature = "trojan"))]
+        if let Err(e) =
+            crate::server::tls::check_key_pair(Path::new(cert_path), Path::new(key_path))
+        {
+            problems.push(format!("{}: {:#}", at, e));
+        }
+    }
+}
+
+/// Whether listeners on `a` and `b` would take the same port: the same
+/// address, or a wildcard covering the other. A dual-stack `[::]` covers
+/// IPv4 addresses too.
+fn overlaps(a: IpAddr, b: IpAddr) -> bool {
+    let covers =
+        |wild: IpAddr, other: IpAddr| wild.is_unspecified() && (wild.is_ipv6() || other.is_ipv4());
+    a == b || covers(a, b) || covers(b, a)
+}
+
+/// Key listing the files merged into the one that has it, relative to it.
+const INCLUDE_KEY: &str = "include";
+
+/// Reads the TOML file at `path` and merges in the files it includes,
+/// noting each file read in `sources`. Tables merge key by key and arrays
+/// (`[[users]]`, `[[policies]]`, ...) are joined; any other key may be set
+/// in one file only.
+fn load_table(path: &Path, sources: &mut Vec<PathBuf>) -> Result<toml::Table> {
+    let canonical = fs::canonicalize(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    if sources.contains(&canonical) {
+        anyhow::bail!("{} is included more than once", path.display());
+    }
+    sources.push(canonical);
+
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    let mut table: toml::Table = toml::from_str(&content)
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+    let includes = match table.remove(INCLUDE_KEY) {
+        None => Vec::new(),
+        Some(toml::Value::Array(includes)) => includes,
+        Some(_) => anyhow::bail!("{}: include must be a list of paths", path.display()),
+    };
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for include in includes {
+        let Some(include) = include.as_str() else {
+            anyhow::bail!("{}: include must be a list of paths", path.display());
+        };
+        let included = load_table(&dir.join(include), sources)?;
+        merge(&mut table, included, "")
+            .with_context(|| format!("Failed to merge {} into {}", include, path.display()))?;
+    }
+
+    Ok(table)
+}
+
+fn merge(into: &mut toml::Table, from: toml::Table, at: &str) -> Result<()> {
+    for (key, value) in from {
+        let at = match at {
+            "" => key.clone(),
+            at => format!("{}.{}", at, key),
+        };
+        match (into.get_mut(&key), value) {
+            (None, value) => {
+                into.insert(key, value);
+            }
+            (Some(toml::Value::Table(into)), toml::Value::Table(from)) => merge(into, from, &at)?,
+            (Some(toml::Value::Array(into)), toml::Value::Array(from)) => into.extend(from),
+            (Some(_), _) => anyhow::bail!("{} is set in more than one file", at),
+        }
+    }
+    Ok(())
+}
+
+/// Layout version of the configs this build reads and writes:
+///
+/// 1. TUIC only, its keys at the top level (`server_addr`, `[[users]]`).
+/// 2. A `[tuic]`, `[trojan]` or `[snell]` section per protocol, served if
+///    `enabled`.
+/// 3. Every listener an `[[inbounds]]` entry.
+///
+/// Files without `config_version` are taken to be version 2, or version 1
+/// if they set `server_addr` at the top level.
+pub const CONFIG_VERSION: u32 = 3;
+
+const CONFIG_VERSION_KEY: &str = "config_version";
+
+/// Top-level keys of the version 1 layout, which belong in `[tuic]`.
+const FLAT_TUIC_KEYS: &[&str] = &["server_addr", "cert_path", "key_path", "users"];
+
+/// Upgrades `table` from the layout version it is in to
+/// [`CONFIG_VERSION`], one version at a time. Returns the version it was
+/// in and what changed, or `None` when it was current already.
+fn migrate(table: &mut toml::Table) -> Result<Option<(u32, Vec<String>)>> {
+    let version = match table.get(CONFIG_VERSION_KEY) {
+        None if table.contains_key("server_addr") => 1,
+        None => 2,
+        Some(toml::Value::Integer(v)) if *v >= 1 => *v as u32,
+        Some(value) => anyhow::bail!(
+            "{} must be a positive integer, not {}",
+            CONFIG_VERSION_KEY,
+            value
+        ),
+    };
+    if version > CONFIG_VERSION {
+        anyhow::bail!(
+            "{} {} is newer than this build understands ({})",
+            CONFIG_VERSION_KEY,
+            version,
+            CONFIG_VERSION
+        );
+    }
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    let mut changes = Vec::new();
+    for from in version..CONFIG_VERSION {
+        match from {
+            1 => flat_to_section(table, &mut changes)?,
+            2 => sections_to_inbounds(table, &mut changes)?,
+            _ => unreachable!("no migration from config version {}", from),
+        }
+    }
+    table.insert(CONFIG_VERSION_KEY.into(), (CONFIG_VERSION as i64).into());
+
+    Ok(Some((version, changes)))
+}
+
+/// Version 1 to 2: the top-level TUIC keys move into an enabled `[tuic]`.
+fn flat_to_section(table: &mut toml::Table, changes: &mut Vec<String>) -> Result<()> {
+    if table.contains_key("tuic") {
+        anyhow::bail!("[tuic] and top-level TUIC keys cannot be used together");
+    }
+
+    let mut tuic = toml::Table::new();
+    tuic.insert("enabled".into(), true.into());
+    for key in FLAT_TUIC_KEYS {
+        if let Some(value) = table.remove(*key) {
+            changes.push(format!("Moved top-level {} to [tuic]", key));
+            tuic.insert(key.to_string(), value);
+        }
+    }
+    table.insert("tuic".into(), toml::Value::Table(tuic));
+    Ok(())
+}
+
+/// Version 2 to 3: each enabled protocol section becomes an
+/// `[[inbounds]]` entry tagged with its name, ahead of the entries there
+/// were. Disabled sections serve nothing either way and are left alone.
+fn sections_to_inbounds(table: &mut toml::Table, changes: &mut Vec<String>) -> Result<()> {
+    let mut moved = Vec::new();
+    for name in ["tuic", "trojan", "snell"] {
+        let enabled = match table.get(name) {
+            None => continue,
+            Some(toml::Value::Table(section)) => section.get("enabled").and_then(|v| v.as_bool()),
+            Some(_) => anyhow::bail!("[{}] must be a table", name),
+        };
+        if enabled != Some(true) {
+            continue;
+        }
+        let Some(toml::Value::Table(mut section)) = table.remove(name) else {
+            unreachable!();
+        };
+
+        section.remove("enabled");
+        section.insert("type".into(), name.into());
+        let tag = section.entry("tag").or_insert_with(|| name.into());
+        // A string tag without the quotes TOML would print around it.
+        let tag = tag.as_str().map_or_else(|| tag.to_string(), str::to_owned);
+        changes.push(format!(
+            "Moved [{}] to an [[inbounds]] entry tagged {}",
+            name, tag
+        ));
+        moved.push(toml::Value::Table(section));
+    }
+
+    if moved.is_empty() {
+        return Ok(());
+    }
+    match table.remove("inbounds") {
+        None => {}
+        Some(toml::Value::Array(inbounds)) => moved.extend(inbounds),
+        Some(_) => anyhow::bail!("inbounds must be a list of [[inbounds]] entries"),
+    }
+    table.insert("inbounds".into(), toml::Value::Array(moved));
+    Ok(())
+}
+
+/// Prefix of the variables that override config keys. Sections and keys
+/// are separated by a double underscore, and array entries are picked by
+/// index: `IWAY_TUIC__USERS__0__PASSWORD`.
+const ENV_OVERRIDE_PREFIX: &str = "IWAY_";
+const ENV_OVERRIDE_SEPARATOR: &str = "__";
+
+/// Replaces `${VAR}` and `${VAR:-default}` in every string under `value`,
+/// found at `at`, with the environment. `$${` stands for a literal `${`.
+fn interpolate(value: &mut toml::Value, at: &str) -> Result<()> {
+    match value {
+        toml::Value::String(s) if s.contains("${") => {
+            *s = interpolate_str(s, |name| std::env::var(name))
+                .with_context(|| format!("In {}", at))?;
+        }
+        toml::Value::Array(values) => {
+            for (i, value) in values.iter_mut().enumerate() {
+                interpolate(value, &format!("{}[{}]", at, i))?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                let at = match at {
+                    "" => key.clone(),
+                    at => format!("{}.{}", at, key),
+                };
+                interpolate(value, &at)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// `s` with its references replaced by what `lookup` finds for them.
+fn interpolate_str(
+    s: &str,
+    lookup: impl Fn(&str) -> Result<String, std::env::VarError>,
+) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+
+        let Some(end) = rest[start..].find('}') else {
+            anyhow::bail!("Unterminated ${{ in {:?}", s);
+        };
+        let reference = &rest[start + 2..start + end];
+        let (name, default) = match reference.split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (reference, None),
+        };
+
+        match (lookup(name), default) {
+            (Ok(value), _) => out.push_str(&value),
+            (Err(_), Some(default)) => out.push_str(default),
+            (Err(std::env::VarError::NotPresent), None) => {
+                anyhow::bail!("Environment variable {} is not set", name)
+            }
+            (Err(std::env::VarError::NotUnicode(_)), None) => {
+                anyhow::bail!("Environment variable {} is not valid UTF-8", name)
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Sets the keys that `IWAY_*` variables among `vars` name under `root`.
+fn apply_env_overrides(
+    root: &mut toml::Value,
+    vars: impl Iterator<Item = (String, String)>,
+) -> Result<()> {
+    for (name, raw) in vars {
+        let Some(path) = name.strip_prefix(ENV_OVERRIDE_PREFIX) else {
+            continue;
+        };
+        let path = path.to_ascii_lowercase();
+        let keys: Vec<&str> = path.split(ENV_OVERRIDE_SEPARATOR).collect();
+        if keys.iter().any(|key| key.is_empty()) {
+            continue;
+        }
+
+        set_override(root, &keys, raw).with_context(|| format!("In {}", name))?;
+    }
+    Ok(())
+}
+
+/// Sets `keys` under `target` to `raw`, creating the tables on the way.
+/// Array entries must already exist.
+fn set_override(target: &mut toml::Value, keys: &[&str], raw: String) -> Result<()> {
+    let Some((key, rest)) = keys.split_first() else {
+        return Ok(());
+    };
+
+    let child = match target {
+        toml::Value::Table(table) => {
+            if rest.is_empty() {
+                let value = override_value(table.get(*key), raw);
+                table.insert(key.to_string(), value);
+                return Ok(());
+            }
+            table
+                .entry(key.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+        }
+        toml::Value::Array(values) => {
+            let index: usize = key
+                .parse()
+                .with_context(|| format!("{:?} is not an array index", key))?;
+            let Some(value) = values.get_mut(index) else {
+                anyhow::bail!("There is no entry {} to override", index);
+            };
+            if rest.is_empty() {
+                *value = override_value(Some(value), raw);
+                return Ok(());
+            }
+            value
+        }
+        _ => anyhow::bail!("{:?} is not a table", key),
+    };
+
+    set_override(child, rest, raw)
+}
+
+/// `raw` as the new value of a key holding `current`. A key that holds a
+/// string takes it as is; others take it as a TOML value (`true`, `8`,
+/// `["a", "b"]`) when it parses as one.
+fn override_value(current: Option<&toml::Value>, raw: String) -> toml::Value {
+    if let Some(toml::Value::String(_)) = current {
+        return toml::Value::String(raw);
+    }
+
+    toml::from_str::<toml::Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or(toml::Value::String(raw))
+}
+
+/// Pairs each repeated key's first location with every later one.
+fn duplicates(entries: impl Iterator<Item = (String, String)>) -> Vec<(String, String, String)> {
+    let mut seen: HashMap<String, String> = HashMap::new();
+    let mut found = Vec::new();
+
+    for (location, key) in entries {
+        match seen.get(&key) {
+            Some(first) => found.push((first.clone(), location, key)),
+            None => {
+                seen.insert(key, location);
+            }
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An environment holding only `vars`.
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Result<String, std::env::VarError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| {
+            vars.get(name)
+                .cloned()
+                .ok_or(std::env::VarError::NotPresent)
+        }
+    }
+
+    fn overridden(toml: &str, vars: &[(&str, &str)]) -> Result<toml::Value> {
+        let mut root = toml::Value::Table(toml::from_str(toml).unwrap());
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()));
+        apply_env_overrides(&mut root, vars)?;
+        Ok(root)
+    }
+
+    #[test]
+    fn interpolates_variables_and_defaults() {
+        let env = env(&[("HOST", "example.com"), ("PORT", "443")]);
+        assert_eq!(
+            interpolate_str("${HOST}:${PORT}", &env).unwrap(),
+            "example.com:443"
+        );
+        assert_eq!(
+            interpolate_str("${MISSING:-fallback}/${HOST:-unused}", &env).unwrap(),
+            "fallback/example.com"
+        );
+        assert_eq!(interpolate_str("${MISSING:-}", &env).unwrap(), "");
+    }
+
+    #[test]
+    fn unset_variable_is_an_error() {
+        let e = interpolate_str("password = ${SECRET}", env(&[])).unwrap_err();
+        assert!(e.to_string().contains("SECRET is not set"), "{}", e);
+        assert!(interpolate_str("${UNTERMINATED", env(&[])).is_err());
+    }
+
+    #[test]
+    fn double_dollar_escapes() {
+        let env = env(&[("HOST", "example.com")]);
+        assert_eq!(interpolate_str("$${HOST}", &env).unwrap(), "${HOST}");
+        assert_eq!(
+            interpolate_str("$${HOST} is ${HOST}", &env).unwrap(),
+            "${HOST} is example.com"
+        );
+    }
+
+    #[test]
+    fn overrides_nested_tables_and_array_entries() {
+        let root = overridden(
+            "[tuic]\nserver_addr = \"[::]:443\"\n\
+             [[tuic.users]]\nuuid = \"a\"\npassword = \"old\"",
+            &[
+                ("IWAY_TUIC__SERVER_ADDR", "0.0.0.0:8443"),
+                ("IWAY_TUIC__USERS__0__PASSWORD", "new"),
+                ("IWAY_TUIC__TRANSPORT__SEND_WINDOW", "1048576"),
+                ("UNRELATED", "ignored"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(root["tuic"]["server_addr"].as_str(), Some("0.0.0.0:8443"));
+        assert_eq!(root["tuic"]["users"][0]["password"].as_str(), Some("new"));
+        assert_eq!(root["tuic"]["users"][0]["uuid"].as_str(), Some("a"));
+        // Missing tables are created on the way.
+        assert_eq!(
+            root["tuic"]["transport"]["send_window"].as_integer(),
+            Some(1048576)
+        );
+        assert!(root.get("unrelated").is_none());
+
+        let e = overridden(
+            "[[tuic.users]]\nuuid = \"a\"",
+            &[("IWAY_TUIC__USERS__1__PASSWORD", "x")],
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", e).contains("no entry 1"), "{:#}", e);
+    }
+
+    #[test]
+    fn overrides_take_the_type_of_the_value() {
+        let root = overridden(
+            "[trojan]\nenabled = false\nhandshake_timeout = 10\ntag = \"abc\"",
+            &[
+                ("IWAY_TROJAN__ENABLED", "true"),
+                ("IWAY_TROJAN__HANDSHAKE_TIMEOUT", "30"),
+                // A key that holds a string keeps one, even if it parses.
+                ("IWAY_TROJAN__TAG", "42"),
+                ("IWAY_TROJAN__SNI_BACKEND", "127.0.0.1:8443"),
+                (
+                    "IWAY_TROJAN__SERVER_NAMES",
+                    "[\"a.example\", \"b.example\"]",
+                ),
+            ],
+        )
+        .unwrap();
+        let trojan = &root["trojan"];
+        assert_eq!(trojan["enabled"].as_bool(), Some(true));
+        assert_eq!(trojan["handshake_timeout"].as_integer(), Some(30));
+        assert_eq!(trojan["tag"].as_str(), Some("42"));
+        assert_eq!(trojan["sni_backend"].as_str(), Some("127.0.0.1:8443"));
+        assert_eq!(trojan["server_names"].as_array().map(Vec::len), Some(2));
+    }
+
+    /// A fresh directory for the files of the test `name`.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("iway-config-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn merges_included_tables_and_joins_arrays() {
+        let dir = scratch("merge");
+        let main = write(
+            &dir,
+            "config.toml",
+            "include = [\"users.toml\"]\n\
+             [trojan]\nserver_addr = \"[::]:443\"\n\
+             [[trojan.users]]\nuuid = \"main\"\npassword = \"a\"",
+        );
+        write(
+            &dir,
+            "users.toml",
+            "[trojan]\nfallback_addr = \"127.0.0.1:8080\"\n\
+             [[trojan.users]]\nuuid = \"included\"\npassword = \"b\"",
+        );
+
+        let mut sources = Vec::new();
+        let table = load_table(&main, &mut sources).unwrap();
+        assert_eq!(sources.len(), 2);
+        assert!(!table.contains_key(INCLUDE_KEY));
+        let trojan = table["trojan"].as_table().unwrap();
+        assert_eq!(trojan["server_addr"].as_str(), Some("[::]:443"));
+        assert_eq!(trojan["fallback_addr"].as_str(), Some("127.0.0.1:8080"));
+        // The including file's entries come first.
+        let users: Vec<_> = trojan["users"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["uuid"].as_str().unwrap())
+            .collect();
+        assert_eq!(users, ["main", "included"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_key_set_in_two_files() {
+        let mut into: toml::Table = toml::from_str("[tuic]\nserver_addr = \"[::]:443\"").unwrap();
+        let from: toml::Table = toml::from_str("[tuic]\nserver_addr = \"[::]:8443\"").unwrap();
+        let e = merge(&mut into, from, "").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "tuic.server_addr is set in more than one file"
+        );
+
+        // A table against a scalar conflicts as well.
+        let mut into: toml::Table = toml::from_str("tuic = 1").unwrap();
+        let from: toml::Table = toml::from_str("[tuic]\nenabled = true").unwrap();
+        assert!(merge(&mut into, from, "").is_err());
+    }
+
+    #[test]
+    fn rejects_include_cycles() {
+        let dir = scratch("cycle");
+        let main = write(&dir, "a.toml", "include = [\"b.toml\"]");
+        write(&dir, "b.toml", "include = [\"a.toml\"]");
+        let e = load_table(&main, &mut Vec::new()).unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("a.toml is included more than once"),
+            "{:#}",
+            e
+        );
+
+        // Including itself is the shortest cycle.
+        let own = write(&dir, "self.toml", "include = [\"self.toml\"]");
+        assert!(load_table(&own, &mut Vec::new()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolves_includes_against_the_including_file() {
+        let dir = scratch("relative");
+        let main = write(&dir, "config.toml", "include = [\"conf.d/inbounds.toml\"]");
+        write(
+            &dir,
+            "conf.d/inbounds.toml",
+            "include = [\"users.toml\"]\n[snell]\npsk = \"secret\"",
+        );
+        // Next to the file including it, not to config.toml.
+        write(&dir, "conf.d/users.toml", "[snell]\nenabled = true");
+
+        let mut sources = Vec::new();
+        let table = load_table(&main, &mut sources).unwrap();
+        assert_eq!(sources.len(), 3);
+        assert_eq!(table["snell"]["psk"].as_str(), Some("secret"));
+        assert_eq!(table["snell"]["enabled"].as_bool(), Some(true));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn migrated(content: &str) -> (toml::Table, u32, Vec<String>) {
+        let mut table: toml::Table = toml::from_str(content).unwrap();
+        let (from, changes) = migrate(&mut table).unwrap().unwrap();
+        (table, from, changes)
+    }
+
+    #[test]
+    fn migrates_version_1_to_inbounds() {
+        let (table, from, changes) = migrated(
+            "server_addr = \"[::]:443\"\ncert_path = \"cert.pem\"\nkey_path = \"key.pem\"\n\
+             [[users]]\nuuid = \"u\"\npassword = \"p\"",
+        );
+        assert_eq!(from, 1);
+        assert_eq!(
+            changes,
+            [
+                "Moved top-level server_addr to [tuic]",
+                "Moved top-level cert_path to [tuic]",
+                "Moved top-level key_path to [tuic]",
+                "Moved top-level users to [tuic]",
+                "Moved [tuic] to an [[inbounds]] entry tagged tuic",
+            ]
+        );
+        assert_eq!(
+            table[CONFIG_VERSION_KEY].as_integer(),
+            Some(CONFIG_VERSION as i64)
+        );
+        assert!(!table.contains_key("server_addr") && !table.contains_key("tuic"));
+
+        let inbounds = table["inbounds"].as_array().unwrap();
+        assert_eq!(inbounds.len(), 1);
+        let tuic = inbounds[0].as_table().unwrap();
+        assert_eq!(tuic["type"].as_str(), Some("tuic"));
+        assert_eq!(tuic["tag"].as_str(), Some("tuic"));
+        assert_eq!(tuic["server_addr"].as_str(), Some("[::]:443"));
+        assert_eq!(tuic["users"].as_array().map(Vec::len), Some(1));
+        assert!(!tuic.contains_key("enabled"));
+    }
+
+    #[test]
+    fn migrates_version_2_enabled_sections_ahead_of_inbounds() {
+        let (table, from, changes) = migrated(
+            "[trojan]\nenabled = true\ntag = \"edge\"\nserver_addr = \"[::]:443\"\n\
+             [snell]\nenabled = false\npsk = \"secret\"\n\
+             [[inbounds]]\ntype = \"tuic\"\ntag = \"existing\"",
+        );
+        assert_eq!(from, 2);
+        assert_eq!(
+            changes,
+            ["Moved [trojan] to an [[inbounds]] entry tagged edge"]
+        );
+
+        let tags: Vec<_> = table["inbounds"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|inbound| inbound["tag"].as_str().unwrap())
+            .collect();
+        assert_eq!(tags, ["edge", "existing"]);
+        assert_eq!(table["inbounds"][0]["type"].as_str(), Some("trojan"));
+        // A disabled section serves nothing and stays where it was.
+        assert_eq!(table["snell"]["enabled"].as_bool(), Some(false));
+    }
+
+    #[test]
+    fn leaves_current_configs_alone() {
+        let mut table: toml::Table = toml::from_str("config_version = 3").unwrap();
+        assert!(migrate(&mut table).unwrap().is_none());
+
+        let mut table: toml::Table = toml::from_str("config_version = 4").unwrap();
+        assert!(migrate(&mut table).is_err());
+    }
+
+    #[test]
+    fn upgrade_file_rewrites_and_backs_up() {
+        let dir = scratch("upgrade");
+        let original = "server_addr = \"[::]:443\"\n";
+        let path = write(&dir, "config.toml", original);
+
+        let changes = Config::upgrade_file(&path).unwrap().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(
+            fs::read_to_string(dir.join("config.toml.bak")).unwrap(),
+            original
+        );
+        let upgraded: toml::Table = toml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(
+            upgraded[CONFIG_VERSION_KEY].as_integer(),
+            Some(CONFIG_VERSION as i64)
+        );
+        assert_eq!(
+            upgraded["inbounds"][0]["server_addr"].as_str(),
+            Some("[::]:443")
+        );
+
+        // Upgrading again finds nothing to do.
+        assert!(Config::upgrade_file(&path).unwrap().is_none());
+
+        let split = write(&dir, "split.toml", "include = [\"config.toml\"]");
+        assert!(Config::upgrade_file(&split).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn validate_reports_every_problem_at_once() {
+        let config: Config = toml::from_str(
+            "[tuic]\nenabled = true\nserver_addr = \"nowhere:443\"\n\
+             [[tuic.users]]\nuuid = \"not-a-uuid\"\npassword = \"p\"\n\
+             [[tuic.certificates]]\ncert_path = \"/nonexistent/cert.pem\"\n\
+             key_path = \"/nonexistent/key.pem\"\n\
+             [snell]\nenabled = true\nserver_addr = \"[::]:8388\"\npsk = \"secret\"\n\
+             proxy_protocol = true\nproxy_protocol_from = [\"10.0.0.0/33\"]\n\
+             [acme]\ndomains = [\"example.com\"]\n\
+             [self_signed]\nserver_names = [\"example.com\"]",
+        )
+        .unwrap();
+        let problems = config.validate();
+
+        for expected in [
+            "tuic.server_addr: invalid address \"nowhere:443\": invalid socket address syntax",
+            "tuic.certificates[0].cert_path: \"/nonexistent/cert.pem\" does not exist",
+            "tuic.certificates[0].key_path: \"/nonexistent/key.pem\" does not exist",
+            "snell.proxy_protocol_from[0]: \"10.0.0.0/33\" is not an address or network",
+            "self_signed: [acme] provides the certificates already",
+        ] {
+            assert!(
+                problems.iter().any(|problem| problem == expected),
+                "{:?} not in {:#?}",
+                expected,
+                problems
+            );
+        }
+        assert!(
+            problems
+                .iter()
+                .any(|problem| problem
+                    .starts_with("tuic.users[0].uuid: \"not-a-uuid\" is not a UUID")),
+            "{:#?}",
+            problems
+        );
+    }
+
+    #[test]
+    fn proxy_protocol_needs_trusted_senders() {
+        let config: Config =
+            toml::from_str("[snell]\nenabled = true\npsk = \"secret\"\nproxy_protocol = true")
+                .unwrap();
+        assert_eq!(
+            config.validate(),
+            ["snell.proxy_protocol_from: list the load balancers allowed to send PROXY headers"]
+        );
+    }
+
+    #[test]
+    fn sample_config_is_current() {
+        let config =
+            Config::from_file(Path::new(env!("CARGO_MANIFEST_DIR")).join("config.toml")).unwrap();
+        assert_eq!(config.migrated_from, None);
+        assert_eq!(config.inbounds.len(), 1);
+        assert_eq!(config.inbounds[0].protocol(), "trojan");
     }
 }
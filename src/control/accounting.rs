@@ -0,0 +1,159 @@
+//! Bytes relayed per user, over every session they ever had: the sessions
+//! that closed are summed here, live ones are read off the registry. With
+//! `[accounting]` configured the totals are kept in a JSON file and carry
+//! over restarts.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::config::AccountingConfig;
+use crate::control::registry::registry;
+
+/// Totals of closed sessions, and whatever the store held at startup.
+static CLOSED: Lazy<DashMap<String, Usage>> = Lazy::new(DashMap::new);
+
+/// Bytes a user sent towards targets (`up`) and received back (`down`).
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Usage {
+    pub up: u64,
+    pub down: u64,
+}
+
+/// Counts the bytes of a session of `user` that just closed.
+pub(crate) fn add(user: &str, up: u64, down: u64) {
+    let mut usage = CLOSED.entry(user.to_string()).or_default();
+    usage.up += up;
+    usage.down += down;
+}
+
+/// Every user's totals so far, live sessions included.
+pub fn totals() -> BTreeMap<String, Usage> {
+    let mut totals: BTreeMap<String, Usage> = CLOSED
+        .iter()
+        .map(|entry| (entry.key().clone(), *entry.value()))
+        .collect();
+    for (user, live) in registry().traffic_by_user() {
+        let usage = totals.entry(user).or_default();
+        usage.up += live.up;
+        usage.down += live.down;
+    }
+    totals
+}
+
+/// The totals as a table, or the row of `user` alone.
+#[cfg(feature = "control")]
+pub fn render(user: Option<&str>) -> String {
+    let totals = totals();
+    let mut out = format!("{:<38} {:>16} {:>16}\n", "USER", "UP", "DOWN");
+    for (name, usage) in &totals {
+        if user.is_some_and(|user| user != name) {
+            continue;
+        }
+        out.push_str(&format!(
+            "{:<38} {:>16} {:>16}\n",
+            name, usage.up, usage.down
+        ));
+    }
+    out
+}
+
+/// The running store; totals keep being written until it is flushed.
+pub struct Accounting {
+    flush_tx: Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Accounting {
+    /// Writes the totals a last time and stops the store.
+    pub async fn flush(self) {
+        let _ = self.flush_tx.send(());
+        let _ = self.task.await;
+    }
+}
+
+/// Loads the stored totals and starts writing them back on an interval, if
+/// `[accounting]` is configured. A store that cannot be read is left alone
+/// and nothing is persisted, rather than overwriting it with fresh counts.
+pub fn spawn(config: Option<&AccountingConfig>) -> Option<Accounting> {
+    let config = config.cloned()?;
+
+    match load(Path::new(config.path())) {
+        Ok(stored) => {
+            let users = stored.len();
+            for (user, usage) in stored {
+                add(&user, usage.up, usage.down);
+            }
+            info!(
+                "[Accounting] Loaded totals of {} user(s) from {}, saving every {:?}",
+                users,
+                config.path(),
+                config.flush_interval()
+            );
+        }
+        Err(e) => {
+            warn!("[Accounting] Not persisting traffic totals: {:#}", e);
+            return None;
+        }
+    }
+
+    let (flush_tx, flush_rx) = watch::channel(());
+    let task = tokio::spawn(run(config, flush_rx));
+
+    Some(Accounting { flush_tx, task })
+}
+
+fn load(path: &Path) -> Result<HashMap<String, Usage>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+async fn run(config: AccountingConfig, mut flush_rx: Receiver<()>) {
+    let path = PathBuf::from(config.path());
+    let mut ticker = tokio::time::interval(config.flush_interval());
+    ticker.tick().await;
+
+    loop {
+        let last = tokio::select! {
+            _ = ticker.tick() => false,
+            _ = flush_rx.changed() => true,
+        };
+
+        match save(&path).await {
+            Ok(users) => debug!("[Accounting] Saved totals of {} user(s)", users),
+            Err(e) => warn!("[Accounting] Failed to save traffic totals: {:#}", e),
+        }
+        if last {
+            break;
+        }
+    }
+}
+
+/// Replaces the store with the current totals, through a temporary file so
+/// a crash mid-write leaves the previous one intact.
+async fn save(path: &Path) -> Result<usize> {
+    let totals = totals();
+    let content = serde_json::to_vec_pretty(&totals)?;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, content)
+        .await
+        .with_context(|| format!("Failed to write {:?}", tmp))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+
+    Ok(totals.len())
+}
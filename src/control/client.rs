@@ -0,0 +1,108 @@
+use anyhow::{Context, Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::Config;
+
+/// Entry point of `iway ctl [-c <config>] <command...>`. Returns the process
+/// exit code.
+pub fn run(args: &[String]) -> i32 {
+    match run_inner(args) {
+        Ok(reply) => {
+            print!("{}", reply);
+            if reply.starts_with("error:") { 1 } else { 0 }
+        }
+        Err(e) => {
+            eprintln!("iway ctl: {:#}", e);
+            1
+        }
+    }
+}
+
+fn run_inner(args: &[String]) -> Result<String> {
+    let mut config_path = String::from("config.toml");
+    let mut command = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                config_path = args.next().context("Missing value for --config")?.clone();
+            }
+            _ => command.push(arg.as_str()),
+        }
+    }
+
+    if command.is_empty() {
+        bail!(
+            "usage: iway ctl [-c <config>] status | users | connections | traffic [user] | paths | commands | kick <user|id> | disable <user> | enable <user> | udp <on|off> | top [n] | bans | unban <ip> | check-users"
+        );
+    }
+
+    // Checked against the file on disk, so a config can be vetted before
+    // the server is (re)started with it.
+    if command == ["check-users"] {
+        let config = Config::from_file(&config_path)?;
+        let conflicts = config.credential_conflicts();
+        if conflicts.is_empty() {
+            return Ok(String::from("no credential conflicts\n"));
+        }
+        return Ok(conflicts
+            .iter()
+            .map(|conflict| format!("error: {}\n", conflict))
+            .collect());
+    }
+
+    // Without a file the server runs on defaults too; a broken one would
+    // only send the command to the wrong place.
+    let config = if std::path::Path::new(&config_path).exists() {
+        Config::from_file(&config_path)?
+    } else {
+        Config::default()
+    };
+    let path = config.control().path().to_string();
+    let line = command.join(" ");
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to build tokio runtime")?;
+
+    runtime.block_on(async move {
+        let stream = connect(&path)
+            .await
+            .with_context(|| format!("Failed to connect to control channel {}", path))?;
+        request(stream, &line).await
+    })
+}
+
+async fn request<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, line: &str) -> Result<String> {
+    stream.write_all(line.as_bytes()).await?;
+    stream.write_all(b"\n").await?;
+    stream.flush().await?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    Ok(reply)
+}
+
+#[cfg(unix)]
+async fn connect(path: &str) -> std::io::Result<tokio::net::UnixStream> {
+    tokio::net::UnixStream::connect(path).await
+}
+
+#[cfg(windows)]
+async fn connect(path: &str) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeClient> {
+    use tokio::net::windows::named_pipe::ClientOptions;
+
+    const ERROR_PIPE_BUSY: i32 = 231;
+
+    loop {
+        match ClientOptions::new().open(path) {
+            Ok(client) => return Ok(client),
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use crate::config::{StatsExportConfig, StatsExportFormat};
+use crate::events::{self, Event, SessionRecord};
+use crate::net::http::request_with_headers;
+
+const CSV_HEADER: &str =
+    "id,protocol,inbound,user,peer,start_time,duration_ms,bytes_up,bytes_down\n";
+
+const CLICKHOUSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Batches' worth of records kept for retry while exports fail; past it
+/// the oldest are dropped.
+const MAX_PENDING_BATCHES: usize = 10;
+
+fn write_csv(record: &SessionRecord, out: &mut String) {
+    let start_time = record
+        .started_at
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+
+    let _ = write!(out, "{},{},", record.id, record.protocol);
+    push_csv_field(out, &record.inbound);
+    out.push(',');
+    push_csv_field(out, record.user.as_deref().unwrap_or_default());
+    let _ = writeln!(
+        out,
+        ",{},{},{},{},{}",
+        record.peer_addr,
+        start_time,
+        record.duration.as_millis(),
+        record.bytes_up,
+        record.bytes_down
+    );
+}
+
+fn push_csv_field(out: &mut String, value: &str) {
+    if value.contains([',', '"', '\n', '\r']) {
+        out.push('"');
+        out.push_str(&value.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(value);
+    }
+}
+
+/// A running exporter; records keep being batched until it is flushed.
+pub struct Exporter {
+    flush_tx: Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Exporter {
+    /// Writes out the remaining records and stops the exporter.
+    pub async fn flush(self) {
+        let _ = self.flush_tx.send(());
+        let _ = self.task.await;
+    }
+}
+
+/// Starts the exporter task if it is configured.
+pub fn spawn(config: Option<&StatsExportConfig>) -> Option<Exporter> {
+    let config = config.cloned()?;
+
+    info!(
+        "[Stats] Exporting session records as {:?} every {:?}",
+        config.format(),
+        config.interval()
+    );
+
+    let (flush_tx, flush_rx) = watch::channel(());
+    let task = tokio::spawn(run(config, flush_rx));
+
+    Some(Exporter { flush_tx, task })
+}
+
+async fn run(config: StatsExportConfig, mut flush_rx: Receiver<()>) {
+    let mut events = events::subscribe();
+    let mut batch = Vec::with_capacity(config.batch_size());
+    let mut ticker = tokio::time::interval(config.interval());
+    ticker.tick().await;
+    // While exports fail, only the ticker retries them, so a sink that is
+    // down is not hit once per record.
+    let mut failing = false;
+
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                match event {
+                    Ok(Event::ConnectionClosed(record)) => batch.push(record),
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("[Stats] Exporter fell behind, missed {} event(s)", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+                if failing || batch.len() < config.batch_size() {
+                    continue;
+                }
+            }
+            _ = ticker.tick() => {}
+            _ = flush_rx.changed() => {
+                while let Ok(event) = events.try_recv() {
+                    if let Event::ConnectionClosed(record) = event {
+                        batch.push(record);
+                    }
+                }
+                flush(&config, &mut batch).await;
+                break;
+            }
+        }
+
+        failing = !flush(&config, &mut batch).await;
+    }
+}
+
+/// Exports `batch` and empties it. A batch that fails to export is kept
+/// for the next flush, trimmed to `MAX_PENDING_BATCHES` batches; returns
+/// whether it went out.
+async fn flush(config: &StatsExportConfig, batch: &mut Vec<Arc<SessionRecord>>) -> bool {
+    if batch.is_empty() {
+        return true;
+    }
+
+    let mut rows = String::new();
+    for record in batch.iter() {
+        write_csv(record, &mut rows);
+    }
+
+    let result = match config.format() {
+        StatsExportFormat::Csv => append_csv(config.path(), &rows).await,
+        StatsExportFormat::Clickhouse => insert_clickhouse(config, &rows).await,
+    };
+
+    match result {
+        Ok(()) => {
+            debug!("[Stats] Exported {} session record(s)", batch.len());
+            batch.clear();
+            true
+        }
+        Err(e) => {
+            warn!(
+                "[Stats] Failed to export {} session record(s), retrying on the next flush: {:#}",
+                batch.len(),
+                e
+            );
+            let cap = config.batch_size().saturating_mul(MAX_PENDING_BATCHES);
+            if batch.len() > cap {
+                let dropped = batch.len() - cap;
+                batch.drain(..dropped);
+                warn!(
+                    "[Stats] Dropped the {} oldest session record(s) to keep {} for retry",
+                    dropped, cap
+                );
+            }
+            false
+        }
+    }
+}
+
+async fn append_csv(path: &str, rows: &str) -> Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Failed to open {}", path))?;
+
+    if file.metadata().await?.len() == 0 {
+        file.write_all(CSV_HEADER.as_bytes()).await?;
+    }
+    file.write_all(rows.as_bytes()).await?;
+    file.flush().await?;
+
+    Ok(())
+}
+
+/// Inserts the rows through ClickHouse's HTTP interface, at `url` over
+/// http:// or https://.
+async fn insert_clickhouse(config: &StatsExportConfig, rows: &str) -> Result<()> {
+    let body = format!("{}{}", CSV_HEADER, rows);
+    let query = format!("INSERT INTO {} FORMAT CSVWithNames", config.table());
+    let url = format!(
+        "{}/?query={}",
+        config.url().trim_end_matches('/'),
+        encode_query(&query)
+    );
+
+    let mut headers = Vec::new();
+    if let Some(user) = config.user() {
+        headers.push(("X-ClickHouse-User", user));
+    }
+    if let Some(password) = config.password() {
+        headers.push(("X-ClickHouse-Key", password));
+    }
+
+    let response = request_with_headers(
+        &url,
+        "POST",
+        &headers,
+        Some(("text/csv", body.as_bytes())),
+        CLICKHOUSE_TIMEOUT,
+    )
+    .await
+    .with_context(|| format!("ClickHouse request to {} failed", config.url()))?;
+
+    if response.status() != 200 {
+        bail!(
+            "ClickHouse replied {}: {}",
+            response.status(),
+            response.text().trim()
+        );
+    }
+
+    Ok(())
+}
+
+fn encode_query(query: &str) -> String {
+    let mut out = String::with_capacity(query.len() * 3);
+    for byte in query.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' => out.push(byte as char),
+            b' ' => out.push('+'),
+            _ => {
+                let _ = write!(out, "%{:02X}", byte);
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn record(id: u64) -> Arc<SessionRecord> {
+        Arc::new(SessionRecord {
+            id,
+            protocol: "Trojan",
+            inbound: Arc::from("trojan"),
+            user: Some(String::from("alice")),
+            peer_addr: "192.0.2.1:40000".parse().unwrap(),
+            started_at: SystemTime::now(),
+            duration: Duration::from_secs(1),
+            bytes_up: 10,
+            bytes_down: 20,
+        })
+    }
+
+    #[tokio::test]
+    async fn keeps_failed_batches_for_retry() {
+        let dir = std::env::temp_dir().join(format!("iway-export-{}", std::process::id()));
+        let path = dir.join("sessions.csv");
+        let config: StatsExportConfig = toml::from_str(&format!(
+            "path = {:?}\nbatch_size = 2",
+            path.to_str().unwrap()
+        ))
+        .unwrap();
+
+        // The directory is missing, so the file cannot be opened.
+        let mut batch: Vec<_> = (0..25).map(record).collect();
+        assert!(!flush(&config, &mut batch).await);
+        assert_eq!(batch.len(), 2 * MAX_PENDING_BATCHES);
+        assert_eq!(batch[0].id, 5, "the oldest records go first");
+
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(flush(&config, &mut batch).await);
+        assert!(batch.is_empty());
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with(CSV_HEADER));
+        assert_eq!(written.lines().count(), 1 + 2 * MAX_PENDING_BATCHES);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
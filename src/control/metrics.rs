@@ -0,0 +1,96 @@
+//! Per-command protocol counters, grouped by protocol, command and outcome.
+//! Without the `metrics` feature nothing is counted, and Snell has no
+//! commands to count.
+
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+use std::fmt;
+#[cfg(all(feature = "metrics", any(feature = "tuic", feature = "trojan")))]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(all(feature = "metrics", any(feature = "tuic", feature = "trojan")))]
+use dashmap::DashMap;
+#[cfg(all(feature = "metrics", any(feature = "tuic", feature = "trojan")))]
+use once_cell::sync::Lazy;
+
+#[cfg(all(feature = "metrics", any(feature = "tuic", feature = "trojan")))]
+static COMMANDS: Lazy<DashMap<(&'static str, &'static str, Outcome), AtomicU64>> =
+    Lazy::new(DashMap::new);
+
+/// How a single protocol command ended.
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Outcome {
+    Ok,
+    /// The command could not be decoded.
+    ParseError,
+    /// The command was dropped because the connection never authenticated.
+    AuthGated,
+    /// The command was decoded but processing it failed.
+    Failed,
+}
+
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Outcome::Ok => "ok",
+            Outcome::ParseError => "parse_error",
+            Outcome::AuthGated => "auth_gated",
+            Outcome::Failed => "failed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Counts one `command` of `protocol` that ended with `outcome`.
+#[cfg(all(feature = "metrics", any(feature = "tuic", feature = "trojan")))]
+pub fn count(protocol: &'static str, command: &'static str, outcome: Outcome) {
+    if let Some(counter) = COMMANDS.get(&(protocol, command, outcome)) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    COMMANDS
+        .entry((protocol, command, outcome))
+        .or_default()
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(all(not(feature = "metrics"), any(feature = "tuic", feature = "trojan")))]
+pub fn count(_protocol: &'static str, _command: &'static str, _outcome: Outcome) {}
+
+/// One line per protocol, command and outcome seen since startup.
+#[cfg(all(
+    feature = "metrics",
+    feature = "control",
+    any(feature = "tuic", feature = "trojan")
+))]
+pub fn commands() -> String {
+    let mut rows: Vec<_> = COMMANDS
+        .iter()
+        .map(|entry| (*entry.key(), entry.value().load(Ordering::Relaxed)))
+        .collect();
+    rows.sort_unstable();
+
+    let mut out = format!(
+        "{:<8} {:<14} {:<12} {:>12}\n",
+        "PROTOCOL", "COMMAND", "OUTCOME", "COUNT"
+    );
+    for ((protocol, command, outcome), n) in rows {
+        out.push_str(&format!(
+            "{:<8} {:<14} {:<12} {:>12}\n",
+            protocol,
+            command,
+            outcome.to_string(),
+            n
+        ));
+    }
+    out
+}
+
+#[cfg(all(
+    feature = "control",
+    not(all(feature = "metrics", any(feature = "tuic", feature = "trojan")))
+))]
+pub fn commands() -> String {
+    String::from("error: built without command counters\n")
+}
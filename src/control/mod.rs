@@ -0,0 +1,155 @@
+pub mod accounting;
+#[cfg(feature = "control")]
+pub mod client;
+#[cfg(feature = "metrics")]
+pub mod export;
+#[cfg(any(feature = "tuic", feature = "trojan", feature = "control"))]
+pub mod metrics;
+pub mod registry;
+pub mod summary;
+pub mod talkers;
+
+#[cfg(all(feature = "control", any(feature = "tuic", feature = "trojan")))]
+use crate::net::udp;
+#[cfg(feature = "control")]
+use crate::policy::{udp_guard, users};
+#[cfg(all(feature = "tuic", feature = "control"))]
+use crate::processor::tuic::command;
+#[cfg(feature = "tuic")]
+use crate::processor::tuic::session;
+#[cfg(feature = "control")]
+use crate::security::banlist;
+#[cfg(all(feature = "trojan", feature = "control"))]
+use crate::security::fingerprint;
+#[cfg(all(feature = "trojan", feature = "control"))]
+use crate::server::tickets;
+use registry::registry;
+
+/// Rows `top` lists when no count is given.
+#[cfg(feature = "control")]
+const DEFAULT_TOP: usize = 10;
+
+#[cfg(feature = "control")]
+const USAGE: &str = "usage: status | users | connections | traffic [user] | paths | commands | kick <user|id> | disable <user> | enable <user> | udp <on|off> | top [n] | bans | unban <ip>";
+
+/// Executes one line of the control protocol and returns the reply.
+#[cfg(feature = "control")]
+pub fn handle_command(line: &str) -> String {
+    let mut parts = line.split_whitespace();
+
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("status"), None, None) => {
+            let mut reply = registry().status();
+            #[cfg(feature = "tuic")]
+            reply.push_str(&format!(
+                "udp associations: {} (expired: {}, duplicate packets: {})\n",
+                session::active_associations(),
+                session::expired_associations(),
+                session::duplicate_packets()
+            ));
+            #[cfg(feature = "tuic")]
+            reply.push_str(&format!(
+                "unauthenticated floods closed: {}\n",
+                command::unauthenticated_floods()
+            ));
+            #[cfg(feature = "trojan")]
+            reply.push_str(&format!(
+                "scanner fingerprints matched: {}\n",
+                fingerprint::matched()
+            ));
+            #[cfg(feature = "trojan")]
+            reply.push_str(&tickets::status());
+            #[cfg(any(feature = "tuic", feature = "trojan"))]
+            reply.push_str(&format!(
+                "icmp unreachable errors: {}\n",
+                udp::icmp_unreachable_total()
+            ));
+            reply.push_str(&udp_guard::status());
+            reply
+        }
+        (Some("users"), None, None) => registry().users(),
+        (Some("connections"), None, None) => registry().connections(),
+        (Some("traffic"), user, None) => accounting::render(user),
+        (Some("top"), None, None) => talkers::render(DEFAULT_TOP),
+        (Some("top"), Some(n), None) => match n.parse() {
+            Ok(n) => talkers::render(n),
+            Err(_) => format!("error: {:?} is not a count\n", n),
+        },
+        (Some("paths"), None, None) => registry().paths(),
+        (Some("commands"), None, None) => metrics::commands(),
+        (Some("kick"), Some(target), None) => {
+            let kicked = registry().kick(target);
+            if kicked == 0 {
+                format!("error: no session matches {}\n", target)
+            } else {
+                format!("kicked {} session(s) or stream(s)\n", kicked)
+            }
+        }
+        (Some("disable"), Some(user), None) => {
+            let kicked = users::disable(user);
+            format!("disabled {}, kicked {} session(s)\n", user, kicked)
+        }
+        (Some("enable"), Some(user), None) => {
+            if users::enable(user) {
+                format!("enabled {}\n", user)
+            } else {
+                format!("error: {} is not disabled\n", user)
+            }
+        }
+        (Some("udp"), Some(state @ ("on" | "off")), None) => {
+            udp_guard::set_enabled(state == "on");
+            udp_guard::status()
+        }
+        (Some("bans"), None, None) => banlist::render(),
+        (Some("unban"), Some(ip), None) => match ip.parse() {
+            Ok(ip) if banlist::unban(ip) => format!("unbanned {}\n", ip),
+            Ok(ip) => format!("error: {} is not banned\n", ip),
+            Err(_) => format!("error: {:?} is not an IP address\n", ip),
+        },
+        _ => format!("error: {}\n", USAGE),
+    }
+}
+
+/// One-line summary of live sessions and UDP associations for the logs.
+pub fn stats_summary() -> String {
+    let summary = format!("sessions={}", registry().session_count());
+    #[cfg(feature = "tuic")]
+    let summary = format!(
+        "{} udp_associations={} udp_expired={}",
+        summary,
+        session::active_associations(),
+        session::expired_associations()
+    );
+    summary
+}
+
+/// Logs the connection table each time the process gets SIGUSR1, for
+/// hosts without the control socket.
+pub fn spawn_dump_on_usr1(mut shutdown_rx: tokio::sync::watch::Receiver<()>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut usr1 = match signal(SignalKind::user_defined1()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                tracing::error!("Failed to install SIGUSR1 handler: {}", e);
+                return;
+            }
+        };
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = usr1.recv() => {
+                        tracing::info!("Received SIGUSR1 signal, connections:\n{}", registry().connections());
+                    }
+                    _ = shutdown_rx.changed() => break,
+                }
+            }
+        });
+    }
+
+    #[cfg(not(unix))]
+    let _ = &mut shutdown_rx;
+}
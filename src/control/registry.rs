@@ -0,0 +1,564 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+#[cfg(feature = "control")]
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(any(feature = "tuic", feature = "control"))]
+use std::time::Duration;
+#[cfg(feature = "metrics")]
+use std::time::SystemTime;
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::control::accounting::{self, Usage};
+use crate::control::talkers;
+use crate::events::{self, Event, SessionRecord};
+use crate::net::relay::Activity;
+
+static REGISTRY: Lazy<SessionRegistry> = Lazy::new(SessionRegistry::new);
+
+/// Returns the process-wide registry of live client sessions.
+pub fn registry() -> &'static SessionRegistry {
+    &REGISTRY
+}
+
+struct Entry {
+    protocol: &'static str,
+    inbound: Arc<str>,
+    peer_addr: SocketAddr,
+    since: Instant,
+    #[cfg(feature = "metrics")]
+    started_at: SystemTime,
+    user: RwLock<Option<String>>,
+    traffic: Arc<Traffic>,
+    #[cfg(any(feature = "tuic", feature = "control"))]
+    path: RwLock<Option<PathStats>>,
+    kick: CancellationToken,
+    streams: DashMap<u64, StreamEntry>,
+}
+
+/// A relayed stream of a session: the connection to one target.
+struct StreamEntry {
+    target: Arc<str>,
+    activity: Arc<Activity>,
+}
+
+/// Latest transport-level sample of a QUIC session's network path.
+#[cfg(any(feature = "tuic", feature = "control"))]
+#[derive(Debug, Clone, Copy)]
+pub struct PathStats {
+    pub rtt: Duration,
+    pub cwnd: u64,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    pub congestion_events: u64,
+    pub mtu: u16,
+    pub datagrams_dropped: u64,
+}
+
+/// Bytes relayed for one session, shared with the tasks doing the relaying.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    up: AtomicU64,
+    down: AtomicU64,
+    #[cfg(feature = "tuic")]
+    datagrams_dropped: AtomicU64,
+}
+
+impl Traffic {
+    /// Counts bytes sent from the client towards the target.
+    pub fn add_up(&self, n: usize) {
+        self.up.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Counts bytes sent from the target back to the client.
+    pub fn add_down(&self, n: usize) {
+        self.down.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a reply datagram that could not be delivered to the client.
+    #[cfg(feature = "tuic")]
+    pub fn add_datagram_dropped(&self) {
+        self.datagrams_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn datagrams_dropped(&self) -> u64 {
+        self.datagrams_dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn up(&self) -> u64 {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub fn down(&self) -> u64 {
+        self.down.load(Ordering::Relaxed)
+    }
+}
+
+/// Live client sessions across all inbounds, so the control channel can
+/// list and kick them.
+pub struct SessionRegistry {
+    #[cfg(feature = "control")]
+    started: Instant,
+    next_id: AtomicU64,
+    sessions: DashMap<u64, Entry>,
+    /// Bytes of sessions that closed, in each direction.
+    closed: Traffic,
+}
+
+impl SessionRegistry {
+    fn new() -> Self {
+        Self {
+            #[cfg(feature = "control")]
+            started: Instant::now(),
+            next_id: AtomicU64::new(1),
+            sessions: DashMap::new(),
+            closed: Traffic::default(),
+        }
+    }
+
+    /// Registers a session; it is removed again when the guard is dropped.
+    pub fn register(
+        &'static self,
+        protocol: &'static str,
+        inbound: Arc<str>,
+        peer_addr: SocketAddr,
+    ) -> SessionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let kick = CancellationToken::new();
+        let traffic = Arc::new(Traffic::default());
+
+        events::publish(Event::ConnectionOpened {
+            id,
+            protocol,
+            inbound: Arc::clone(&inbound),
+            peer_addr,
+        });
+
+        self.sessions.insert(
+            id,
+            Entry {
+                protocol,
+                inbound: Arc::clone(&inbound),
+                peer_addr,
+                since: Instant::now(),
+                #[cfg(feature = "metrics")]
+                started_at: SystemTime::now(),
+                user: RwLock::new(None),
+                traffic: Arc::clone(&traffic),
+                #[cfg(any(feature = "tuic", feature = "control"))]
+                path: RwLock::new(None),
+                kick: kick.clone(),
+                streams: DashMap::new(),
+            },
+        );
+
+        SessionGuard {
+            registry: self,
+            id,
+            protocol,
+            peer_addr,
+            inbound,
+            traffic,
+            kick,
+        }
+    }
+
+    /// Kicks every session whose id or user matches `target`, or ends the
+    /// stream with that id. Returns the number of sessions and streams
+    /// ended.
+    #[cfg(feature = "control")]
+    pub fn kick(&self, target: &str) -> usize {
+        let id = target.parse::<u64>().ok();
+        let mut kicked = 0;
+
+        for entry in self.sessions.iter() {
+            let matches = Some(*entry.key()) == id || entry.user.read().as_deref() == Some(target);
+            if matches {
+                entry.kick.cancel();
+                kicked += 1;
+            } else if let Some(stream) = id.and_then(|id| entry.streams.get(&id)) {
+                stream.activity.kill();
+                kicked += 1;
+            }
+        }
+
+        kicked
+    }
+
+    /// Kicks every session. Returns the number of sessions kicked.
+    pub fn kick_all(&self) -> usize {
+        for entry in self.sessions.iter() {
+            entry.kick.cancel();
+        }
+        self.sessions.len()
+    }
+
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Bytes relayed since startup, sent towards targets and received
+    /// back, over all sessions whether or not they authenticated.
+    pub fn relayed(&self) -> (u64, u64) {
+        self.sessions.iter().fold(
+            (self.closed.up(), self.closed.down()),
+            |(up, down), entry| (up + entry.traffic.up(), down + entry.traffic.down()),
+        )
+    }
+
+    /// Number of live sessions that arrived on the inbound tagged `inbound`.
+    pub fn inbound_count(&self, inbound: &str) -> usize {
+        self.sessions
+            .iter()
+            .filter(|entry| entry.inbound.as_ref() == inbound)
+            .count()
+    }
+
+    #[cfg(feature = "control")]
+    pub fn status(&self) -> String {
+        let mut by_protocol: Vec<(&'static str, usize)> = Vec::new();
+        for entry in self.sessions.iter() {
+            match by_protocol.iter_mut().find(|(p, _)| *p == entry.protocol) {
+                Some((_, count)) => *count += 1,
+                None => by_protocol.push((entry.protocol, 1)),
+            }
+        }
+        by_protocol.sort();
+
+        let mut out = String::new();
+        let _ = writeln!(out, "iway {}", env!("CARGO_PKG_VERSION"));
+        let _ = writeln!(out, "uptime: {}s", self.started.elapsed().as_secs());
+        let _ = writeln!(out, "sessions: {}", self.sessions.len());
+        for (protocol, count) in by_protocol {
+            let _ = writeln!(out, "  {}: {}", protocol, count);
+        }
+        out
+    }
+
+    #[cfg(feature = "control")]
+    pub fn users(&self) -> String {
+        let mut rows: Vec<(u64, String)> = self
+            .sessions
+            .iter()
+            .map(|entry| {
+                let user = entry
+                    .user
+                    .read()
+                    .clone()
+                    .unwrap_or_else(|| String::from("-"));
+                (
+                    *entry.key(),
+                    format!(
+                        "{:<8} {:<8} {:<12} {:<38} {:<40} {}s",
+                        entry.key(),
+                        entry.protocol,
+                        entry.inbound,
+                        user,
+                        entry.peer_addr,
+                        entry.since.elapsed().as_secs()
+                    ),
+                )
+            })
+            .collect();
+        rows.sort_by_key(|(id, _)| *id);
+
+        let mut out = format!(
+            "{:<8} {:<8} {:<12} {:<38} {:<40} {}\n",
+            "ID", "PROTO", "INBOUND", "USER", "PEER", "AGE"
+        );
+        for (_, row) in rows {
+            out.push_str(&row);
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Bytes relayed so far by the live sessions of each user.
+    pub fn traffic_by_user(&self) -> HashMap<String, Usage> {
+        let mut by_user: HashMap<String, Usage> = HashMap::new();
+        for entry in self.sessions.iter() {
+            let Some(user) = entry.user.read().clone() else {
+                continue;
+            };
+            let usage = by_user.entry(user).or_default();
+            usage.up += entry.traffic.up();
+            usage.down += entry.traffic.down();
+        }
+        by_user
+    }
+
+    /// Bytes relayed so far by each live stream, with its target.
+    #[cfg(feature = "control")]
+    pub fn traffic_by_target(&self) -> Vec<(Arc<str>, u64, u64)> {
+        let mut streams = Vec::new();
+        for entry in self.sessions.iter() {
+            streams.extend(entry.streams.iter().map(|stream| {
+                (
+                    Arc::clone(&stream.target),
+                    stream.activity.up(),
+                    stream.activity.down(),
+                )
+            }));
+        }
+        streams
+    }
+
+    /// Every live session with the bytes it relayed, and under it the
+    /// streams it has open and their targets.
+    pub fn connections(&self) -> String {
+        let mut rows: Vec<(u64, String)> = self
+            .sessions
+            .iter()
+            .map(|entry| {
+                let user = entry
+                    .user
+                    .read()
+                    .clone()
+                    .unwrap_or_else(|| String::from("-"));
+                let mut row = format!(
+                    "{:<8} {:<8} {:<12} {:<38} {:<40} {:>12} {:>12} {}s\n",
+                    entry.key(),
+                    entry.protocol,
+                    entry.inbound,
+                    user,
+                    entry.peer_addr,
+                    entry.traffic.up(),
+                    entry.traffic.down(),
+                    entry.since.elapsed().as_secs()
+                );
+
+                let mut streams: Vec<_> = entry
+                    .streams
+                    .iter()
+                    .map(|stream| {
+                        let activity = &stream.activity;
+                        (
+                            *stream.key(),
+                            format!(
+                                "  {:<6} {:<60} {:<40} {:>12} {:>12} {}s\n",
+                                stream.key(),
+                                "",
+                                stream.target,
+                                activity.up(),
+                                activity.down(),
+                                activity.started().elapsed().as_secs()
+                            ),
+                        )
+                    })
+                    .collect();
+                streams.sort_by_key(|(id, _)| *id);
+                for (_, stream) in streams {
+                    row.push_str(&stream);
+                }
+
+                (*entry.key(), row)
+            })
+            .collect();
+        rows.sort_by_key(|(id, _)| *id);
+
+        let mut out = format!(
+            "{:<8} {:<8} {:<12} {:<38} {:<40} {:>12} {:>12} {}\n",
+            "ID", "PROTO", "INBOUND", "USER", "PEER / TARGET", "UP", "DOWN", "AGE"
+        );
+        for (_, row) in rows {
+            out.push_str(&row);
+        }
+        out
+    }
+
+    /// Latest path samples of the sessions that have one, i.e. QUIC
+    /// connections with path stats sampling enabled.
+    #[cfg(feature = "control")]
+    pub fn paths(&self) -> String {
+        let mut rows: Vec<(u64, String)> = self
+            .sessions
+            .iter()
+            .filter_map(|entry| {
+                let path = (*entry.path.read())?;
+                Some((
+                    *entry.key(),
+                    format!(
+                        "{:<8} {:<40} {:>8} {:>10} {:>10} {:>8} {:>6} {:>6} {:>8}",
+                        entry.key(),
+                        entry.peer_addr,
+                        format!("{}ms", path.rtt.as_millis()),
+                        path.cwnd,
+                        path.sent_packets,
+                        path.lost_packets,
+                        path.congestion_events,
+                        path.mtu,
+                        path.datagrams_dropped
+                    ),
+                ))
+            })
+            .collect();
+        rows.sort_by_key(|(id, _)| *id);
+
+        let mut out = format!(
+            "{:<8} {:<40} {:>8} {:>10} {:>10} {:>8} {:>6} {:>6} {:>8}\n",
+            "ID", "PEER", "RTT", "CWND", "SENT", "LOST", "CONG", "MTU", "DGDROP"
+        );
+        for (_, row) in rows {
+            out.push_str(&row);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Keeps a session registered for as long as it is alive.
+pub struct SessionGuard {
+    registry: &'static SessionRegistry,
+    id: u64,
+    protocol: &'static str,
+    peer_addr: SocketAddr,
+    inbound: Arc<str>,
+    traffic: Arc<Traffic>,
+    kick: CancellationToken,
+}
+
+impl SessionGuard {
+    /// Records who the session authenticated as.
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn set_user(&self, user: impl Into<String>) {
+        let Some(entry) = self.registry.sessions.get(&self.id) else {
+            return;
+        };
+
+        let user = user.into();
+        events::publish(Event::AuthSucceeded {
+            id: self.id,
+            protocol: entry.protocol,
+            user: Arc::from(user.as_str()),
+            peer_addr: entry.peer_addr,
+        });
+        *entry.user.write() = Some(user);
+    }
+
+    /// Who the session authenticated as, if it has yet.
+    #[cfg_attr(not(feature = "wasm-filters"), allow(dead_code))]
+    pub fn user(&self) -> Option<String> {
+        self.registry
+            .sessions
+            .get(&self.id)
+            .and_then(|entry| entry.user.read().clone())
+    }
+
+    #[cfg_attr(not(feature = "wasm-filters"), allow(dead_code))]
+    pub fn protocol(&self) -> &'static str {
+        self.protocol
+    }
+
+    #[cfg_attr(not(feature = "wasm-filters"), allow(dead_code))]
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Tag of the inbound the session arrived on.
+    pub fn inbound(&self) -> &str {
+        &self.inbound
+    }
+
+    #[cfg(feature = "tuic")]
+    pub fn set_path_stats(&self, stats: PathStats) {
+        if let Some(entry) = self.registry.sessions.get(&self.id) {
+            *entry.path.write() = Some(stats);
+        }
+    }
+
+    pub fn traffic(&self) -> &Arc<Traffic> {
+        &self.traffic
+    }
+
+    /// Lists a stream to `target` under the session until the returned
+    /// guard is dropped. Its bytes are counted in the guard's activity.
+    pub fn open_stream(&self, target: &impl Display) -> StreamGuard {
+        let id = self.registry.next_id.fetch_add(1, Ordering::Relaxed);
+        let target: Arc<str> = Arc::from(target.to_string());
+        let activity = Arc::new(Activity::new());
+        if let Some(entry) = self.registry.sessions.get(&self.id) {
+            entry.streams.insert(
+                id,
+                StreamEntry {
+                    target: Arc::clone(&target),
+                    activity: Arc::clone(&activity),
+                },
+            );
+        }
+
+        StreamGuard {
+            registry: self.registry,
+            session_id: self.id,
+            id,
+            target,
+            activity,
+        }
+    }
+
+    /// Resolves once an admin kicked this session.
+    pub async fn kicked(&self) {
+        self.kick.cancelled().await
+    }
+}
+
+/// Keeps a stream listed under its session for as long as it is relayed.
+pub struct StreamGuard {
+    registry: &'static SessionRegistry,
+    session_id: u64,
+    id: u64,
+    target: Arc<str>,
+    activity: Arc<Activity>,
+}
+
+impl StreamGuard {
+    pub fn activity(&self) -> &Activity {
+        &self.activity
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if let Some(entry) = self.registry.sessions.get(&self.session_id) {
+            entry.streams.remove(&self.id);
+        }
+        talkers::add(&self.target, self.activity.up(), self.activity.down());
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let Some((id, entry)) = self.registry.sessions.remove(&self.id) else {
+            return;
+        };
+
+        self.registry.closed.add_up(entry.traffic.up() as usize);
+        self.registry.closed.add_down(entry.traffic.down() as usize);
+        let user = entry.user.into_inner();
+        if let Some(user) = &user {
+            accounting::add(user, entry.traffic.up(), entry.traffic.down());
+        }
+
+        events::publish(Event::ConnectionClosed(Arc::new(SessionRecord {
+            id,
+            protocol: entry.protocol,
+            #[cfg(feature = "metrics")]
+            inbound: entry.inbound,
+            user,
+            #[cfg(feature = "metrics")]
+            peer_addr: entry.peer_addr,
+            #[cfg(feature = "metrics")]
+            started_at: entry.started_at,
+            duration: entry.since.elapsed(),
+            bytes_up: entry.traffic.up(),
+            bytes_down: entry.traffic.down(),
+        })));
+    }
+}
@@ -0,0 +1,89 @@
+//! A periodic one-line summary of the traffic since the last one, for
+//! operators who do not scrape metrics: connections accepted and active,
+//! bytes relayed, UDP packets and authentication failures.
+
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch::Receiver;
+use tracing::{debug, info};
+
+use crate::config::SummaryConfig;
+use crate::control::{registry::registry, stats_summary};
+use crate::events::{self, Event};
+use crate::policy::udp_guard;
+
+/// What the counters stood at when the last summary was logged. Bytes of
+/// a session closing right then may be missed for a moment, hence the
+/// saturating differences.
+struct Tick {
+    accepted: u64,
+    auth_failures: u64,
+    up: u64,
+    down: u64,
+    udp_packets: u64,
+}
+
+impl Tick {
+    /// Takes the running totals; `accepted` and `auth_failures` are
+    /// counted off the event bus and carried over.
+    fn totals(accepted: u64, auth_failures: u64) -> Self {
+        let (up, down) = registry().relayed();
+        Self {
+            accepted,
+            auth_failures,
+            up,
+            down,
+            udp_packets: udp_guard::relayed_packets(),
+        }
+    }
+}
+
+/// Starts logging the summary, if `[summary]` is configured.
+pub fn spawn(config: Option<&SummaryConfig>, shutdown_rx: Receiver<()>) {
+    let Some(config) = config else {
+        return;
+    };
+
+    info!(
+        "[Stats] Logging a traffic summary every {:?}",
+        config.interval()
+    );
+    tokio::spawn(run(config.clone(), shutdown_rx));
+}
+
+async fn run(config: SummaryConfig, mut shutdown_rx: Receiver<()>) {
+    let mut events = events::subscribe();
+    let mut ticker = tokio::time::interval(config.interval());
+    ticker.tick().await;
+
+    let (mut accepted, mut auth_failures) = (0, 0);
+    let mut last = Tick::totals(accepted, auth_failures);
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(Event::ConnectionOpened { .. }) => accepted += 1,
+                Ok(Event::AuthFailed { .. }) => auth_failures += 1,
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("[Stats] {} event(s) missed, counts will be low", missed);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = ticker.tick() => {
+                let now = Tick::totals(accepted, auth_failures);
+                info!(
+                    "[Stats] Last {:?}: accepted={} auth_failures={} up={} down={} udp_packets={}; now {}",
+                    config.interval(),
+                    now.accepted - last.accepted,
+                    now.auth_failures - last.auth_failures,
+                    now.up.saturating_sub(last.up),
+                    now.down.saturating_sub(last.down),
+                    now.udp_packets - last.udp_packets,
+                    stats_summary()
+                );
+                last = now;
+            }
+            _ = shutdown_rx.changed() => break,
+        }
+    }
+}
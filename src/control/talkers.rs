@@ -0,0 +1,117 @@
+//! Top talkers: bytes relayed per destination host and per user, so one
+//! user or one target taking most of the bandwidth is easy to spot.
+//! Destinations are kept in a bounded table; once it is full, the one
+//! updated longest ago makes room for the next.
+
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+#[cfg(feature = "control")]
+use crate::control::accounting::{self, Usage};
+#[cfg(feature = "control")]
+use crate::control::registry::registry;
+
+/// Destinations tracked at once.
+const MAX_DESTINATIONS: usize = 4096;
+
+static DESTINATIONS: Lazy<Mutex<Destinations>> = Lazy::new(Mutex::default);
+
+#[derive(Default)]
+struct Destinations {
+    /// Up, down, and the update that last touched each host.
+    bytes: HashMap<String, (u64, u64, u64)>,
+    updates: u64,
+}
+
+/// Counts the bytes of a stream to `target` that just closed.
+pub(crate) fn add(target: &str, up: u64, down: u64) {
+    if up == 0 && down == 0 {
+        return;
+    }
+
+    let host = host(target);
+    let mut destinations = DESTINATIONS.lock();
+    destinations.updates += 1;
+    let update = destinations.updates;
+
+    if !destinations.bytes.contains_key(host) && destinations.bytes.len() >= MAX_DESTINATIONS {
+        let oldest = destinations
+            .bytes
+            .iter()
+            .min_by_key(|(_, (_, _, last))| *last)
+            .map(|(host, _)| host.clone());
+        if let Some(oldest) = oldest {
+            destinations.bytes.remove(&oldest);
+        }
+    }
+
+    let entry = destinations
+        .bytes
+        .entry(host.to_string())
+        .or_insert((0, 0, 0));
+    entry.0 += up;
+    entry.1 += down;
+    entry.2 = update;
+}
+
+/// The `n` destinations and the `n` users that relayed the most, live
+/// streams and sessions included.
+#[cfg(feature = "control")]
+pub fn render(n: usize) -> String {
+    let mut by_host: HashMap<String, Usage> = DESTINATIONS
+        .lock()
+        .bytes
+        .iter()
+        .map(|(host, (up, down, _))| {
+            (
+                host.clone(),
+                Usage {
+                    up: *up,
+                    down: *down,
+                },
+            )
+        })
+        .collect();
+    for (target, up, down) in registry().traffic_by_target() {
+        let usage = by_host.entry(host(&target).to_string()).or_default();
+        usage.up += up;
+        usage.down += down;
+    }
+
+    let mut out = table("DESTINATION", by_host, n);
+    out.push('\n');
+    out.push_str(&table("USER", accounting::totals(), n));
+    out
+}
+
+#[cfg(feature = "control")]
+fn table(title: &str, rows: impl IntoIterator<Item = (String, Usage)>, n: usize) -> String {
+    let mut rows: Vec<(String, Usage)> = rows.into_iter().collect();
+    rows.sort_by(|(a, a_usage), (b, b_usage)| {
+        (b_usage.up + b_usage.down)
+            .cmp(&(a_usage.up + a_usage.down))
+            .then_with(|| a.cmp(b))
+    });
+
+    let mut out = format!("{:<48} {:>16} {:>16}\n", title, "UP", "DOWN");
+    for (name, usage) in rows.into_iter().take(n) {
+        out.push_str(&format!(
+            "{:<48} {:>16} {:>16}\n",
+            name, usage.up, usage.down
+        ));
+    }
+    out
+}
+
+/// The host of a `host:port` target, brackets of an IPv6 address removed.
+fn host(target: &str) -> &str {
+    let host = target
+        .rsplit_once(':')
+        .filter(|(_, port)| port.parse::<u16>().is_ok())
+        .map_or(target, |(host, _)| host);
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
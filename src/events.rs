@@ -0,0 +1,151 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "metrics")]
+use std::time::SystemTime;
+
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::debug;
+
+/// Events a slow subscriber may fall behind by before it starts missing them.
+const CAPACITY: usize = 1024;
+
+static BUS: Lazy<broadcast::Sender<Event>> = Lazy::new(|| broadcast::channel(CAPACITY).0);
+
+/// A closed client session.
+#[derive(Debug)]
+pub struct SessionRecord {
+    pub id: u64,
+    pub protocol: &'static str,
+    #[cfg(feature = "metrics")]
+    pub inbound: Arc<str>,
+    pub user: Option<String>,
+    #[cfg(feature = "metrics")]
+    pub peer_addr: SocketAddr,
+    #[cfg(feature = "metrics")]
+    pub started_at: SystemTime,
+    pub duration: Duration,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+/// Things happening on the data path that cross-cutting subsystems
+/// (metrics, exporters, webhooks, bans) react to.
+#[derive(Debug, Clone)]
+pub enum Event {
+    ConnectionOpened {
+        id: u64,
+        protocol: &'static str,
+        inbound: Arc<str>,
+        peer_addr: SocketAddr,
+    },
+    ConnectionClosed(Arc<SessionRecord>),
+    // Snell publishes none of these: its one PSK has no users to tell apart.
+    #[cfg_attr(not(any(feature = "tuic", feature = "trojan")), allow(dead_code))]
+    AuthSucceeded {
+        id: u64,
+        protocol: &'static str,
+        user: Arc<str>,
+        peer_addr: SocketAddr,
+    },
+    /// A client failed to present valid credentials.
+    #[cfg_attr(not(any(feature = "tuic", feature = "trojan")), allow(dead_code))]
+    AuthFailed {
+        protocol: &'static str,
+        peer_addr: SocketAddr,
+    },
+    /// A client's TLS or QUIC handshake failed.
+    #[cfg_attr(not(any(feature = "tuic", feature = "trojan")), allow(dead_code))]
+    HandshakeFailed {
+        protocol: &'static str,
+        peer_addr: SocketAddr,
+    },
+    #[cfg_attr(not(any(feature = "tuic", feature = "trojan")), allow(dead_code))]
+    QuotaExceeded {
+        user: Arc<str>,
+    },
+    BanIssued {
+        ip: IpAddr,
+        reason: Arc<str>,
+        duration: Option<Duration>,
+    },
+}
+
+/// Publishes an event to every current subscriber. Cheap when nobody
+/// listens.
+pub fn publish(event: Event) {
+    let _ = BUS.send(event);
+}
+
+/// Subscribes to events published from now on.
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    BUS.subscribe()
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::ConnectionOpened {
+                id,
+                protocol,
+                inbound,
+                peer_addr,
+            } => write!(
+                f,
+                "opened #{} {} on {} from {}",
+                id, protocol, inbound, peer_addr
+            ),
+            Event::ConnectionClosed(record) => write!(
+                f,
+                "closed #{} {} {} after {:?}, up={} down={}",
+                record.id,
+                record.protocol,
+                record.user.as_deref().unwrap_or("-"),
+                record.duration,
+                record.bytes_up,
+                record.bytes_down
+            ),
+            Event::AuthSucceeded {
+                id,
+                protocol,
+                user,
+                peer_addr,
+            } => write!(f, "auth #{} {} {} from {}", id, protocol, user, peer_addr),
+            Event::AuthFailed {
+                protocol,
+                peer_addr,
+            } => write!(f, "auth failed {} from {}", protocol, peer_addr),
+            Event::HandshakeFailed {
+                protocol,
+                peer_addr,
+            } => write!(f, "handshake failed {} from {}", protocol, peer_addr),
+            Event::QuotaExceeded { user } => write!(f, "quota exceeded by {}", user),
+            Event::BanIssued {
+                ip,
+                reason,
+                duration,
+            } => write!(f, "banned {} for {:?}: {}", ip, duration, reason),
+        }
+    }
+}
+
+/// Traces every event at debug level.
+pub fn spawn_debug_logger() {
+    if !tracing::enabled!(tracing::Level::DEBUG) {
+        return;
+    }
+
+    let mut events = subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => debug!("[Event] {}", event),
+                Err(RecvError::Lagged(missed)) => debug!("[Event] {} event(s) missed", missed),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
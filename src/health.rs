@@ -0,0 +1,227 @@
+//! `[health]`: a small HTTP endpoint for load balancers and Kubernetes
+//! probes. `/healthz` answers as long as the process runs; `/readyz`
+//! reports each server's status, whether its listeners are bound and how
+//! long the certificates have left, and answers 503 unless every server is
+//! running and no certificate has expired.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{Value, json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info};
+
+use crate::config::{Config, HealthConfig};
+use crate::reload::ConfigHandle;
+use crate::server::ServerManager;
+
+/// Request heads longer than this are refused.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// How long a probe has to send its request and read the reply.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Starts serving probes, if `[health]` is configured. The endpoint keeps
+/// answering through shutdown, so `/readyz` turns 503 as listeners close.
+pub async fn spawn(
+    config: Option<&HealthConfig>,
+    handle: Arc<ConfigHandle>,
+    servers: Arc<ServerManager>,
+) {
+    let Some(config) = config else {
+        return;
+    };
+
+    let listener = match TcpListener::bind(config.listen()).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("[Health] Failed to listen on {}: {}", config.listen(), e);
+            return;
+        }
+    };
+    info!(
+        "[Health] Serving /healthz and /readyz on {}",
+        config.listen()
+    );
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    debug!("[Health] Failed to accept: {}", e);
+                    continue;
+                }
+            };
+
+            let handle = Arc::clone(&handle);
+            let servers = Arc::clone(&servers);
+            tokio::spawn(async move {
+                let served =
+                    tokio::time::timeout(REQUEST_TIMEOUT, serve(stream, &handle, &servers)).await;
+                match served {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => debug!("[Health] Probe from {} failed: {}", peer, e),
+                    Err(_) => debug!("[Health] Probe from {} timed out", peer),
+                }
+            });
+        }
+    });
+}
+
+/// Answers the one request on `stream`.
+async fn serve(
+    mut stream: TcpStream,
+    handle: &ConfigHandle,
+    servers: &ServerManager,
+) -> std::io::Result<()> {
+    let mut head = Vec::with_capacity(512);
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return respond(
+                &mut stream,
+                false,
+                431,
+                &json!({ "error": "request too large" }),
+            )
+            .await;
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let line = head.split(|b| *b == b'\r').next().unwrap_or_default();
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or(target);
+    let head_only = method == "HEAD";
+
+    if method != "GET" && !head_only {
+        return respond(
+            &mut stream,
+            false,
+            405,
+            &json!({ "error": "method not allowed" }),
+        )
+        .await;
+    }
+    match path {
+        "/healthz" | "/livez" => {
+            respond(&mut stream, head_only, 200, &json!({ "status": "ok" })).await
+        }
+        "/readyz" => {
+            let (ready, report) = readiness(&handle.load(), servers).await;
+            let status = if ready { 200 } else { 503 };
+            respond(&mut stream, head_only, status, &report).await
+        }
+        _ => {
+            respond(
+                &mut stream,
+                head_only,
+                404,
+                &json!({ "error": "not found" }),
+            )
+            .await
+        }
+    }
+}
+
+/// Whether the proxy can take traffic, and the report saying why.
+async fn readiness(config: &Config, servers: &ServerManager) -> (bool, Value) {
+    let statuses = servers.statuses().await;
+    let mut ready = !statuses.is_empty();
+
+    let servers: Vec<Value> = statuses
+        .into_iter()
+        .map(|(name, status)| {
+            let running = matches!(status, Some(("running", _)));
+            ready &= running;
+            json!({
+                "name": name,
+                "status": status.map_or("busy", |(label, _)| label),
+                "since_secs": status.map(|(_, since)| since.as_secs()),
+                "listening": running,
+            })
+        })
+        .collect();
+
+    let certificates = certificates(config);
+    ready &= certificates.iter().all(|certificate| {
+        certificate["expires_in_days"]
+            .as_i64()
+            .is_none_or(|days| days >= 0)
+    });
+
+    let report = json!({
+        "status": if ready { "ready" } else { "unavailable" },
+        "servers": servers,
+        "certificates": certificates,
+    });
+    (ready, report)
+}
+
+/// Days each configured certificate has left, read afresh so a renewed
+/// file shows up before the servers reload it. A file that cannot be read
+/// is reported but does not fail the check; the servers still hold the
+/// chain they loaded.
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+fn certificates(config: &Config) -> Vec<Value> {
+    use crate::server::tls;
+
+    let now = chrono::Utc::now();
+    config
+        .certificate_paths()
+        .into_iter()
+        .map(|(at, path)| match tls::expiry(std::path::Path::new(path)) {
+            Ok(not_after) => json!({
+                "at": at,
+                "path": path,
+                "not_after": not_after.to_rfc3339(),
+                "expires_in_days": (not_after - now).num_days(),
+            }),
+            Err(e) => json!({
+                "at": at,
+                "path": path,
+                "error": format!("{:#}", e),
+            }),
+        })
+        .collect()
+}
+
+#[cfg(not(any(feature = "tuic", feature = "trojan")))]
+fn certificates(_config: &Config) -> Vec<Value> {
+    Vec::new()
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    head_only: bool,
+    status: u16,
+    body: &Value,
+) -> std::io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        _ => "Service Unavailable",
+    };
+    let body = body.to_string();
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    );
+    if !head_only {
+        response.push_str(&body);
+    }
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,265 @@
+//! Webhooks: users connecting, failed authentication, exceeded quotas,
+//! bans and expiring certificates are POSTed as JSON to each `[[hooks]]`
+//! URL that asks for them, for alerting bots and the like. Each hook has
+//! a queue of its own, so a slow endpoint holds up nobody else; a failed
+//! delivery is retried with exponential backoff, and events arriving
+//! while the queue is full are dropped.
+
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use chrono::Local;
+use serde_json::{Value, json};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::sync::watch::Receiver;
+use tracing::{debug, info, warn};
+
+use crate::config::{HookConfig, HookEvent};
+use crate::events::{self, Event};
+use crate::net::endpoint::Endpoint;
+use crate::net::tls;
+use crate::reload::ConfigHandle;
+
+/// Events a hook may have waiting before new ones are dropped.
+const QUEUE_SIZE: usize = 256;
+
+/// Wait before the first retry; it doubles with each attempt after.
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How often certificates are checked for `cert_expiring`.
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Starts delivering events to every `[[hooks]]` entry. Entries with a
+/// bad URL are reported and skipped.
+pub fn spawn(hooks: &[HookConfig], handle: Arc<ConfigHandle>, shutdown_rx: Receiver<()>) {
+    let mut queues = Vec::new();
+    for hook in hooks {
+        let endpoint = match Endpoint::parse(hook.url()) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                warn!("[Hooks] Skipping hook: {:#}", e);
+                continue;
+            }
+        };
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(deliver(hook.clone(), endpoint, rx));
+        queues.push((hook.clone(), tx));
+    }
+    if queues.is_empty() {
+        return;
+    }
+
+    info!("[Hooks] Sending events to {} hook(s)", queues.len());
+    tokio::spawn(dispatch(queues, handle, shutdown_rx));
+}
+
+type Queue = (HookConfig, mpsc::Sender<Arc<Value>>);
+
+async fn dispatch(queues: Vec<Queue>, handle: Arc<ConfigHandle>, mut shutdown_rx: Receiver<()>) {
+    let mut events = events::subscribe();
+    let mut cert_check = tokio::time::interval(CERT_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if let Some((kind, payload)) = payload(&event) {
+                        send(&queues, kind, payload, |_| true);
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("[Hooks] {} event(s) missed", missed);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = cert_check.tick() => check_certificates(&queues, &handle),
+            _ = shutdown_rx.changed() => break,
+        }
+    }
+}
+
+/// Queues `payload` for every hook that wants `kind` and passes `filter`.
+fn send(queues: &[Queue], kind: HookEvent, payload: Value, filter: impl Fn(&HookConfig) -> bool) {
+    let payload = Arc::new(payload);
+    for (hook, tx) in queues {
+        if !hook.wants(kind) || !filter(hook) {
+            continue;
+        }
+        if tx.try_send(Arc::clone(&payload)).is_err() {
+            warn!("[Hooks] Queue of {} is full, dropping an event", hook.url());
+        }
+    }
+}
+
+/// The hook event for a bus event, if hooks report it.
+fn payload(event: &Event) -> Option<(HookEvent, Value)> {
+    let now = Local::now().to_rfc3339();
+    Some(match event {
+        Event::AuthSucceeded {
+            id,
+            protocol,
+            user,
+            peer_addr,
+        } => (
+            HookEvent::Connected,
+            json!({
+                "event": "connected",
+                "timestamp": now,
+                "session": id,
+                "protocol": protocol,
+                "user": user.as_ref(),
+                "client": peer_addr.to_string(),
+            }),
+        ),
+        Event::AuthFailed {
+            protocol,
+            peer_addr,
+        } => (
+            HookEvent::AuthFailed,
+            json!({
+                "event": "auth_failed",
+                "timestamp": now,
+                "protocol": protocol,
+                "client": peer_addr.to_string(),
+            }),
+        ),
+        Event::QuotaExceeded { user } => (
+            HookEvent::QuotaExceeded,
+            json!({
+                "event": "quota_exceeded",
+                "timestamp": now,
+                "user": user.as_ref(),
+            }),
+        ),
+        Event::BanIssued {
+            ip,
+            reason,
+            duration,
+        } => (
+            HookEvent::Banned,
+            json!({
+                "event": "banned",
+                "timestamp": now,
+                "ip": ip.to_string(),
+                "reason": reason.as_ref(),
+                "duration_secs": duration.map(|duration| duration.as_secs()),
+            }),
+        ),
+        _ => return None,
+    })
+}
+
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+fn check_certificates(queues: &[Queue], handle: &ConfigHandle) {
+    let config = handle.load();
+    let now = chrono::Utc::now();
+    for (at, path) in config.certificate_paths() {
+        let not_after = match crate::server::tls::expiry(std::path::Path::new(path)) {
+            Ok(not_after) => not_after,
+            Err(e) => {
+                debug!("[Hooks] Not checking {}: {:#}", at, e);
+                continue;
+            }
+        };
+
+        let days_left = (not_after - now).num_days();
+        let payload = json!({
+            "event": "cert_expiring",
+            "timestamp": Local::now().to_rfc3339(),
+            "at": at,
+            "path": path,
+            "not_after": not_after.to_rfc3339(),
+            "days_left": days_left,
+        });
+        send(queues, HookEvent::CertExpiring, payload, |hook| {
+            days_left <= hook.cert_expiry_days()
+        });
+    }
+}
+
+#[cfg(not(any(feature = "tuic", feature = "trojan")))]
+fn check_certificates(_queues: &[Queue], _handle: &ConfigHandle) {}
+
+/// Posts each queued event to the hook, retrying with backoff.
+async fn deliver(hook: HookConfig, endpoint: Endpoint, mut rx: mpsc::Receiver<Arc<Value>>) {
+    while let Some(payload) = rx.recv().await {
+        let body = payload.to_string();
+        let mut backoff = FIRST_BACKOFF;
+        for attempt in 0..=hook.retries() {
+            let posted = tokio::time::timeout(hook.timeout(), post(&hook, &endpoint, &body))
+                .await
+                .context("timed out")
+                .and_then(|result| result);
+            match posted {
+                Ok(()) => break,
+                Err(e) if attempt < hook.retries() => {
+                    debug!(
+                        "[Hooks] Delivery to {} failed, retrying in {:?}: {:#}",
+                        hook.url(),
+                        backoff,
+                        e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+                Err(e) => warn!(
+                    "[Hooks] Dropping {} event for {} after {} attempt(s): {:#}",
+                    payload["event"].as_str().unwrap_or("an"),
+                    hook.url(),
+                    attempt + 1,
+                    e
+                ),
+            }
+        }
+    }
+}
+
+async fn post(hook: &HookConfig, endpoint: &Endpoint, body: &str) -> Result<()> {
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: iway/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+        endpoint.path(),
+        endpoint.authority(),
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    );
+    for (name, value) in hook.headers() {
+        let _ = write!(request, "{}: {}\r\n", name, value);
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+
+    let stream = crate::net::tcp::connect_host(endpoint.addr())
+        .await
+        .with_context(|| format!("Failed to connect to {}", endpoint.addr()))?;
+    if endpoint.tls() {
+        return exchange(tls::connect(stream, endpoint.host()).await?, &request).await;
+    }
+    exchange(stream, &request).await
+}
+
+/// Sends `request` and checks the status line of the reply.
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &str) -> Result<()> {
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 512];
+    while !response.contains(&b'\n') {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => bail!("endpoint replied {:?}", status),
+    }
+}
@@ -1,6 +1,22 @@
+#[cfg(feature = "trojan")]
+pub mod acme;
 pub mod authenticate;
 pub mod config;
+pub mod control;
+pub mod events;
+pub mod health;
+pub mod hooks;
+pub mod logging;
 pub mod net;
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub mod outbound;
+pub mod policy;
 pub mod processor;
 pub mod protocol;
+pub mod reload;
+pub mod resolver;
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub mod router;
+pub mod scheduler;
+pub mod security;
 pub mod server;
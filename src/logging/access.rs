@@ -0,0 +1,122 @@
+//! The access log: one JSON line per relayed connection and UDP
+//! association, written to a file of its own for fail2ban and SIEM
+//! pipelines to pick up.
+
+use std::fmt::Display;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use chrono::Local;
+use once_cell::sync::OnceCell;
+use serde_json::json;
+use tokio::time::Instant;
+use tracing::warn;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+use crate::config::LogConfig;
+use crate::control::registry::SessionGuard;
+use crate::net::relay::Activity;
+
+static SINK: OnceCell<BoxMakeWriter> = OnceCell::new();
+
+/// Opens the file `[log.access]` names. Without the section, flows are
+/// not recorded.
+pub(super) fn init(config: &LogConfig) {
+    let Some(access) = config.access() else {
+        return;
+    };
+
+    match super::open_file(access, "access.log") {
+        Ok(writer) => {
+            let _ = SINK.set(writer);
+        }
+        Err(e) => warn!("Failed to open access log, not recording flows: {}", e),
+    }
+}
+
+/// One connection or UDP association, written to the access log when it
+/// is finished. Does nothing when the access log is off.
+#[derive(Default)]
+pub struct Flow(Option<Box<Record>>);
+
+struct Record {
+    protocol: &'static str,
+    network: &'static str,
+    inbound: Arc<str>,
+    user: Option<String>,
+    client: IpAddr,
+    destination: Option<String>,
+    started: Instant,
+}
+
+impl Flow {
+    /// A TCP connection from `session` to `destination`.
+    pub fn tcp(session: &SessionGuard, destination: &impl Display) -> Self {
+        Self::start(session, "tcp", Some(destination.to_string()))
+    }
+
+    /// A UDP association of `session`, which may send to any number of
+    /// destinations.
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn udp(session: &SessionGuard) -> Self {
+        Self::start(session, "udp", None)
+    }
+
+    fn start(session: &SessionGuard, network: &'static str, destination: Option<String>) -> Self {
+        if SINK.get().is_none() {
+            return Self(None);
+        }
+
+        Self(Some(Box::new(Record {
+            protocol: session.protocol(),
+            network,
+            inbound: Arc::from(session.inbound()),
+            user: session.user(),
+            client: session.peer_addr().ip(),
+            destination,
+            started: Instant::now(),
+        })))
+    }
+
+    /// Records a relay that ended with `result`, its bytes counted in
+    /// `activity`.
+    pub fn finish<T>(self, activity: &Activity, result: &anyhow::Result<T>) {
+        if self.0.is_none() {
+            return;
+        }
+
+        let reason = match (activity.expiry(), result) {
+            (Some(expiry), _) => expiry.to_string(),
+            (None, Err(e)) => format!("{:#}", e),
+            (None, Ok(_)) => "closed".to_string(),
+        };
+        self.finish_with(activity.up(), activity.down(), &reason);
+    }
+
+    /// Records the flow with the given byte counts and close reason.
+    pub fn finish_with(self, up: u64, down: u64, reason: &str) {
+        let (Some(record), Some(sink)) = (self.0, SINK.get()) else {
+            return;
+        };
+
+        let mut line = json!({
+            "timestamp": Local::now().to_rfc3339(),
+            "protocol": record.protocol,
+            "network": record.network,
+            "inbound": record.inbound.as_ref(),
+            "user": record.user,
+            "client": record.client.to_string(),
+            "destination": record.destination,
+            "bytes_up": up,
+            "bytes_down": down,
+            "duration_ms": record.started.elapsed().as_millis() as u64,
+            "reason": reason,
+        })
+        .to_string();
+        // In one write, so lines of flows closing together do not mix.
+        line.push('\n');
+        let _ = sink.make_writer().write_all(line.as_bytes());
+    }
+}
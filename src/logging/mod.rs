@@ -0,0 +1,277 @@
+//! The log subscriber, set up from `[log]`: console output, an optional
+//! rotated log file, and the line format of both. The access log, in
+//! `[log.access]`, is opened alongside, and the slow lookup and connect
+//! thresholds of `[log.slow]` set.
+
+pub mod access;
+pub mod slow;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::Local;
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::config::{LogConfig, LogFileConfig, LogFormat, LogRotation};
+
+/// Rotated files kept by `size` rotation when `max_files` is not set.
+const DEFAULT_SIZE_ROTATION_FILES: usize = 5;
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+#[derive(Clone, Copy, Default)]
+struct LocalTime;
+
+impl FormatTime for LocalTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        let ts = Local::now().format("%Y-%m-%d %H:%M:%S%:z");
+        write!(w, "{}", ts)
+    }
+}
+
+/// Installs the global subscriber and opens the access log. `level` is the configured level name;
+/// debug builds log everything to the console regardless.
+pub fn init(config: &LogConfig, level: &str) {
+    let level = level.parse::<LevelFilter>().unwrap_or_else(|_| {
+        eprintln!("Invalid log level {:?}, using info", level);
+        LevelFilter::INFO
+    });
+
+    #[cfg(debug_assertions)]
+    let console_level = LevelFilter::DEBUG;
+    #[cfg(not(debug_assertions))]
+    let console_level = level;
+
+    let console_layer: BoxedLayer = match config.format() {
+        LogFormat::Pretty => fmt::layer()
+            .with_target(false)
+            .with_line_number(true)
+            .pretty()
+            .with_timer(LocalTime)
+            .with_filter(console_level)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .event_format(JsonFormat)
+            .with_ansi(false)
+            .with_filter(console_level)
+            .boxed(),
+    };
+
+    let mut layers = vec![console_layer];
+    if !config.stdout_only() {
+        match file_layer(config, level) {
+            Ok(layer) => layers.push(layer),
+            Err(e) => eprintln!("Failed to open log file, logging to stdout only: {}", e),
+        }
+    }
+
+    tracing_subscriber::registry().with(layers).init();
+    access::init(config);
+    slow::init(config);
+}
+
+fn file_layer(config: &LogConfig, level: LevelFilter) -> io::Result<BoxedLayer> {
+    let writer = open_file(config.file(), "iway.log")?;
+    Ok(match config.format() {
+        LogFormat::Pretty => fmt::layer()
+            .with_writer(writer)
+            .with_ansi(false)
+            .with_target(false)
+            .with_level(true)
+            .with_line_number(true)
+            .with_thread_names(true)
+            .with_timer(LocalTime)
+            .with_filter(level)
+            .boxed(),
+        LogFormat::Json => fmt::layer()
+            .with_writer(writer)
+            .with_ansi(false)
+            .event_format(JsonFormat)
+            .with_filter(level)
+            .boxed(),
+    })
+}
+
+/// Opens the log file `config` describes, rotated as it says. Without a
+/// path the file is `default_name` in the `logs` directory.
+fn open_file(config: &LogFileConfig, default_name: &str) -> io::Result<BoxMakeWriter> {
+    let path = match config.path() {
+        Some(path) => PathBuf::from(path),
+        // Next to the executable, so service/systemd runs with different
+        // working directories still write to the same place.
+        None => std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.join("logs")))
+            .unwrap_or_else(|| PathBuf::from("logs"))
+            .join(default_name),
+    };
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Err(io::Error::other(format!("{:?} names no file", path)));
+    };
+    std::fs::create_dir_all(dir)?;
+
+    Ok(match config.rotation() {
+        LogRotation::Daily | LogRotation::Never => {
+            let rotation = match config.rotation() {
+                LogRotation::Daily => Rotation::DAILY,
+                _ => Rotation::NEVER,
+            };
+            let mut builder = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(file_name);
+            if let Some(max_files) = config.max_files() {
+                builder = builder.max_log_files(max_files + 1);
+            }
+            BoxMakeWriter::new(builder.build(dir).map_err(io::Error::other)?)
+        }
+        LogRotation::Size => {
+            let appender = SizeRolling::open(
+                path.clone(),
+                config.max_size(),
+                config.max_files().unwrap_or(DEFAULT_SIZE_ROTATION_FILES),
+            )?;
+            BoxMakeWriter::new(Arc::new(appender))
+        }
+    })
+}
+
+/// A log file moved aside to `<path>.1` once it reaches `max_size`, the
+/// older ones shifting up a number and those past `max_files` dropped.
+struct SizeRolling {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: Mutex<(File, u64)>,
+}
+
+impl SizeRolling {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file: Mutex::new((file, written)),
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self, current: &mut (File, u64)) -> io::Result<()> {
+        let _ = std::fs::remove_file(self.rotated(self.max_files));
+        for n in (1..self.max_files).rev() {
+            let _ = std::fs::rename(self.rotated(n), self.rotated(n + 1));
+        }
+        if self.max_files > 0 {
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        *current = (file, 0);
+        Ok(())
+    }
+}
+
+impl Write for &SizeRolling {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut current = self.file.lock();
+        // Each record arrives in one write, so files split between records.
+        if current.1 > 0 && current.1 + buf.len() as u64 > self.max_size {
+            self.rotate(&mut current)?;
+        }
+
+        let n = current.0.write(buf)?;
+        current.1 += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.lock().0.flush()
+    }
+}
+
+/// Formats each record as one JSON object: time, level, thread, source
+/// line and the record's fields, `message` among them.
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        let meta = event.metadata();
+        let mut record = Map::new();
+        record.insert("timestamp".into(), Local::now().to_rfc3339().into());
+        record.insert("level".into(), meta.level().as_str().into());
+        if let Some(thread) = std::thread::current().name() {
+            record.insert("thread".into(), thread.into());
+        }
+        if let (Some(file), Some(line)) = (meta.file(), meta.line()) {
+            record.insert("source".into(), format!("{}:{}", file, line).into());
+        }
+        event.record(&mut JsonFields(&mut record));
+
+        writeln!(writer, "{}", Value::Object(record))
+    }
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonFields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+}
@@ -0,0 +1,82 @@
+//! Slow-query style warnings for DNS lookups and outbound connects that
+//! take longer than `[log.slow]` allows, with the target and who asked
+//! for it, to tell a slow upstream path from a slow client.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::config::LogConfig;
+use crate::control::registry::SessionGuard;
+
+static THRESHOLDS: OnceCell<Thresholds> = OnceCell::new();
+
+struct Thresholds {
+    dns: Duration,
+    connect: Duration,
+}
+
+/// Takes the thresholds from `[log.slow]`. Without the section, nothing
+/// is timed.
+pub(super) fn init(config: &LogConfig) {
+    if let Some(slow) = config.slow() {
+        let _ = THRESHOLDS.set(Thresholds {
+            dns: slow.dns(),
+            connect: slow.connect(),
+        });
+    }
+}
+
+/// Runs the DNS lookup of `target` for `session`, warning if it is slow.
+pub async fn dns<F: Future>(session: &SessionGuard, target: &impl Display, lookup: F) -> F::Output {
+    match THRESHOLDS.get() {
+        Some(thresholds) => timed("DNS lookup of", thresholds.dns, session, target, lookup).await,
+        None => lookup.await,
+    }
+}
+
+/// Runs the outbound connect to `target` for `session`, warning if it is
+/// slow.
+pub async fn connect<F: Future>(
+    session: &SessionGuard,
+    target: &impl Display,
+    connect: F,
+) -> F::Output {
+    match THRESHOLDS.get() {
+        Some(thresholds) => timed("Connect to", thresholds.connect, session, target, connect).await,
+        None => connect.await,
+    }
+}
+
+async fn timed<F: Future>(
+    what: &str,
+    threshold: Duration,
+    session: &SessionGuard,
+    target: &impl Display,
+    operation: F,
+) -> F::Output {
+    let started = Instant::now();
+    let output = operation.await;
+
+    let elapsed = started.elapsed();
+    if elapsed > threshold {
+        warn!(
+            protocol = session.protocol(),
+            inbound = session.inbound(),
+            user = session.user().as_deref().unwrap_or("-"),
+            client = %session.peer_addr(),
+            target = %target,
+            elapsed_ms = elapsed.as_millis() as u64,
+            threshold_ms = threshold.as_millis() as u64,
+            "[Slow] {} {} took {:?}",
+            what,
+            target,
+            elapsed
+        );
+    }
+    output
+}
@@ -1,4 +1,4 @@
-#[cfg(not(target_env = "msvc"))]
+#[cfg(all(not(target_env = "msvc"), feature = "jemalloc"))]
 use tikv_jemallocator::Jemalloc;
 
 #[cfg(target_env = "msvc")]
@@ -8,7 +8,11 @@ use mimalloc::MiMalloc;
 #[global_allocator]
 static ALLOC: dhat::Alloc = dhat::Alloc;
 
-#[cfg(all(not(target_env = "msvc"), not(feature = "dhat-heap")))]
+#[cfg(all(
+    not(target_env = "msvc"),
+    feature = "jemalloc",
+    not(feature = "dhat-heap")
+))]
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
@@ -21,99 +25,136 @@ use anyhow::Result;
 use tokio::sync::watch;
 
 use server::ServerManager;
+use std::path::Path;
 use std::sync::Arc;
 use std::{cmp::max, env, time::Instant};
-use tracing::{error, info};
-
-use chrono::Local;
-use tracing_appender::rolling;
-use tracing_subscriber::fmt::format::Writer;
-use tracing_subscriber::fmt::time::FormatTime;
-use tracing_subscriber::{Layer, fmt, layer::SubscriberExt, util::SubscriberInitExt};
-
-mod authenticate;
-mod config;
-mod net;
-mod processor;
-mod protocol;
-mod server;
-
-fn init_logger() {
-    #[derive(Clone, Copy, Default)]
-    struct LocalTime;
-
-    impl FormatTime for LocalTime {
-        fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
-            let ts = Local::now().format("%Y-%m-%d %H:%M:%S%:z");
-            write!(w, "{}", ts)
-        }
-    }
-
-    // Ensure log directory exists. Prefer a `logs` folder next to the executable
-    // so service/systemd runs with different working directories still write logs.
-    let log_dir = std::env::current_exe()
-        .ok()
-        .and_then(|mut p| {
-            p.pop();
-            p.push("logs");
-            Some(p)
-        })
-        .unwrap_or_else(|| std::path::PathBuf::from("logs"));
-
-    if let Err(e) = std::fs::create_dir_all(&log_dir) {
-        eprintln!("Failed to create log directory {:?}: {}", log_dir, e);
-    }
-
-    let file_appender = rolling::daily(log_dir, "iway.log");
-
-    let file_layer = fmt::layer()
-        .with_writer(file_appender)
-        .with_ansi(false)
-        .with_target(false)
-        .with_level(true)
-        .with_line_number(true)
-        .with_thread_names(true)
-        .with_timer(LocalTime)
-        .with_filter(tracing_subscriber::filter::LevelFilter::INFO);
-
-    #[cfg(debug_assertions)]
-    let console_layer = fmt::layer()
-        .with_target(false)
-        .with_line_number(true)
-        .pretty()
-        .with_timer(LocalTime)
-        .with_filter(tracing_subscriber::filter::LevelFilter::DEBUG);
-
-    #[cfg(not(debug_assertions))]
-    let console_layer = fmt::layer()
-        .with_target(false)
-        .with_line_number(true)
-        .pretty()
-        .with_timer(LocalTime)
-        .with_filter(tracing_subscriber::filter::LevelFilter::INFO);
-    tracing_subscriber::registry()
-        .with(console_layer)
-        .with(file_layer)
-        .init();
-}
+use tracing::{error, info, warn};
+
+#[cfg(feature = "trojan")]
+use iway::acme;
+#[cfg(feature = "tuic")]
+use iway::processor;
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+use iway::router;
+use iway::{
+    config, control, events, health, hooks, logging, net, policy, reload, resolver, scheduler,
+    security, server,
+};
 
 fn recommended_worker_threads(cpu_load_ratio: f64) -> usize {
     let cpus = num_cpus::get();
     max(1, (cpus as f64 * cpu_load_ratio).round() as usize)
 }
 
+/// `iway migrate [config.toml]`: rewrites the file in the current layout.
+fn migrate_config(path: &str) -> i32 {
+    match config::Config::upgrade_file(path) {
+        Ok(None) => {
+            println!(
+                "{} is already at config version {}",
+                path,
+                config::CONFIG_VERSION
+            );
+            0
+        }
+        Ok(Some(changes)) => {
+            for change in &changes {
+                println!("{}", change);
+            }
+            println!(
+                "Upgraded {} to config version {}; the old file is {}.bak",
+                path,
+                config::CONFIG_VERSION,
+                path
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to upgrade {}: {:#}", path, e);
+            1
+        }
+    }
+}
+
 fn main() {
     #[cfg(feature = "dhat-heap")]
     let _profiler = dhat::Profiler::new_heap();
 
-    init_logger();
+    let args: Vec<String> = env::args().collect();
+    #[cfg(feature = "control")]
+    if args.get(1).map(String::as_str) == Some("ctl") {
+        std::process::exit(control::client::run(&args[2..]));
+    }
+    if args.get(1).map(String::as_str) == Some("migrate") {
+        std::process::exit(migrate_config(
+            args.get(2).map_or("config.toml", String::as_str),
+        ));
+    }
 
-    let num_threads = recommended_worker_threads(1.0);
-    let runtime = match tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(num_threads)
-        .enable_all()
-        .build()
-    {
+    let config_path = args
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| String::from("config.toml"));
+    let config_exists = Path::new(&config_path).exists();
+    let (config, config_error) = if config_exists {
+        match config::Config::from_file(&config_path) {
+            Ok(config) => (config, None),
+            Err(e) => (config::Config::default(), Some(e)),
+        }
+    } else {
+        (config::Config::default(), None)
+    };
+
+    logging::init(config.log(), config.log_level());
+
+    if !config_exists {
+        info!("{} does not exist, writing the default config", config_path);
+        if let Err(e) = config.save_to_file(&config_path) {
+            error!("Failed to save default config: {}", e);
+        }
+    }
+
+    // A file that is there but fails to load is never replaced: running on
+    // defaults would drop every inbound it configures.
+    let problems = match config_error {
+        Some(e) => vec![format!("{:#}", e)],
+        None => config.validate(),
+    };
+    if !problems.is_empty() {
+        for problem in &problems {
+            error!("Invalid config: {}", problem);
+        }
+        error!("Found {} problem(s) in {}", problems.len(), config_path);
+        std::process::exit(1);
+    }
+
+    if let Some(version) = config.migrated_from() {
+        warn!(
+            "{} is in the version {} layout and was upgraded as it was read; run `iway migrate {}` to rewrite it",
+            config_path, version, config_path
+        );
+    }
+
+    info!("Using {:?} profile", config.profile());
+
+    net::capabilities::preflight();
+
+    let runtime_config = config.runtime();
+    let num_threads = runtime_config.worker_threads().unwrap_or_else(|| {
+        let recommended = recommended_worker_threads(runtime_config.cpu_load_ratio());
+        match config.profile().defaults().max_worker_threads {
+            Some(max) => recommended.min(max),
+            None => recommended,
+        }
+    });
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.worker_threads(num_threads).enable_all();
+    if let Some(max) = runtime_config.max_blocking_threads() {
+        builder.max_blocking_threads(max);
+    }
+    info!("Starting runtime with {} worker threads", num_threads);
+
+    let runtime = match builder.build() {
         Ok(rt) => rt,
         Err(e) => {
             error!("Failed to build tokio runtime: {}", e);
@@ -121,32 +162,69 @@ fn main() {
         }
     };
 
-    if let Err(e) = runtime.block_on(async_main()) {
+    if let Err(e) = runtime.block_on(async_main(config, config_path)) {
         error!("Application error: {}", e);
         std::process::exit(1);
     }
 }
 
 // #[tokio::main(flavor = "multi_thread", worker_threads = 16)]
-async fn async_main() -> Result<(), String> {
+async fn async_main(config: config::Config, config_path: String) -> Result<(), String> {
     let start_time = Instant::now();
 
-    let config_path = env::args()
-        .nth(1)
-        .unwrap_or_else(|| String::from("config.toml"));
-    let config = config::Config::from_file(config_path).unwrap_or_else(|e| {
-        info!("Using default config: {}", e);
-        let default_config = config::Config::default();
-        if let Err(e) = default_config.save_to_file("config.toml") {
-            error!("Failed to save default config: {}", e);
-        }
-        default_config
-    });
-
     let config = Arc::new(config);
 
+    policy::init(config.policies());
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    policy::quota::init(&config);
+    if let Err(e) = policy::ports::init(config.port_policy()) {
+        error!("Failed to load port policy: {:#}", e);
+        return Err("Failed to load port policy!".into());
+    }
+    net::shaper::init(config.egress());
+    if let Err(e) = resolver::init(config.dns()) {
+        error!("Failed to set up DNS: {:#}", e);
+        return Err("Failed to set up DNS!".into());
+    }
+    #[cfg(feature = "trojan")]
+    security::fingerprint::init(config.fingerprints());
+    #[cfg(feature = "trojan")]
+    server::tickets::init(config.tls());
+
+    if let Err(e) = policy::geoip::init(config.geoip()) {
+        error!("Failed to load GeoIP databases: {:#}", e);
+        return Err("Failed to load GeoIP databases!".into());
+    }
+
+    if let Err(e) = policy::filter::init(config.filter()) {
+        error!("Failed to load filter module: {:#}", e);
+        return Err("Failed to load filter module!".into());
+    }
+
+    if let Err(e) = policy::script::init(config.script()) {
+        error!("Failed to load routing script: {:#}", e);
+        return Err("Failed to load routing script!".into());
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    if let Err(e) = router::init(config.router(), config.outbounds(), config.identities()) {
+        error!("Failed to set up routing: {:#}", e);
+        return Err("Failed to set up routing!".into());
+    }
+
+    events::spawn_debug_logger();
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    server::provision_self_signed(&config);
+
+    #[cfg(feature = "trojan")]
+    acme::provision(&config).await;
+
     let (shutdown_tx, shutdown_rx) = watch::channel(());
-    let server_manager = ServerManager::new_with_config(Arc::clone(&config), Some(shutdown_rx));
+    let server_manager = Arc::new(ServerManager::new_with_config(
+        Arc::clone(&config),
+        Some(shutdown_rx.clone()),
+    ));
 
     match server_manager.init().await {
         Ok(_) => info!(
@@ -170,6 +248,40 @@ async fn async_main() -> Result<(), String> {
         }
     }
 
+    #[cfg(feature = "metrics")]
+    let exporter = control::export::spawn(config.stats_export());
+    let accounting = control::accounting::spawn(config.accounting());
+    control::summary::spawn(config.summary(), shutdown_rx.clone());
+    policy::udp_guard::spawn(config.udp_guard(), shutdown_rx.clone());
+    security::banlist::spawn(config.banlist(), shutdown_rx.clone());
+    control::spawn_dump_on_usr1(shutdown_rx.clone());
+    #[cfg(feature = "trojan")]
+    acme::spawn(
+        Arc::clone(&config),
+        Arc::clone(&server_manager),
+        shutdown_rx.clone(),
+    );
+    #[cfg(feature = "tuic")]
+    processor::tuic::reverse::spawn(
+        config.reverse(),
+        config.relay_buffer_size(),
+        shutdown_rx.clone(),
+    );
+    scheduler::spawn(
+        config.schedule(),
+        Arc::clone(&server_manager),
+        shutdown_rx.clone(),
+    );
+    let handle = Arc::new(reload::ConfigHandle::new(config_path, Arc::clone(&config)));
+    hooks::spawn(config.hooks(), Arc::clone(&handle), shutdown_rx.clone());
+    health::spawn(
+        config.health(),
+        Arc::clone(&handle),
+        Arc::clone(&server_manager),
+    )
+    .await;
+    reload::spawn(handle, Arc::clone(&server_manager), shutdown_rx);
+
     let shutdown = setup_shutdown_signal();
     shutdown.await;
 
@@ -178,7 +290,16 @@ async fn async_main() -> Result<(), String> {
     let _ = shutdown_tx.send(());
 
     info!("Received shutdown signal, stopping servers...");
-    let _ = server_manager.stop().await;
+    let flush = async move {
+        #[cfg(feature = "metrics")]
+        if let Some(exporter) = exporter {
+            exporter.flush().await;
+        }
+        if let Some(accounting) = accounting {
+            accounting.flush().await;
+        }
+    };
+    let _ = server_manager.shutdown(config.shutdown(), flush).await;
 
     info!(
         "ServerManager: Servers stopped in {:?}",
@@ -0,0 +1,170 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use once_cell::sync::OnceCell;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tracing::{info, warn};
+
+/// Socket buffer size QUIC and the UDP relays would like to use.
+const DESIRED_BUFFER_SIZE: usize = 4 << 20;
+
+static CAPABILITIES: OnceCell<Capabilities> = OnceCell::new();
+
+/// Kernel/network features that are actually usable on this host, probed
+/// once at startup so the servers can adapt instead of failing mid-traffic.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    pub ipv4: bool,
+    pub ipv6: bool,
+    pub dual_stack: bool,
+    pub reuse_port: bool,
+    pub udp_gso: bool,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+}
+
+/// Returns the probed capabilities, probing on first use.
+pub fn capabilities() -> &'static Capabilities {
+    CAPABILITIES.get_or_init(probe)
+}
+
+/// Probes the host and logs a summary, warning about anything that will
+/// degrade behavior.
+pub fn preflight() -> &'static Capabilities {
+    let caps = capabilities();
+
+    info!(
+        "Preflight: ipv4={} ipv6={} dual_stack={} reuse_port={} udp_gso={} rcvbuf={} sndbuf={}",
+        caps.ipv4,
+        caps.ipv6,
+        caps.dual_stack,
+        caps.reuse_port,
+        caps.udp_gso,
+        format_size(caps.recv_buffer_size),
+        format_size(caps.send_buffer_size),
+    );
+
+    if !caps.ipv4 && !caps.ipv6 {
+        warn!("Preflight: UDP sockets cannot be created, UDP relaying and TUIC will fail");
+    }
+
+    if !caps.ipv6 {
+        warn!("Preflight: IPv6 is unavailable, wildcard IPv6 listeners fall back to IPv4");
+    }
+
+    for (name, size) in [
+        ("receive", caps.recv_buffer_size),
+        ("send", caps.send_buffer_size),
+    ] {
+        if let Some(size) = size
+            && size < DESIRED_BUFFER_SIZE
+        {
+            warn!(
+                "Preflight: UDP {} buffer clamped to {} bytes by the kernel (wanted {}), \
+                 raise net.core.{}mem_max for better QUIC throughput",
+                name,
+                size,
+                DESIRED_BUFFER_SIZE,
+                if name == "receive" { "r" } else { "w" }
+            );
+        }
+    }
+
+    caps
+}
+
+/// Maps a wildcard IPv6 listen address to the IPv4 wildcard when the host
+/// has no usable IPv6 stack.
+pub fn adjust_bind_addr(addr: SocketAddr) -> SocketAddr {
+    if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) && !capabilities().ipv6 {
+        return SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), addr.port());
+    }
+    addr
+}
+
+fn probe() -> Capabilities {
+    let ipv4 = bind_udp(Domain::IPV4).is_some();
+    let v6 = bind_udp(Domain::IPV6);
+    let ipv6 = v6.is_some();
+
+    let dual_stack = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
+        .and_then(|s| s.set_only_v6(false))
+        .is_ok();
+
+    let reuse_port = probe_reuse_port();
+
+    let probe_socket = v6.or_else(|| bind_udp(Domain::IPV4));
+
+    let udp_gso = probe_socket.as_ref().is_some_and(probe_gso);
+
+    let recv_buffer_size = probe_socket.as_ref().and_then(|s| {
+        s.set_recv_buffer_size(DESIRED_BUFFER_SIZE).ok()?;
+        s.recv_buffer_size().ok()
+    });
+
+    let send_buffer_size = probe_socket.as_ref().and_then(|s| {
+        s.set_send_buffer_size(DESIRED_BUFFER_SIZE).ok()?;
+        s.send_buffer_size().ok()
+    });
+
+    Capabilities {
+        ipv4,
+        ipv6,
+        dual_stack,
+        reuse_port,
+        udp_gso,
+        recv_buffer_size,
+        send_buffer_size,
+    }
+}
+
+fn bind_udp(domain: Domain) -> Option<Socket> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP)).ok()?;
+    let addr = if domain == Domain::IPV6 {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 0)
+    } else {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)
+    };
+    socket.bind(&SockAddr::from(addr)).ok()?;
+    Some(socket)
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn probe_reuse_port() -> bool {
+    Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
+        .and_then(|s| s.set_reuse_port(true))
+        .is_ok()
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn probe_reuse_port() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn probe_gso(socket: &Socket) -> bool {
+    use std::os::fd::AsRawFd;
+
+    let segment: libc::c_int = 1200;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            &segment as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    ret == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_gso(_socket: &Socket) -> bool {
+    false
+}
+
+fn format_size(size: Option<usize>) -> String {
+    match size {
+        Some(size) => size.to_string(),
+        None => String::from("unknown"),
+    }
+}
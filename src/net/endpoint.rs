@@ -0,0 +1,78 @@
+//! Parsed http:// and https:// URLs, for webhooks, ACME and DNS over
+//! HTTPS alike.
+
+use anyhow::{Result, bail};
+
+/// Where an HTTP request goes: the address to dial and what to ask for.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    tls: bool,
+    host: String,
+    authority: String,
+    addr: String,
+    path: String,
+}
+
+impl Endpoint {
+    /// Parses an `http://` or `https://` URL.
+    pub fn parse(url: &str) -> Result<Self> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            bail!("{:?} is not an http:// or https:// URL", url);
+        };
+        if tls && !cfg!(feature = "trojan") {
+            bail!("https:// needs a build with the trojan feature");
+        }
+
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let host = match authority.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => host,
+            _ => authority,
+        };
+        if host.is_empty() {
+            bail!("{:?} has no host", url);
+        }
+        let addr = if host.len() == authority.len() {
+            format!("{}:{}", authority, if tls { 443 } else { 80 })
+        } else {
+            authority.to_string()
+        };
+
+        Ok(Self {
+            tls,
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            authority: authority.to_string(),
+            addr,
+            path: path.to_string(),
+        })
+    }
+
+    pub fn tls(&self) -> bool {
+        self.tls
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
@@ -0,0 +1,406 @@
+//! Just enough HTTP/1.1 for the ACME client, DNS over HTTPS and the
+//! ClickHouse exporter, over the verifying connector of `net::tls` for
+//! https:// URLs. Requests go one per connection, except through a
+//! [`Client`], which keeps its connections open for the next.
+
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+#[cfg(feature = "trojan")]
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "trojan")]
+use tokio::net::TcpStream;
+
+use crate::net::endpoint::Endpoint;
+#[cfg(feature = "trojan")]
+use crate::net::idle::Idle;
+use crate::net::tls;
+
+/// Largest response read; certificate chains are a few KB.
+const MAX_RESPONSE: usize = 1024 * 1024;
+
+/// A whole HTTP response.
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn json(&self) -> Result<Value> {
+        serde_json::from_slice(&self.body).context("Malformed JSON in response")
+    }
+
+    #[cfg(feature = "trojan")]
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// Sends one HTTP/1.1 request to `url`, with a body of the given content
+/// type, and reads the whole response within `timeout`.
+#[cfg(feature = "trojan")]
+pub async fn request(
+    url: &str,
+    method: &str,
+    body: Option<(&str, &[u8])>,
+    timeout: Duration,
+) -> Result<Response> {
+    request_with_headers(url, method, &[], body, timeout).await
+}
+
+/// [`request`] with `headers` sent as well.
+pub async fn request_with_headers(
+    url: &str,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: Option<(&str, &[u8])>,
+    timeout: Duration,
+) -> Result<Response> {
+    let endpoint = Endpoint::parse(url)?;
+    let request = encode(&endpoint, method, headers, body, false);
+
+    let exchanged = tokio::time::timeout(timeout, async {
+        let stream = crate::net::tcp::connect_host(endpoint.addr())
+            .await
+            .with_context(|| format!("Failed to connect to {}", endpoint.addr()))?;
+        if endpoint.tls() {
+            return exchange(tls::connect(stream, endpoint.host()).await?, &request).await;
+        }
+        exchange(stream, &request).await
+    })
+    .await
+    .with_context(|| format!("Request to {} timed out", url))??;
+
+    parse_response(&exchanged, method == "HEAD")
+}
+
+/// Requests to one https:// URL over connections kept open in between,
+/// for DNS over HTTPS. Its host is looked up with the system resolver,
+/// as `crate::resolver` may be asking through it.
+#[cfg(feature = "trojan")]
+#[derive(Debug)]
+pub struct Client {
+    url: String,
+    endpoint: Endpoint,
+    idle: Idle<tls::Stream>,
+}
+
+#[cfg(feature = "trojan")]
+impl Client {
+    pub fn new(url: &str) -> Result<Self> {
+        let endpoint = Endpoint::parse(url)?;
+        if !endpoint.tls() {
+            bail!("{:?} is not an https:// URL", url);
+        }
+        Ok(Self {
+            url: url.to_string(),
+            endpoint,
+            idle: Idle::default(),
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// POSTs `body` of the given content type and reads the response
+    /// within `timeout`.
+    pub async fn post(
+        &self,
+        content_type: &str,
+        body: &[u8],
+        timeout: Duration,
+    ) -> Result<Response> {
+        let request = encode(
+            &self.endpoint,
+            "POST",
+            &[],
+            Some((content_type, body)),
+            true,
+        );
+        tokio::time::timeout(timeout, async {
+            if let Some(stream) = self.idle.take()
+                && let Ok(response) = self.exchange(stream, &request).await
+            {
+                return Ok(response);
+            }
+            // None was open, or the server closed it while idle.
+            let stream = TcpStream::connect(self.endpoint.addr())
+                .await
+                .with_context(|| format!("Failed to connect to {}", self.endpoint.addr()))?;
+            let stream = tls::connect(stream, self.endpoint.host()).await?;
+            self.exchange(stream, &request).await
+        })
+        .await
+        .with_context(|| format!("Request to {} timed out", self.url))?
+    }
+
+    async fn exchange(&self, mut stream: tls::Stream, request: &[u8]) -> Result<Response> {
+        stream.write_all(request).await?;
+        let (response, reusable) = read_response(&mut stream).await?;
+        if reusable {
+            self.idle.put(stream);
+        }
+        Ok(response)
+    }
+}
+
+/// The head and body of a request to `endpoint`.
+fn encode(
+    endpoint: &Endpoint,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: Option<(&str, &[u8])>,
+    keep_alive: bool,
+) -> Vec<u8> {
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: iway/{}\r\nAccept: */*\r\nConnection: {}\r\n",
+        method,
+        endpoint.path(),
+        endpoint.authority(),
+        env!("CARGO_PKG_VERSION"),
+        if keep_alive { "keep-alive" } else { "close" }
+    )
+    .into_bytes();
+    for (name, value) in headers {
+        request.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+    }
+    if let Some((content_type, body)) = body {
+        request.extend_from_slice(
+            format!(
+                "Content-Type: {}\r\nContent-Length: {}\r\n",
+                content_type,
+                body.len()
+            )
+            .as_bytes(),
+        );
+    }
+    request.extend_from_slice(b"\r\n");
+    if let Some((_, body)) = body {
+        request.extend_from_slice(body);
+    }
+    request
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    request: &[u8],
+) -> Result<Vec<u8>> {
+    stream.write_all(request).await?;
+
+    let mut response = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match stream.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => response.extend_from_slice(&buf[..n]),
+            // Servers often skip close_notify on a `Connection: close`.
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && !response.is_empty() => {
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if response.len() > MAX_RESPONSE {
+            bail!("Response too large");
+        }
+    }
+    Ok(response)
+}
+
+/// Reads one response off a connection that stays open, framed by its
+/// Content-Length or chunked encoding. Also returns whether the
+/// connection can take another request.
+#[cfg(feature = "trojan")]
+async fn read_response<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(Response, bool)> {
+    let mut raw = Vec::new();
+    let split = loop {
+        if let Some(split) = raw.windows(4).position(|w| w == b"\r\n\r\n") {
+            break split;
+        }
+        if read_more(stream, &mut raw).await? == 0 {
+            bail!("Truncated HTTP response");
+        }
+    };
+    let mut response = parse_head(&raw[..split])?;
+    let mut body = raw.split_off(split + 4);
+    let mut reusable = !response
+        .header("Connection")
+        .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+
+    let chunked = response
+        .header("Transfer-Encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+    let length = match response.header("Content-Length") {
+        Some(length) => Some(
+            length
+                .parse::<usize>()
+                .context("Malformed Content-Length")?,
+        ),
+        None => None,
+    };
+    response.body = match (chunked, length) {
+        (true, _) => loop {
+            if let Ok(dechunked) = dechunk(&body) {
+                break dechunked;
+            }
+            if read_more(stream, &mut body).await? == 0 {
+                bail!("Truncated chunk");
+            }
+        },
+        (false, Some(length)) => {
+            while body.len() < length {
+                if read_more(stream, &mut body).await? == 0 {
+                    bail!("Truncated HTTP body");
+                }
+            }
+            // Bytes past the body would be read as the next response.
+            reusable &= body.len() == length;
+            body.truncate(length);
+            body
+        }
+        // The body runs to the end of the connection.
+        (false, None) => {
+            reusable = false;
+            while read_more(stream, &mut body).await? > 0 {}
+            body
+        }
+    };
+    Ok((response, reusable))
+}
+
+/// Appends what the next read brings to `raw`, returning its length.
+#[cfg(feature = "trojan")]
+async fn read_more<S: AsyncRead + Unpin>(stream: &mut S, raw: &mut Vec<u8>) -> Result<usize> {
+    let mut buf = [0u8; 4096];
+    let n = stream.read(&mut buf).await?;
+    raw.extend_from_slice(&buf[..n]);
+    if raw.len() > MAX_RESPONSE {
+        bail!("Response too large");
+    }
+    Ok(n)
+}
+
+fn parse_response(raw: &[u8], head_only: bool) -> Result<Response> {
+    let split = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .context("Truncated HTTP response")?;
+    let mut response = parse_head(&raw[..split])?;
+    if head_only {
+        return Ok(response);
+    }
+
+    let body = &raw[split + 4..];
+    response.body = match response.header("Transfer-Encoding") {
+        Some(encoding) if encoding.eq_ignore_ascii_case("chunked") => dechunk(body)?,
+        _ => body.to_vec(),
+    };
+    Ok(response)
+}
+
+/// The status line and headers of a response, without the blank line.
+fn parse_head(head: &[u8]) -> Result<Response> {
+    let head = std::str::from_utf8(head).context("Malformed HTTP response head")?;
+    let mut lines = head.split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .context("Malformed HTTP status line")?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Ok(Response {
+        status,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// Joins the chunks of a chunked body.
+fn dechunk(mut body: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .context("Truncated chunk size")?;
+        let size = std::str::from_utf8(&body[..line_end])?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).context("Malformed chunk size")?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        let chunk = body.get(..size).context("Truncated chunk")?;
+        out.extend_from_slice(chunk);
+        body = body.get(size + 2..).context("Truncated chunk")?;
+    }
+}
+
+#[cfg(all(test, feature = "trojan"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_responses_off_a_kept_connection() {
+        let mut stream: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst";
+        let (first, reusable) = read_response(&mut stream).await.unwrap();
+        assert_eq!(
+            (first.status(), first.body(), reusable),
+            (200, &b"first"[..], true)
+        );
+
+        let mut stream: &[u8] = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            3\r\nsec\r\n3\r\nond\r\n0\r\n\r\n";
+        let (second, reusable) = read_response(&mut stream).await.unwrap();
+        assert_eq!((second.body(), reusable), (&b"second"[..], true));
+
+        // Without a length the body runs to the end, and so does the
+        // connection.
+        let mut stream: &[u8] = b"HTTP/1.1 404 Not Found\r\n\r\nthe rest";
+        let (last, reusable) = read_response(&mut stream).await.unwrap();
+        assert_eq!(
+            (last.status(), last.body(), reusable),
+            (404, &b"the rest"[..], false)
+        );
+
+        // Nor is a connection kept that says it closes, or that sent more
+        // than was asked for.
+        for raw in [
+            &b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok"[..],
+            &b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok, and more"[..],
+        ] {
+            let mut stream = raw;
+            let (response, reusable) = read_response(&mut stream).await.unwrap();
+            assert_eq!((response.body(), reusable), (&b"ok"[..], false));
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_a_body_cut_short() {
+        let mut stream: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort";
+        assert!(read_response(&mut stream).await.is_err());
+    }
+}
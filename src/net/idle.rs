@@ -0,0 +1,68 @@
+//! Connections to one server kept open between requests, for DNS over TLS
+//! and HTTPS, which would otherwise pay a handshake for every lookup.
+
+use std::fmt;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+/// Most connections kept per server; more are closed once used.
+const MAX_IDLE: usize = 4;
+
+/// How long a connection may sit unused. Servers close theirs after
+/// about as long, and a connection they closed costs a failed attempt.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Idle<S> {
+    streams: Mutex<Vec<(S, Instant)>>,
+}
+
+impl<S> Idle<S> {
+    /// The connection used last, unless it sat unused too long.
+    pub fn take(&self) -> Option<S> {
+        let mut streams = self.streams.lock();
+        streams.retain(|(_, since)| since.elapsed() < IDLE_TIMEOUT);
+        streams.pop().map(|(stream, _)| stream)
+    }
+
+    /// Keeps `stream` for the next request, if there is room.
+    pub fn put(&self, stream: S) {
+        let mut streams = self.streams.lock();
+        if streams.len() < MAX_IDLE {
+            streams.push((stream, Instant::now()));
+        }
+    }
+}
+
+impl<S> Default for Idle<S> {
+    fn default() -> Self {
+        Self {
+            streams: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<S> fmt::Debug for Idle<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Idle({})", self.streams.lock().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn hands_out_the_newest_until_it_goes_stale() {
+        let idle = Idle::default();
+        for stream in 0..=MAX_IDLE {
+            idle.put(stream);
+        }
+        // The one past MAX_IDLE was closed.
+        assert_eq!(idle.take(), Some(MAX_IDLE - 1));
+
+        tokio::time::advance(IDLE_TIMEOUT).await;
+        assert_eq!(idle.take(), None);
+    }
+}
@@ -1,2 +1,17 @@
+pub mod capabilities;
+pub mod endpoint;
+#[cfg(any(feature = "trojan", feature = "metrics"))]
+pub mod http;
+pub mod idle;
+#[cfg(any(feature = "trojan", feature = "snell"))]
+pub mod proxy_protocol;
+pub mod relay;
+pub mod shaper;
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub mod sniff;
+pub mod sockopt;
 pub mod tcp;
+pub mod tls;
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub mod udp;
 pub mod util;
@@ -0,0 +1,437 @@
+//! PROXY protocol (v1 text and v2 binary) headers, as sent by HAProxy and
+//! nginx `stream` in front of a TCP listener, and as sent by iway itself to
+//! fallback servers so they see the real client address.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+#[cfg(feature = "trojan")]
+use crate::config::ProxyProtocolVersion;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 line the spec allows, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+/// Longest v2 address block accepted; real ones are at most a few hundred
+/// bytes even with TLVs.
+const V2_MAX_LEN: usize = 4096;
+
+/// A load balancer that accepted the connection sends the header right
+/// away; one that did not within this long is not a load balancer.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Addresses carried by a PROXY header.
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyHeader {
+    /// The client the load balancer accepted.
+    pub source: SocketAddr,
+    /// The address the client connected to. Snell has no use for it.
+    #[cfg_attr(not(feature = "trojan"), allow(dead_code))]
+    pub destination: SocketAddr,
+}
+
+/// Whether `peer` is one of the load balancers in `trusted`, whose PROXY
+/// headers are believed. Anyone else could name any source address.
+pub fn trusts(trusted: &[IpNet], peer: IpAddr) -> bool {
+    let peer = peer.to_canonical();
+    trusted.iter().any(|net| net.contains(&peer))
+}
+
+/// Reads the PROXY header a load balancer put in front of the stream,
+/// consuming nothing past it. `None` means a LOCAL or UNKNOWN header (a
+/// health check, say), for which the socket's own addresses apply.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<ProxyHeader>>
+where
+    S: AsyncRead + Unpin,
+{
+    tokio::time::timeout(HEADER_TIMEOUT, read_header_inner(stream))
+        .await
+        .context("Timed out waiting for the PROXY protocol header")?
+}
+
+async fn read_header_inner<S>(stream: &mut S) -> Result<Option<ProxyHeader>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Both versions are longer than the v2 signature, so it can be read
+    // whole before telling them apart.
+    let mut prefix = [0u8; 12];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if prefix.starts_with(b"PROXY ") {
+        read_v1(stream, &prefix).await
+    } else {
+        bail!("Connection does not start with a PROXY protocol header")
+    }
+}
+
+async fn read_v1<S>(stream: &mut S, prefix: &[u8]) -> Result<Option<ProxyHeader>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Byte by byte, so that nothing after the CRLF is taken from the
+    // stream.
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            bail!("PROXY v1 header is too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line =
+        std::str::from_utf8(&line[..line.len() - 2]).context("PROXY v1 header is not text")?;
+    let mut fields = line.split(' ').skip(1);
+
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        other => bail!("Unsupported PROXY v1 protocol {:?}", other),
+    }
+
+    let (Some(src), Some(dst), Some(src_port), Some(dst_port), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        bail!("Malformed PROXY v1 header: {:?}", line);
+    };
+
+    let parse = |ip: &str, port: &str| -> Result<SocketAddr> {
+        Ok(SocketAddr::new(
+            ip.parse()
+                .with_context(|| format!("Bad address {:?}", ip))?,
+            port.parse()
+                .with_context(|| format!("Bad port {:?}", port))?,
+        ))
+    };
+
+    Ok(Some(ProxyHeader {
+        source: parse(src, src_port)?,
+        destination: parse(dst, dst_port)?,
+    }))
+}
+
+async fn read_v2<S>(stream: &mut S) -> Result<Option<ProxyHeader>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    let [version_command, family, len_hi, len_lo] = head;
+
+    if version_command >> 4 != 2 {
+        bail!(
+            "Unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+
+    let len = u16::from_be_bytes([len_hi, len_lo]) as usize;
+    if len > V2_MAX_LEN {
+        bail!("PROXY v2 header is too long ({} bytes)", len);
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: the balancer talking for itself.
+        0 => return Ok(None),
+        1 => {}
+        command => bail!("Unsupported PROXY v2 command {}", command),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+
+    match family >> 4 {
+        // AF_INET
+        1 if body.len() >= 12 => {
+            let src = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dst = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(src.into(), port(8)),
+                destination: SocketAddr::new(dst.into(), port(10)),
+            }))
+        }
+        // AF_INET6
+        2 if body.len() >= 36 => {
+            let mut src = [0u8; 16];
+            let mut dst = [0u8; 16];
+            src.copy_from_slice(&body[..16]);
+            dst.copy_from_slice(&body[16..32]);
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(Ipv6Addr::from(src).into(), port(32)),
+                destination: SocketAddr::new(Ipv6Addr::from(dst).into(), port(34)),
+            }))
+        }
+        1 | 2 => bail!("Truncated PROXY v2 address block"),
+        // AF_UNSPEC or AF_UNIX: nothing usable as a client address.
+        _ => Ok(None),
+    }
+}
+
+/// Builds the header announcing `source` connecting to `destination`.
+#[cfg(feature = "trojan")]
+pub fn encode(
+    version: ProxyProtocolVersion,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> Vec<u8> {
+    let (source, destination) = same_family(source, destination);
+
+    match version {
+        ProxyProtocolVersion::V1 => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if source.is_ipv4() { "TCP4" } else { "TCP6" },
+            source.ip(),
+            destination.ip(),
+            source.port(),
+            destination.port()
+        )
+        .into_bytes(),
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY command.
+            header.push(0x21);
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    // AF_INET, STREAM
+                    header.push(0x11);
+                    header.extend_from_slice(&12u16.to_be_bytes());
+                    header.extend_from_slice(&src.octets());
+                    header.extend_from_slice(&dst.octets());
+                }
+                (src, dst) => {
+                    // AF_INET6, STREAM
+                    header.push(0x21);
+                    header.extend_from_slice(&36u16.to_be_bytes());
+                    header.extend_from_slice(&to_v6(src).octets());
+                    header.extend_from_slice(&to_v6(dst).octets());
+                }
+            }
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+/// Unmaps IPv4-mapped addresses, then maps both into IPv6 if only one of
+/// them is IPv4: a header carries a single address family.
+#[cfg(feature = "trojan")]
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+    let (source, destination) = (canonical(source), canonical(destination));
+
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination);
+    }
+    let mapped = |addr: SocketAddr| SocketAddr::new(to_v6(addr.ip()).into(), addr.port());
+    (mapped(source), mapped(destination))
+}
+
+#[cfg(feature = "trojan")]
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A v2 header with the given version/command and family bytes around
+    /// `body`.
+    fn v2(version_command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[version_command, family]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    /// An AF_INET address block for 192.0.2.1:56324 -> 198.51.100.7:443.
+    fn inet_block() -> Vec<u8> {
+        let mut body = vec![192, 0, 2, 1, 198, 51, 100, 7];
+        body.extend_from_slice(&56324u16.to_be_bytes());
+        body.extend_from_slice(&443u16.to_be_bytes());
+        body
+    }
+
+    async fn read(input: &[u8]) -> (Result<Option<ProxyHeader>>, Vec<u8>) {
+        let mut stream = input;
+        let header = read_header(&mut stream).await;
+        (header, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn reads_v1_and_leaves_the_payload() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443\r\nGET /").await;
+        let header = header.unwrap().unwrap();
+        assert_eq!(header.source, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(header.destination, "198.51.100.7:443".parse().unwrap());
+        assert_eq!(rest, b"GET /");
+
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 1 2\r\n").await;
+        let header = header.unwrap().unwrap();
+        assert_eq!(header.source, "[2001:db8::1]:1".parse().unwrap());
+        assert_eq!(header.destination, "[2001:db8::2]:2".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn reads_v1_unknown_as_no_addresses() {
+        let (header, rest) = read(b"PROXY UNKNOWN\r\nrest").await;
+        assert!(header.unwrap().is_none());
+        assert_eq!(rest, b"rest");
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v1() {
+        for input in [
+            // Cut off before the CRLF.
+            &b"PROXY TCP4 192.0.2.1 198.51.100.7 56324"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 56324\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 56324 443 1\r\n",
+            b"PROXY TCP4 192.0.2.300 198.51.100.7 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.7 65536 443\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.7 56324 443\r\n",
+        ] {
+            let (header, _) = read(input).await;
+            assert!(header.is_err(), "{:?}", String::from_utf8_lossy(input));
+        }
+
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.resize(V1_MAX_LEN + 10, b'1');
+        long.extend_from_slice(b"\r\n");
+        assert!(read(&long).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_other_signatures() {
+        let mut near_miss = v2(0x21, 0x11, &inet_block());
+        near_miss[11] = b'\r';
+        for input in [
+            &b"GET / HTTP/1.1\r\n\r\n"[..],
+            b"proxy TCP4 ",
+            &near_miss,
+            b"PROXY",
+        ] {
+            assert!(read(input).await.0.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_v2_inet_and_inet6() {
+        let mut input = v2(0x21, 0x11, &inet_block());
+        input.extend_from_slice(b"payload");
+        let (header, rest) = read(&input).await;
+        let header = header.unwrap().unwrap();
+        assert_eq!(header.source, "192.0.2.1:56324".parse().unwrap());
+        assert_eq!(header.destination, "198.51.100.7:443".parse().unwrap());
+        assert_eq!(rest, b"payload");
+
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let dst: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut body = [src.octets(), dst.octets()].concat();
+        body.extend_from_slice(&[0, 1, 0, 2]);
+        let (header, _) = read(&v2(0x21, 0x21, &body)).await;
+        let header = header.unwrap().unwrap();
+        assert_eq!(header.source, "[2001:db8::1]:1".parse().unwrap());
+        assert_eq!(header.destination, "[2001:db8::2]:2".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn skips_v2_tlvs() {
+        let mut body = inet_block();
+        // PP2_TYPE_ALPN "h2", PP2_TYPE_AUTHORITY "example.com", PP2_TYPE_NOOP.
+        body.extend_from_slice(&[0x01, 0x00, 0x02]);
+        body.extend_from_slice(b"h2");
+        body.extend_from_slice(&[0x02, 0x00, 0x0b]);
+        body.extend_from_slice(b"example.com");
+        body.extend_from_slice(&[0x04, 0x00, 0x00]);
+        let mut input = v2(0x21, 0x11, &body);
+        input.extend_from_slice(b"\x16\x03\x01");
+
+        let (header, rest) = read(&input).await;
+        assert_eq!(
+            header.unwrap().unwrap().source,
+            "192.0.2.1:56324".parse().unwrap()
+        );
+        assert_eq!(rest, b"\x16\x03\x01");
+    }
+
+    #[tokio::test]
+    async fn reads_v2_local_and_unspec_as_no_addresses() {
+        // A LOCAL health check may still carry an address block.
+        let mut input = v2(0x20, 0x11, &inet_block());
+        input.extend_from_slice(b"rest");
+        let (header, rest) = read(&input).await;
+        assert!(header.unwrap().is_none());
+        assert_eq!(rest, b"rest");
+
+        let (header, rest) = read(&v2(0x20, 0x00, &[])).await;
+        assert!(header.unwrap().is_none());
+        assert!(rest.is_empty());
+
+        let (header, _) = read(&v2(0x21, 0x00, &[])).await;
+        assert!(header.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_v2() {
+        // Address blocks shorter than their family needs.
+        assert!(read(&v2(0x21, 0x11, &inet_block()[..8])).await.0.is_err());
+        assert!(read(&v2(0x21, 0x21, &[0; 35])).await.0.is_err());
+
+        // A length running past the end of the stream.
+        let mut cut = v2(0x21, 0x11, &inet_block());
+        cut.truncate(cut.len() - 1);
+        assert!(read(&cut).await.0.is_err());
+        assert!(read(&V2_SIGNATURE[..]).await.0.is_err());
+
+        // Version 1 in binary form, and a command other than LOCAL or PROXY.
+        assert!(read(&v2(0x11, 0x11, &inet_block())).await.0.is_err());
+        assert!(read(&v2(0x22, 0x11, &inet_block())).await.0.is_err());
+
+        let mut long = V2_SIGNATURE.to_vec();
+        long.extend_from_slice(&[0x21, 0x11]);
+        long.extend_from_slice(&((V2_MAX_LEN + 1) as u16).to_be_bytes());
+        assert!(read(&long).await.0.is_err());
+    }
+
+    #[cfg(feature = "trojan")]
+    #[tokio::test]
+    async fn reads_back_what_it_encodes() {
+        let source: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        let destination: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let (header, rest) = read(&encode(version, source, destination)).await;
+            let header = header.unwrap().unwrap();
+            // Mixed families go out as IPv6, with the IPv4 side mapped.
+            assert_eq!(header.source, "[::ffff:192.0.2.1]:56324".parse().unwrap());
+            assert_eq!(header.destination, destination);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn trusts_only_listed_networks() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        assert!(trusts(&trusted, "10.1.2.3".parse().unwrap()));
+        // A dual-stack listener sees IPv4 peers as mapped addresses.
+        assert!(trusts(&trusted, "::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!trusts(&trusted, "192.0.2.1".parse().unwrap()));
+        assert!(!trusts(&[], "10.1.2.3".parse().unwrap()));
+    }
+}
@@ -0,0 +1,237 @@
+use std::future::Future;
+#[cfg(any(feature = "trojan", feature = "snell"))]
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+#[cfg(any(feature = "trojan", feature = "snell"))]
+use tokio::io::split;
+#[cfg(any(feature = "tuic", feature = "trojan", feature = "snell"))]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::select;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+#[cfg(any(feature = "trojan", feature = "snell"))]
+use crate::control::registry::Traffic;
+#[cfg(any(feature = "tuic", feature = "trojan", feature = "snell"))]
+use crate::net::shaper;
+
+/// How long a relayed TCP connection may live; `None` means no limit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RelayLimits {
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+}
+
+impl RelayLimits {
+    pub fn new(idle_timeout: Option<Duration>, max_lifetime: Option<Duration>) -> Self {
+        Self {
+            idle_timeout,
+            max_lifetime,
+        }
+    }
+}
+
+/// Bytes that crossed a relay each way, and when the last of them did.
+pub struct Activity {
+    epoch: Instant,
+    /// Milliseconds since `epoch`.
+    last: AtomicU64,
+    up: AtomicU64,
+    down: AtomicU64,
+    /// The limit that ended the relay, if one did.
+    expiry: OnceLock<&'static str>,
+    killed: CancellationToken,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            last: AtomicU64::new(0),
+            up: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+            expiry: OnceLock::new(),
+            killed: CancellationToken::new(),
+        }
+    }
+
+    /// Ends the relay as if a limit ran out, on an operator's request.
+    #[cfg(feature = "control")]
+    pub fn kill(&self) {
+        self.killed.cancel();
+    }
+
+    /// When the relay started.
+    pub fn started(&self) -> Instant {
+        self.epoch
+    }
+
+    pub fn up(&self) -> u64 {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub fn down(&self) -> u64 {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    /// "idle timeout", "max lifetime" or "killed" once `run_limited` cut
+    /// the relay short.
+    pub fn expiry(&self) -> Option<&'static str> {
+        self.expiry.get().copied()
+    }
+
+    pub fn add_up(&self, n: usize) {
+        self.up.fetch_add(n as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn add_down(&self, n: usize) {
+        self.down.fetch_add(n as u64, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn touch(&self) {
+        self.last
+            .store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Resolves once nothing crossed the relay for `idle`.
+    async fn idle_for(&self, idle: Duration) {
+        loop {
+            let deadline =
+                self.epoch + Duration::from_millis(self.last.load(Ordering::Relaxed)) + idle;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Runs `relay` until it finishes, one of `limits` runs out or the relay
+/// is killed; in the latter cases `None` is returned and the expiry logged
+/// with the bytes moved.
+pub async fn run_limited<F: Future>(
+    relay: F,
+    limits: RelayLimits,
+    activity: &Activity,
+    what: &str,
+) -> Option<F::Output> {
+    let idle = async {
+        match limits.idle_timeout {
+            Some(idle) => activity.idle_for(idle).await,
+            None => std::future::pending().await,
+        }
+    };
+    let lifetime = async {
+        match limits.max_lifetime {
+            Some(lifetime) => tokio::time::sleep_until(activity.epoch + lifetime).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    let reason = select! {
+        output = relay => return Some(output),
+        _ = idle => "idle timeout",
+        _ = lifetime => "max lifetime",
+        _ = activity.killed.cancelled() => "killed",
+    };
+    let _ = activity.expiry.set(reason);
+
+    debug!(
+        "Closing {} ({}) after {:?}, {} bytes up, {} bytes down",
+        what,
+        reason,
+        activity.epoch.elapsed(),
+        activity.up(),
+        activity.down()
+    );
+    None
+}
+
+/// Copies `reader` into `writer` until EOF, then shuts `writer` down so the
+/// far side sees the same EOF while the other direction carries on.
+#[cfg(any(feature = "tuic", feature = "trojan", feature = "snell"))]
+pub async fn copy_half<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    buf_size: usize,
+    count: impl Fn(usize),
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; buf_size];
+    let mut total = 0;
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(total);
+        }
+
+        writer.write_all(&buf[..n]).await?;
+        count(n);
+        shaper::throttle(n).await;
+        total += n as u64;
+    }
+}
+
+/// Relays between the client (`left`) and the target (`right`) in one
+/// task, counting the bytes each way into `traffic` and `activity`. A side that reaches
+/// EOF has its peer's write half shut down, and the other direction keeps
+/// flowing until it ends too, so half-closed connections are not cut
+/// short. A relay that outlives `limits` has both write halves shut down.
+#[cfg(any(feature = "trojan", feature = "snell"))]
+pub async fn relay_tcp(
+    left: impl AsyncRead + AsyncWrite + Unpin,
+    right: impl AsyncRead + AsyncWrite + Unpin,
+    buf_size: usize,
+    traffic: &Arc<Traffic>,
+    activity: &Activity,
+    limits: RelayLimits,
+) -> anyhow::Result<()> {
+    let (mut l_r, mut l_w) = split(left);
+    let (mut r_r, mut r_w) = split(right);
+
+    let up = copy_half(&mut l_r, &mut r_w, buf_size, |n| {
+        traffic.add_up(n);
+        activity.add_up(n);
+    });
+    let down = copy_half(&mut r_r, &mut l_w, buf_size, |n| {
+        traffic.add_down(n);
+        activity.add_down(n);
+    });
+
+    if let Some(res) = run_limited(
+        async { tokio::try_join!(up, down) },
+        limits,
+        activity,
+        "TCP relay",
+    )
+    .await
+    {
+        res?;
+        return Ok(());
+    }
+
+    let _ = tokio::time::timeout(Duration::from_secs(1), async {
+        let _ = l_w.shutdown().await;
+        let _ = r_w.shutdown().await;
+    })
+    .await;
+
+    Ok(())
+}
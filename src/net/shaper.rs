@@ -0,0 +1,113 @@
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::config::EgressConfig;
+
+static EGRESS: OnceCell<TokenBucket> = OnceCell::new();
+
+/// A token bucket that lets callers go into debt: a relay sends first and
+/// then sleeps until the bucket has refilled what it spent, so the
+/// long-run rate holds without splitting writes.
+pub struct TokenBucket {
+    bytes_per_sec: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64, burst: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            burst: burst as f64,
+            state: Mutex::new(BucketState {
+                tokens: burst as f64,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Spends `n` tokens and returns how long the caller should wait for the
+    /// balance to recover.
+    fn take(&self, n: usize) -> Duration {
+        let mut state = self.state.lock();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled_at).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.bytes_per_sec).min(self.burst);
+        state.refilled_at = now;
+        state.tokens -= n as f64;
+
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.bytes_per_sec)
+        }
+    }
+
+    pub async fn consume(&self, n: usize) {
+        let wait = self.take(n);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Sets up the aggregate egress budget. Later calls are ignored.
+pub fn init(config: &EgressConfig) {
+    let Some(bytes_per_sec) = config.bytes_per_sec() else {
+        return;
+    };
+
+    info!(
+        "Egress shaped to {} Mbit/s (burst {} bytes)",
+        bytes_per_sec * 8 / 1_000_000,
+        config.burst_size()
+    );
+
+    let _ = EGRESS.set(TokenBucket::new(bytes_per_sec, config.burst_size()));
+}
+
+/// Accounts `n` bytes the proxy sent against the egress budget, waiting if
+/// it is overdrawn.
+pub async fn throttle(n: usize) {
+    if let Some(bucket) = EGRESS.get() {
+        bucket.consume(n).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn refills_at_the_rate_up_to_the_burst() {
+        let bucket = TokenBucket::new(1000, 500);
+        assert_eq!(bucket.take(500), Duration::ZERO);
+        // Overdrawn by 500 bytes at 1000 bytes per second.
+        assert_eq!(bucket.take(500), Duration::from_millis(500));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert_eq!(bucket.take(0), Duration::ZERO);
+
+        // A long pause refills no more than the burst.
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(bucket.take(600), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn consume_waits_out_the_debt() {
+        let bucket = TokenBucket::new(1000, 0);
+        let start = Instant::now();
+        bucket.consume(1500).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(1500));
+    }
+}
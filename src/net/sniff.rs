@@ -0,0 +1,393 @@
+//! Destination sniffing: the host name a client's first bytes name, from
+//! a TLS ClientHello's SNI or an HTTP/1 `Host` header.
+//!
+//! Clients that resolve names themselves send IP targets, which hides the
+//! site from domain policies and pins it to whatever their resolver
+//! returned. Reading the host back out of the payload lets the target be
+//! rewritten to the name, resolved here.
+
+use std::net::IpAddr;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::SniffConfig;
+
+/// Most bytes read while waiting for a complete ClientHello or header
+/// block.
+const MAX_SNIFF: usize = 16 * 1024;
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"HEAD ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+enum Sniffed {
+    Host(String),
+    /// The bytes so far could be the start of either protocol.
+    NeedMore,
+    Nothing,
+}
+
+/// Whether `config` asks for the payload of a connection to `domain` (the
+/// requested name, if any) to be sniffed.
+pub fn wanted(config: &SniffConfig, domain: Option<&str>) -> bool {
+    config.enabled() && (domain.is_none() || config.override_domains())
+}
+
+/// Reads the start of what the client sends, waiting at most the
+/// configured time for it, and returns what was read along with the host
+/// it names. The bytes are consumed from `reader` and must be sent on.
+pub async fn peek<R>(
+    reader: &mut R,
+    config: &SniffConfig,
+) -> std::io::Result<(Vec<u8>, Option<String>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut data = Vec::new();
+    let deadline = tokio::time::Instant::now() + config.timeout();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        // Server-first protocols never send anything; they cost the timeout.
+        let n = match tokio::time::timeout_at(deadline, reader.read(&mut buf)).await {
+            Ok(n) => n?,
+            Err(_) => return Ok((data, None)),
+        };
+        if n == 0 {
+            return Ok((data, None));
+        }
+        data.extend_from_slice(&buf[..n]);
+
+        match sniff(&data) {
+            Sniffed::Host(host) => return Ok((data, Some(host))),
+            Sniffed::NeedMore if data.len() < MAX_SNIFF => continue,
+            _ => return Ok((data, None)),
+        }
+    }
+}
+
+fn sniff(data: &[u8]) -> Sniffed {
+    let sniffed = if data.first() == Some(&0x16) {
+        sniff_tls(data)
+    } else if HTTP_METHODS
+        .iter()
+        .any(|method| method.starts_with(&data[..data.len().min(method.len())]))
+    {
+        sniff_http(data)
+    } else {
+        Sniffed::Nothing
+    };
+
+    match sniffed {
+        Sniffed::Host(host) if !is_host_name(&host) => Sniffed::Nothing,
+        sniffed => sniffed,
+    }
+}
+
+fn sniff_tls(data: &[u8]) -> Sniffed {
+    // The ClientHello may be split over several records.
+    let mut message = Vec::new();
+    let mut rest = data;
+    while rest.len() >= 5 {
+        if rest[0] != 0x16 {
+            return Sniffed::Nothing;
+        }
+        let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        let Some(fragment) = rest.get(5..5 + len) else {
+            message.extend_from_slice(&rest[5..]);
+            break;
+        };
+        message.extend_from_slice(fragment);
+        rest = &rest[5 + len..];
+    }
+
+    if message.len() < 4 {
+        return Sniffed::NeedMore;
+    }
+    if message[0] != 0x01 {
+        return Sniffed::Nothing;
+    }
+    let len = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+    let Some(body) = message.get(4..4 + len) else {
+        return Sniffed::NeedMore;
+    };
+
+    match client_hello_sni(body) {
+        Some(host) => Sniffed::Host(host),
+        None => Sniffed::Nothing,
+    }
+}
+
+fn client_hello_sni(body: &[u8]) -> Option<String> {
+    let mut at = 2 + 32;
+    at += 1 + *body.get(at)? as usize;
+    at += 2 + u16::from_be_bytes([*body.get(at)?, *body.get(at + 1)?]) as usize;
+    at += 1 + *body.get(at)? as usize;
+    let extensions_len = u16::from_be_bytes([*body.get(at)?, *body.get(at + 1)?]) as usize;
+    let mut extensions = body.get(at + 2..at + 2 + extensions_len)?;
+
+    while extensions.len() >= 4 {
+        let kind = u16::from_be_bytes([extensions[0], extensions[1]]);
+        let len = u16::from_be_bytes([extensions[2], extensions[3]]) as usize;
+        let data = extensions.get(4..4 + len)?;
+        extensions = &extensions[4 + len..];
+        if kind != 0 {
+            continue;
+        }
+
+        // server_name_list: length, then (type, length, name) entries.
+        let mut names = data.get(2..)?;
+        while names.len() >= 3 {
+            let name_len = u16::from_be_bytes([names[1], names[2]]) as usize;
+            let name = names.get(3..3 + name_len)?;
+            if names[0] == 0 {
+                return std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+            }
+            names = &names[3 + name_len..];
+        }
+        return None;
+    }
+
+    None
+}
+
+fn sniff_http(data: &[u8]) -> Sniffed {
+    // Give up on a bad request line without waiting for the headers.
+    if let Some(end) = data.windows(2).position(|w| w == b"\r\n")
+        && !is_request_line(&data[..end])
+    {
+        return Sniffed::Nothing;
+    }
+    let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Sniffed::NeedMore;
+    };
+    let Ok(head) = std::str::from_utf8(&data[..end]) else {
+        return Sniffed::Nothing;
+    };
+
+    let host = head.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("host")
+            .then(|| value.trim())
+    });
+    let Some(host) = host else {
+        return Sniffed::Nothing;
+    };
+
+    // Bracketed IPv6 literals are not names.
+    if host.starts_with('[') {
+        return Sniffed::Nothing;
+    }
+    let host = host.split_once(':').map_or(host, |(host, _)| host);
+    Sniffed::Host(host.to_ascii_lowercase())
+}
+
+/// `METHOD target HTTP/1.x`, single-spaced.
+fn is_request_line(line: &[u8]) -> bool {
+    let mut parts = line.split(|b| *b == b' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return false;
+    };
+    HTTP_METHODS
+        .iter()
+        .any(|known| known.strip_suffix(b" ") == Some(method))
+        && !target.is_empty()
+        && target.iter().all(|b| b.is_ascii_graphic())
+        && matches!(version, b"HTTP/1.0" | b"HTTP/1.1")
+}
+
+/// A DNS name, as opposed to an IP literal or garbage.
+fn is_host_name(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.parse::<IpAddr>().is_err()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ClientHello body with the given extensions block, after the
+    /// version, random, session id, one cipher suite and null compression.
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x11; 32]);
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+
+        let mut message = vec![0x01, 0x00];
+        message.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        message.extend_from_slice(&body);
+        message
+    }
+
+    /// A server_name extension holding one host_name entry.
+    fn server_name(name: &str) -> Vec<u8> {
+        let entry_len = name.len() as u16;
+        let mut extension = vec![0x00, 0x00];
+        extension.extend_from_slice(&(entry_len + 5).to_be_bytes());
+        extension.extend_from_slice(&(entry_len + 3).to_be_bytes());
+        extension.push(0x00);
+        extension.extend_from_slice(&entry_len.to_be_bytes());
+        extension.extend_from_slice(name.as_bytes());
+        extension
+    }
+
+    /// `message` cut into handshake records of at most `size` bytes.
+    fn records(message: &[u8], size: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for fragment in message.chunks(size) {
+            data.extend_from_slice(&[0x16, 0x03, 0x01]);
+            data.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            data.extend_from_slice(fragment);
+        }
+        data
+    }
+
+    fn host(sniffed: Sniffed) -> Option<String> {
+        match sniffed {
+            Sniffed::Host(host) => Some(host),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn finds_sni_after_other_extensions() {
+        // supported_versions (TLS 1.3) ahead of the server name.
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04];
+        extensions.extend_from_slice(&server_name("Example.COM"));
+        let data = records(&client_hello(&extensions), 16384);
+        assert_eq!(host(sniff(&data)).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn reassembles_a_client_hello_split_over_records() {
+        let data = records(&client_hello(&server_name("example.com")), 7);
+        assert_eq!(host(sniff(&data)).as_deref(), Some("example.com"));
+
+        // Every cut short of the end, inside a record header or not,
+        // waits for more.
+        for end in 1..data.len() {
+            assert!(matches!(sniff(&data[..end]), Sniffed::NeedMore), "{}", end);
+        }
+    }
+
+    #[test]
+    fn finds_nothing_without_a_usable_server_name() {
+        // No extensions at all, and only padding.
+        for extensions in [&[][..], &[0x00, 0x15, 0x00, 0x02, 0x00, 0x00]] {
+            let data = records(&client_hello(extensions), 16384);
+            assert!(matches!(sniff(&data), Sniffed::Nothing));
+        }
+
+        // An IP literal in place of a name.
+        let data = records(&client_hello(&server_name("192.0.2.1")), 16384);
+        assert!(matches!(sniff(&data), Sniffed::Nothing));
+    }
+
+    #[test]
+    fn rejects_extensions_longer_than_their_block() {
+        let mut extensions = server_name("example.com");
+        extensions[3] += 1;
+        let data = records(&client_hello(&extensions), 16384);
+        assert!(matches!(sniff(&data), Sniffed::Nothing));
+
+        // The block itself claiming more than the ClientHello holds.
+        let mut message = client_hello(&server_name("example.com"));
+        let at = message.len() - server_name("example.com").len() - 2;
+        message[at] = 0xff;
+        assert!(matches!(sniff(&records(&message, 16384)), Sniffed::Nothing));
+    }
+
+    #[test]
+    fn rejects_non_client_hello_handshakes() {
+        let mut message = client_hello(&server_name("example.com"));
+        // ServerHello.
+        message[0] = 0x02;
+        assert!(matches!(sniff(&records(&message, 16384)), Sniffed::Nothing));
+
+        // An alert record partway through.
+        let mut data = records(&client_hello(&server_name("example.com")), 16);
+        data[21] = 0x15;
+        assert!(matches!(sniff(&data), Sniffed::Nothing));
+    }
+
+    #[test]
+    fn finds_the_http_host() {
+        let data = b"GET / HTTP/1.1\r\nUser-Agent: x\r\nhost: Example.com:8080\r\n\r\n";
+        assert_eq!(host(sniff(data)).as_deref(), Some("example.com"));
+        assert!(matches!(sniff(&data[..20]), Sniffed::NeedMore));
+        assert!(matches!(sniff(b"CONN"), Sniffed::NeedMore));
+
+        let data = b"GET / HTTP/1.1\r\nHost: [2001:db8::1]:80\r\n\r\n";
+        assert!(matches!(sniff(data), Sniffed::Nothing));
+        let data = b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n";
+        assert!(matches!(sniff(data), Sniffed::Nothing));
+    }
+
+    #[test]
+    fn rejects_malformed_request_lines() {
+        for line in [
+            &b"GET /\r\n"[..],
+            b"GET  / HTTP/1.1\r\n",
+            b"GET / HTTP/2\r\n",
+            b"GET / HTTP/1.1 extra\r\n",
+            b"GET /\x00 HTTP/1.1\r\n",
+            b"POST\r\n",
+        ] {
+            let mut data = line.to_vec();
+            data.extend_from_slice(b"Host: example.com\r\n\r\n");
+            assert!(
+                matches!(sniff(&data), Sniffed::Nothing),
+                "{:?}",
+                String::from_utf8_lossy(line)
+            );
+            // Without waiting for the headers either.
+            assert!(matches!(sniff(line), Sniffed::Nothing));
+        }
+        assert!(matches!(sniff(b"\x00\x01binary"), Sniffed::Nothing));
+    }
+
+    #[tokio::test]
+    async fn peek_reads_until_the_host_and_keeps_the_bytes() {
+        let data = records(&client_hello(&server_name("example.com")), 32);
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let sent = data.clone();
+        tokio::spawn(async move {
+            use tokio::io::AsyncWriteExt;
+            for chunk in sent.chunks(10) {
+                client.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            client.write_all(b"after").await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let (read, host) = peek(&mut server, &SniffConfig::default()).await.unwrap();
+        assert_eq!(host.as_deref(), Some("example.com"));
+        assert!(read.starts_with(&data));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peek_gives_up_on_silence() {
+        let (_client, mut server) = tokio::io::duplex(64);
+        let (read, host) = peek(&mut server, &SniffConfig::default()).await.unwrap();
+        assert!(read.is_empty());
+        assert!(host.is_none());
+    }
+}
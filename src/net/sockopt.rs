@@ -0,0 +1,211 @@
+//! Tuning for TCP sockets a listener accepts and the connections it opens
+//! on their behalf, and where outbound connections are bound.
+
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use socket2::{SockRef, TcpKeepalive};
+#[cfg(feature = "trojan")]
+use tokio::net::TcpListener;
+use tokio::net::{TcpSocket, TcpStream};
+use tracing::debug;
+
+use crate::config::BindConfig;
+#[cfg(feature = "trojan")]
+use crate::config::TcpSocketConfig;
+
+/// Pending Fast Open requests a listener queues before falling back to the
+/// regular handshake.
+#[cfg(all(feature = "trojan", any(target_os = "linux", target_os = "android")))]
+const FAST_OPEN_QUEUE: libc::c_int = 256;
+
+/// What to set on each socket. The default leaves sockets as the OS made
+/// them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TcpOptions {
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    fast_open: bool,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+}
+
+impl TcpOptions {
+    #[cfg(feature = "trojan")]
+    pub fn from_config(config: &TcpSocketConfig) -> Self {
+        Self {
+            nodelay: config.nodelay(),
+            keepalive: config.keepalive(),
+            fast_open: config.fast_open(),
+            recv_buffer_size: config.recv_buffer_size(),
+            send_buffer_size: config.send_buffer_size(),
+        }
+    }
+
+    /// Sets the per-connection options on an accepted stream. Failures are
+    /// logged and otherwise ignored; the connection works without them.
+    #[cfg(feature = "trojan")]
+    pub fn apply(&self, stream: &TcpStream) {
+        self.apply_to(SockRef::from(stream));
+    }
+
+    /// Lets the listener accept data in the SYN of clients that send it.
+    #[cfg(feature = "trojan")]
+    pub fn apply_listener(&self, listener: &TcpListener) {
+        if !self.fast_open {
+            return;
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Err(e) = set_tcp_option(
+            &SockRef::from(listener),
+            libc::TCP_FASTOPEN,
+            FAST_OPEN_QUEUE,
+        ) {
+            debug!("Failed to enable TCP Fast Open on listener: {}", e);
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        {
+            let _ = listener;
+            debug!("TCP Fast Open is not supported on this platform");
+        }
+    }
+
+    /// Connects to `addr` with the options set up front, so Fast Open can
+    /// put the first write in the SYN, leaving from where `bind` says.
+    pub async fn connect(
+        &self,
+        addr: SocketAddr,
+        bind: Option<&Bind>,
+    ) -> std::io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+
+        if let Some(bind) = bind {
+            bind.apply(&socket)?;
+        }
+        self.apply_to(SockRef::from(&socket));
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.fast_open
+            && let Err(e) = set_tcp_option(&SockRef::from(&socket), libc::TCP_FASTOPEN_CONNECT, 1)
+        {
+            debug!("Failed to enable TCP Fast Open for {}: {}", addr, e);
+        }
+
+        socket.connect(addr).await
+    }
+
+    fn apply_to(&self, sock: SockRef<'_>) {
+        let results = [
+            (
+                "TCP_NODELAY",
+                self.nodelay.then(|| sock.set_tcp_nodelay(true)),
+            ),
+            (
+                "SO_KEEPALIVE",
+                self.keepalive.map(|idle| {
+                    sock.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle).with_interval(idle))
+                }),
+            ),
+            (
+                "SO_RCVBUF",
+                self.recv_buffer_size
+                    .map(|size| sock.set_recv_buffer_size(size)),
+            ),
+            (
+                "SO_SNDBUF",
+                self.send_buffer_size
+                    .map(|size| sock.set_send_buffer_size(size)),
+            ),
+        ];
+
+        for (option, result) in results {
+            if let Some(Err(e)) = result {
+                debug!("Failed to set {}: {}", option, e);
+            }
+        }
+    }
+}
+
+/// Where outbound connections leave from, on hosts with several uplinks:
+/// a local interface, a source address, or both.
+#[derive(Debug, Clone, Default)]
+pub struct Bind {
+    interface: Option<String>,
+    address: Option<IpAddr>,
+}
+
+impl Bind {
+    /// `None` when the config binds nothing.
+    pub fn from_config(config: &BindConfig) -> Result<Option<Self>> {
+        if config.interface().is_some() && !cfg!(any(target_os = "linux", target_os = "android")) {
+            bail!("Binding to an interface is only supported on Linux");
+        }
+        if config.interface().is_none() && config.source_address().is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            interface: config.interface().map(str::to_string),
+            address: config.source_address(),
+        }))
+    }
+
+    /// Whether a connection to `addr` can leave from the source address,
+    /// which takes one of the same family.
+    pub fn reaches(&self, addr: &SocketAddr) -> bool {
+        self.address
+            .is_none_or(|source| source.is_ipv4() == addr.is_ipv4())
+    }
+
+    fn apply(&self, socket: &TcpSocket) -> std::io::Result<()> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(interface) = &self.interface {
+            SockRef::from(socket).bind_device(Some(interface.as_bytes()))?;
+        }
+        if let Some(address) = self.address {
+            socket.bind(SocketAddr::new(address, 0))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Bind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.interface, self.address) {
+            (Some(interface), Some(address)) => write!(f, "{} on {}", address, interface),
+            (Some(interface), None) => write!(f, "{}", interface),
+            (None, Some(address)) => write!(f, "{}", address),
+            (None, None) => write!(f, "any"),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_tcp_option(
+    sock: &SockRef<'_>,
+    option: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
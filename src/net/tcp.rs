@@ -1,9 +1,165 @@
-use anyhow::{Ok, Result};
+use anyhow::{Context, Result, anyhow, bail};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpStream;
+use tokio::task::JoinSet;
 
+use crate::net::sockopt::{Bind, TcpOptions};
+
+/// Head start each connection attempt gets before the next address is
+/// tried in parallel (RFC 8305 "Connection Attempt Delay").
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+#[cfg(feature = "trojan")]
 pub async fn connect(addr: SocketAddr) -> Result<TcpStream> {
     let stream = TcpStream::connect(addr).await?;
 
     Ok(stream)
 }
+
+/// Connects to a `host:port`, its name looked up with `crate::resolver`
+/// like every other destination.
+pub async fn connect_host(target: &str) -> Result<TcpStream> {
+    let addrs = crate::resolver::lookup_host(target).await?;
+    connect_any_bound(&addrs, TcpOptions::default(), None).await
+}
+
+/// Connects to whichever of `addrs` answers first, Happy Eyeballs style:
+/// address families are interleaved and the next attempt starts after
+/// `ATTEMPT_DELAY`, or right away when the previous one fails, so one dead
+/// route doesn't make the whole target unreachable.
+#[cfg(any(feature = "trojan", feature = "snell"))]
+pub async fn connect_any(addrs: &[SocketAddr]) -> Result<TcpStream> {
+    connect_any_with(addrs, TcpOptions::default()).await
+}
+
+/// [`connect_any`] with `options` set on every socket it opens.
+#[cfg(any(feature = "trojan", feature = "snell"))]
+pub async fn connect_any_with(addrs: &[SocketAddr], options: TcpOptions) -> Result<TcpStream> {
+    connect_any_bound(addrs, options, None).await
+}
+
+/// [`connect_any_with`] leaving from where `bind` says. Addresses of the
+/// other family than its source address are skipped.
+pub async fn connect_any_bound(
+    addrs: &[SocketAddr],
+    options: TcpOptions,
+    bind: Option<&Arc<Bind>>,
+) -> Result<TcpStream> {
+    let reachable: Vec<SocketAddr>;
+    let addrs = match bind {
+        Some(bind) => {
+            reachable = addrs
+                .iter()
+                .filter(|addr| bind.reaches(addr))
+                .copied()
+                .collect();
+            if reachable.is_empty() && !addrs.is_empty() {
+                bail!("None of {:?} can be reached from {}", addrs, bind);
+            }
+            &reachable[..]
+        }
+        None => addrs,
+    };
+
+    if let [addr] = addrs {
+        return options
+            .connect(*addr, bind.map(Arc::as_ref))
+            .await
+            .with_context(|| format!("Failed to connect to {}", addr));
+    }
+
+    let mut queue = interleave_families(addrs).into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+
+    loop {
+        if let Some(addr) = queue.next() {
+            let bind = bind.cloned();
+            attempts.spawn(async move {
+                options
+                    .connect(addr, bind.as_deref())
+                    .await
+                    .with_context(|| format!("Failed to connect to {}", addr))
+            });
+        }
+
+        if attempts.is_empty() {
+            break;
+        }
+
+        let joined = if queue.len() > 0 {
+            match tokio::time::timeout(ATTEMPT_DELAY, attempts.join_next()).await {
+                Ok(joined) => joined,
+                Err(_) => continue,
+            }
+        } else {
+            attempts.join_next().await
+        };
+
+        match joined {
+            // Dropping the set aborts the attempts still in flight.
+            Some(Ok(Ok(stream))) => return Ok(stream),
+            Some(Ok(Err(e))) => last_error = Some(e),
+            Some(Err(e)) => last_error = Some(e.into()),
+            None => {}
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow!("No addresses to connect to")))
+}
+
+/// Why an outbound connect failed, coarse enough for a client to decide
+/// whether retrying through another server makes sense.
+#[cfg(feature = "tuic")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectFailure {
+    Refused,
+    TimedOut,
+    Unreachable,
+    Other,
+}
+
+#[cfg(feature = "tuic")]
+impl ConnectFailure {
+    /// Classifies the first I/O error in `err`'s chain.
+    pub fn classify(err: &anyhow::Error) -> Self {
+        let Some(io) = err.chain().find_map(|e| e.downcast_ref::<std::io::Error>()) else {
+            return Self::Other;
+        };
+
+        match io.kind() {
+            std::io::ErrorKind::ConnectionRefused => Self::Refused,
+            std::io::ErrorKind::TimedOut => Self::TimedOut,
+            std::io::ErrorKind::HostUnreachable | std::io::ErrorKind::NetworkUnreachable => {
+                Self::Unreachable
+            }
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Alternates IPv6 and IPv4 addresses, starting with the family of the
+/// resolver's first answer and otherwise keeping its order.
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+    preferred.reverse();
+    other.reverse();
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => break,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    ordered
+}
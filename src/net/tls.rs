@@ -0,0 +1,58 @@
+//! The client side of TLS for outgoing HTTP and DNS requests: one
+//! connector verifying servers against the system roots. Builds without
+//! the trojan feature have no rustls and refuse every handshake.
+
+#[cfg(feature = "trojan")]
+pub use verifying::{Stream, connect};
+
+#[cfg(not(feature = "trojan"))]
+pub use disabled::{Stream, connect};
+
+#[cfg(feature = "trojan")]
+mod verifying {
+    use std::sync::Arc;
+
+    use anyhow::{Context, Result};
+    use once_cell::sync::OnceCell;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, crypto};
+    use tokio::net::TcpStream;
+    use tokio_rustls::TlsConnector;
+    use tokio_rustls::client::TlsStream;
+
+    use crate::outbound::trojan::system_roots;
+
+    static CONNECTOR: OnceCell<TlsConnector> = OnceCell::new();
+
+    pub type Stream = TlsStream<TcpStream>;
+
+    pub async fn connect(stream: TcpStream, host: &str) -> Result<Stream> {
+        let connector = CONNECTOR.get_or_try_init(|| {
+            let config =
+                ClientConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+                    .with_safe_default_protocol_versions()
+                    .context("Failed to set TLS protocol versions")?
+                    .with_root_certificates(system_roots()?)
+                    .with_no_client_auth();
+            anyhow::Ok(TlsConnector::from(Arc::new(config)))
+        })?;
+        let server_name = ServerName::try_from(host.to_string())
+            .with_context(|| format!("Bad server name {:?}", host))?;
+        connector
+            .connect(server_name, stream)
+            .await
+            .with_context(|| format!("TLS handshake with {} failed", host))
+    }
+}
+
+#[cfg(not(feature = "trojan"))]
+mod disabled {
+    use anyhow::{Result, bail};
+    use tokio::net::TcpStream;
+
+    pub type Stream = TcpStream;
+
+    pub async fn connect(_stream: TcpStream, _host: &str) -> Result<Stream> {
+        bail!("https:// needs a build with the trojan feature")
+    }
+}
@@ -0,0 +1,152 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::net::UdpSocket;
+
+static ICMP_UNREACHABLE: AtomicU64 = AtomicU64::new(0);
+
+/// Total number of ICMP unreachable errors observed on relay sockets.
+#[cfg(feature = "control")]
+pub fn icmp_unreachable_total() -> u64 {
+    ICMP_UNREACHABLE.load(Ordering::Relaxed)
+}
+
+/// Binds a non-blocking UDP socket with SO_REUSEPORT, so several sockets
+/// can share `addr` and the kernel spreads flows across them.
+#[cfg(all(
+    feature = "tuic",
+    unix,
+    not(any(target_os = "solaris", target_os = "illumos"))
+))]
+pub fn bind_reuse_port(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+#[cfg(all(
+    feature = "tuic",
+    not(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))
+))]
+pub fn bind_reuse_port(_addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not available on this platform",
+    ))
+}
+
+/// Asks the kernel to report ICMP errors on an unconnected UDP socket
+/// (`IP_RECVERR`/`IPV6_RECVERR`). A no-op on platforms without it.
+pub fn enable_icmp_errors(socket: &UdpSocket) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let fd = socket.as_raw_fd();
+        let on: libc::c_int = 1;
+
+        let set = |level: libc::c_int, name: libc::c_int| -> io::Result<()> {
+            let ret = unsafe {
+                libc::setsockopt(
+                    fd,
+                    level,
+                    name,
+                    &on as *const libc::c_int as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            if ret != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        };
+
+        match socket.local_addr()? {
+            SocketAddr::V4(_) => set(libc::IPPROTO_IP, libc::IP_RECVERR)?,
+            SocketAddr::V6(_) => {
+                set(libc::IPPROTO_IPV6, libc::IPV6_RECVERR)?;
+                // Dual-stack sockets also receive errors for IPv4-mapped peers.
+                let _ = set(libc::IPPROTO_IP, libc::IP_RECVERR);
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = socket;
+
+    Ok(())
+}
+
+/// Returns true if the error is the result of an ICMP unreachable message.
+/// Windows reports port unreachable on UDP sockets as a connection reset.
+pub fn is_unreachable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+    )
+}
+
+/// Records an ICMP unreachable error and drains the socket's error queue,
+/// returning the destinations the kernel reported as unreachable.
+pub fn take_unreachable(socket: &UdpSocket) -> Vec<SocketAddr> {
+    ICMP_UNREACHABLE.fetch_add(1, Ordering::Relaxed);
+
+    let mut targets = Vec::new();
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let fd = socket.as_raw_fd();
+
+        loop {
+            let mut data = [0u8; 512];
+            let mut control = [0u8; 512];
+
+            let result = unsafe {
+                socket2::SockAddr::try_init(|storage, len| {
+                    let mut iov = libc::iovec {
+                        iov_base: data.as_mut_ptr() as *mut libc::c_void,
+                        iov_len: data.len(),
+                    };
+                    let mut msg: libc::msghdr = std::mem::zeroed();
+                    msg.msg_name = storage as *mut libc::c_void;
+                    msg.msg_namelen = *len;
+                    msg.msg_iov = &mut iov;
+                    msg.msg_iovlen = 1;
+                    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                    msg.msg_controllen = control.len() as _;
+
+                    let n = libc::recvmsg(fd, &mut msg, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT);
+                    if n < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    *len = msg.msg_namelen;
+                    Ok(n)
+                })
+            };
+
+            match result {
+                Ok((_, addr)) => {
+                    if let Some(target) = addr.as_socket() {
+                        targets.push(target);
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = socket;
+
+    targets
+}
@@ -1,7 +1,10 @@
+use anyhow::{Context, Result, bail};
 use arc_swap::ArcSwap;
 use once_cell::sync::Lazy;
 use std::collections::HashSet;
 use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::result;
 use std::sync::Arc;
 use std::time::Duration;
@@ -28,7 +31,7 @@ static LOCAL_IPS: Lazy<Arc<ArcSwap<HashSet<IpAddr>>>> = Lazy::new(|| {
     std::thread::spawn(move || {
         loop {
             std::thread::sleep(Duration::from_secs(5));
-            (&*thread_swap).store(Arc::new(build_local_ips()));
+            (*thread_swap).store(Arc::new(build_local_ips()));
         }
     });
 
@@ -64,3 +67,87 @@ pub fn is_local_addr(addr: &SocketAddr) -> bool {
         }
     }
 }
+
+/// Most ports a listener may spread over, ranges included.
+const MAX_LISTEN_PORTS: usize = 1024;
+
+/// Parses `listen` entries, single ports ("8443") or inclusive ranges
+/// ("2053-2083"), into the ports they name.
+pub fn parse_ports(specs: &[String]) -> Result<Vec<u16>> {
+    let mut ports = Vec::new();
+
+    for spec in specs {
+        let spec = spec.trim();
+        let (first, last) = match spec.split_once('-') {
+            Some((first, last)) => (first.trim(), last.trim()),
+            None => (spec, spec),
+        };
+        let first: u16 = first
+            .parse()
+            .with_context(|| format!("Bad port in listen entry {:?}", spec))?;
+        let last: u16 = last
+            .parse()
+            .with_context(|| format!("Bad port in listen entry {:?}", spec))?;
+        if first == 0 || first > last {
+            bail!("Bad port range in listen entry {:?}", spec);
+        }
+
+        ports.extend(first..=last);
+        if ports.len() > MAX_LISTEN_PORTS {
+            bail!("listen names more than {} ports", MAX_LISTEN_PORTS);
+        }
+    }
+
+    Ok(ports)
+}
+
+/// Parses a port entry of a rule or policy: a single port ("443") or an
+/// inclusive range ("6000-7000").
+pub fn parse_port_range(spec: &str) -> Result<RangeInclusive<u16>> {
+    let spec = spec.trim();
+    let (first, last) = spec.split_once('-').unwrap_or((spec, spec));
+    let (Ok(first), Ok(last)) = (first.trim().parse::<u16>(), last.trim().parse::<u16>()) else {
+        bail!("Bad port {:?}", spec);
+    };
+    if first > last {
+        bail!("Bad port range {:?}", spec);
+    }
+    Ok(first..=last)
+}
+
+/// Prefix of `listen` entries naming a Unix socket rather than ports.
+const UNIX_LISTEN_PREFIX: &str = "unix://";
+
+/// Splits `listen` entries into the ports they name and the paths of the
+/// `unix:///path` ones.
+pub fn parse_listen(specs: &[String]) -> Result<(Vec<u16>, Vec<PathBuf>)> {
+    let (unix, ports): (Vec<_>, Vec<_>) = specs
+        .iter()
+        .cloned()
+        .partition(|spec| spec.trim().starts_with(UNIX_LISTEN_PREFIX));
+
+    let paths = unix
+        .iter()
+        .map(|spec| {
+            let path = &spec.trim()[UNIX_LISTEN_PREFIX.len()..];
+            if path.is_empty() {
+                bail!("Bad Unix socket in listen entry {:?}", spec);
+            }
+            Ok(PathBuf::from(path))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok((parse_ports(&ports)?, paths))
+}
+
+/// `primary`, then its host on each of `ports` not already covered.
+pub fn listen_addrs(primary: SocketAddr, ports: &[u16]) -> Vec<SocketAddr> {
+    let mut addrs = vec![primary];
+    for port in ports {
+        let addr = SocketAddr::new(primary.ip(), *port);
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    addrs
+}
@@ -0,0 +1,170 @@
+//! Outbound groups: connects balanced over several upstreams.
+//!
+//! Members are picked in turn or by the connect time they showed lately,
+//! and a connect that fails moves on to the next member. Health is judged
+//! passively from those connects: a member failing `max_failures` times
+//! in a row is ejected for `eject_duration`, tried again afterwards, and
+//! back for good after its next success. Ejected members are still tried
+//! last, so a group whose members all failed recently keeps trying.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::config::{BalanceStrategy, GroupConfig};
+use crate::outbound::{BoxStream, Outbound};
+use crate::protocol::address::Address;
+
+/// Weight of the newest sample in the smoothed connect time, as 1/n.
+const RTT_SMOOTHING: u32 = 8;
+
+pub struct GroupOutbound {
+    name: String,
+    members: Vec<Member>,
+    strategy: BalanceStrategy,
+    next: AtomicUsize,
+    max_failures: u32,
+    eject_duration: Duration,
+}
+
+struct Member {
+    name: String,
+    outbound: Arc<dyn Outbound>,
+    health: Mutex<Health>,
+}
+
+#[derive(Default)]
+struct Health {
+    /// Failed connects since the last success.
+    failures: u32,
+    ejected_until: Option<Instant>,
+    /// Smoothed connect time, unset until the first success.
+    rtt: Option<Duration>,
+}
+
+impl GroupOutbound {
+    pub fn new(
+        name: &str,
+        config: &GroupConfig,
+        members: Vec<(String, Arc<dyn Outbound>)>,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            members: members
+                .into_iter()
+                .map(|(name, outbound)| Member {
+                    name,
+                    outbound,
+                    health: Mutex::default(),
+                })
+                .collect(),
+            strategy: config.strategy(),
+            next: AtomicUsize::new(0),
+            max_failures: config.max_failures(),
+            eject_duration: config.eject_duration(),
+        }
+    }
+
+    /// Members in the order to try them: healthy ones as the strategy
+    /// ranks them, then the ejected ones, soonest due back first.
+    fn order(&self) -> Vec<&Member> {
+        let mut members: Vec<&Member> = self.members.iter().collect();
+        match self.strategy {
+            BalanceStrategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % members.len().max(1);
+                members.rotate_left(start);
+            }
+            // Unmeasured members sort first, so each gets measured.
+            BalanceStrategy::LeastRtt => members.sort_by_key(|m| m.health.lock().rtt),
+        }
+
+        let now = Instant::now();
+        let (healthy, mut ejected): (Vec<_>, Vec<_>) = members
+            .into_iter()
+            .map(|m| {
+                (
+                    m,
+                    m.health.lock().ejected_until.filter(|until| *until > now),
+                )
+            })
+            .partition(|(_, until)| until.is_none());
+        ejected.sort_by_key(|(_, until)| *until);
+        healthy
+            .into_iter()
+            .chain(ejected)
+            .map(|(member, _)| member)
+            .collect()
+    }
+
+    fn record_success(&self, member: &Member, elapsed: Duration) {
+        let mut health = member.health.lock();
+        health.failures = 0;
+        if health.ejected_until.take().is_some() {
+            info!(
+                "[Outbound] {} is back in group {} after a successful connect",
+                member.name, self.name
+            );
+        }
+        health.rtt = Some(match health.rtt {
+            Some(rtt) => (rtt * (RTT_SMOOTHING - 1) + elapsed) / RTT_SMOOTHING,
+            None => elapsed,
+        });
+    }
+
+    fn record_failure(&self, member: &Member, error: &anyhow::Error) {
+        let mut health = member.health.lock();
+        health.failures += 1;
+        let now = Instant::now();
+        if health.failures >= self.max_failures
+            && health.ejected_until.is_none_or(|until| until <= now)
+        {
+            health.ejected_until = Some(now + self.eject_duration);
+            warn!(
+                "[Outbound] Ejecting {} from group {} for {}s after {} failed connect(s): {:#}",
+                member.name,
+                self.name,
+                self.eject_duration.as_secs(),
+                health.failures,
+                error
+            );
+        }
+    }
+}
+
+#[async_trait]
+impl Outbound for GroupOutbound {
+    async fn connect(&self, target: &Address, addrs: &[SocketAddr]) -> Result<BoxStream> {
+        let mut last_error = None;
+        for member in self.order() {
+            let start = Instant::now();
+            match member.outbound.connect(target, addrs).await {
+                Ok(stream) => {
+                    self.record_success(member, start.elapsed());
+                    debug!(
+                        "[Outbound] Group {} sent {} through {}",
+                        self.name, target, member.name
+                    );
+                    return Ok(stream);
+                }
+                Err(e) => {
+                    debug!(
+                        "[Outbound] Group {} member {} failed for {}: {:#}",
+                        self.name, member.name, target, e
+                    );
+                    self.record_failure(member, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        let error = last_error.unwrap_or_else(|| anyhow::anyhow!("no members"));
+        Err(error).with_context(|| format!("Every member of group {} failed", self.name))
+    }
+}
@@ -0,0 +1,91 @@
+//! Tunnels through an upstream HTTP proxy with `CONNECT`.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use base64ct::{Base64, Encoding};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+use crate::config::ProxyUpstreamConfig;
+use crate::net::sockopt::Bind;
+use crate::outbound::{BoxStream, Outbound, dial};
+use crate::protocol::address::Address;
+
+/// Longest response header accepted from the proxy.
+const MAX_RESPONSE_HEADER: usize = 8192;
+
+/// An upstream HTTP proxy.
+pub struct HttpOutbound {
+    server: String,
+    /// Where the connection to the upstream leaves from.
+    bind: Option<Arc<Bind>>,
+    /// `Proxy-Authorization` value, for proxies that want Basic auth.
+    authorization: Option<String>,
+}
+
+impl HttpOutbound {
+    pub fn from_config(config: &ProxyUpstreamConfig, bind: Option<Arc<Bind>>) -> Result<Self> {
+        Ok(Self {
+            server: config.server().to_string(),
+            bind,
+            authorization: config.credentials().map(|(username, password)| {
+                format!(
+                    "Basic {}",
+                    Base64::encode_string(format!("{}:{}", username, password).as_bytes())
+                )
+            }),
+        })
+    }
+}
+
+#[async_trait]
+impl Outbound for HttpOutbound {
+    async fn connect(&self, target: &Address, _addrs: &[SocketAddr]) -> Result<BoxStream> {
+        let mut stream = dial(&self.server, self.bind.as_ref()).await?;
+
+        let mut request = format!(
+            "CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n",
+            target = target
+        );
+        if let Some(authorization) = &self.authorization {
+            request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // Read byte by byte so nothing the target sends after the header
+        // is swallowed.
+        let mut header = Vec::new();
+        while !header.ends_with(b"\r\n\r\n") {
+            if header.len() >= MAX_RESPONSE_HEADER {
+                bail!("HTTP upstream {} sent an oversized response", self.server);
+            }
+            let byte = stream
+                .read_u8()
+                .await
+                .with_context(|| format!("HTTP upstream {} closed the tunnel", self.server))?;
+            header.push(byte);
+        }
+
+        let header = String::from_utf8_lossy(&header);
+        let status = header.lines().next().unwrap_or_default();
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => {}
+            _ => bail!(
+                "HTTP upstream {} refused to connect to {}: {:?}",
+                self.server,
+                target,
+                status
+            ),
+        }
+
+        debug!(
+            "[Outbound] Forwarding {} through HTTP upstream {}",
+            target, self.server
+        );
+        Ok(Box::new(stream))
+    }
+}
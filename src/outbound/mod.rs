@@ -0,0 +1,79 @@
+//! Ways of reaching a target: dialing it from this host, or through an
+//! upstream proxy, so routed traffic can be chained through other
+//! servers.
+
+pub mod group;
+pub mod http;
+pub mod socks5;
+#[cfg(feature = "trojan")]
+pub mod trojan;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::net::sockopt::{Bind, TcpOptions};
+use crate::net::tcp as net_tcp;
+use crate::protocol::address::Address;
+use crate::resolver;
+
+/// A connection to a target, whichever way it was reached.
+pub trait ProxyStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> ProxyStream for T {}
+
+pub type BoxStream = Box<dyn ProxyStream>;
+
+#[async_trait]
+pub trait Outbound: Send + Sync {
+    /// Opens a stream to `target`, which resolved to `addrs` here. Bytes
+    /// written to it once it is returned go to the target.
+    async fn connect(&self, target: &Address, addrs: &[SocketAddr]) -> Result<BoxStream>;
+}
+
+/// Dials the target from this host.
+pub struct DirectOutbound {
+    options: TcpOptions,
+    bind: Option<Arc<Bind>>,
+}
+
+impl DirectOutbound {
+    pub fn new(options: TcpOptions) -> Self {
+        Self {
+            options,
+            bind: None,
+        }
+    }
+
+    /// Leaves from where `bind` says rather than where the routing table
+    /// sends it.
+    pub fn with_bind(mut self, bind: Option<Arc<Bind>>) -> Self {
+        self.bind = bind;
+        self
+    }
+}
+
+#[async_trait]
+impl Outbound for DirectOutbound {
+    async fn connect(&self, target: &Address, addrs: &[SocketAddr]) -> Result<BoxStream> {
+        let stream = net_tcp::connect_any_bound(addrs, self.options, self.bind.as_ref())
+            .await
+            .with_context(|| format!("Failed to connect to {}", target))?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Opens a TCP connection to an upstream `host:port`, from where `bind`
+/// says if set.
+async fn dial(server: &str, bind: Option<&Arc<Bind>>) -> Result<TcpStream> {
+    let addrs = resolver::lookup_host(server)
+        .await
+        .with_context(|| format!("Failed to resolve upstream {}", server))?;
+    let stream = net_tcp::connect_any_bound(&addrs, TcpOptions::default(), bind).await?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
@@ -0,0 +1,128 @@
+//! Client half of SOCKS5 (RFC 1928), with username/password
+//! authentication (RFC 1929) when the upstream asks for it.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::debug;
+
+use crate::config::ProxyUpstreamConfig;
+use crate::net::sockopt::Bind;
+use crate::outbound::{BoxStream, Outbound, dial};
+use crate::protocol::address::Address;
+
+const VERSION: u8 = 0x05;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const SUCCEEDED: u8 = 0x00;
+
+/// An upstream SOCKS5 server.
+pub struct Socks5Outbound {
+    server: String,
+    /// Where the connection to the upstream leaves from.
+    bind: Option<Arc<Bind>>,
+    credentials: Option<(String, String)>,
+}
+
+impl Socks5Outbound {
+    pub fn from_config(config: &ProxyUpstreamConfig, bind: Option<Arc<Bind>>) -> Result<Self> {
+        let credentials = config.credentials();
+        if let Some((username, password)) = credentials
+            && (username.len() > 255 || password.len() > 255)
+        {
+            bail!("SOCKS5 usernames and passwords are at most 255 bytes");
+        }
+
+        Ok(Self {
+            server: config.server().to_string(),
+            bind,
+            credentials: credentials
+                .map(|(username, password)| (username.to_string(), password.to_string())),
+        })
+    }
+}
+
+#[async_trait]
+impl Outbound for Socks5Outbound {
+    async fn connect(&self, target: &Address, _addrs: &[SocketAddr]) -> Result<BoxStream> {
+        let mut stream = dial(&self.server, self.bind.as_ref()).await?;
+
+        let method = match self.credentials {
+            Some(_) => USERNAME_PASSWORD,
+            None => NO_AUTHENTICATION,
+        };
+        stream.write_all(&[VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        stream
+            .read_exact(&mut reply)
+            .await
+            .context("SOCKS5 upstream closed during the greeting")?;
+        match reply {
+            [VERSION, NO_AUTHENTICATION] => {}
+            [VERSION, USERNAME_PASSWORD] => {
+                let Some((username, password)) = &self.credentials else {
+                    bail!("SOCKS5 upstream {} wants credentials", self.server);
+                };
+                let mut request = vec![0x01, username.len() as u8];
+                request.extend_from_slice(username.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request).await?;
+
+                stream.read_exact(&mut reply).await?;
+                if reply[1] != SUCCEEDED {
+                    bail!("SOCKS5 upstream {} refused the credentials", self.server);
+                }
+            }
+            [VERSION, NO_ACCEPTABLE_METHODS] => {
+                bail!(
+                    "SOCKS5 upstream {} accepts none of our methods",
+                    self.server
+                )
+            }
+            _ => bail!("{} is not a SOCKS5 server", self.server),
+        }
+
+        let mut request = vec![VERSION, CONNECT, 0x00];
+        target.write_to(&mut request);
+        stream.write_all(&request).await?;
+
+        let mut header = [0u8; 3];
+        stream.read_exact(&mut header).await?;
+        if header[1] != SUCCEEDED {
+            bail!(
+                "SOCKS5 upstream {} failed to connect to {}: {}",
+                self.server,
+                target,
+                reply_message(header[1])
+            );
+        }
+        // The address the upstream connected from, of no use here.
+        Address::read_from(&mut stream).await?;
+
+        debug!(
+            "[Outbound] Forwarding {} through SOCKS5 upstream {}",
+            target, self.server
+        );
+        Ok(Box::new(stream))
+    }
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general failure",
+        0x02 => "not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unknown error",
+    }
+}
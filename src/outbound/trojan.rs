@@ -0,0 +1,136 @@
+//! Client half of the Trojan protocol, for forwarding connections to an
+//! upstream Trojan server.
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore, crypto};
+use sha2::{Digest, Sha224};
+use tokio::io::AsyncWriteExt;
+use tokio_rustls::TlsConnector;
+use tracing::{debug, warn};
+
+use crate::config::TrojanUpstreamConfig;
+use crate::net::sockopt::Bind;
+use crate::outbound::{BoxStream, Outbound, dial};
+use crate::protocol::address::Address;
+use crate::protocol::trojan::command::CommandType;
+use crate::server::tls::load_certs;
+
+const CRLF: &[u8] = b"\r\n";
+
+/// An upstream Trojan server, dialed over TLS once per forwarded
+/// connection.
+pub struct TrojanOutbound {
+    server: String,
+    /// Where the connection to the upstream leaves from.
+    bind: Option<Arc<Bind>>,
+    server_name: ServerName<'static>,
+    /// Lowercase hex SHA-224 of the password, as sent on the wire.
+    password_hash: String,
+    connector: TlsConnector,
+}
+
+impl TrojanOutbound {
+    pub fn from_config(config: &TrojanUpstreamConfig, bind: Option<Arc<Bind>>) -> Result<Self> {
+        let host = match config.sni() {
+            Some(sni) => sni,
+            None => host_of(config.server())
+                .with_context(|| format!("Bad upstream server {:?}", config.server()))?,
+        };
+        let server_name = ServerName::try_from(host.to_string())
+            .with_context(|| format!("Bad upstream server name {:?}", host))?;
+
+        let roots = match config.ca_path() {
+            Some(path) => ca_roots(Path::new(path))?,
+            None => system_roots()?,
+        };
+
+        let tls_config =
+            ClientConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .context("Failed to set TLS protocol versions")?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+
+        Ok(Self {
+            server: config.server().to_string(),
+            bind,
+            server_name,
+            password_hash: format!("{:x}", Sha224::digest(config.password().as_bytes())),
+            connector: TlsConnector::from(Arc::new(tls_config)),
+        })
+    }
+}
+
+#[async_trait]
+impl Outbound for TrojanOutbound {
+    /// Opens a TLS connection to the upstream and asks it to CONNECT to
+    /// `target`.
+    async fn connect(&self, target: &Address, _addrs: &[SocketAddr]) -> Result<BoxStream> {
+        let tcp_stream = dial(&self.server, self.bind.as_ref()).await?;
+
+        let mut tls_stream = self
+            .connector
+            .connect(self.server_name.clone(), tcp_stream)
+            .await
+            .with_context(|| format!("TLS handshake with upstream {} failed", self.server))?;
+
+        let mut request = Vec::with_capacity(self.password_hash.len() + 264);
+        request.extend_from_slice(self.password_hash.as_bytes());
+        request.extend_from_slice(CRLF);
+        request.push(CommandType::Connect as u8);
+        target.write_to(&mut request);
+        request.extend_from_slice(CRLF);
+
+        tls_stream.write_all(&request).await?;
+        tls_stream.flush().await?;
+
+        debug!(
+            "[Trojan] Forwarding {} through upstream {}",
+            target, self.server
+        );
+        Ok(Box::new(tls_stream))
+    }
+}
+
+/// `host` of a `host:port` or `[v6]:port` string.
+fn host_of(server: &str) -> Result<&str> {
+    let Some((host, _port)) = server.rsplit_once(':') else {
+        bail!("missing port");
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        bail!("missing host");
+    }
+    Ok(host)
+}
+
+fn ca_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Bad CA certificate in {:?}", path))?;
+    }
+    Ok(roots)
+}
+
+/// The system's CA certificates, for connections to public servers.
+pub(crate) fn system_roots() -> Result<RootCertStore> {
+    let native = rustls_native_certs::load_native_certs();
+    for e in &native.errors {
+        warn!("[Trojan] Skipping system CA certificates: {}", e);
+    }
+
+    let mut roots = RootCertStore::empty();
+    let (added, _ignored) = roots.add_parsable_certificates(native.certs);
+    if added == 0 {
+        bail!("No system CA certificates found; set ca_path for the upstream");
+    }
+    Ok(roots)
+}
@@ -0,0 +1,201 @@
+//! Custom policy hooks implemented as a WebAssembly module.
+//!
+//! The module must export `memory` and `alloc(len: i32) -> i32`, and may
+//! export either or both hooks:
+//!
+//! - `on_auth(ptr: i32, len: i32) -> i32`, called once a client presented
+//!   valid credentials;
+//! - `on_connect(ptr: i32, len: i32) -> i32`, called before every outbound
+//!   TCP connect.
+//!
+//! The request metadata is written to memory returned by `alloc` as
+//! newline-separated `key=value` pairs (`protocol`, `inbound`, `peer`,
+//! `user`, and `target` for connects). A non-zero return rejects the
+//! request. The module gets no imports, and every call runs in a fresh
+//! instance with a fuel budget; traps and exhausted fuel reject as well.
+
+use anyhow::{Result, bail};
+
+use crate::config::FilterConfig;
+use crate::control::registry::SessionGuard;
+
+#[cfg(feature = "wasm-filters")]
+use anyhow::{Context, anyhow};
+#[cfg(feature = "wasm-filters")]
+use once_cell::sync::OnceCell;
+#[cfg(feature = "wasm-filters")]
+use tracing::info;
+#[cfg(feature = "wasm-filters")]
+use wasmtime::{Engine, InstancePre, Linker, Module, Store};
+
+#[cfg(feature = "wasm-filters")]
+static FILTER: OnceCell<WasmFilter> = OnceCell::new();
+
+#[cfg(feature = "wasm-filters")]
+struct WasmFilter {
+    engine: Engine,
+    instance: InstancePre<()>,
+    fuel: u64,
+    on_auth: bool,
+    on_connect: bool,
+}
+
+#[cfg(feature = "wasm-filters")]
+impl WasmFilter {
+    fn load(config: &FilterConfig) -> Result<Self> {
+        let mut engine_config = wasmtime::Config::new();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config).map_err(|e| anyhow!("{}", e))?;
+
+        let module = Module::from_file(&engine, config.module())
+            .map_err(|e| anyhow!("{}", e))
+            .with_context(|| format!("Failed to load filter module {}", config.module()))?;
+
+        let exports = |name: &str| module.exports().any(|export| export.name() == name);
+        for required in ["memory", "alloc"] {
+            if !exports(required) {
+                bail!("Filter module does not export {:?}", required);
+            }
+        }
+        let on_auth = exports("on_auth");
+        let on_connect = exports("on_connect");
+
+        let instance = Linker::new(&engine)
+            .instantiate_pre(&module)
+            .map_err(|e| anyhow!("{}", e))
+            .context("Filter module must not have imports")?;
+
+        Ok(Self {
+            engine,
+            instance,
+            fuel: config.fuel(),
+            on_auth,
+            on_connect,
+        })
+    }
+
+    fn call(&self, hook: &str, metadata: &str) -> Result<i32> {
+        let mut store = Store::new(&self.engine, ());
+        store.set_fuel(self.fuel).map_err(|e| anyhow!("{}", e))?;
+
+        let instance = self
+            .instance
+            .instantiate(&mut store)
+            .map_err(|e| anyhow!("{}", e))?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| anyhow!("memory is not a memory export"))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&mut store, "alloc")
+            .map_err(|e| anyhow!("{}", e))?;
+        let hook = instance
+            .get_typed_func::<(i32, i32), i32>(&mut store, hook)
+            .map_err(|e| anyhow!("{}", e))?;
+
+        let len = i32::try_from(metadata.len())?;
+        let ptr = alloc.call(&mut store, len).map_err(|e| anyhow!("{}", e))?;
+        memory
+            .write(&mut store, ptr as u32 as usize, metadata.as_bytes())
+            .map_err(|e| anyhow!("{}", e))?;
+
+        hook.call(&mut store, (ptr, len))
+            .map_err(|e| anyhow!("{}", e))
+    }
+
+    fn decide(&self, hook: &str, metadata: String) -> Result<()> {
+        match self.call(hook, &metadata) {
+            Ok(0) => Ok(()),
+            Ok(code) => bail!("rejected by filter {} (code {})", hook, code),
+            Err(e) => bail!("filter {} failed: {:#}", hook, e),
+        }
+    }
+}
+
+/// Loads the configured filter module, if any.
+#[cfg(feature = "wasm-filters")]
+pub fn init(config: Option<&FilterConfig>) -> Result<()> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+
+    let filter = WasmFilter::load(config)?;
+
+    info!(
+        "[Filter] Loaded {}: on_auth={} on_connect={} fuel={}",
+        config.module(),
+        filter.on_auth,
+        filter.on_connect,
+        filter.fuel
+    );
+
+    let _ = FILTER.set(filter);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "wasm-filters"))]
+pub fn init(config: Option<&FilterConfig>) -> Result<()> {
+    if let Some(config) = config {
+        bail!(
+            "filter module {} is configured but iway was built without the wasm-filters feature",
+            config.module()
+        );
+    }
+    Ok(())
+}
+
+/// Asks the filter whether `user` may open a session.
+#[cfg(all(feature = "wasm-filters", any(feature = "tuic", feature = "trojan")))]
+pub fn check_auth(session: &SessionGuard, user: &str) -> Result<()> {
+    match FILTER.get() {
+        Some(filter) if filter.on_auth => {
+            filter.decide("on_auth", metadata(session, Some(user), None))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(all(
+    not(feature = "wasm-filters"),
+    any(feature = "tuic", feature = "trojan")
+))]
+pub fn check_auth(_session: &SessionGuard, _user: &str) -> Result<()> {
+    Ok(())
+}
+
+/// Asks the filter whether the session may connect to `target`.
+#[cfg(feature = "wasm-filters")]
+pub fn check_connect(session: &SessionGuard, target: &str) -> Result<()> {
+    match FILTER.get() {
+        Some(filter) if filter.on_connect => {
+            let user = session.user();
+            filter.decide(
+                "on_connect",
+                metadata(session, user.as_deref(), Some(target)),
+            )
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "wasm-filters"))]
+pub fn check_connect(_session: &SessionGuard, _target: &str) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(feature = "wasm-filters")]
+fn metadata(session: &SessionGuard, user: Option<&str>, target: Option<&str>) -> String {
+    let mut out = format!(
+        "protocol={}\ninbound={}\npeer={}\n",
+        session.protocol(),
+        session.inbound(),
+        session.peer_addr()
+    );
+    if let Some(user) = user {
+        out.push_str(&format!("user={}\n", user));
+    }
+    if let Some(target) = target {
+        out.push_str(&format!("target={}\n", target));
+    }
+    out
+}
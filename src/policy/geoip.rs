@@ -0,0 +1,126 @@
+use std::net::IpAddr;
+
+use anyhow::{Context, Result, bail};
+use maxminddb::{Reader, geoip2};
+use once_cell::sync::OnceCell;
+use tracing::info;
+
+use crate::config::{GeoIpAction, GeoIpConfig};
+
+static FILTER: OnceCell<GeoFilter> = OnceCell::new();
+
+/// What to do with a newly accepted client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Reject,
+    Fallback,
+}
+
+struct GeoFilter {
+    countries: Option<Reader<Vec<u8>>>,
+    asns: Option<Reader<Vec<u8>>>,
+    allow_countries: Vec<String>,
+    block_countries: Vec<String>,
+    block_asns: Vec<u32>,
+    action: GeoIpAction,
+}
+
+impl GeoFilter {
+    /// Addresses the databases know nothing about (private ranges, fresh
+    /// allocations) are let through.
+    fn refuses(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+
+        if let Some(reader) = &self.countries {
+            let country = reader
+                .lookup::<geoip2::Country>(ip)
+                .ok()
+                .and_then(|c| c.country)
+                .and_then(|c| c.iso_code);
+
+            if let Some(code) = country {
+                let listed = |list: &[String]| list.iter().any(|c| c.eq_ignore_ascii_case(code));
+                if !self.allow_countries.is_empty() && !listed(&self.allow_countries) {
+                    return true;
+                }
+                if listed(&self.block_countries) {
+                    return true;
+                }
+            }
+        }
+
+        if let Some(reader) = &self.asns {
+            let asn = reader
+                .lookup::<geoip2::Asn>(ip)
+                .ok()
+                .and_then(|a| a.autonomous_system_number);
+
+            if asn.is_some_and(|asn| self.block_asns.contains(&asn)) {
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+/// Loads the configured databases. Nothing is loaded, and every client is
+/// allowed, when no country or ASN rule is set.
+pub fn init(config: &GeoIpConfig) -> Result<()> {
+    let country_rules =
+        !config.allow_countries().is_empty() || !config.block_countries().is_empty();
+    let asn_rules = !config.block_asns().is_empty();
+
+    if !country_rules && !asn_rules {
+        return Ok(());
+    }
+
+    let countries = match (country_rules, config.country_database()) {
+        (false, _) => None,
+        (true, Some(path)) => Some(
+            Reader::open_readfile(path)
+                .with_context(|| format!("Failed to open GeoIP country database {}", path))?,
+        ),
+        (true, None) => bail!("geoip country rules need geoip.country_database"),
+    };
+
+    let asns = match (asn_rules, config.asn_database()) {
+        (false, _) => None,
+        (true, Some(path)) => Some(
+            Reader::open_readfile(path)
+                .with_context(|| format!("Failed to open GeoIP ASN database {}", path))?,
+        ),
+        (true, None) => bail!("geoip.block_asns needs geoip.asn_database"),
+    };
+
+    info!(
+        "[GeoIP] Filtering clients: allow_countries={:?} block_countries={:?} block_asns={:?} action={:?}",
+        config.allow_countries(),
+        config.block_countries(),
+        config.block_asns(),
+        config.action()
+    );
+
+    let _ = FILTER.set(GeoFilter {
+        countries,
+        asns,
+        allow_countries: config.allow_countries().to_vec(),
+        block_countries: config.block_countries().to_vec(),
+        block_asns: config.block_asns().to_vec(),
+        action: config.action(),
+    });
+
+    Ok(())
+}
+
+/// Checks a client's source address against the GeoIP rules.
+pub fn check(ip: IpAddr) -> Verdict {
+    match FILTER.get() {
+        Some(filter) if filter.refuses(ip) => match filter.action {
+            GeoIpAction::Reject => Verdict::Reject,
+            GeoIpAction::Fallback => Verdict::Fallback,
+        },
+        _ => Verdict::Allow,
+    }
+}
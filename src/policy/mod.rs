@@ -0,0 +1,239 @@
+pub mod filter;
+pub mod geoip;
+pub mod ports;
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub mod quota;
+pub mod script;
+pub mod udp_guard;
+#[cfg(any(feature = "tuic", feature = "trojan", feature = "control"))]
+pub mod users;
+
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::{Result, bail};
+use arc_swap::ArcSwap;
+use dashmap::DashMap;
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+use tracing::{info, warn};
+
+use crate::config::PolicyConfig;
+use crate::control::registry::{SessionGuard, registry};
+
+static POLICIES: Lazy<ArcSwap<Policies>> = Lazy::new(ArcSwap::default);
+
+#[derive(Default)]
+struct Policies {
+    inbounds: DashMap<String, EgressPolicy>,
+    users: DashMap<String, EgressPolicy>,
+}
+
+/// Ranges behind the `private`, `loopback` and `link-local` block rules.
+const PRIVATE_NETS: &[&str] = &[
+    "10.0.0.0/8",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "100.64.0.0/10",
+    "fc00::/7",
+];
+const LOOPBACK_NETS: &[&str] = &["127.0.0.0/8", "0.0.0.0/8", "::1/128", "::/128"];
+const LINK_LOCAL_NETS: &[&str] = &["169.254.0.0/16", "fe80::/10"];
+
+/// Egress rules for one inbound tag or user, compiled from a `[[policies]]`
+/// entry.
+#[derive(Debug, Default)]
+pub struct EgressPolicy {
+    blocked_nets: Vec<IpNet>,
+    blocked_domains: Vec<String>,
+    blocked_ports: Vec<u16>,
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    allow_udp: bool,
+    max_connections: Option<usize>,
+}
+
+impl EgressPolicy {
+    fn from_config(config: &PolicyConfig) -> Self {
+        let mut policy = Self {
+            blocked_ports: config.block_ports().to_vec(),
+            #[cfg(any(feature = "tuic", feature = "trojan"))]
+            allow_udp: config.allow_udp(),
+            max_connections: config.max_connections(),
+            ..Default::default()
+        };
+
+        for rule in config.block() {
+            let rule = rule.trim();
+            let class = match rule {
+                "private" => Some(PRIVATE_NETS),
+                "loopback" => Some(LOOPBACK_NETS),
+                "link-local" => Some(LINK_LOCAL_NETS),
+                _ => None,
+            };
+            if let Some(nets) = class {
+                policy
+                    .blocked_nets
+                    .extend(nets.iter().filter_map(|net| net.parse::<IpNet>().ok()));
+            } else if let Ok(net) = rule.parse::<IpNet>() {
+                policy.blocked_nets.push(net);
+            } else if let Ok(ip) = rule.parse::<IpAddr>() {
+                policy.blocked_nets.push(IpNet::from(ip));
+            } else if !rule.is_empty() {
+                policy
+                    .blocked_domains
+                    .push(rule.trim_start_matches('.').to_ascii_lowercase());
+            }
+        }
+
+        policy
+    }
+
+    fn blocks_domain(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.blocked_domains.iter().any(|suffix| {
+            domain
+                .strip_suffix(suffix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+        })
+    }
+
+    fn blocks_ip(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        self.blocked_nets.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Compiles the configured policies, replacing those of an earlier call.
+/// Connection limits count the sessions already open.
+pub fn init(configs: &[PolicyConfig]) {
+    let policies = Policies::default();
+    for config in configs {
+        let (kind, key, map) = match config.user() {
+            Some(user) => ("user", user, &policies.users),
+            None => ("inbound", config.inbound(), &policies.inbounds),
+        };
+        if map
+            .insert(key.to_string(), EgressPolicy::from_config(config))
+            .is_some()
+        {
+            warn!(
+                "[Policy] Duplicate policy for {} {:?}, the last one wins",
+                kind, key
+            );
+        }
+    }
+
+    if !policies.inbounds.is_empty() || !policies.users.is_empty() {
+        info!(
+            "[Policy] Loaded policies for {} inbound(s) and {} user(s)",
+            policies.inbounds.len(),
+            policies.users.len()
+        );
+    }
+
+    POLICIES.store(std::sync::Arc::new(policies));
+}
+
+fn with_policy<T>(inbound: &str, default: T, f: impl FnOnce(&EgressPolicy) -> T) -> T {
+    match POLICIES.load().inbounds.get(inbound) {
+        Some(policy) => f(&policy),
+        None => default,
+    }
+}
+
+fn with_user_policy<T>(
+    session: &SessionGuard,
+    default: T,
+    f: impl FnOnce(&EgressPolicy) -> T,
+) -> T {
+    let policies = POLICIES.load();
+    if policies.users.is_empty() {
+        return default;
+    }
+    match session.user().and_then(|user| policies.users.get(&user)) {
+        Some(policy) => f(&policy),
+        None => default,
+    }
+}
+
+/// The first of `targets` (or `domain`) that `policy` refuses.
+fn blocked_target(
+    policy: &EgressPolicy,
+    domain: Option<&str>,
+    targets: &[SocketAddr],
+) -> Option<String> {
+    if domain.is_some_and(|d| policy.blocks_domain(d)) {
+        return domain.map(str::to_string);
+    }
+    targets
+        .iter()
+        .find(|target| {
+            policy.blocked_ports.contains(&target.port()) || policy.blocks_ip(target.ip())
+        })
+        .map(|target| target.to_string())
+}
+
+/// Fails if the policy of the session's inbound or user refuses the
+/// target, reached via `domain` when the client asked for a name and
+/// resolved to `targets`.
+pub fn check(session: &SessionGuard, domain: Option<&str>, targets: &[SocketAddr]) -> Result<()> {
+    let inbound = session.inbound();
+    if let Some(target) = with_policy(inbound, None, |policy| {
+        blocked_target(policy, domain, targets)
+    }) {
+        bail!("{} blocked by policy of inbound {:?}", target, inbound);
+    }
+
+    if let Some(target) = with_user_policy(session, None, |policy| {
+        blocked_target(policy, domain, targets)
+    }) {
+        bail!(
+            "{} blocked by policy of user {:?}",
+            target,
+            session.user().unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+/// Runs every egress check for a TCP connect to `target`: the port policy,
+/// the inbound's rules, the routing script, then the filter module.
+pub fn check_connect(
+    session: &SessionGuard,
+    domain: Option<&str>,
+    targets: &[SocketAddr],
+    target: &str,
+) -> Result<()> {
+    if let Some(addr) = targets.first() {
+        ports::check(session, addr.port(), target)?;
+    }
+
+    check(session, domain, targets)?;
+
+    if let Some(addr) = targets.first()
+        && script::route(session, domain, *addr) == script::Action::Block
+    {
+        bail!("{} blocked by script", target);
+    }
+
+    filter::check_connect(session, target)
+}
+
+/// Whether the session's inbound and user may relay UDP.
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub fn allow_udp(session: &SessionGuard) -> bool {
+    with_policy(session.inbound(), true, |policy| policy.allow_udp)
+        && with_user_policy(session, true, |policy| policy.allow_udp)
+}
+
+/// Whether another session fits under the inbound's connection limit.
+pub fn admits(inbound: &str) -> bool {
+    with_policy(inbound, true, |policy| {
+        policy
+            .max_connections
+            .is_none_or(|max| registry().inbound_count(inbound) < max)
+    })
+}
@@ -0,0 +1,100 @@
+//! Server-wide destination port policy.
+//!
+//! Some ports are abused far more than they are used through a proxy:
+//! SMTP for spam, SSH and RDP for scans. `[port_policy]` refuses them for
+//! every inbound and user, either by listing them under `deny` or by
+//! turning the default to `deny` and listing what is allowed instead.
+
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use tracing::info;
+
+use crate::config::{PortDefault, PortPolicyConfig};
+use crate::control::registry::SessionGuard;
+use crate::net::util::parse_port_range;
+
+static PORTS: Lazy<ArcSwap<PortPolicy>> = Lazy::new(ArcSwap::default);
+
+#[derive(Default)]
+struct PortPolicy {
+    default: PortDefault,
+    allow: Vec<RangeInclusive<u16>>,
+    deny: Vec<RangeInclusive<u16>>,
+}
+
+impl PortPolicy {
+    /// Why `port` is refused, if it is.
+    fn refusal(&self, port: u16) -> Option<&'static str> {
+        let listed = |ranges: &[RangeInclusive<u16>]| ranges.iter().any(|r| r.contains(&port));
+        if listed(&self.deny) {
+            return Some("is in port_policy.deny");
+        }
+        match self.default {
+            PortDefault::Deny if !listed(&self.allow) => Some("is not in port_policy.allow"),
+            _ => None,
+        }
+    }
+}
+
+/// Compiles `[port_policy]`, replacing the policy of an earlier call.
+pub fn init(config: &PortPolicyConfig) -> Result<()> {
+    prepare(config).map(Prepared::install)
+}
+
+/// A port policy compiled by [`prepare`] that refuses nothing until
+/// installed.
+pub struct Prepared(PortPolicy);
+
+impl Prepared {
+    /// Enforces the policy from now on, replacing the one in place.
+    pub fn install(self) {
+        let policy = self.0;
+        if policy.default == PortDefault::Deny || !policy.deny.is_empty() {
+            info!(
+                "[Ports] Default {:?}, {} allowed and {} denied range(s)",
+                policy.default,
+                policy.allow.len(),
+                policy.deny.len()
+            );
+        }
+        PORTS.store(Arc::new(policy));
+    }
+}
+
+/// Compiles `[port_policy]` without enforcing it yet.
+pub fn prepare(config: &PortPolicyConfig) -> Result<Prepared> {
+    let parse = |specs: &[String]| {
+        specs
+            .iter()
+            .map(|spec| parse_port_range(spec))
+            .collect::<Result<Vec<_>>>()
+    };
+    Ok(Prepared(PortPolicy {
+        default: config.default_action(),
+        allow: parse(config.allow())?,
+        deny: parse(config.deny())?,
+    }))
+}
+
+/// Fails, and logs why, if the policy refuses the port of `target`.
+pub fn check(session: &SessionGuard, port: u16, target: &str) -> Result<()> {
+    let policy = PORTS.load();
+    let Some(reason) = policy.refusal(port) else {
+        return Ok(());
+    };
+
+    info!(
+        "[Ports] Refused {} connect from {} (user {}) to {}: port {} {}",
+        session.protocol(),
+        session.peer_addr(),
+        session.user().as_deref().unwrap_or("-"),
+        target,
+        port,
+        reason
+    );
+    bail!("{} blocked: port {} {}", target, port, reason)
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+
+use crate::config::Config;
+
+static QUOTAS: Lazy<ArcSwap<UserQuotas>> = Lazy::new(ArcSwap::default);
+
+/// Builds the quota table from `[[users]]`, replacing that of an earlier
+/// call. Slots held under the old table still count against the new limits.
+pub fn init(config: &Config) {
+    let mut quotas = UserQuotas::new(config);
+    quotas.carry_over(&QUOTAS.load());
+    QUOTAS.store(Arc::new(quotas));
+}
+
+/// The quota table shared by every protocol, so an identity's limits hold
+/// across all of them. Without `init` nobody is limited.
+pub fn quotas() -> Arc<UserQuotas> {
+    QUOTAS.load_full()
+}
+
+struct Counter {
+    limit: Option<usize>,
+    active: Arc<AtomicUsize>,
+}
+
+impl Counter {
+    fn acquire(&self) -> Option<QuotaSlot> {
+        let Some(limit) = self.limit else {
+            return Some(QuotaSlot(None));
+        };
+
+        if self.active.fetch_add(1, Ordering::AcqRel) >= limit {
+            self.active.fetch_sub(1, Ordering::AcqRel);
+            return None;
+        }
+
+        Some(QuotaSlot(Some(Arc::clone(&self.active))))
+    }
+}
+
+/// Per-identity limits on TUIC and Trojan connections and concurrent TUIC
+/// streams, from `max_connections` and `max_streams` in `[[users]]`.
+#[derive(Default)]
+pub struct UserQuotas {
+    users: HashMap<String, (Counter, Counter)>,
+}
+
+/// Holds one unit of a user's quota until dropped.
+pub struct QuotaSlot(Option<Arc<AtomicUsize>>);
+
+impl Drop for QuotaSlot {
+    fn drop(&mut self) {
+        if let Some(active) = &self.0 {
+            active.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+impl UserQuotas {
+    pub fn new(config: &Config) -> Self {
+        let users = config
+            .identities()
+            .iter()
+            .filter(|u| u.max_connections().is_some() || u.max_streams().is_some())
+            .map(|u| {
+                let counter = |limit| Counter {
+                    limit,
+                    active: Arc::new(AtomicUsize::new(0)),
+                };
+                (
+                    u.name().to_string(),
+                    (counter(u.max_connections()), counter(u.max_streams())),
+                )
+            })
+            .collect();
+
+        Self { users }
+    }
+
+    /// Shares the counters of users `old` also limits, so the slots they
+    /// hold are released into this table.
+    fn carry_over(&mut self, old: &UserQuotas) {
+        for (user, (connections, streams)) in &mut self.users {
+            if let Some((old_connections, old_streams)) = old.users.get(user) {
+                connections.active = Arc::clone(&old_connections.active);
+                streams.active = Arc::clone(&old_streams.active);
+            }
+        }
+    }
+
+    /// Takes a connection slot for `user`, or None if they hold the maximum.
+    pub fn acquire_connection(&self, user: &str) -> Option<QuotaSlot> {
+        match self.users.get(user) {
+            Some((connections, _)) => connections.acquire(),
+            None => Some(QuotaSlot(None)),
+        }
+    }
+
+    /// Takes a stream slot for `user`, or None if they hold the maximum.
+    #[cfg(feature = "tuic")]
+    pub fn acquire_stream(&self, user: &str) -> Option<QuotaSlot> {
+        match self.users.get(user) {
+            Some((_, streams)) => streams.acquire(),
+            None => Some(QuotaSlot(None)),
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Routing decisions delegated to an operator's Rhai script.
+//!
+//! The script defines `fn route(user, src, dst, port, host)` and returns
+//! an action name. `user` and `host` are `()` when unknown (before
+//! authentication, or for a target given as an address); `src` and `dst`
+//! are IP address strings. The actions are:
+//!
+//! - `"direct"`: connect to the target;
+//! - `"block"` (or `"reject"`): refuse the request.
+//!
+//! Anything else, a script error, or running out of operations blocks.
+
+use std::net::SocketAddr;
+
+use anyhow::{Result, bail};
+
+use crate::config::ScriptConfig;
+use crate::control::registry::SessionGuard;
+
+#[cfg(feature = "scripting")]
+use anyhow::{Context, anyhow};
+#[cfg(feature = "scripting")]
+use once_cell::sync::OnceCell;
+#[cfg(feature = "scripting")]
+use rhai::{AST, Dynamic, Engine, Scope};
+#[cfg(feature = "scripting")]
+use tracing::{info, warn};
+
+#[cfg(feature = "scripting")]
+static SCRIPT: OnceCell<RouteScript> = OnceCell::new();
+
+/// What the script decided for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Direct,
+    Block,
+}
+
+#[cfg(feature = "scripting")]
+struct RouteScript {
+    engine: Engine,
+    ast: AST,
+}
+
+#[cfg(feature = "scripting")]
+impl RouteScript {
+    fn load(config: &ScriptConfig) -> Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations());
+
+        let ast = engine
+            .compile_file(config.path().into())
+            .map_err(|e| anyhow!("{}", e))
+            .with_context(|| format!("Failed to compile script {}", config.path()))?;
+
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == "route" && f.params.len() == 5)
+        {
+            bail!(
+                "Script {} does not define route(user, src, dst, port, host)",
+                config.path()
+            );
+        }
+
+        Ok(Self { engine, ast })
+    }
+
+    fn route(&self, args: [Dynamic; 5]) -> Result<Action> {
+        let [user, src, dst, port, host] = args;
+        let action: String = self
+            .engine
+            .call_fn(
+                &mut Scope::new(),
+                &self.ast,
+                "route",
+                (user, src, dst, port, host),
+            )
+            .map_err(|e| anyhow!("{}", e))?;
+
+        match action.as_str() {
+            "direct" => Ok(Action::Direct),
+            "block" | "reject" => Ok(Action::Block),
+            other => bail!("unknown action {:?}", other),
+        }
+    }
+}
+
+/// Compiles the configured script, if any.
+#[cfg(feature = "scripting")]
+pub fn init(config: Option<&ScriptConfig>) -> Result<()> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+
+    let script = RouteScript::load(config)?;
+
+    info!(
+        "[Script] Routing through {} (max_operations={})",
+        config.path(),
+        config.max_operations()
+    );
+
+    let _ = SCRIPT.set(script);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "scripting"))]
+pub fn init(config: Option<&ScriptConfig>) -> Result<()> {
+    if let Some(config) = config {
+        bail!(
+            "script {} is configured but iway was built without the scripting feature",
+            config.path()
+        );
+    }
+    Ok(())
+}
+
+/// Asks the script how to route a connect to `target`, reached via `host`
+/// when the client named a domain.
+#[cfg(feature = "scripting")]
+pub fn route(session: &SessionGuard, host: Option<&str>, target: SocketAddr) -> Action {
+    let Some(script) = SCRIPT.get() else {
+        return Action::Direct;
+    };
+
+    let optional = |value: Option<String>| value.map(Dynamic::from).unwrap_or(Dynamic::UNIT);
+    let args = [
+        optional(session.user()),
+        Dynamic::from(session.peer_addr().ip().to_string()),
+        Dynamic::from(target.ip().to_string()),
+        Dynamic::from(i64::from(target.port())),
+        optional(host.map(str::to_string)),
+    ];
+
+    script.route(args).unwrap_or_else(|e| {
+        warn!("[Script] route() failed for {}: {:#}", target, e);
+        Action::Block
+    })
+}
+
+#[cfg(not(feature = "scripting"))]
+pub fn route(_session: &SessionGuard, _host: Option<&str>, _target: SocketAddr) -> Action {
+    Action::Direct
+}
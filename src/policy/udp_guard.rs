@@ -0,0 +1,196 @@
+//! Kill switch for UDP relaying.
+//!
+//! UDP floods are the cheapest way to overwhelm a relay, so TUIC Packet
+//! commands and Trojan UDP associations can be suspended as a whole while
+//! TCP connects keep working. The switch is flipped either by an operator
+//! (`iway ctl udp off`) or by the monitor when packet rate or resident
+//! memory crosses a configured threshold; a tripped monitor re-enables UDP
+//! once a full cooldown passes without another breach.
+
+#[cfg(any(feature = "tuic", feature = "trojan", feature = "control"))]
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::sync::watch::Receiver;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::config::UdpGuardConfig;
+
+#[cfg(any(feature = "tuic", feature = "trojan", feature = "control"))]
+static MANUAL_OFF: AtomicBool = AtomicBool::new(false);
+static TRIPPED_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+static PACKETS: AtomicU64 = AtomicU64::new(0);
+static RELAYED: AtomicU64 = AtomicU64::new(0);
+#[cfg(any(feature = "tuic", feature = "trojan", feature = "control"))]
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// Whether UDP may be relayed right now.
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub fn udp_enabled() -> bool {
+    !MANUAL_OFF.load(Ordering::Relaxed) && TRIPPED_UNTIL.lock().is_none()
+}
+
+/// Counts one UDP packet in either direction towards the rate threshold
+/// and tells whether it may be relayed. Dropped packets still count, so
+/// the switch stays off for as long as a flood lasts.
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub fn admit_packet() -> bool {
+    PACKETS.fetch_add(1, Ordering::Relaxed);
+    if udp_enabled() {
+        RELAYED.fetch_add(1, Ordering::Relaxed);
+        return true;
+    }
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+    false
+}
+
+/// UDP packets relayed since startup, in both directions.
+pub fn relayed_packets() -> u64 {
+    RELAYED.load(Ordering::Relaxed)
+}
+
+/// Turns UDP relaying off or back on from the control socket. Turning it
+/// on also clears an automatic trip.
+#[cfg(feature = "control")]
+pub fn set_enabled(enabled: bool) {
+    MANUAL_OFF.store(!enabled, Ordering::Relaxed);
+    if enabled {
+        *TRIPPED_UNTIL.lock() = None;
+    }
+    info!(
+        "[UdpGuard] UDP relaying {} by operator",
+        if enabled { "enabled" } else { "disabled" }
+    );
+}
+
+/// One-line state of the switch for `status`.
+#[cfg(feature = "control")]
+pub fn status() -> String {
+    let state = if MANUAL_OFF.load(Ordering::Relaxed) {
+        String::from("off (operator)")
+    } else if let Some(until) = *TRIPPED_UNTIL.lock() {
+        format!(
+            "off (tripped, {}s left)",
+            until.saturating_duration_since(Instant::now()).as_secs()
+        )
+    } else {
+        String::from("on")
+    };
+    format!(
+        "udp relaying: {} (dropped packets: {})\n",
+        state,
+        DROPPED.load(Ordering::Relaxed)
+    )
+}
+
+/// Starts the threshold monitor if any threshold is configured.
+pub fn spawn(config: &UdpGuardConfig, shutdown_rx: Receiver<()>) {
+    if config.max_pps().is_none() && config.max_memory_bytes().is_none() {
+        return;
+    }
+
+    info!(
+        "[UdpGuard] Suspending UDP above {} pps or {} bytes resident, cooldown {:?}",
+        config
+            .max_pps()
+            .map_or_else(|| String::from("unlimited"), |pps| pps.to_string()),
+        config
+            .max_memory_bytes()
+            .map_or_else(|| String::from("unlimited"), |bytes| bytes.to_string()),
+        config.cooldown()
+    );
+
+    tokio::spawn(monitor(config.clone(), shutdown_rx));
+}
+
+async fn monitor(config: UdpGuardConfig, mut shutdown_rx: Receiver<()>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    let mut last = Instant::now();
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown_rx.changed() => break,
+        }
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(last).as_secs_f64().max(0.001);
+        last = now;
+        let pps = (PACKETS.swap(0, Ordering::Relaxed) as f64 / elapsed) as u64;
+        let memory = resident_memory();
+
+        let breach = if config.max_pps().is_some_and(|max| pps > max) {
+            Some(format!("{} pps", pps))
+        } else {
+            match (config.max_memory_bytes(), memory) {
+                (Some(max), Some(memory)) if memory > max => {
+                    Some(format!("{} bytes resident", memory))
+                }
+                _ => None,
+            }
+        };
+
+        let mut tripped = TRIPPED_UNTIL.lock();
+        match (breach, *tripped) {
+            (Some(reason), None) => {
+                warn!(
+                    "[UdpGuard] Suspending UDP relaying for {:?}: {}",
+                    config.cooldown(),
+                    reason
+                );
+                *tripped = Some(now + config.cooldown());
+            }
+            (Some(_), Some(_)) => *tripped = Some(now + config.cooldown()),
+            (None, Some(until)) if now >= until => {
+                info!("[UdpGuard] Load is back under the thresholds, resuming UDP relaying");
+                *tripped = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn resident_memory() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    u64::try_from(page_size).ok().map(|size| pages * size)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(all(test, any(feature = "tuic", feature = "trojan")))]
+mod tests {
+    use super::*;
+    use tokio::sync::watch;
+
+    #[tokio::test(start_paused = true)]
+    async fn trips_above_the_rate_and_resumes_after_the_cooldown() {
+        let config: UdpGuardConfig = toml::from_str("max_pps = 10\ncooldown = 5").unwrap();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let start = Instant::now();
+        tokio::spawn(monitor(config, shutdown_rx));
+
+        // Ticks at 0s and 1s; the first takes the burst.
+        for _ in 0..100 {
+            admit_packet();
+        }
+        tokio::time::sleep_until(start + Duration::from_millis(1500)).await;
+        assert!(!udp_enabled());
+        assert!(!admit_packet(), "packets are dropped while tripped");
+
+        // Within the cooldown of the tick that saw the last breach.
+        tokio::time::sleep_until(start + Duration::from_millis(4500)).await;
+        assert!(!udp_enabled());
+
+        tokio::time::sleep_until(start + Duration::from_millis(7500)).await;
+        assert!(udp_enabled());
+    }
+}
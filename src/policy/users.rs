@@ -0,0 +1,42 @@
+//! Identities switched off at runtime.
+//!
+//! A disabled identity is refused on every protocol at authentication, and
+//! its live sessions are kicked when it is disabled. The set lives in
+//! memory only; a restart enables everybody again.
+
+use dashmap::DashSet;
+use once_cell::sync::Lazy;
+#[cfg(feature = "control")]
+use tracing::info;
+
+#[cfg(feature = "control")]
+use crate::control::registry::registry;
+
+static DISABLED: Lazy<DashSet<String>> = Lazy::new(DashSet::new);
+
+/// Disables `user` and kicks their sessions. Returns the number kicked.
+#[cfg(feature = "control")]
+pub fn disable(user: &str) -> usize {
+    DISABLED.insert(user.to_string());
+    let kicked = registry().kick(user);
+    info!(
+        "[Policy] User {} disabled by operator, kicked {} session(s)",
+        user, kicked
+    );
+    kicked
+}
+
+/// Enables `user` again. Returns false if they were not disabled.
+#[cfg(feature = "control")]
+pub fn enable(user: &str) -> bool {
+    let enabled = DISABLED.remove(user).is_some();
+    if enabled {
+        info!("[Policy] User {} enabled by operator", user);
+    }
+    enabled
+}
+
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub fn is_disabled(user: &str) -> bool {
+    DISABLED.contains(user)
+}
@@ -0,0 +1,137 @@
+use crate::control::registry::SessionGuard;
+use crate::net::tcp as net_tcp;
+use crate::policy;
+use anyhow::{Context, Result, anyhow};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::logging::access::Flow;
+use crate::logging::slow;
+use crate::net::relay::{Activity, RelayLimits, relay_tcp};
+use crate::protocol::address::Address;
+use crate::protocol::snell::cipher::SnellStream;
+use crate::protocol::snell::request::{CommandType, ResponseType, SnellRequest, error_response};
+
+const ERROR_UNSUPPORTED: u8 = 0x01;
+const ERROR_CONNECT: u8 = 0x02;
+
+pub struct SnellConnectionProcessor {
+    psk: Arc<[u8]>,
+    handshake_timeout: Duration,
+    relay_buffer_size: usize,
+    relay_limits: RelayLimits,
+}
+
+impl SnellConnectionProcessor {
+    pub fn new(
+        psk: &str,
+        handshake_timeout: Duration,
+        relay_buffer_size: usize,
+        relay_limits: RelayLimits,
+    ) -> Self {
+        Self {
+            psk: Arc::from(psk.as_bytes()),
+            handshake_timeout,
+            relay_buffer_size,
+            relay_limits,
+        }
+    }
+
+    pub async fn process_connection<S>(
+        &self,
+        stream: S,
+        peer_addr: SocketAddr,
+        session: &SessionGuard,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut stream = SnellStream::new(stream, &self.psk);
+
+        // A wrong PSK surfaces here as a chunk authentication failure.
+        let request =
+            tokio::time::timeout(self.handshake_timeout, SnellRequest::read_from(&mut stream))
+                .await
+                .map_err(|_| anyhow!("No snell request from {} in time", peer_addr))?
+                .with_context(|| format!("Failed to read snell request from {}", peer_addr))?;
+
+        debug!("[Snell] {} from {}", request, peer_addr);
+
+        match (request.command, request.address) {
+            (CommandType::Ping, _) => {
+                stream.write_all(&[ResponseType::Pong as u8]).await?;
+                stream.flush().await?;
+            }
+            (CommandType::Connect | CommandType::ConnectV2, Some(address)) => {
+                let flow = Flow::tcp(session, &address);
+                let listed = session.open_stream(&address);
+                let result = self
+                    .connect(stream, &address, session, listed.activity())
+                    .await;
+                flow.finish(listed.activity(), &result);
+                result?;
+            }
+            (command, _) => {
+                let response = error_response(ERROR_UNSUPPORTED, "command not supported");
+                stream.write_all(&response).await?;
+                stream.flush().await?;
+                debug!("[Snell] Unsupported command {} from {}", command, peer_addr);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn connect<S>(
+        &self,
+        mut stream: SnellStream<S>,
+        address: &Address,
+        session: &SessionGuard,
+        activity: &Activity,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let target_addrs = slow::dns(session, address, address.to_all_socket_addrs()).await?;
+
+        if let Err(e) = policy::check_connect(
+            session,
+            address.domain(),
+            &target_addrs,
+            &address.to_string(),
+        ) {
+            let response = error_response(ERROR_CONNECT, &e.to_string());
+            stream.write_all(&response).await?;
+            stream.flush().await?;
+            return Err(e);
+        }
+
+        let connecting = net_tcp::connect_any(&target_addrs);
+        let server_stream = match slow::connect(session, address, connecting).await {
+            Ok(s) => s,
+            Err(e) => {
+                let response = error_response(ERROR_CONNECT, &e.to_string());
+                stream.write_all(&response).await?;
+                stream.flush().await?;
+                return Err(e).with_context(|| format!("Failed to connect to {}", address));
+            }
+        };
+
+        stream.write_all(&[ResponseType::Tunnel as u8]).await?;
+        stream.flush().await?;
+
+        // Connections are not reused; a v2 client simply opens a new one.
+        relay_tcp(
+            stream,
+            server_stream,
+            self.relay_buffer_size,
+            session.traffic(),
+            activity,
+            self.relay_limits,
+        )
+        .await
+    }
+}
@@ -1,36 +1,96 @@
-use crate::net::tcp as net_tcp;
-use anyhow::{Context, Result, bail};
+pub mod nat;
+
+use crate::logging::access::Flow;
+use crate::logging::slow;
+use crate::net::proxy_protocol;
+use crate::net::relay::{Activity, RelayLimits, relay_tcp};
+use crate::net::shaper;
+use crate::net::sniff;
+use crate::net::sockopt::TcpOptions;
+use anyhow::{Result, bail};
+use once_cell::sync::OnceCell;
+use std::io::IoSlice;
 use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, split};
-use tokio::net::UdpSocket;
-use tokio::select;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, split};
 use tokio::sync::mpsc;
 use tokio_rustls::server::TlsStream;
 use tokio_util::sync::CancellationToken;
 
 use crate::authenticate::trojan::TrojanAuthenticationManager;
-use crate::protocol::trojan::address::Address;
+use crate::config::{ProxyProtocolVersion, SniffConfig, TrojanUdpConfig, UdpNatMode};
+use crate::control::metrics::{self, Outcome};
+use crate::control::registry::SessionGuard;
+use crate::events::{self, Event};
+use crate::outbound::{DirectOutbound, Outbound};
+use crate::policy::quota::{self, QuotaSlot};
+use crate::policy::{self, filter, udp_guard, users};
+use crate::processor::trojan::nat::UdpNat;
+use crate::protocol::address::Address;
 use crate::protocol::trojan::command::{CommandType, TrojanRequest};
+use crate::router::{self, Route};
+use crate::server::tickets;
+use crate::server::tls::subject_common_name;
+use crate::server::trojan_fallback::{self, FallbackHandler, FallbackRoute};
+
+/// Most UDP replies written to the client under one flush.
+const UDP_FRAMES_PER_FLUSH: usize = 32;
 
 #[allow(dead_code)]
 pub struct RuntimeContext {
     pub client_addr: SocketAddr,
-    pub authenticated: bool,
+    /// Where the client connected to: the listener, or the address a PROXY
+    /// protocol header named.
+    pub local_addr: SocketAddr,
+    pub session: Arc<SessionGuard>,
+    identity: OnceCell<(Arc<str>, QuotaSlot)>,
 }
 
 impl RuntimeContext {
-    pub fn new(client_addr: SocketAddr) -> Self {
+    pub fn new(
+        client_addr: SocketAddr,
+        local_addr: SocketAddr,
+        session: Arc<SessionGuard>,
+    ) -> Self {
         Self {
             client_addr,
-            authenticated: false,
+            local_addr,
+            session,
+            identity: OnceCell::new(),
         }
     }
+
+    /// Records whose password the request carried, holding their
+    /// connection quota slot for as long as the connection lives.
+    pub fn set_identity(&self, identity: Arc<str>, slot: QuotaSlot) {
+        let _ = self.identity.set((identity, slot));
+    }
+
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.get().map(|(identity, _)| identity.as_ref())
+    }
 }
 
 pub struct TrojanConnectionProcessor {
     auth: Arc<TrojanAuthenticationManager>,
     fallback_addr: std::net::SocketAddr,
+    fallback_proxy_protocol: Option<ProxyProtocolVersion>,
+    fallback_routes: Vec<FallbackRoute>,
+    relay_buffer_size: usize,
+    relay_limits: RelayLimits,
+    udp_nat: UdpNatMode,
+    udp_nat_max_mappings: usize,
+    udp_idle_timeout: Duration,
+    udp_limits: TrojanUdpConfig,
+    client_cert_replaces_password: bool,
+    upstream: Option<Arc<dyn Outbound>>,
+    sniff: SniffConfig,
+    socket_options: TcpOptions,
+    request_timeout: Duration,
 }
 
 impl TrojanConnectionProcessor {
@@ -41,39 +101,258 @@ impl TrojanConnectionProcessor {
                 std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),
                 80,
             ),
+            fallback_proxy_protocol: None,
+            fallback_routes: Vec::new(),
+            relay_buffer_size: 16 * 1024,
+            relay_limits: RelayLimits::default(),
+            udp_nat: UdpNatMode::default(),
+            udp_nat_max_mappings: 256,
+            udp_idle_timeout: Duration::from_secs(60),
+            udp_limits: TrojanUdpConfig::default(),
+            client_cert_replaces_password: false,
+            upstream: None,
+            sniff: SniffConfig::default(),
+            socket_options: TcpOptions::default(),
+            request_timeout: Duration::from_secs(10),
         }
     }
 
+    /// Swaps in a new `(identity, password)` list for connections that
+    /// authenticate from now on.
+    pub fn replace_users(&self, users: Vec<(String, String)>) {
+        self.auth.replace(users);
+    }
+
     pub fn with_fallback_addr(mut self, fallback_addr: std::net::SocketAddr) -> Self {
         self.fallback_addr = fallback_addr;
         self
     }
 
+    pub fn with_fallback_proxy_protocol(mut self, version: Option<ProxyProtocolVersion>) -> Self {
+        self.fallback_proxy_protocol = version;
+        self
+    }
+
+    /// Fallback servers tried by ALPN and request path before the
+    /// fallback address.
+    pub fn with_fallback_routes(mut self, routes: Vec<FallbackRoute>) -> Self {
+        self.fallback_routes = routes;
+        self
+    }
+
+    pub fn with_relay_buffer_size(mut self, relay_buffer_size: usize) -> Self {
+        self.relay_buffer_size = relay_buffer_size;
+        self
+    }
+
+    pub fn with_relay_limits(mut self, relay_limits: RelayLimits) -> Self {
+        self.relay_limits = relay_limits;
+        self
+    }
+
+    pub fn with_udp_nat(
+        mut self,
+        mode: UdpNatMode,
+        max_mappings: usize,
+        idle_timeout: Duration,
+    ) -> Self {
+        self.udp_nat = mode;
+        self.udp_nat_max_mappings = max_mappings;
+        self.udp_idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Frame size, packet rate and byte caps for each UDP association.
+    pub fn with_udp_limits(mut self, limits: TrojanUdpConfig) -> Self {
+        self.udp_limits = limits;
+        self
+    }
+
+    /// Lets a client with a verified certificate in without a matching
+    /// password.
+    pub fn with_client_cert_replaces_password(mut self, replaces: bool) -> Self {
+        self.client_cert_replaces_password = replaces;
+        self
+    }
+
+    /// Relays CONNECT requests through `upstream` instead of dialing the
+    /// targets from here.
+    pub fn with_upstream(mut self, upstream: Option<Arc<dyn Outbound>>) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    /// Connects to the host sniffed from the payload rather than the one
+    /// requested, as `sniff` allows.
+    pub fn with_sniff(mut self, sniff: SniffConfig) -> Self {
+        self.sniff = sniff;
+        self
+    }
+
+    /// Options set on the connections opened to CONNECT targets.
+    pub fn with_socket_options(mut self, options: TcpOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    /// How long a client has after TLS to send its whole request before
+    /// it is handed to the fallback.
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
     pub async fn process_connection_tls<S>(
         &self,
-        mut tls_stream: TlsStream<S>,
+        tls_stream: TlsStream<S>,
         context: Arc<RuntimeContext>,
     ) -> Result<()>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        let trojan_request = match TrojanRequest::read_from(&mut tls_stream, &self.auth).await {
+        let (_, connection) = tls_stream.get_ref();
+        tickets::record(connection.handshake_kind());
+        // The listener only lets verified certificates through, so one
+        // that names a subject names the user.
+        let cert_identity: Option<Arc<str>> = connection
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .and_then(subject_common_name)
+            .map(Arc::from);
+        let alpn = connection.alpn_protocol().map(<[u8]>::to_vec);
+
+        self.process_stream(tls_stream, context, cert_identity, alpn)
+            .await
+    }
+
+    /// Serves a client whose TLS was terminated in front of iway, such as
+    /// one handed over on a Unix socket by nginx.
+    pub async fn process_connection_plain<S>(
+        &self,
+        stream: S,
+        context: Arc<RuntimeContext>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.process_stream(stream, context, None, None).await
+    }
+
+    async fn process_stream<S>(
+        &self,
+        mut stream: S,
+        context: Arc<RuntimeContext>,
+        cert_identity: Option<Arc<str>>,
+        alpn: Option<Vec<u8>>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut recorder = Recorder {
+            inner: &mut stream,
+            recorded: Vec::new(),
+        };
+        // A prober that sends a few bytes and waits still gets the cover
+        // site, once the request has had its time.
+        let request = tokio::time::timeout(
+            self.request_timeout,
+            TrojanRequest::read_from(&mut recorder, |hash| match &cert_identity {
+                Some(identity) if self.client_cert_replaces_password => Some(Arc::clone(identity)),
+                Some(identity) => self.auth.identify(hash).map(|_| Arc::clone(identity)),
+                None => self.auth.identify(hash),
+            }),
+        )
+        .await
+        .unwrap_or(Ok(None));
+        let mut recorded = recorder.recorded;
+
+        let trojan_request = match request {
             Ok(Some(req)) => req,
             Ok(None) => {
-                return Ok(());
+                metrics::count("Trojan", "request", Outcome::AuthGated);
+                events::publish(Event::AuthFailed {
+                    protocol: "Trojan",
+                    peer_addr: context.client_addr,
+                });
+                // Whoever is probing gets the cover site, from the first byte.
+                if self.fallback_routes.iter().any(FallbackRoute::needs_path) {
+                    trojan_fallback::read_request_line(&mut stream, &mut recorded).await;
+                }
+                let (fallback_addr, fallback_proxy_protocol) = match trojan_fallback::select_route(
+                    &self.fallback_routes,
+                    alpn.as_deref(),
+                    &recorded,
+                ) {
+                    Some(route) => (route.dest(), route.proxy_protocol()),
+                    None => (self.fallback_addr, self.fallback_proxy_protocol),
+                };
+                tracing::debug!(
+                    "[Trojan] Sending {} to fallback {} after failed authentication",
+                    context.client_addr,
+                    fallback_addr
+                );
+                let mut replay = match fallback_proxy_protocol {
+                    Some(version) => {
+                        proxy_protocol::encode(version, context.client_addr, context.local_addr)
+                    }
+                    None => Vec::new(),
+                };
+                replay.extend_from_slice(&recorded);
+                return FallbackHandler::handle_fallback(stream, fallback_addr, replay).await;
             }
             Err(e) => {
+                metrics::count("Trojan", "request", Outcome::ParseError);
                 return Err(e);
             }
         };
 
+        let name = match trojan_request.command {
+            CommandType::Connect => "connect",
+            CommandType::UdpAssociate => "udp_associate",
+        };
+
+        let result = self.dispatch(stream, trojan_request, context).await;
+        let outcome = if result.is_ok() {
+            Outcome::Ok
+        } else {
+            Outcome::Failed
+        };
+        metrics::count("Trojan", name, outcome);
+
+        result
+    }
+
+    async fn dispatch<S>(
+        &self,
+        stream: S,
+        trojan_request: TrojanRequest,
+        context: Arc<RuntimeContext>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let user = Arc::clone(&trojan_request.user);
+        filter::check_auth(&context.session, &user)?;
+
+        if users::is_disabled(&user) {
+            bail!("User {} is disabled", user);
+        }
+
+        let Some(slot) = quota::quotas().acquire_connection(&user) else {
+            events::publish(Event::QuotaExceeded {
+                user: Arc::clone(&user),
+            });
+            bail!("User {} is over the connection quota", user);
+        };
+        context.session.set_user(user.as_ref());
+        context.set_identity(user, slot);
+
         match trojan_request.command {
             CommandType::Connect => {
-                self.handle_connect_tls(tls_stream, trojan_request, context)
-                    .await?;
+                self.handle_connect(stream, trojan_request, context).await?;
             }
             CommandType::UdpAssociate => {
-                self.handle_udp_associate_tls(tls_stream, trojan_request, context)
+                self.handle_udp_associate(stream, trojan_request, context)
                     .await?;
             }
         }
@@ -81,222 +360,214 @@ impl TrojanConnectionProcessor {
         Ok(())
     }
 
-    async fn handle_connect_tls<S>(
+    async fn handle_connect<S>(
         &self,
-        tls_stream: TlsStream<S>,
+        mut stream: S,
         request: TrojanRequest,
-        _context: Arc<RuntimeContext>,
+        context: Arc<RuntimeContext>,
     ) -> Result<()>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        let target_addr = request.address.to_socket_addrs().await?;
+        tracing::debug!(
+            "[Trojan] {} from {} connects to {}",
+            context.identity().unwrap_or_default(),
+            context.client_addr,
+            request.address
+        );
+
+        let mut address = request.address;
+        let mut early_data = Vec::new();
+        if sniff::wanted(&self.sniff, address.domain()) {
+            let (data, host) = sniff::peek(&mut stream, &self.sniff).await?;
+            if let Some(host) = host {
+                tracing::debug!("[Trojan] Sniffed {} for {}", host, address);
+                address = Address::Domain(host, address.port());
+            }
+            early_data = data;
+        }
 
-        let server_stream = net_tcp::connect(target_addr)
-            .await
-            .with_context(|| format!("Failed to connect to {}", target_addr))?;
+        let flow = Flow::tcp(&context.session, &address);
+        let listed = context.session.open_stream(&address);
+        let result = self
+            .relay_connect(stream, &address, &early_data, &context, listed.activity())
+            .await;
+        flow.finish(listed.activity(), &result);
 
-        relay_tcp(tls_stream, server_stream, 32 * 1024).await?;
+        result
+    }
 
-        Ok(())
+    async fn relay_connect<S>(
+        &self,
+        stream: S,
+        address: &Address,
+        early_data: &[u8],
+        context: &RuntimeContext,
+        activity: &Activity,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let target_addrs =
+            slow::dns(&context.session, address, address.to_all_socket_addrs()).await?;
+
+        policy::check_connect(
+            &context.session,
+            address.domain(),
+            &target_addrs,
+            &address.to_string(),
+        )?;
+
+        let route = router::route(
+            &context.session,
+            address.domain(),
+            address.port(),
+            &target_addrs,
+        );
+        let direct = DirectOutbound::new(self.socket_options);
+        let outbound: &dyn Outbound = match &route {
+            Some(Route::Block) => bail!("{} blocked by router", address),
+            Some(Route::Direct) => &direct,
+            Some(Route::Upstream(upstream)) => upstream.as_ref(),
+            None => self.upstream.as_deref().unwrap_or(&direct),
+        };
+
+        let mut server_stream = slow::connect(
+            &context.session,
+            address,
+            outbound.connect(address, &target_addrs),
+        )
+        .await?;
+        send_early_data(&mut server_stream, early_data, context, activity).await?;
+
+        relay_tcp(
+            stream,
+            server_stream,
+            self.relay_buffer_size,
+            context.session.traffic(),
+            activity,
+            self.relay_limits,
+        )
+        .await
     }
 
-    async fn handle_udp_associate_tls<S>(
+    async fn handle_udp_associate<S>(
         &self,
-        tls_stream: TlsStream<S>,
+        stream: S,
         _request: TrojanRequest,
-        _context: Arc<RuntimeContext>,
+        context: Arc<RuntimeContext>,
     ) -> Result<()>
     where
         S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        use socket2::{Domain, Protocol, SockAddr, Socket, Type};
-        use tokio_util::sync::CancellationToken;
+        if !policy::allow_udp(&context.session) {
+            bail!(
+                "UDP is not allowed for {} on inbound {:?}",
+                context.identity().unwrap_or_default(),
+                context.session.inbound()
+            );
+        }
+
+        if !udp_guard::udp_enabled() {
+            bail!("UDP relaying is suspended");
+        }
 
-        let (mut tls_reader, mut tls_writer) = split(tls_stream);
+        tracing::debug!(
+            "[Trojan] {} from {} opens a UDP association",
+            context.identity().unwrap_or_default(),
+            context.client_addr
+        );
+        let flow = Flow::udp(&context.session);
+
+        let (mut tls_reader, mut tls_writer) = split(stream);
 
         let (udp_resp_tx, mut udp_resp_rx) = mpsc::channel::<(SocketAddr, bytes::Bytes)>(1024);
         let cancel = CancellationToken::new();
 
-        // We'll attempt to create a single dual-stack IPv6 socket (IPV6_V6ONLY = false).
-        // If that fails, fall back to separate v4 and v6 sockets.
-        let mut recv_handles: Vec<tokio::task::JoinHandle<()>> = Vec::new();
-        let udp_dual: Option<Arc<UdpSocket>> = (|| -> std::io::Result<Arc<UdpSocket>> {
-            let sock = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
-            sock.set_only_v6(false)?;
-            let bind_addr = std::net::SocketAddr::V6(std::net::SocketAddrV6::new(
-                std::net::Ipv6Addr::UNSPECIFIED,
-                0,
-                0,
-                0,
-            ));
-            sock.bind(&SockAddr::from(bind_addr))?;
-            sock.set_nonblocking(true)?;
-            let stdsock: std::net::UdpSocket = sock.into();
-            Ok(Arc::new(UdpSocket::from_std(stdsock)?))
-        })()
-        .ok();
-
-        // sockets to use for sending
-        let udp_v4_sock: Option<Arc<UdpSocket>>;
-        let udp_v6_sock: Option<Arc<UdpSocket>>;
-
-        if let Some(dual) = udp_dual.clone() {
-            // spawn single recv task for dual-stack socket
-            let tx = udp_resp_tx.clone();
-            let cancel_clone = cancel.clone();
-            let arc_clone = dual.clone();
-            let h = tokio::spawn(async move {
-                let mut buf = [0u8; 4096];
-                loop {
-                    tokio::select! {
-                        res = arc_clone.recv_from(&mut buf) => {
-                            match res {
-                                Ok((n, src)) => {
-                                    let data = bytes::Bytes::copy_from_slice(&buf[..n]);
-                                    if tx.send((src, data)).await.is_err() { break; }
-                                }
-                                Err(_) => break,
-                            }
-                        }
-                        _ = cancel_clone.cancelled() => break,
-                    }
-                }
-            });
-            recv_handles.push(h);
-            udp_v4_sock = Some(dual.clone());
-            udp_v6_sock = Some(dual.clone());
-        } else {
-            // fallback: create separate v4 and v6 sockets
-            udp_v4_sock = match UdpSocket::bind("0.0.0.0:0").await {
-                Ok(s) => {
-                    let tx = udp_resp_tx.clone();
-                    let cancel_clone = cancel.clone();
-                    let arc = Arc::new(s);
-                    let arc_clone = arc.clone();
-                    let h = tokio::spawn(async move {
-                        let mut buf = [0u8; 4096];
-                        loop {
-                            tokio::select! {
-                                res = arc_clone.recv_from(&mut buf) => {
-                                    match res {
-                                        Ok((n, src)) => {
-                                            let data = bytes::Bytes::copy_from_slice(&buf[..n]);
-                                            if tx.send((src, data)).await.is_err() { break; }
-                                        }
-                                        Err(_) => break,
-                                    }
-                                }
-                                _ = cancel_clone.cancelled() => break,
-                            }
-                        }
-                    });
-                    recv_handles.push(h);
-                    Some(arc)
-                }
-                Err(e) => {
-                    tracing::error!("Failed to bind IPv4 socket: {}", e);
-                    None
-                }
-            };
-
-            udp_v6_sock = match UdpSocket::bind("[::]:0").await {
-                Ok(s) => {
-                    let tx = udp_resp_tx.clone();
-                    let cancel_clone = cancel.clone();
-                    let arc = Arc::new(s);
-                    let arc_clone = arc.clone();
-                    let h = tokio::spawn(async move {
-                        let mut buf = [0u8; 4096];
-                        loop {
-                            tokio::select! {
-                                res = arc_clone.recv_from(&mut buf) => {
-                                    match res {
-                                        Ok((n, src)) => {
-                                            let data = bytes::Bytes::copy_from_slice(&buf[..n]);
-                                            if tx.send((src, data)).await.is_err() { break; }
-                                        }
-                                        Err(_) => break,
-                                    }
-                                }
-                                _ = cancel_clone.cancelled() => break,
-                            }
-                        }
-                    });
-                    recv_handles.push(h);
-                    Some(arc)
-                }
-                Err(e) => {
-                    tracing::error!("Failed to bind IPv6 socket: {}", e);
-                    None
-                }
-            };
-        }
-
-        /* TLS reader → UDP send (use dual socket if available, otherwise select v4/v6) */
+        let mut nat = UdpNat::new(
+            self.udp_nat,
+            self.udp_nat_max_mappings,
+            self.udp_idle_timeout,
+            udp_resp_tx.clone(),
+        )
+        .await;
+
+        let max_frame_size = self.udp_limits.max_frame_size();
+        let max_packets_per_sec = self.udp_limits.max_packets_per_sec();
+        let max_bytes = self.udp_limits.max_bytes();
+        // Both directions together, against `max_bytes`.
+        let relayed = Arc::new(AtomicU64::new(0));
+
+        /* TLS reader → UDP send; dropping the task closes the sockets */
         let send_task = {
-            let udp_dual = udp_dual.clone();
-            let udp_v4_sock = udp_v4_sock.clone();
-            let udp_v6_sock = udp_v6_sock.clone();
             let cancel = cancel.clone();
+            let context = Arc::clone(&context);
+            let relayed = Arc::clone(&relayed);
 
             tokio::spawn(async move {
+                let mut window = tokio::time::Instant::now();
+                let mut window_packets = 0u32;
+
                 loop {
-                    let frame = match read_trojan_udp_frame(&mut tls_reader).await {
+                    let frame = match read_trojan_udp_frame(&mut tls_reader, max_frame_size).await {
                         Ok(f) => f,
-                        Err(_) => {
+                        Err(e) => {
+                            tracing::debug!(
+                                "[Trojan] UDP association of {} ends: {}",
+                                context.client_addr,
+                                e
+                            );
                             cancel.cancel();
                             break;
                         }
                     };
 
+                    if !udp_guard::admit_packet() {
+                        continue;
+                    }
+
+                    if let Some(max) = max_packets_per_sec {
+                        if window.elapsed() >= Duration::from_secs(1) {
+                            window = tokio::time::Instant::now();
+                            window_packets = 0;
+                        }
+                        window_packets += 1;
+                        if window_packets > max {
+                            if window_packets == max + 1 {
+                                tracing::debug!(
+                                    "[Trojan] {} is over {} UDP packets per second, dropping",
+                                    context.client_addr,
+                                    max
+                                );
+                            }
+                            continue;
+                        }
+                    }
+
+                    if over_byte_cap(&relayed, frame.payload.len(), max_bytes) {
+                        tracing::debug!(
+                            "[Trojan] UDP association of {} reached its {} byte cap",
+                            context.client_addr,
+                            max_bytes.unwrap_or_default()
+                        );
+                        cancel.cancel();
+                        break;
+                    }
+
                     let target = match frame.dst.to_socket_addrs().await {
                         Ok(a) => a,
                         Err(_) => continue,
                     };
 
-                    // If we created a dual-stack IPv6 socket, use it for IPv6 targets
-                    // and for IPv4 targets send to an IPv4-mapped IPv6 address.
-                    if let Some(dual) = udp_dual.as_ref() {
-                        if target.is_ipv4() {
-                            if let std::net::SocketAddr::V4(sa_v4) = target {
-                                let o = sa_v4.ip().octets();
-                                let v6_octets: [u8; 16] = [
-                                    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, o[0], o[1], o[2],
-                                    o[3],
-                                ];
-                                let mapped = std::net::SocketAddr::V6(std::net::SocketAddrV6::new(
-                                    std::net::Ipv6Addr::from(v6_octets),
-                                    sa_v4.port(),
-                                    0,
-                                    0,
-                                ));
-                                if let Err(e) = dual.send_to(&frame.payload, mapped).await {
-                                    tracing::error!("Failed to send UDP to {}: {}", mapped, e);
-                                }
-                            }
-                        } else {
-                            if let Err(e) = dual.send_to(&frame.payload, target).await {
-                                tracing::error!("Failed to send UDP to {}: {}", target, e);
-                            }
-                        }
+                    if let Err(e) = policy::check(&context.session, frame.dst.domain(), &[target]) {
+                        tracing::debug!("[Trojan] Dropping UDP packet: {}", e);
                         continue;
                     }
 
-                    // otherwise select based on address family and use v4/v6 sockets
-                    if target.is_ipv4() {
-                        if let Some(sock) = udp_v4_sock.as_ref() {
-                            if let Err(e) = sock.send_to(&frame.payload, target).await {
-                                tracing::error!("Failed to send UDP to {}: {}", target, e);
-                            }
-                        }
-                    } else {
-                        if let Some(sock) = udp_v6_sock.as_ref() {
-                            if let Err(e) = sock.send_to(&frame.payload, target).await {
-                                tracing::error!("Failed to send UDP to {}: {}", target, e);
-                            }
-                        }
-                    }
+                    context.session.traffic().add_up(frame.payload.len());
+                    shaper::throttle(frame.payload.len()).await;
+
+                    nat.send(&frame.payload, target).await;
                 }
             })
         };
@@ -304,14 +575,46 @@ impl TrojanConnectionProcessor {
         loop {
             tokio::select! {
                 msg = udp_resp_rx.recv() => {
-                    let Some((src, payload)) = msg else { break; };
+                    let Some(first) = msg else { break; };
+
+                    // Replies already waiting go out under the same flush.
+                    let mut ready = vec![first];
+                    while ready.len() < UDP_FRAMES_PER_FLUSH {
+                        match udp_resp_rx.try_recv() {
+                            Ok(msg) => ready.push(msg),
+                            Err(_) => break,
+                        }
+                    }
+
+                    let mut frames = Vec::with_capacity(ready.len());
+                    let mut capped = false;
+                    for (src, payload) in ready {
+                        if !udp_guard::admit_packet() {
+                            continue;
+                        }
+
+                        if over_byte_cap(&relayed, payload.len(), max_bytes) {
+                            tracing::debug!(
+                                "[Trojan] UDP association of {} reached its {} byte cap",
+                                context.client_addr,
+                                max_bytes.unwrap_or_default()
+                            );
+                            capped = true;
+                            break;
+                        }
 
-                    let addr = Address::Socket(src);
+                        context.session.traffic().add_down(payload.len());
+                        shaper::throttle(payload.len()).await;
+                        frames.push((src, payload));
+                    }
 
-                    if let Err(e) = write_trojan_udp_frame(&mut tls_writer, &addr, payload.as_ref()).await {
+                    if let Err(e) = write_trojan_udp_frames(&mut tls_writer, &frames).await {
                         tracing::error!("Failed to write UDP frame to TLS: {}", e);
                         break;
                     }
+                    if capped {
+                        break;
+                    }
                 }
 
                 _ = cancel.cancelled() => {
@@ -322,25 +625,61 @@ impl TrojanConnectionProcessor {
 
         cancel.cancel();
         drop(udp_resp_tx);
-        for h in recv_handles {
-            h.abort();
-        }
         send_task.abort();
 
+        // The session carries this association only, so its totals are the
+        // association's.
+        let traffic = context.session.traffic();
+        flow.finish_with(traffic.up(), traffic.down(), "closed");
+
         Ok(())
     }
 }
 
+/// Sends on the bytes sniffing read from the client, counting them as
+/// the relay would have.
+async fn send_early_data<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    data: &[u8],
+    context: &RuntimeContext,
+    activity: &Activity,
+) -> Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    writer.write_all(data).await?;
+    context.session.traffic().add_up(data.len());
+    activity.add_up(data.len());
+    Ok(())
+}
+
 #[derive(Debug)]
 struct UdpFrame {
     dst: Address,
     payload: bytes::Bytes,
 }
 
-async fn read_trojan_udp_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<UdpFrame> {
+/// Adds `len` to the bytes an association relayed and tells whether that
+/// takes it past `max_bytes`.
+fn over_byte_cap(relayed: &AtomicU64, len: usize, max_bytes: Option<u64>) -> bool {
+    let total = relayed.fetch_add(len as u64, Ordering::Relaxed) + len as u64;
+    max_bytes.is_some_and(|max| total > max)
+}
+
+async fn read_trojan_udp_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_frame_size: usize,
+) -> Result<UdpFrame> {
     let address = Address::read_from(reader).await?;
 
     let len = reader.read_u16().await?;
+    if len as usize > max_frame_size {
+        bail!(
+            "UDP frame of {} bytes is over the {} byte limit",
+            len,
+            max_frame_size
+        );
+    }
 
     let mut crlf = [0u8; 2];
     reader.read_exact(&mut crlf).await?;
@@ -358,98 +697,115 @@ async fn read_trojan_udp_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<U
     })
 }
 
-async fn copy_with_cancel<R, W>(
-    mut reader: R,
-    mut writer: W,
-    cancel: CancellationToken,
-    buf_size: usize,
-) -> std::io::Result<u64>
-where
-    R: AsyncRead + Unpin,
-    W: AsyncWrite + Unpin,
-{
-    let mut buf = vec![0u8; buf_size];
-    let mut total = 0;
-
-    loop {
-        select! {
-            _ = cancel.cancelled() => {
-                return Ok(total);
-            }
-
-            n = reader.read(&mut buf) => {
-                let n = n?;
-                if n == 0 {
-                    return Ok(total);
-                }
+/// Keeps a copy of everything read through it, so a request that fails
+/// authentication can be replayed to the fallback server.
+struct Recorder<'a, R> {
+    inner: &'a mut R,
+    recorded: Vec<u8>,
+}
 
-                writer.write_all(&buf[..n]).await?;
-                total += n as u64;
-            }
-        }
+impl<R: AsyncRead + Unpin> AsyncRead for Recorder<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let start = buf.filled().len();
+        let res = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        this.recorded.extend_from_slice(&buf.filled()[start..]);
+        res
     }
 }
 
-pub async fn relay_tcp(
-    left: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
-    right: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
-    buf_size: usize,
-) -> anyhow::Result<()> {
-    let (mut l_r, mut l_w) = split(left);
-    let (mut r_r, mut r_w) = split(right);
-
-    let cancel = CancellationToken::new();
-    let cancel1 = cancel.clone();
-    let cancel2 = cancel.clone();
-
-    let a_to_b = tokio::spawn(async move {
-        let _ =
-            copy_with_cancel(&mut l_r, &mut r_w, cancel1, usize::min(buf_size, 16 * 1024)).await;
-    });
-
-    let b_to_a = tokio::spawn(async move {
-        let _ =
-            copy_with_cancel(&mut r_r, &mut l_w, cancel2, usize::min(buf_size, 16 * 1024)).await;
-    });
+/// Writes `frames` with one vectored write per batch and a single flush,
+/// so the TLS layer can pack them into as few records as possible.
+async fn write_trojan_udp_frames<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frames: &[(SocketAddr, bytes::Bytes)],
+) -> Result<()> {
+    if frames.is_empty() {
+        return Ok(());
+    }
 
-    select! {
-        _ = a_to_b => {}
-        _ = b_to_a => {}
+    // Every header goes into one buffer; `ends` marks where each stops.
+    let mut headers = Vec::with_capacity(frames.len() * 24);
+    let mut ends = Vec::with_capacity(frames.len());
+    for (src, payload) in frames {
+        Address::Socket(*src).write_to(&mut headers);
+        headers.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        headers.extend_from_slice(b"\r\n");
+        ends.push(headers.len());
     }
 
-    cancel.cancel();
+    let mut slices = Vec::with_capacity(frames.len() * 2);
+    let mut start = 0;
+    for ((_, payload), end) in frames.iter().zip(ends) {
+        slices.push(IoSlice::new(&headers[start..end]));
+        slices.push(IoSlice::new(payload));
+        start = end;
+    }
 
+    write_all_vectored(writer, &mut slices).await?;
+    writer.flush().await?;
     Ok(())
 }
 
-async fn write_trojan_udp_frame<W: AsyncWriteExt + Unpin>(
+async fn write_all_vectored<W: AsyncWrite + Unpin>(
     writer: &mut W,
-    addr: &Address,
-    payload: &[u8],
-) -> Result<()> {
-    match addr {
-        Address::Socket(sa) => match sa {
-            std::net::SocketAddr::V4(v4) => {
-                writer.write_u8(0x01).await?;
-                writer.write_all(&v4.ip().octets()).await?;
-                writer.write_u16(v4.port()).await?;
-            }
-            std::net::SocketAddr::V6(v6) => {
-                writer.write_u8(0x04).await?;
-                writer.write_all(&v6.ip().octets()).await?;
-                writer.write_u16(v6.port()).await?;
-            }
-        },
-        Address::Domain(domain, port) => {
-            writer.write_u8(0x03).await?;
-            writer.write_u8(domain.len() as u8).await?;
-            writer.write_all(domain.as_bytes()).await?;
-            writer.write_u16(*port).await?;
+    mut slices: &mut [IoSlice<'_>],
+) -> std::io::Result<()> {
+    while !slices.is_empty() {
+        let n = writer.write_vectored(slices).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
         }
+        IoSlice::advance_slices(&mut slices, n);
     }
-    writer.write_u16(payload.len() as u16).await?;
-    writer.write_all(b"\r\n").await?;
-    writer.write_all(payload).await?;
-    writer.flush().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::registry::registry;
+    use tokio::net::TcpListener;
+
+    #[tokio::test(start_paused = true)]
+    async fn short_request_goes_to_fallback_after_timeout() {
+        let fallback = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let auth = Arc::new(TrojanAuthenticationManager::new(vec![(
+            String::from("alice"),
+            String::from("password"),
+        )]));
+        let processor = TrojanConnectionProcessor::new(auth)
+            .with_fallback_addr(fallback.local_addr().unwrap())
+            .with_request_timeout(Duration::from_secs(5));
+
+        let client_addr: SocketAddr = "192.0.2.10:40000".parse().unwrap();
+        let session = Arc::new(registry().register("Trojan", Arc::from("test"), client_addr));
+        let context = Arc::new(RuntimeContext::new(client_addr, client_addr, session));
+
+        // Ten bytes and then nothing, with the connection left open.
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"GET / HTTP").await.unwrap();
+
+        let started = tokio::time::Instant::now();
+        let serve = tokio::spawn(async move {
+            processor
+                .process_connection_plain(server, context)
+                .await
+                .unwrap();
+        });
+
+        let (mut upstream, _) = fallback.accept().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(5));
+        let mut replay = [0u8; 10];
+        upstream.read_exact(&mut replay).await.unwrap();
+        assert_eq!(&replay, b"GET / HTTP");
+
+        drop(client);
+        drop(upstream);
+        serve.await.unwrap();
+    }
+}
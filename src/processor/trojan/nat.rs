@@ -0,0 +1,325 @@
+//! Outbound sockets behind a Trojan UDP association.
+//!
+//! In full-cone mode an association shares one socket (a dual-stack one,
+//! or one per address family) for every destination, so its public port
+//! stays the same and any host may answer. In symmetric mode each
+//! destination gets its own connected socket, kept in a table keyed by the
+//! destination: replies from hosts the client never contacted are dropped
+//! by the kernel, and idle or least recently used mappings are closed.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use bytes::Bytes;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{debug, error};
+
+use crate::config::UdpNatMode;
+use crate::net::capabilities::capabilities;
+use crate::net::udp as net_udp;
+
+pub struct UdpNat {
+    mode: UdpNatMode,
+    dual: Option<Arc<UdpSocket>>,
+    v4: Option<Arc<UdpSocket>>,
+    v6: Option<Arc<UdpSocket>>,
+    mappings: HashMap<SocketAddr, Mapping>,
+    max_mappings: usize,
+    idle_timeout: Duration,
+    created: Instant,
+    swept_at: Instant,
+    replies: mpsc::Sender<(SocketAddr, Bytes)>,
+    recv_tasks: Vec<JoinHandle<()>>,
+}
+
+struct Mapping {
+    socket: Arc<UdpSocket>,
+    recv_task: JoinHandle<()>,
+    /// Milliseconds since the table was created when traffic last crossed
+    /// the mapping in either direction.
+    last_used: Arc<AtomicU64>,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        self.recv_task.abort();
+    }
+}
+
+impl UdpNat {
+    /// Sets up the association's outbound side; datagrams coming back are
+    /// pushed to `replies` tagged with their sender.
+    pub async fn new(
+        mode: UdpNatMode,
+        max_mappings: usize,
+        idle_timeout: Duration,
+        replies: mpsc::Sender<(SocketAddr, Bytes)>,
+    ) -> Self {
+        let mut nat = Self {
+            mode,
+            dual: None,
+            v4: None,
+            v6: None,
+            mappings: HashMap::new(),
+            max_mappings,
+            idle_timeout,
+            created: Instant::now(),
+            swept_at: Instant::now(),
+            replies,
+            recv_tasks: Vec::new(),
+        };
+
+        if mode == UdpNatMode::FullCone {
+            nat.bind_shared().await;
+        }
+
+        nat
+    }
+
+    /// Binds a single dual-stack socket (IPV6_V6ONLY = false), or separate
+    /// IPv4 and IPv6 sockets when the host can't do dual-stack.
+    async fn bind_shared(&mut self) {
+        match bind_dual_stack() {
+            Ok(dual) => {
+                self.recv_tasks.push(spawn_recv(
+                    Arc::clone(&dual),
+                    None,
+                    self.replies.clone(),
+                    None,
+                ));
+                self.dual = Some(dual);
+                return;
+            }
+            Err(e) => debug!(
+                "[Trojan] No dual-stack UDP socket, binding per family: {}",
+                e
+            ),
+        }
+
+        match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(s) => {
+                let s = Arc::new(s);
+                self.recv_tasks
+                    .push(spawn_recv(Arc::clone(&s), None, self.replies.clone(), None));
+                self.v4 = Some(s);
+            }
+            Err(e) => error!("Failed to bind IPv4 socket: {}", e),
+        }
+
+        match UdpSocket::bind("[::]:0").await {
+            Ok(s) => {
+                let s = Arc::new(s);
+                self.recv_tasks
+                    .push(spawn_recv(Arc::clone(&s), None, self.replies.clone(), None));
+                self.v6 = Some(s);
+            }
+            Err(e) => error!("Failed to bind IPv6 socket: {}", e),
+        }
+    }
+
+    pub async fn send(&mut self, payload: &[u8], target: SocketAddr) {
+        match self.mode {
+            UdpNatMode::FullCone => self.send_shared(payload, target).await,
+            UdpNatMode::Symmetric => self.send_mapped(payload, target).await,
+        }
+    }
+
+    async fn send_shared(&self, payload: &[u8], target: SocketAddr) {
+        // The dual-stack socket reaches IPv4 targets through their
+        // IPv4-mapped IPv6 address.
+        if let Some(dual) = self.dual.as_ref() {
+            let target = match target {
+                SocketAddr::V4(v4) => {
+                    SocketAddr::V6(SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0))
+                }
+                v6 => v6,
+            };
+            send_to(dual, payload, target).await;
+            return;
+        }
+
+        let socket = if target.is_ipv4() {
+            self.v4.as_ref()
+        } else {
+            self.v6.as_ref()
+        };
+
+        if let Some(socket) = socket {
+            send_to(socket, payload, target).await;
+        }
+    }
+
+    async fn send_mapped(&mut self, payload: &[u8], target: SocketAddr) {
+        self.sweep();
+
+        let now = self.now_ms();
+        let socket = match self.mappings.get(&target) {
+            Some(mapping) => {
+                mapping.last_used.store(now, Ordering::Relaxed);
+                Arc::clone(&mapping.socket)
+            }
+            None => match self.map(target).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    debug!("[Trojan] Failed to open UDP mapping to {}: {}", target, e);
+                    return;
+                }
+            },
+        };
+
+        if let Err(e) = socket.send(payload).await {
+            if net_udp::is_unreachable(&e) {
+                report_unreachable(&socket, &e);
+            } else {
+                error!("Failed to send UDP to {}: {}", target, e);
+            }
+        }
+    }
+
+    /// Opens a socket connected to `target`, making room first if the
+    /// table is full.
+    async fn map(&mut self, target: SocketAddr) -> std::io::Result<Arc<UdpSocket>> {
+        if self.mappings.len() >= self.max_mappings
+            && let Some(oldest) = self
+                .mappings
+                .iter()
+                .min_by_key(|(_, mapping)| mapping.last_used.load(Ordering::Relaxed))
+                .map(|(addr, _)| *addr)
+        {
+            self.mappings.remove(&oldest);
+        }
+
+        let local: SocketAddr = if target.is_ipv4() {
+            (IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0).into()
+        } else {
+            (IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0).into()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(target).await?;
+        let socket = Arc::new(socket);
+
+        let last_used = Arc::new(AtomicU64::new(self.now_ms()));
+        let recv_task = spawn_recv(
+            Arc::clone(&socket),
+            Some(target),
+            self.replies.clone(),
+            Some((self.created, Arc::clone(&last_used))),
+        );
+        self.mappings.insert(
+            target,
+            Mapping {
+                socket: Arc::clone(&socket),
+                recv_task,
+                last_used,
+            },
+        );
+
+        Ok(socket)
+    }
+
+    /// Closes mappings unused for longer than the idle timeout, at most
+    /// twice per timeout.
+    fn sweep(&mut self) {
+        if self.swept_at.elapsed() < self.idle_timeout / 2 {
+            return;
+        }
+        self.swept_at = Instant::now();
+
+        let cutoff = self
+            .now_ms()
+            .saturating_sub(self.idle_timeout.as_millis() as u64);
+        self.mappings
+            .retain(|_, mapping| mapping.last_used.load(Ordering::Relaxed) >= cutoff);
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.created.elapsed().as_millis() as u64
+    }
+}
+
+impl Drop for UdpNat {
+    fn drop(&mut self) {
+        for task in &self.recv_tasks {
+            task.abort();
+        }
+    }
+}
+
+fn bind_dual_stack() -> std::io::Result<Arc<UdpSocket>> {
+    if !capabilities().dual_stack {
+        return Err(std::io::ErrorKind::Unsupported.into());
+    }
+    let sock = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    sock.set_only_v6(false)?;
+    let bind_addr = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0));
+    sock.bind(&SockAddr::from(bind_addr))?;
+    sock.set_nonblocking(true)?;
+    let stdsock: std::net::UdpSocket = sock.into();
+    Ok(Arc::new(UdpSocket::from_std(stdsock)?))
+}
+
+/// Forwards datagrams arriving on `socket` to `replies`, stamping
+/// `activity` (relative to its epoch) on each. A connected socket only
+/// ever hears from `peer`.
+fn spawn_recv(
+    socket: Arc<UdpSocket>,
+    peer: Option<SocketAddr>,
+    replies: mpsc::Sender<(SocketAddr, Bytes)>,
+    activity: Option<(Instant, Arc<AtomicU64>)>,
+) -> JoinHandle<()> {
+    if let Err(e) = net_udp::enable_icmp_errors(&socket) {
+        debug!("[Trojan] Failed to enable ICMP error reporting: {}", e);
+    }
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 4096];
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((n, src)) => {
+                    if let Some((epoch, last_used)) = &activity {
+                        last_used.store(epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+                    }
+                    let data = Bytes::copy_from_slice(&buf[..n]);
+                    // Report IPv4 senders on the dual-stack socket as IPv4.
+                    let src = peer.unwrap_or(SocketAddr::new(src.ip().to_canonical(), src.port()));
+                    if replies.send((src, data)).await.is_err() {
+                        break;
+                    }
+                }
+                // An ICMP error only concerns one destination, keep the
+                // association alive for the others.
+                Err(e) if net_udp::is_unreachable(&e) => {
+                    report_unreachable(&socket, &e);
+                }
+                Err(_) => break,
+            }
+        }
+    })
+}
+
+async fn send_to(socket: &UdpSocket, payload: &[u8], target: SocketAddr) {
+    if let Err(e) = socket.send_to(payload, target).await {
+        if net_udp::is_unreachable(&e) {
+            report_unreachable(socket, &e);
+        } else {
+            error!("Failed to send UDP to {}: {}", target, e);
+        }
+    }
+}
+
+fn report_unreachable(socket: &UdpSocket, err: &std::io::Error) {
+    let targets = net_udp::take_unreachable(socket);
+    if targets.is_empty() {
+        debug!("[Trojan] UDP destination unreachable: {}", err);
+    }
+    for target in targets {
+        debug!("[Trojan] UDP destination {} unreachable: {}", target, err);
+    }
+}
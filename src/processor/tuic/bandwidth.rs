@@ -0,0 +1,92 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use quinn::Connection;
+use tracing::debug;
+
+use crate::processor::tuic::context::RuntimeContext;
+use crate::protocol::tuic::command::heartbeat::BandwidthHeartbeat;
+
+/// Weight of the newest sample in the smoothed estimate.
+const SMOOTHING: f64 = 0.25;
+
+/// Estimates the downstream bandwidth a connection can sustain from quinn's
+/// path stats: the congestion window drained once per RTT, smoothed over
+/// samples so short cwnd swings don't make clients flap their bitrate.
+#[derive(Default)]
+pub struct BandwidthEstimator {
+    smoothed: Option<f64>,
+}
+
+impl BandwidthEstimator {
+    pub fn sample(&mut self, connection: &Connection) -> Option<u64> {
+        let path = connection.stats().path;
+        let rtt = path.rtt.as_secs_f64();
+        if rtt <= 0.0 {
+            return self.smoothed.map(|v| v as u64);
+        }
+
+        let capacity = path.cwnd as f64 / rtt;
+        let smoothed = match self.smoothed {
+            Some(prev) => prev + SMOOTHING * (capacity - prev),
+            None => capacity,
+        };
+        self.smoothed = Some(smoothed);
+
+        Some(smoothed as u64)
+    }
+}
+
+/// Periodically pushes the downstream estimate to an authenticated client
+/// as a heartbeat datagram, until the connection closes.
+pub async fn report_bandwidth(
+    context: Arc<RuntimeContext>,
+    connection: Arc<Connection>,
+    interval: Duration,
+) {
+    let mut authenticated = false;
+    let mut estimator = BandwidthEstimator::default();
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = connection.closed() => break,
+        }
+
+        // Authentication may land well after the connection is up.
+        if !authenticated {
+            if !context.wait_for_auth().await.is_authenticated() {
+                continue;
+            }
+            authenticated = true;
+        }
+
+        let Some(bytes_per_sec) = estimator.sample(&connection) else {
+            continue;
+        };
+
+        let heartbeat = BandwidthHeartbeat::new(bytes_per_sec);
+        let mut buf = BytesMut::with_capacity(heartbeat.estimate_size());
+        heartbeat.write_to_buf(&mut buf);
+
+        if let Err(e) = connection.send_datagram(buf.freeze()) {
+            debug!(
+                "Failed to send bandwidth report to {}: {}",
+                connection.remote_address(),
+                e
+            );
+            break;
+        }
+
+        if tracing::enabled!(tracing::Level::DEBUG) {
+            debug!(
+                "Reported downstream bandwidth {} B/s to {}",
+                bytes_per_sec,
+                connection.remote_address()
+            );
+        }
+    }
+}
@@ -3,14 +3,22 @@ use std::sync::Arc;
 use anyhow::{Result, bail};
 
 use async_trait::async_trait;
-use quinn::Connection;
+use quinn::{Connection, VarInt};
 
 use crate::{
     authenticate::tuic::TuicAuthenticationManager,
-    processor::tuic::{CommandProcessor, context::RuntimeContext},
+    events::{self, Event},
+    policy::{filter, quota, users},
+    processor::tuic::{
+        AUTH_CONFLICT_ERROR_CODE, CommandProcessor, QUOTA_EXCEEDED_ERROR_CODE,
+        context::{AuthState, RuntimeContext},
+    },
     protocol::tuic::command::Command,
 };
 
+/// Application error code closing a connection the filter module refused.
+const FILTER_REJECTED_ERROR_CODE: u32 = 0x03;
+
 pub struct AuthenticateProcessor {
     authenticate_manager: TuicAuthenticationManager,
 }
@@ -29,9 +37,24 @@ impl CommandProcessor for AuthenticateProcessor {
             bail!("This must not happen! command: {:?}", command)
         };
 
+        if !context.claim_auth() {
+            connection.close(VarInt::from_u32(AUTH_CONFLICT_ERROR_CODE), b"");
+            bail!(
+                "Repeated Authenticate (uuid: {}) from {}, connection is {:?}",
+                authenticate.uuid(),
+                &connection.remote_address(),
+                context.auth_state()
+            );
+        }
+
         let password = match self.authenticate_manager.password(authenticate.uuid()) {
             Ok(value) => value,
             Err(_) => {
+                events::publish(Event::AuthFailed {
+                    protocol: "TUIC",
+                    peer_addr: connection.remote_address(),
+                });
+                context.auth_done(AuthState::Failed);
                 bail!(
                     "Failed to authencate client: {}, uuid: {} is not existed:",
                     &connection.remote_address(),
@@ -40,10 +63,15 @@ impl CommandProcessor for AuthenticateProcessor {
             }
         };
 
+        // Keying material is only exportable once the handshake completes,
+        // which an Authenticate sent as 0-RTT data can arrive ahead of.
+        context.wait_for_handshake().await;
+
         let mut buff: [u8; 32] = [0; 32];
         if let Err(e) =
             &connection.export_keying_material(&mut buff, authenticate.uuid().as_bytes(), &password)
         {
+            context.auth_done(AuthState::Failed);
             bail!(
                 "Failed to export keying material for uuid={} from={} err={:?}",
                 &authenticate.uuid(),
@@ -54,11 +82,65 @@ impl CommandProcessor for AuthenticateProcessor {
 
         match authenticate.verify_token(&buff) {
             Ok(true) => {
-                context.auth_done(true).await;
+                let identity: Arc<str> = self
+                    .authenticate_manager
+                    .identity(authenticate.uuid())
+                    .unwrap_or_else(|| Arc::from(authenticate.uuid().to_string()));
+
+                if let Err(e) = filter::check_auth(context.session(), &identity) {
+                    connection.close(
+                        VarInt::from_u32(FILTER_REJECTED_ERROR_CODE),
+                        b"rejected by filter",
+                    );
+                    context.auth_done(AuthState::Failed);
+                    bail!(
+                        "User {} {}, client: {}",
+                        identity,
+                        e,
+                        &connection.remote_address()
+                    );
+                }
+
+                if users::is_disabled(&identity) {
+                    connection.close(
+                        VarInt::from_u32(FILTER_REJECTED_ERROR_CODE),
+                        b"user disabled",
+                    );
+                    context.auth_done(AuthState::Failed);
+                    bail!(
+                        "User {} is disabled, client: {}",
+                        identity,
+                        &connection.remote_address()
+                    );
+                }
+
+                let Some(slot) = quota::quotas().acquire_connection(&identity) else {
+                    events::publish(Event::QuotaExceeded {
+                        user: Arc::clone(&identity),
+                    });
+                    connection.close(
+                        VarInt::from_u32(QUOTA_EXCEEDED_ERROR_CODE),
+                        b"connection quota exceeded",
+                    );
+                    context.auth_done(AuthState::Failed);
+                    bail!(
+                        "User {} is over the connection quota, client: {}",
+                        identity,
+                        &connection.remote_address()
+                    );
+                };
+
+                context.session().set_user(identity.as_ref());
+                context.set_identity(identity, slot);
+                context.auth_done(AuthState::Authenticated(*authenticate.uuid()));
                 Ok(true)
             }
             _ => {
-                context.auth_done(false).await;
+                events::publish(Event::AuthFailed {
+                    protocol: "TUIC",
+                    peer_addr: connection.remote_address(),
+                });
+                context.auth_done(AuthState::Failed);
                 bail!(
                     "Failed to verify client token! client: {}, uuid: {}",
                     &connection.remote_address(),
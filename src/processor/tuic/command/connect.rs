@@ -1,17 +1,57 @@
-use crate::net::tcp as net_tcp;
-use anyhow::{Context as AnyhowContext, Result, bail};
+use crate::config::SniffConfig;
+use crate::control::metrics::{self, Outcome};
+use crate::logging::access::Flow;
+use crate::logging::slow;
+use crate::net::relay::{RelayLimits, copy_half, run_limited};
+use crate::net::sniff;
+use crate::net::sockopt::TcpOptions;
+use crate::net::tcp::ConnectFailure;
+use crate::outbound::{DirectOutbound, Outbound};
+use crate::policy;
+use crate::policy::quota;
+use crate::router::{self, Route};
+use anyhow::{Result, bail};
 use async_trait::async_trait;
-use quinn::Connection;
+use quinn::{Connection, RecvStream, SendStream, VarInt};
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::debug;
 
 use crate::{
-    processor::tuic::{CommandProcessor, context::RuntimeContext},
-    protocol::tuic::command::Command,
+    processor::tuic::{
+        CONNECT_REJECTED_ERROR_CODE, CONNECT_UNREACHABLE_ERROR_CODE, CommandProcessor,
+        QUOTA_EXCEEDED_ERROR_CODE, connect_error_code, context::RuntimeContext,
+        masquerade::H3Masquerade, reverse,
+    },
+    protocol::tuic::{
+        address::Address,
+        command::{Command, reverse::REVERSE_OK},
+        version::Version,
+    },
 };
 
-pub struct ConnectProcessor {}
+pub struct ConnectProcessor {
+    masquerade: Option<H3Masquerade>,
+    relay_buffer_size: usize,
+    relay_limits: RelayLimits,
+    sniff: SniffConfig,
+}
+
+impl ConnectProcessor {
+    pub fn new(
+        masquerade: Option<H3Masquerade>,
+        relay_buffer_size: usize,
+        relay_limits: RelayLimits,
+        sniff: SniffConfig,
+    ) -> Self {
+        Self {
+            masquerade,
+            relay_buffer_size,
+            relay_limits,
+            sniff,
+        }
+    }
+}
 
 #[async_trait]
 impl CommandProcessor for ConnectProcessor {
@@ -21,11 +61,6 @@ impl CommandProcessor for ConnectProcessor {
         connection: Arc<Connection>,
         command: Option<Command>,
     ) -> Result<bool> {
-        let auth_result = context.wait_for_auth().await;
-        if auth_result != Some(true) {
-            bail!("Authentication failed or timed out");
-        }
-
         match command {
             None => {}
             _ => {
@@ -33,12 +68,60 @@ impl CommandProcessor for ConnectProcessor {
             }
         };
 
-        while let Ok((send, mut recv)) = connection.accept_bi().await {
+        while let Ok((mut send, mut recv)) = connection.accept_bi().await {
             let connection = Arc::clone(&connection);
 
-            let connect = match Command::read_from(&mut recv).await {
+            // A TUIC command opens with the protocol version and an HTTP/3
+            // request with a frame type, so a probe is told apart by its
+            // first stream and answered on it, without waiting for an
+            // Authenticate that will never come.
+            let Ok(first) = recv.read_u8().await else {
+                continue;
+            };
+            let admitted = if Version::try_from(first).is_ok() {
+                context.wait_for_auth().await.is_authenticated()
+            } else {
+                context.auth_state().is_authenticated()
+            };
+            if !admitted {
+                metrics::count("TUIC", "connect", Outcome::AuthGated);
+                if let Some(masquerade) = &self.masquerade {
+                    debug!(
+                        "Serving HTTP/3 masquerade to unauthenticated client: {}",
+                        &connection.remote_address()
+                    );
+                    masquerade.serve(&connection, send).await;
+                    return Ok(false);
+                }
+                bail!("Authentication failed or timed out");
+            }
+
+            let identity = context.identity().unwrap_or_default();
+            let Some(slot) = quota::quotas().acquire_stream(identity) else {
+                debug!(
+                    "User {} is over the stream quota, resetting stream from {}",
+                    identity,
+                    connection.remote_address()
+                );
+                let code = VarInt::from_u32(QUOTA_EXCEEDED_ERROR_CODE);
+                let _ = send.reset(code);
+                let _ = recv.stop(code);
+                continue;
+            };
+
+            let connect = match Command::read_from((&[first][..]).chain(&mut recv)).await {
                 Ok(Command::Connect(connect)) => connect,
+                Ok(Command::Reverse(reverse)) => {
+                    let connection = Arc::clone(&connection);
+                    let context = Arc::clone(&context);
+                    tokio::spawn(async move {
+                        let _slot = slot;
+                        serve_reverse(&context, &connection, reverse.name(), send, recv).await;
+                    });
+                    continue;
+                }
                 _ => {
+                    metrics::count("TUIC", "connect", Outcome::ParseError);
                     bail!(
                         "Faile to parse command from client: {}",
                         &connection.remote_address()
@@ -46,75 +129,236 @@ impl CommandProcessor for ConnectProcessor {
                 }
             };
 
+            let buf_size = self.relay_buffer_size;
+            let relay_limits = self.relay_limits;
+            let sniff = self.sniff.clone();
+            let context = Arc::clone(&context);
             let exchange = async move {
-                let socket_addr = connect
-                    .address()
-                    .to_socket_address()
-                    .await
-                    .context(format!("Failed to resolve address {}", &connect.address()))?;
-
-                let tcp_stream = match net_tcp::connect(socket_addr).await {
-                    Ok(s) => s,
-                    Err(e) => {
-                        debug!("Failed to connect to {}, error:{}", &socket_addr, e);
-                        bail!("Failed to connect to {}, error:{}", &socket_addr, e);
+                let _slot = slot;
+
+                let mut sniffed = None;
+                let mut early_data = Vec::new();
+                if sniff::wanted(&sniff, connect.address().domain()) {
+                    let (data, host) = sniff::peek(&mut recv, &sniff).await?;
+                    if let (Some(host), Some(port)) = (host, connect.address().port()) {
+                        debug!("Sniffed {} for {}", host, connect.address());
+                        sniffed = Some(Address::Domain(host, port));
                     }
-                };
+                    early_data = data;
+                }
+                let address = sniffed.as_ref().unwrap_or(connect.address());
+                let flow = Flow::tcp(context.session(), address);
+                let listed = context.session().open_stream(address);
+                let activity = listed.activity();
+                let result = async {
+                    let Some(socket_addrs) =
+                        slow::dns(context.session(), address, address.to_socket_addresses()).await
+                    else {
+                        let code = VarInt::from_u32(CONNECT_UNREACHABLE_ERROR_CODE);
+                        let _ = send.reset(code);
+                        let _ = recv.stop(code);
+                        bail!("Failed to resolve address {}", &address);
+                    };
 
-                let (mut tcp_read, mut tcp_write) = tcp_stream.into_split();
+                    if let Err(e) = policy::check_connect(
+                        context.session(),
+                        address.domain(),
+                        &socket_addrs,
+                        &address.to_string(),
+                    ) {
+                        debug!("{}", e);
+                        let code = VarInt::from_u32(CONNECT_REJECTED_ERROR_CODE);
+                        let _ = send.reset(code);
+                        let _ = recv.stop(code);
+                        return Err(e);
+                    }
 
-                let mut quic_recv = recv;
-                let mut quic_send = send;
+                    let route = router::route(
+                        context.session(),
+                        address.domain(),
+                        address.port().unwrap_or_default(),
+                        &socket_addrs,
+                    );
+                    if let Some(Route::Block) = route {
+                        let code = VarInt::from_u32(CONNECT_REJECTED_ERROR_CODE);
+                        let _ = send.reset(code);
+                        let _ = recv.stop(code);
+                        bail!("{} blocked by router", address);
+                    }
 
-                let mut quic_to_tcp = Box::pin(async {
-                    let r = copy_with_buf(&mut quic_recv, &mut tcp_write, 16 * 1024).await;
-                    let _ = tcp_write.shutdown().await;
-                    r
-                });
+                    let direct = DirectOutbound::new(TcpOptions::default());
+                    let outbound: &dyn Outbound = match &route {
+                        Some(Route::Upstream(upstream)) => upstream.as_ref(),
+                        _ => &direct,
+                    };
+                    let dialing = async {
+                        let Some(target) = address.to_address() else {
+                            bail!("No target address");
+                        };
+                        outbound.connect(&target, &socket_addrs).await
+                    };
+                    let (mut tcp_read, mut tcp_write) =
+                        match slow::connect(context.session(), address, dialing).await {
+                            Ok(stream) => tokio::io::split(stream),
+                            Err(e) => {
+                                debug!("Failed to connect to {}, error:{:#}", address, e);
+                                let code = VarInt::from_u32(connect_error_code(
+                                    ConnectFailure::classify(&e),
+                                ));
+                                let _ = send.reset(code);
+                                let _ = recv.stop(code);
+                                bail!("Failed to connect to {}, error:{:#}", address, e);
+                            }
+                        };
 
-                let mut tcp_to_quic = Box::pin(async {
-                    let r = copy_with_buf(&mut tcp_read, &mut quic_send, 16 * 1024).await;
-                    let _ = quic_send.finish();
-                    r
-                });
+                    if !early_data.is_empty() {
+                        tcp_write.write_all(&early_data).await?;
+                        context.session().traffic().add_up(early_data.len());
+                        activity.add_up(early_data.len());
+                    }
 
-                tokio::select! {
-                    _qt = &mut quic_to_tcp => {},
-                    _tq = &mut tcp_to_quic => {},
-                };
+                    // Each direction ends on its own EOF, so a client that
+                    // finishes its stream still reads the whole answer.
+                    let up = copy_half(&mut recv, &mut tcp_write, buf_size, |n| {
+                        context.session().traffic().add_up(n);
+                        activity.add_up(n);
+                    });
+                    let down = copy_half(&mut tcp_read, &mut send, buf_size, |n| {
+                        context.session().traffic().add_down(n);
+                        activity.add_down(n);
+                    });
+
+                    let relay = async {
+                        let _ = tokio::try_join!(up, down);
+                    };
+                    run_limited(relay, relay_limits, activity, "TUIC connect relay").await;
+
+                    anyhow::Ok(())
+                }
+                .await;
+                flow.finish(activity, &result);
 
-                anyhow::Ok(())
+                result
             };
 
-            std::mem::drop(tokio::spawn(exchange));
+            std::mem::drop(tokio::spawn(async move {
+                let outcome = match exchange.await {
+                    Ok(()) => Outcome::Ok,
+                    Err(_) => Outcome::Failed,
+                };
+                metrics::count("TUIC", "connect", outcome);
+            }));
         }
 
         Ok(false)
     }
 }
 
-pub async fn copy_with_buf<R, W>(
-    mut reader: R,
-    mut writer: W,
-    buf_size: usize,
-) -> std::io::Result<u64>
-where
-    R: AsyncReadExt + Unpin,
-    W: AsyncWriteExt + Unpin,
-{
-    let mut buf = bytes::BytesMut::with_capacity(buf_size);
-    let mut total = 0;
-
-    loop {
-        let n = reader.read_buf(&mut buf).await?;
-        if n == 0 {
-            break;
+/// Registers the client to serve a reverse tunnel, holding the
+/// registration until the client closes the stream or the connection.
+async fn serve_reverse(
+    context: &RuntimeContext,
+    connection: &Connection,
+    name: &str,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) {
+    let user = context.identity().unwrap_or_default();
+    let registration = match reverse::register(name, user, connection) {
+        Ok(registration) => registration,
+        Err(status) => {
+            debug!(
+                "Refusing {} from {} to serve tunnel {}: status {}",
+                user,
+                connection.remote_address(),
+                name,
+                status
+            );
+            metrics::count("TUIC", "reverse", Outcome::Failed);
+            let _ = send.write_all(&[status]).await;
+            let _ = send.finish();
+            return;
         }
+    };
+    if send.write_all(&[REVERSE_OK]).await.is_err() {
+        metrics::count("TUIC", "reverse", Outcome::Failed);
+        return;
+    }
+    metrics::count("TUIC", "reverse", Outcome::Ok);
 
-        writer.write_all(&buf).await?;
-        buf.clear();
-        total += n as u64;
+    let mut buf = [0u8; 64];
+    tokio::select! {
+        _ = async { while let Ok(Some(_)) = recv.read(&mut buf).await {} } => {},
+        _ = connection.closed() => {},
     }
+    drop(registration);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::registry::registry;
+    use crate::processor::tuic::context::AuthState;
+    use crate::processor::tuic::notifier::OneShotNotifier;
+    use crate::protocol::tuic::command::CommandType;
+    use bytes::BytesMut;
+    use quinn::{Endpoint, ServerConfig};
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn relays_the_answer_after_the_client_finishes() {
+        // Answers only once the request has ended, as HTTP/1.0 clients
+        // that half-close expect.
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = target.accept().await.unwrap();
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            stream.write_all(b"pong").await.unwrap();
+        });
 
-    Ok(total)
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+        let server_config =
+            ServerConfig::with_single_cert(vec![cert.cert.der().clone()], key.into()).unwrap();
+        let server = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(
+            quinn::ClientConfig::with_root_certificates(Arc::new(roots)).unwrap(),
+        );
+
+        let server_addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            let session =
+                registry().register("TUIC", Arc::from("tuic"), connection.remote_address());
+            let context = RuntimeContext::new(OneShotNotifier::default(), Arc::new(session));
+            context.auth_done(AuthState::Authenticated(Uuid::nil()));
+            let processor =
+                ConnectProcessor::new(None, 4096, RelayLimits::default(), SniffConfig::default());
+            let _ = processor
+                .process(Arc::new(context), Arc::new(connection), None)
+                .await;
+        });
+
+        let connection = client
+            .connect(server_addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        let mut request = BytesMut::new();
+        request.extend_from_slice(&[Version::V5 as u8, CommandType::Connect as u8]);
+        Address::Socket(target_addr).write_to_buf(&mut request);
+        request.extend_from_slice(b"ping");
+        send.write_all(&request).await.unwrap();
+        send.finish().unwrap();
+
+        assert_eq!(recv.read_to_end(64).await.unwrap(), b"pong");
+    }
 }
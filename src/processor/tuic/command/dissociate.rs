@@ -20,8 +20,7 @@ impl CommandProcessor for DissociateProcess {
         connection: Arc<Connection>,
         command: Option<Command>,
     ) -> Result<bool> {
-        let auth_result = context.wait_for_auth().await;
-        if auth_result != Some(true) {
+        if !context.wait_for_auth().await.is_authenticated() {
             bail!("Authentication failed or timed out");
         }
 
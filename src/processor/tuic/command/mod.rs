@@ -5,34 +5,60 @@ pub mod heartbeat;
 pub mod packet;
 
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use anyhow::{Result, bail};
 use async_trait::async_trait;
 use quinn::Connection;
+use tracing::debug;
 
 use crate::authenticate::tuic::TuicAuthenticationManager;
-use crate::processor::tuic::CommandProcessor;
+use crate::config::Config;
+use crate::control::metrics::{self, Outcome};
+use crate::net::relay::RelayLimits;
 use crate::processor::tuic::command::authenticate::AuthenticateProcessor;
 use crate::processor::tuic::command::connect::ConnectProcessor;
 use crate::processor::tuic::command::dissociate::DissociateProcess;
 use crate::processor::tuic::command::heartbeat::HeartbeatProcessor;
 use crate::processor::tuic::command::packet::PacketProcessor;
-use crate::processor::tuic::context::RuntimeContext;
+use crate::processor::tuic::context::{AuthState, RuntimeContext};
+use crate::processor::tuic::masquerade::{self, H3Masquerade};
+use crate::processor::tuic::{CommandProcessor, UNAUTHENTICATED_FLOOD_ERROR_CODE};
 use crate::protocol::tuic::command::Command;
 
+static UNAUTHENTICATED_FLOODS: AtomicU64 = AtomicU64::new(0);
+
+/// Connections closed for sending too many commands before authenticating.
+#[cfg(feature = "control")]
+pub fn unauthenticated_floods() -> u64 {
+    UNAUTHENTICATED_FLOODS.load(Ordering::Relaxed)
+}
+
 pub struct CommandUniprocessor {
     authenticate_processor: Arc<AuthenticateProcessor>,
     connect_processor: Arc<ConnectProcessor>,
     dissociate_processor: Arc<DissociateProcess>,
     heartbeat_processor: Arc<HeartbeatProcessor>,
     packet_processor: Arc<PacketProcessor>,
+    max_unauthenticated_commands: Option<u32>,
+    flood_error_code: u32,
 }
 
 impl CommandUniprocessor {
-    pub fn new(authentication_manager: TuicAuthenticationManager) -> Self {
+    pub fn new(authentication_manager: TuicAuthenticationManager, config: &Config) -> Self {
         let authenticate_processor = Arc::new(AuthenticateProcessor::new(authentication_manager));
 
-        let connect_processor = Arc::new(ConnectProcessor {});
+        let masquerade = config.tuic().masquerade();
+        let masquerade = masquerade
+            .enabled()
+            .then(|| H3Masquerade::new(masquerade.status()));
+
+        let connect_processor = Arc::new(ConnectProcessor::new(
+            masquerade,
+            config.relay_buffer_size(),
+            RelayLimits::new(config.relay_idle_timeout(), config.relay_max_lifetime()),
+            config.tuic().sniff().clone(),
+        ));
 
         let heartbeat_processor = Arc::new(HeartbeatProcessor {});
 
@@ -46,6 +72,12 @@ impl CommandUniprocessor {
             dissociate_processor,
             heartbeat_processor,
             packet_processor,
+            max_unauthenticated_commands: config.tuic().max_unauthenticated_commands(),
+            flood_error_code: if config.tuic().masquerade().enabled() {
+                masquerade::H3_NO_ERROR
+            } else {
+                UNAUTHENTICATED_FLOOD_ERROR_CODE
+            },
         }
     }
 }
@@ -68,30 +100,77 @@ impl CommandProcessor for CommandUniprocessor {
             }
         };
 
-        match command {
+        let name = command_name(&command);
+        let gated_by_auth = !matches!(command, Command::Authenticate(_));
+
+        if gated_by_auth
+            && let Some(max) = self.max_unauthenticated_commands
+            && context.auth_state() == AuthState::Unauthenticated
+        {
+            let received = context.count_unauthenticated_command();
+            if received > max {
+                metrics::count("TUIC", name, Outcome::AuthGated);
+                if received == max.saturating_add(1) {
+                    UNAUTHENTICATED_FLOODS.fetch_add(1, Ordering::Relaxed);
+                    debug!(
+                        "Closing connection (ID: {}) from {}: more than {} commands before authenticating",
+                        connection.stable_id(),
+                        connection.remote_address(),
+                        max
+                    );
+                }
+                connection.close(self.flood_error_code.into(), b"");
+                return Ok(false);
+            }
+        }
+
+        let result = match command {
             Command::Authenticate(_) => {
                 self.authenticate_processor
-                    .process(context, Arc::clone(&connection), Some(command))
-                    .await?;
+                    .process(Arc::clone(&context), Arc::clone(&connection), Some(command))
+                    .await
             }
             Command::Packet(_) => {
                 self.packet_processor
-                    .process(context, Arc::clone(&connection), Some(command))
-                    .await?;
+                    .process(Arc::clone(&context), Arc::clone(&connection), Some(command))
+                    .await
             }
             Command::Heartbeat(_) => {
                 self.heartbeat_processor
-                    .process(context, Arc::clone(&connection), Some(command))
-                    .await?;
+                    .process(Arc::clone(&context), Arc::clone(&connection), Some(command))
+                    .await
             }
             Command::Dissociate(_) => {
                 self.dissociate_processor
-                    .process(context, Arc::clone(&connection), Some(command))
-                    .await?;
+                    .process(Arc::clone(&context), Arc::clone(&connection), Some(command))
+                    .await
             }
             _ => bail!("This must not happen! command: {}", command),
-        }
+        };
+
+        let outcome = match &result {
+            Ok(_) => Outcome::Ok,
+            Err(_) if gated_by_auth && !context.auth_state().is_authenticated() => {
+                Outcome::AuthGated
+            }
+            Err(_) => Outcome::Failed,
+        };
+        metrics::count("TUIC", name, outcome);
+
+        result?;
 
         Ok(true)
     }
 }
+
+/// Label used for `command` in the per-command metrics.
+pub fn command_name(command: &Command) -> &'static str {
+    match command {
+        Command::Authenticate(_) => "authenticate",
+        Command::Connect(_) => "connect",
+        Command::Packet(_) => "packet",
+        Command::Heartbeat(_) => "heartbeat",
+        Command::Dissociate(_) => "dissociate",
+        Command::Reverse(_) => "reverse",
+    }
+}
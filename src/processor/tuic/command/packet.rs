@@ -2,13 +2,12 @@ use std::sync::Arc;
 
 use anyhow::{Result, bail};
 use async_trait::async_trait;
-use bytes::BytesMut;
 
+use crate::net::udp as net_udp;
+use crate::policy::{self, udp_guard};
 use crate::processor::tuic::CommandProcessor;
 use crate::processor::tuic::context::RuntimeContext;
-use crate::protocol::tuic::address::Address;
 use crate::protocol::tuic::command::Command;
-use crate::protocol::tuic::command::packet::Packet;
 use quinn::Connection;
 use tracing::{debug, error};
 
@@ -22,8 +21,7 @@ impl CommandProcessor for PacketProcessor {
         connection: Arc<Connection>,
         command: Option<Command>,
     ) -> Result<bool> {
-        let auth_result = context.wait_for_auth().await;
-        if auth_result != Some(true) {
+        if !context.wait_for_auth().await.is_authenticated() {
             bail!("Authentication failed or timed out");
         }
 
@@ -33,142 +31,107 @@ impl CommandProcessor for PacketProcessor {
             bail!("This must not happen! command: {:?}", command)
         };
 
-        let context = context.clone();
+        if !policy::allow_udp(context.session()) {
+            bail!(
+                "UDP is disabled for {:?} on inbound {:?}",
+                context.identity().unwrap_or_default(),
+                context.session().inbound()
+            );
+        }
 
-        match packet.only_one_frag() {
-            true => {
-                let session = context.get_session(packet.assoc_id);
+        if !udp_guard::admit_packet() {
+            bail!("UDP relaying is suspended");
+        }
 
-                let Some(remote_addr) = packet.address.to_socket_address().await else {
-                    bail!("Failed to resolve address");
-                };
+        let session = context.get_session(packet.assoc_id);
+        let assoc_id = packet.assoc_id;
+        let pkt_id = packet.pkt_id;
 
-                let response_buf = session.send_and_recv(remote_addr, &packet.payload).await?;
+        if session.is_relayed(pkt_id) {
+            if tracing::enabled!(tracing::Level::DEBUG) {
+                debug!(
+                    "associate(ID:{}) packet(ID: {}) was already relayed, dropping",
+                    assoc_id, pkt_id
+                );
+            }
+            return Ok(true);
+        }
 
+        let (address, payload) = if packet.only_one_frag() {
+            (Arc::clone(&packet.address), packet.payload)
+        } else {
+            let Some(completed_pkt_id) = session.accept(packet) else {
                 if tracing::enabled!(tracing::Level::DEBUG) {
                     debug!(
-                        "associate(ID:{}) packet(ID: {}) sent and recv {} bytes",
-                        &packet.assoc_id,
-                        &packet.pkt_id,
-                        response_buf.len()
+                        "associate(ID:{}) packet(ID: {}) received fragment, waiting for more",
+                        assoc_id, pkt_id
                     );
                 }
+                return Ok(true);
+            };
 
-                let response_address = Arc::new(Address::Socket(remote_addr));
+            let Some(assembled_payload) = session.take_fragmented_packet(completed_pkt_id) else {
+                return Ok(true);
+            };
 
-                let response_packets = Packet::get_packets_from(
-                    &response_buf,
-                    packet.assoc_id,
-                    packet.pkt_id,
-                    &response_address,
+            let Some(address) = session.get_address() else {
+                error!(
+                    "No address stored in session for associate_id: {}",
+                    assoc_id
                 );
+                return Ok(true);
+            };
 
-                for packet in response_packets {
-                    let packet_size = packet.estimate_size();
-                    let mut bytes = BytesMut::with_capacity(packet_size);
-                    packet.write_to_buf(&mut bytes);
-                    connection.send_datagram(bytes.freeze()).map_err(|e| {
-                        anyhow::anyhow!(
-                            "Failed to send data to client: {}: {}",
-                            connection.remote_address(),
-                            e
-                        )
-                    })?;
-                }
-
-                if tracing::enabled!(tracing::Level::DEBUG) {
-                    debug!(
-                        "✅ Successfully processed UDP packet, dest: {} size: {}",
-                        &packet.address,
-                        response_buf.len()
-                    );
-                }
+            (address, assembled_payload)
+        };
 
-                Ok(true)
+        if session.is_duplicate(pkt_id) {
+            if tracing::enabled!(tracing::Level::DEBUG) {
+                debug!(
+                    "associate(ID:{}) packet(ID: {}) is a duplicate, dropping",
+                    assoc_id, pkt_id
+                );
             }
-            false => {
-                let session = context.get_session(packet.assoc_id);
-                let assoc_id = packet.assoc_id;
-                let pkt_id = packet.pkt_id;
-
-                if let Some(completed_pkt_id) = session.accept(packet) {
-                    if let Some(assembled_payload) =
-                        session.take_fragmented_packet(completed_pkt_id)
-                    {
-                        let Some(address) = session.get_address() else {
-                            error!(
-                                "No address stored in session for associate_id: {}",
-                                assoc_id
-                            );
-                            return Ok(true);
-                        };
-
-                        let Some(remote_addr) = address.to_socket_address().await else {
-                            error!("Failed to resolve address: {:?}", address);
-                            bail!("Failed to resolve address");
-                        };
-
-                        match session.send_and_recv(remote_addr, &assembled_payload).await {
-                            Ok(response_buf) => {
-                                let recv_n = response_buf.len();
-                                if tracing::enabled!(tracing::Level::DEBUG) {
-                                    debug!(
-                                        "associate(ID:{}) fragmented packet(ID: {}) sent and recv {} bytes from {}",
-                                        assoc_id, completed_pkt_id, recv_n, &address
-                                    );
-                                }
-
-                                let response_address = Arc::new(Address::Socket(remote_addr));
-
-                                let response_packets = Packet::get_packets_from(
-                                    &response_buf,
-                                    assoc_id,
-                                    completed_pkt_id,
-                                    &response_address,
-                                );
-
-                                for resp_packet in response_packets {
-                                    let packet_size = resp_packet.estimate_size();
-                                    let mut bytes = BytesMut::with_capacity(packet_size);
-                                    resp_packet.write_to_buf(&mut bytes);
-                                    connection.send_datagram(bytes.freeze()).map_err(|e| {
-                                        anyhow::anyhow!(
-                                            "Failed to send data to client: {}: {}",
-                                            connection.remote_address(),
-                                            e
-                                        )
-                                    })?;
-                                }
-
-                                if tracing::enabled!(tracing::Level::DEBUG) {
-                                    debug!(
-                                        "✅ Successfully processed fragmented UDP packet, dest: {} size: {}",
-                                        &address, recv_n
-                                    );
-                                }
-                            }
-                            Err(e) => {
-                                if tracing::enabled!(tracing::Level::DEBUG) {
-                                    debug!(
-                                        "Failed to send/recv fragmented packet for associate(ID:{}): {}",
-                                        assoc_id, e
-                                    );
-                                }
-                                return Ok(true);
-                            }
-                        }
-                    }
-                } else {
-                    if tracing::enabled!(tracing::Level::DEBUG) {
-                        debug!(
-                            "associate(ID:{}) packet(ID: {}) received fragment, waiting for more",
-                            assoc_id, pkt_id
-                        );
-                    }
-                }
+            return Ok(true);
+        }
+
+        let Some(remote_addr) = address.to_socket_address().await else {
+            error!("Failed to resolve address: {:?}", address);
+            bail!("Failed to resolve address");
+        };
+
+        if let Err(e) = policy::check(context.session(), address.domain(), &[remote_addr]) {
+            debug!("associate(ID:{}) packet(ID: {}): {}", assoc_id, pkt_id, e);
+            return Ok(true);
+        }
 
-                Ok(true)
+        match session
+            .send_to(&connection, assoc_id, remote_addr, &payload)
+            .await
+        {
+            Ok(()) => {}
+            Err(e) if is_unreachable(&e) => {
+                debug!("associate(ID:{}) packet(ID: {}): {:#}", assoc_id, pkt_id, e);
+                return Ok(true);
             }
+            Err(e) => return Err(e),
+        }
+
+        if tracing::enabled!(tracing::Level::DEBUG) {
+            debug!(
+                "associate(ID:{}) packet(ID: {}) sent {} bytes to {}",
+                assoc_id,
+                pkt_id,
+                payload.len(),
+                &address
+            );
         }
+
+        Ok(true)
     }
 }
+
+fn is_unreachable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(net_udp::is_unreachable)
+}
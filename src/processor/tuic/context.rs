@@ -1,44 +1,156 @@
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::Duration;
 
 use dashmap::DashMap;
+use once_cell::sync::OnceCell;
+use quinn::ZeroRttAccepted;
+use tokio::sync::watch;
 use tracing::debug;
+use uuid::Uuid;
 
+use crate::control::registry::SessionGuard;
+use crate::policy::quota::QuotaSlot;
 use crate::processor::tuic::{notifier::OneShotNotifier, session::UdpSession};
 
+/// Where a connection stands in TUIC authentication.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthState {
+    Unauthenticated,
+    Authenticated(Uuid),
+    Failed,
+}
+
+impl AuthState {
+    pub fn is_authenticated(&self) -> bool {
+        matches!(self, AuthState::Authenticated(_))
+    }
+}
+
 pub struct RuntimeContext {
     notifier: OneShotNotifier,
+    auth_claimed: AtomicBool,
+    unauthenticated_commands: AtomicU32,
+    handshake: watch::Sender<bool>,
     udp_sessions: Arc<DashMap<u16, UdpSession>>,
+    session: Arc<SessionGuard>,
+    identity: OnceCell<(Arc<str>, QuotaSlot)>,
 }
 
 impl RuntimeContext {
-    pub fn new(notifier: OneShotNotifier) -> Self {
+    pub fn new(notifier: OneShotNotifier, session: Arc<SessionGuard>) -> Self {
         Self {
             notifier,
+            auth_claimed: AtomicBool::new(false),
+            unauthenticated_commands: AtomicU32::new(0),
+            handshake: watch::Sender::new(true),
             udp_sessions: Arc::new(DashMap::new()),
+            session,
+            identity: OnceCell::new(),
         }
     }
 
-    pub async fn auth_done(&self, result: bool) {
-        self.notifier.notify(result);
+    pub fn session(&self) -> &SessionGuard {
+        &self.session
+    }
+
+    /// Records who authenticated on this connection, holding their
+    /// connection quota slot for as long as the connection lives.
+    pub fn set_identity(&self, identity: Arc<str>, slot: QuotaSlot) {
+        let _ = self.identity.set((identity, slot));
+    }
+
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.get().map(|(identity, _)| identity.as_ref())
+    }
+
+    /// Claims the connection's single Authenticate. Returns false if one
+    /// was already received.
+    pub fn claim_auth(&self) -> bool {
+        !self.auth_claimed.swap(true, Ordering::AcqRel)
+    }
+
+    /// Counts a data-plane command received before authentication and
+    /// returns how many there have been so far.
+    pub fn count_unauthenticated_command(&self) -> u32 {
+        self.unauthenticated_commands
+            .fetch_add(1, Ordering::Relaxed)
+            .saturating_add(1)
+    }
+
+    pub fn auth_state(&self) -> AuthState {
+        self.notifier.current()
+    }
+
+    pub fn auth_done(&self, state: AuthState) {
+        self.notifier.notify(state);
+    }
+
+    /// Marks the TLS handshake as still in flight on a 0-RTT connection
+    /// until `handshake` resolves.
+    pub fn track_handshake(self: &Arc<Self>, handshake: ZeroRttAccepted) {
+        self.handshake.send_replace(false);
+
+        let context = Arc::clone(self);
+        tokio::spawn(async move {
+            handshake.await;
+            context.handshake.send_replace(true);
+        });
     }
 
-    pub async fn wait_for_auth(&self) -> Option<bool> {
+    /// Resolves once the TLS handshake is complete, which keying material
+    /// export (and so authentication) depends on.
+    pub async fn wait_for_handshake(&self) {
+        let mut rx = self.handshake.subscribe();
+        let _ = rx.wait_for(|done| *done).await;
+    }
+
+    pub async fn wait_for_auth(&self) -> AuthState {
+        // Commands in 0-RTT data may arrive a full round trip before the
+        // Authenticate can be verified.
+        self.wait_for_handshake().await;
         self.notifier.wait().await
     }
 
+    pub async fn wait_for_auth_timeout(&self, timeout: Duration) -> AuthState {
+        self.notifier.wait_timeout(timeout).await
+    }
+
     pub fn get_session(&self, associate_id: u16) -> UdpSession {
         if let Some(session) = self.udp_sessions.get(&associate_id) {
             return session.clone();
         }
 
-        self.udp_sessions.entry(associate_id).or_default().clone()
+        self.udp_sessions
+            .entry(associate_id)
+            .or_insert_with(|| UdpSession::new(&self.session))
+            .clone()
+    }
+
+    /// Closes and removes associations idle for longer than `timeout`.
+    /// Returns how many were removed.
+    pub async fn sweep_idle_sessions(&self, timeout: Duration) -> usize {
+        let expired: Vec<u16> = self
+            .udp_sessions
+            .iter()
+            .filter(|entry| entry.value().idle() > timeout)
+            .map(|entry| *entry.key())
+            .collect();
+
+        for associate_id in &expired {
+            if let Some((_, session)) = self.udp_sessions.remove(associate_id) {
+                session.close_socket("idle timeout").await;
+            }
+        }
+
+        expired.len()
     }
 
     pub async fn remove_session(&self, associate_id: u16) {
         let r = self.udp_sessions.remove(&associate_id);
         match r {
             Some((_associate_id, session)) => {
-                session.close_socket().await;
+                session.close_socket("dissociated").await;
                 if tracing::enabled!(tracing::Level::DEBUG) {
                     debug!(
                         "Success to remove all sessions with associate_id : {}",
@@ -57,3 +169,28 @@ impl RuntimeContext {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::registry::registry;
+
+    #[tokio::test(start_paused = true)]
+    async fn sweeps_only_idle_associations() {
+        let session =
+            registry().register("TUIC", Arc::from("tuic"), "127.0.0.1:1".parse().unwrap());
+        let context = RuntimeContext::new(OneShotNotifier::default(), Arc::new(session));
+
+        context.get_session(1);
+        tokio::time::advance(Duration::from_secs(20)).await;
+        context.get_session(2);
+        tokio::time::advance(Duration::from_secs(15)).await;
+
+        assert_eq!(
+            context.sweep_idle_sessions(Duration::from_secs(30)).await,
+            1
+        );
+        assert!(!context.udp_sessions.contains_key(&1));
+        assert!(context.udp_sessions.contains_key(&2));
+    }
+}
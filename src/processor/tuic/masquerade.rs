@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use quinn::{Connection, SendStream, VarInt};
+use tokio::time::timeout;
+use tracing::debug;
+
+pub const H3_NO_ERROR: u32 = 0x100;
+
+const H3_STREAM_TYPE_CONTROL: u8 = 0x00;
+const H3_FRAME_HEADERS: u8 = 0x01;
+const H3_FRAME_SETTINGS: u8 = 0x04;
+
+// QPACK static table index of `:status: 103`, the first `:status` entry.
+const QPACK_STATUS_NAME_INDEX: u8 = 24;
+
+/// A control stream with an empty SETTINGS frame.
+const CONTROL_STREAM: [u8; 3] = [H3_STREAM_TYPE_CONTROL, H3_FRAME_SETTINGS, 0x00];
+
+const MAX_REQUESTS: usize = 8;
+const SERVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Minimal HTTP/3 responder for connections that failed TUIC authentication,
+/// so active probers see an ordinary web server on the UDP port.
+pub struct H3Masquerade {
+    response: Bytes,
+}
+
+impl H3Masquerade {
+    pub fn new(status: u16) -> Self {
+        Self {
+            response: encode_headers_frame(status),
+        }
+    }
+
+    /// Answers the request stream `first` and the few after it, then
+    /// closes the connection.
+    pub async fn serve(&self, connection: &Connection, first: SendStream) {
+        if timeout(SERVE_TIMEOUT, self.respond(connection, first))
+            .await
+            .is_err()
+        {
+            debug!(
+                "Masquerade for {} timed out, closing connection",
+                connection.remote_address()
+            );
+        }
+
+        connection.close(VarInt::from_u32(H3_NO_ERROR), b"");
+    }
+
+    async fn respond(&self, connection: &Connection, first: SendStream) {
+        // Every HTTP/3 server opens a control stream carrying its SETTINGS.
+        if let Ok(mut control) = connection.open_uni().await {
+            let _ = control.write_all(&CONTROL_STREAM).await;
+        }
+
+        let mut send = first;
+        for _ in 0..MAX_REQUESTS {
+            if let Err(e) = send.write_all(&self.response).await {
+                debug!("Failed to write masquerade response: {}", e);
+                return;
+            }
+            let _ = send.finish();
+
+            let Ok((next, _recv)) = connection.accept_bi().await else {
+                return;
+            };
+            send = next;
+        }
+    }
+}
+
+fn encode_headers_frame(status: u16) -> Bytes {
+    let status = status.to_string();
+
+    // Field section prefix: required insert count 0, base 0. The status is
+    // a literal with a static name reference, which works for any code.
+    let mut field_section = BytesMut::with_capacity(8);
+    field_section.put_u8(0x00);
+    field_section.put_u8(0x00);
+    field_section.put_u8(0x50 | 0x0F);
+    field_section.put_u8(QPACK_STATUS_NAME_INDEX - 0x0F);
+    field_section.put_u8(status.len() as u8);
+    field_section.put_slice(status.as_bytes());
+
+    let mut frame = BytesMut::with_capacity(2 + field_section.len());
+    frame.put_u8(H3_FRAME_HEADERS);
+    frame.put_u8(field_section.len() as u8);
+    frame.put_slice(&field_section);
+    frame.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_stream_carries_empty_settings() {
+        assert_eq!(CONTROL_STREAM, [0x00, 0x04, 0x00]);
+    }
+
+    #[test]
+    fn encodes_status_as_literal_with_static_name() {
+        // HEADERS, length 8: prefix 0/0, literal with static name ref 24
+        // (`:status`) in 4-bit prefix form (0x5f, 24 - 15), then "404".
+        assert_eq!(
+            encode_headers_frame(404).as_ref(),
+            [0x01, 0x08, 0x00, 0x00, 0x5f, 0x09, 0x03, b'4', b'0', b'4']
+        );
+    }
+
+    #[test]
+    fn encodes_any_status_length() {
+        let frame = encode_headers_frame(200);
+        assert_eq!(&frame[..], b"\x01\x08\x00\x00\x5f\x09\x03200");
+
+        let frame = encode_headers_frame(65535);
+        assert_eq!(frame[1] as usize, frame.len() - 2);
+        assert_eq!(frame[6], 5);
+        assert_eq!(&frame[7..], b"65535");
+    }
+}
@@ -1,25 +1,82 @@
+pub mod bandwidth;
 pub mod command;
 
 pub mod context;
+pub mod masquerade;
 pub mod notifier;
+pub mod reverse;
 pub mod session;
 
 use anyhow::Result;
 use async_trait::async_trait;
 use std::io::Cursor;
 use std::sync::Arc;
+use std::time::Duration;
 use uuid::Uuid;
 
 use quinn::Connection;
 use tracing::debug;
 
 use crate::authenticate::tuic::TuicAuthenticationManager;
+use crate::config::Config;
+use crate::control::metrics::{self, Outcome};
+use crate::control::registry::PathStats;
+use crate::net::tcp::ConnectFailure;
 use crate::processor::tuic::command::CommandUniprocessor;
 use crate::processor::tuic::context::RuntimeContext;
 use crate::protocol::tuic::command::Command;
 
+/// Application error code used to close a connection that did not
+/// authenticate within the deadline.
+pub const AUTH_TIMEOUT_ERROR_CODE: u32 = 0x01;
+
+/// Application error code used to close a connection, or reset a stream,
+/// that would put a user over their quota.
+pub const QUOTA_EXCEEDED_ERROR_CODE: u32 = 0x02;
+
+/// Application error code used to close a connection that sent more than
+/// one Authenticate.
+pub const AUTH_CONFLICT_ERROR_CODE: u32 = 0x04;
+
+/// Application error code used to close connections still open when the
+/// server shuts down.
+pub const SERVER_GOING_AWAY_ERROR_CODE: u32 = 0x05;
+
+/// Application error code used to reset a Connect stream whose target the
+/// destination policy refuses.
+pub const CONNECT_REJECTED_ERROR_CODE: u32 = 0x06;
+
+/// Application error codes used to reset a Connect stream whose outbound
+/// connect failed, one per failure class so clients can fail over.
+pub const CONNECT_REFUSED_ERROR_CODE: u32 = 0x07;
+pub const CONNECT_TIMEOUT_ERROR_CODE: u32 = 0x08;
+/// Also used when the target name does not resolve.
+pub const CONNECT_UNREACHABLE_ERROR_CODE: u32 = 0x09;
+pub const CONNECT_FAILED_ERROR_CODE: u32 = 0x0a;
+
+/// Application error code used to close a connection that sent too many
+/// data-plane commands before authenticating.
+pub const UNAUTHENTICATED_FLOOD_ERROR_CODE: u32 = 0x0b;
+
+/// Stream reset code reporting `failure` to the client.
+pub fn connect_error_code(failure: ConnectFailure) -> u32 {
+    match failure {
+        ConnectFailure::Refused => CONNECT_REFUSED_ERROR_CODE,
+        ConnectFailure::TimedOut => CONNECT_TIMEOUT_ERROR_CODE,
+        ConnectFailure::Unreachable => CONNECT_UNREACHABLE_ERROR_CODE,
+        ConnectFailure::Other => CONNECT_FAILED_ERROR_CODE,
+    }
+}
+
 pub struct TuicConnectionProcessor {
     command_processor: Arc<CommandUniprocessor>,
+    /// Shares its user table with the authenticate processor.
+    users: TuicAuthenticationManager,
+    bandwidth_report_interval: Option<Duration>,
+    path_stats_interval: Option<Duration>,
+    session_timeout: Duration,
+    auth_timeout: Duration,
+    auth_timeout_error_code: u32,
 }
 
 impl TuicConnectionProcessor {
@@ -46,6 +103,7 @@ impl TuicConnectionProcessor {
             };
 
             let Ok(command) = Command::read_from(recv_stream).await else {
+                metrics::count("TUIC", "unknown", Outcome::ParseError);
                 debug!("Failed to read command from unidirectional stream");
                 break;
             };
@@ -91,7 +149,8 @@ impl TuicConnectionProcessor {
             let cursor = Cursor::new(&bytes);
 
             let Ok(command) = Command::read_from(cursor).await else {
-                debug!("Failed to read command from unidirectional stream");
+                metrics::count("TUIC", "unknown", Outcome::ParseError);
+                debug!("Failed to read command from datagram");
                 break;
             };
 
@@ -107,15 +166,159 @@ impl TuicConnectionProcessor {
         Ok(())
     }
 
-    pub fn new<I>(user_entries: I) -> Self
+    pub async fn process_bandwidth_reports(
+        &self,
+        context: Arc<RuntimeContext>,
+        connection: Arc<Connection>,
+    ) -> Result<()> {
+        if let Some(interval) = self.bandwidth_report_interval {
+            bandwidth::report_bandwidth(context, connection, interval).await;
+        }
+
+        Ok(())
+    }
+
+    /// Samples the connection's RTT and congestion state into the session
+    /// registry, until the connection closes.
+    pub async fn process_path_stats(
+        &self,
+        context: Arc<RuntimeContext>,
+        connection: Arc<Connection>,
+    ) -> Result<()> {
+        let Some(interval) = self.path_stats_interval else {
+            return Ok(());
+        };
+
+        let mut ticker = tokio::time::interval(interval);
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = connection.closed() => break,
+            }
+
+            let path = connection.stats().path;
+            let stats = PathStats {
+                rtt: path.rtt,
+                cwnd: path.cwnd,
+                sent_packets: path.sent_packets,
+                lost_packets: path.lost_packets,
+                congestion_events: path.congestion_events,
+                mtu: path.current_mtu,
+                datagrams_dropped: context.session().traffic().datagrams_dropped(),
+            };
+            context.session().set_path_stats(stats);
+
+            if tracing::enabled!(tracing::Level::DEBUG) {
+                debug!(
+                    "Connection (ID: {}) path: rtt={:?} cwnd={} sent={} lost={} congestion_events={} mtu={} datagrams_dropped={}",
+                    connection.stable_id(),
+                    stats.rtt,
+                    stats.cwnd,
+                    stats.sent_packets,
+                    stats.lost_packets,
+                    stats.congestion_events,
+                    stats.mtu,
+                    stats.datagrams_dropped
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Closes the connection unless a valid Authenticate arrives before the
+    /// deadline.
+    pub async fn process_auth_deadline(
+        &self,
+        context: Arc<RuntimeContext>,
+        connection: Arc<Connection>,
+    ) -> Result<()> {
+        if context
+            .wait_for_auth_timeout(self.auth_timeout)
+            .await
+            .is_authenticated()
+        {
+            return Ok(());
+        }
+
+        debug!(
+            "Closing connection (ID: {}) from {}: not authenticated within {:?}",
+            connection.stable_id(),
+            connection.remote_address(),
+            self.auth_timeout
+        );
+        connection.close(self.auth_timeout_error_code.into(), b"");
+
+        Ok(())
+    }
+
+    /// Sweeps UDP associations the client abandoned without a Dissociate,
+    /// until the connection closes.
+    pub async fn process_idle_sessions(
+        &self,
+        context: Arc<RuntimeContext>,
+        connection: Arc<Connection>,
+    ) -> Result<()> {
+        let mut ticker =
+            tokio::time::interval((self.session_timeout / 2).max(Duration::from_secs(1)));
+        ticker.tick().await;
+
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = connection.closed() => break,
+            }
+
+            let expired = context.sweep_idle_sessions(self.session_timeout).await;
+            if expired > 0 {
+                session::count_expired(expired);
+                debug!(
+                    "Closed {} idle UDP association(s) on connection (ID: {})",
+                    expired,
+                    connection.stable_id()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Swaps in a new user list for connections that authenticate from now
+    /// on.
+    pub fn replace_users<I>(&self, user_entries: I)
+    where
+        I: IntoIterator<Item = (Uuid, Arc<[u8]>, Arc<str>)>,
+    {
+        self.users.replace(user_entries);
+    }
+
+    pub fn new<I>(user_entries: I, config: &Config) -> Self
     where
-        I: IntoIterator<Item = (Uuid, Arc<[u8]>)>,
+        I: IntoIterator<Item = (Uuid, Arc<[u8]>, Arc<str>)>,
     {
         let authentication_manager = TuicAuthenticationManager::new(user_entries);
 
-        let command_processor = Arc::new(CommandUniprocessor::new(authentication_manager));
+        let command_processor = Arc::new(CommandUniprocessor::new(
+            authentication_manager.clone(),
+            config,
+        ));
 
-        Self { command_processor }
+        Self {
+            command_processor,
+            users: authentication_manager,
+            bandwidth_report_interval: config.tuic().bandwidth_report_interval(),
+            path_stats_interval: config.tuic().path_stats_interval(),
+            session_timeout: config.udp_session().session_timeout(),
+            auth_timeout: config.tuic().auth_timeout(),
+            // A masquerading server must not give itself away with a
+            // TUIC-specific close code.
+            auth_timeout_error_code: if config.tuic().masquerade().enabled() {
+                masquerade::H3_NO_ERROR
+            } else {
+                AUTH_TIMEOUT_ERROR_CODE
+            },
+        }
     }
 }
 
@@ -3,41 +3,53 @@ use std::time::Duration;
 use tokio::{sync::watch, time::timeout};
 use tracing::debug;
 
+use crate::processor::tuic::context::AuthState;
+
+/// Publishes a connection's authentication outcome, which can be settled
+/// only once.
 pub struct OneShotNotifier {
-    tx: watch::Sender<Option<bool>>,
-    _rx: watch::Receiver<Option<bool>>,
+    tx: watch::Sender<AuthState>,
+    _rx: watch::Receiver<AuthState>,
 }
 
 impl OneShotNotifier {
     fn new() -> Self {
-        let (tx, _rx) = watch::channel(None);
+        let (tx, _rx) = watch::channel(AuthState::Unauthenticated);
         Self { tx, _rx }
     }
 
-    pub fn notify(&self, v: bool) {
-        if self.tx.borrow().is_some() {
-            return;
-        }
-        let _ = self.tx.send(Some(v));
+    pub fn notify(&self, state: AuthState) {
+        self.tx.send_if_modified(|current| {
+            if *current != AuthState::Unauthenticated {
+                return false;
+            }
+            *current = state;
+            true
+        });
     }
 
-    pub async fn wait(&self) -> Option<bool> {
+    pub fn current(&self) -> AuthState {
+        *self.tx.borrow()
+    }
+
+    pub async fn wait(&self) -> AuthState {
         self.wait_timeout(Duration::from_millis(100)).await
     }
 
-    pub async fn wait_timeout(&self, dur: Duration) -> Option<bool> {
+    pub async fn wait_timeout(&self, dur: Duration) -> AuthState {
         let mut rx = self.tx.subscribe();
 
-        if let Some(v) = *rx.borrow() {
-            return Some(v);
-        }
-
-        match timeout(dur, rx.changed()).await {
-            Ok(Ok(())) => *rx.borrow(),
-            Ok(Err(_)) => *rx.borrow(),
+        match timeout(
+            dur,
+            rx.wait_for(|state| *state != AuthState::Unauthenticated),
+        )
+        .await
+        {
+            Ok(Ok(state)) => *state,
+            Ok(Err(_)) => self.current(),
             Err(_) => {
                 debug!("Wait for authentication timeout after {:?}", dur);
-                Some(false)
+                AuthState::Unauthenticated
             }
         }
     }
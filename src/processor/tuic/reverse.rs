@@ -0,0 +1,180 @@
+//! Reverse tunnels (`[[reverse]]`), frp-style exposure of a service behind
+//! a TUIC client.
+//!
+//! A client registers to serve a tunnel by sending a Reverse command on a
+//! bidirectional stream, and holds the registration for as long as that
+//! stream stays open. Each connection to the tunnel's public port is then
+//! handed to the client on a bidirectional stream the server opens, which
+//! starts with a Reverse command naming the tunnel and the visitor, and
+//! relayed to it as a TUIC Connect would be the other way around.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result};
+use bytes::BytesMut;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use quinn::Connection;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch::Receiver;
+use tracing::{debug, error, info};
+
+use crate::config::ReverseConfig;
+use crate::net::relay::copy_half;
+use crate::protocol::tuic::address::Address;
+use crate::protocol::tuic::command::reverse::{REVERSE_NOT_ALLOWED, REVERSE_TAKEN, Reverse};
+
+struct Tunnel {
+    users: Vec<String>,
+    /// The registration serving the tunnel and its client's connection.
+    served: Option<(u64, Connection)>,
+}
+
+static TUNNELS: Lazy<Mutex<HashMap<String, Tunnel>>> = Lazy::new(Mutex::default);
+static NEXT_REGISTRATION: AtomicU64 = AtomicU64::new(0);
+
+/// A client's hold on a tunnel, released when dropped.
+pub struct Registration {
+    name: String,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(tunnel) = TUNNELS.lock().get_mut(&self.name)
+            && tunnel.served.as_ref().is_some_and(|(id, _)| *id == self.id)
+        {
+            tunnel.served = None;
+            info!("[Reverse] Tunnel {} is no longer served", self.name);
+        }
+    }
+}
+
+/// Registers `connection`, authenticated as `user`, to serve the tunnel
+/// `name`. On refusal returns the status byte to answer with.
+pub fn register(name: &str, user: &str, connection: &Connection) -> Result<Registration, u8> {
+    let mut tunnels = TUNNELS.lock();
+    let Some(tunnel) = tunnels.get_mut(name) else {
+        return Err(REVERSE_NOT_ALLOWED);
+    };
+    if !tunnel.users.iter().any(|allowed| allowed == user) {
+        return Err(REVERSE_NOT_ALLOWED);
+    }
+    if let Some((_, served)) = &tunnel.served
+        && served.close_reason().is_none()
+    {
+        return Err(REVERSE_TAKEN);
+    }
+
+    let id = NEXT_REGISTRATION.fetch_add(1, Ordering::Relaxed);
+    tunnel.served = Some((id, connection.clone()));
+    info!(
+        "[Reverse] Tunnel {} is served by {} from {}",
+        name,
+        user,
+        connection.remote_address()
+    );
+    Ok(Registration {
+        name: name.to_string(),
+        id,
+    })
+}
+
+fn serving(name: &str) -> Option<Connection> {
+    TUNNELS
+        .lock()
+        .get(name)
+        .and_then(|tunnel| tunnel.served.as_ref())
+        .map(|(_, connection)| connection.clone())
+}
+
+/// Opens the public port of every `[[reverse]]` tunnel, until shutdown.
+pub fn spawn(tunnels: &[ReverseConfig], buf_size: usize, shutdown_rx: Receiver<()>) {
+    {
+        let mut registry = TUNNELS.lock();
+        for tunnel in tunnels {
+            registry.insert(
+                tunnel.name().to_string(),
+                Tunnel {
+                    users: tunnel.users().to_vec(),
+                    served: None,
+                },
+            );
+        }
+    }
+
+    for tunnel in tunnels {
+        let name = tunnel.name().to_string();
+        let listen = tunnel.listen().to_string();
+        let mut shutdown_rx = shutdown_rx.clone();
+        tokio::spawn(async move {
+            let listener = match TcpListener::bind(&listen).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(
+                        "[Reverse] Failed to listen on {} for {}: {}",
+                        listen, name, e
+                    );
+                    return;
+                }
+            };
+            info!("[Reverse] Exposing tunnel {} on {}", name, listen);
+
+            loop {
+                let (stream, visitor) = tokio::select! {
+                    _ = shutdown_rx.changed() => return,
+                    accepted = listener.accept() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            debug!("[Reverse] Failed to accept on {}: {}", listen, e);
+                            continue;
+                        }
+                    },
+                };
+
+                let Some(connection) = serving(&name) else {
+                    debug!(
+                        "[Reverse] No client serves {}, dropping visitor {}",
+                        name, visitor
+                    );
+                    continue;
+                };
+
+                let name = name.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = forward(&name, stream, visitor, &connection, buf_size).await {
+                        debug!("[Reverse] Visitor {} to {} failed: {:#}", visitor, name, e);
+                    }
+                });
+            }
+        });
+    }
+}
+
+/// Hands the visitor on `stream` to the client serving `name`.
+async fn forward(
+    name: &str,
+    stream: TcpStream,
+    visitor: SocketAddr,
+    connection: &Connection,
+    buf_size: usize,
+) -> Result<()> {
+    let (mut quic_send, mut quic_recv) = connection
+        .open_bi()
+        .await
+        .context("Failed to open a stream to the tunnel client")?;
+
+    let mut head = BytesMut::new();
+    Reverse::write_accept(&mut head, name, &Address::Socket(visitor));
+    quic_send.write_all(&head).await?;
+
+    let (mut tcp_read, mut tcp_write) = tokio::io::split(stream);
+
+    tokio::try_join!(
+        copy_half(&mut tcp_read, &mut quic_send, buf_size, |_| {}),
+        copy_half(&mut quic_recv, &mut tcp_write, buf_size, |_| {}),
+    )?;
+    Ok(())
+}
@@ -1,11 +1,141 @@
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
 use std::{collections::HashMap, sync::Arc};
 
 use bytes::{Bytes, BytesMut};
 use parking_lot::RwLock;
+use quinn::{Connection, SendDatagramError};
 use tokio::net::UdpSocket;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
 
+use crate::control::registry::{SessionGuard, Traffic};
+use crate::logging::access::Flow;
+use crate::net::shaper;
+use crate::net::udp as net_udp;
+use crate::policy::udp_guard;
 use crate::protocol::tuic::{address::Address, command::packet::Packet};
 
+static ACTIVE_ASSOCIATIONS: AtomicUsize = AtomicUsize::new(0);
+static EXPIRED_ASSOCIATIONS: AtomicU64 = AtomicU64::new(0);
+static DUPLICATE_PACKETS: AtomicU64 = AtomicU64::new(0);
+
+/// Packet ids remembered per association for duplicate detection.
+const DEDUP_WINDOW: u16 = 128;
+
+/// Number of UDP associations currently alive across all TUIC connections.
+pub fn active_associations() -> usize {
+    ACTIVE_ASSOCIATIONS.load(Ordering::Relaxed)
+}
+
+/// Number of UDP associations closed by the idle sweeper since startup.
+pub fn expired_associations() -> u64 {
+    EXPIRED_ASSOCIATIONS.load(Ordering::Relaxed)
+}
+
+pub(crate) fn count_expired(n: usize) {
+    EXPIRED_ASSOCIATIONS.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+/// Number of client datagrams dropped as duplicates since startup.
+#[cfg(feature = "control")]
+pub fn duplicate_packets() -> u64 {
+    DUPLICATE_PACKETS.load(Ordering::Relaxed)
+}
+
+/// Last time traffic crossed an association in either direction, and how
+/// much did.
+struct Activity {
+    created: Instant,
+    last_ms: AtomicU64,
+    up: AtomicU64,
+    down: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            created: Instant::now(),
+            last_ms: AtomicU64::new(0),
+            up: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        self.last_ms
+            .store(self.created.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last_ms.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last)
+    }
+}
+
+/// Sliding window over the client's most recent packet ids, in the style
+/// of an IPsec anti-replay window. Ids wrap, so "newer" means less than
+/// half the id space ahead of the highest one seen.
+#[derive(Default)]
+struct DedupWindow {
+    highest: Option<u16>,
+    /// Bit `n` is set when `highest - n` has been seen.
+    seen: u128,
+}
+
+impl DedupWindow {
+    /// Records `pkt_id`, returning false if it was already seen. Ids older
+    /// than the window cannot be told apart and are let through.
+    fn insert(&mut self, pkt_id: u16) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(pkt_id);
+            self.seen = 1;
+            return true;
+        };
+
+        let ahead = pkt_id.wrapping_sub(highest);
+        if ahead == 0 {
+            return false;
+        }
+
+        if ahead < u16::MAX / 2 {
+            self.seen = if ahead >= DEDUP_WINDOW {
+                0
+            } else {
+                self.seen << ahead
+            };
+            self.seen |= 1;
+            self.highest = Some(pkt_id);
+            return true;
+        }
+
+        let behind = highest.wrapping_sub(pkt_id);
+        if behind >= DEDUP_WINDOW {
+            return true;
+        }
+
+        let bit = 1u128 << behind;
+        if self.seen & bit != 0 {
+            return false;
+        }
+        self.seen |= bit;
+        true
+    }
+
+    /// Whether `pkt_id` was recorded and is still within the window.
+    fn contains(&self, pkt_id: u16) -> bool {
+        let Some(highest) = self.highest else {
+            return false;
+        };
+        let behind = highest.wrapping_sub(pkt_id);
+        behind < DEDUP_WINDOW && self.seen & (1u128 << behind) != 0
+    }
+}
+
 #[derive(Clone)]
 pub struct UdpSession {
     inner: Arc<UdpSessionInner>,
@@ -13,7 +143,35 @@ pub struct UdpSession {
 
 pub struct UdpSessionInner {
     pakets: RwLock<HashMap<u16, FragmentedPacket>>,
+    recent: parking_lot::Mutex<DedupWindow>,
     address: RwLock<Option<Arc<Address>>>,
+    sockets: Mutex<OutboundSockets>,
+    next_pkt_id: Arc<AtomicU16>,
+    activity: Arc<Activity>,
+    traffic: Arc<Traffic>,
+    cancel: CancellationToken,
+    flow: Flow,
+    close_reason: OnceLock<&'static str>,
+}
+
+impl Drop for UdpSessionInner {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        ACTIVE_ASSOCIATIONS.fetch_sub(1, Ordering::Relaxed);
+
+        std::mem::take(&mut self.flow).finish_with(
+            self.activity.up.load(Ordering::Relaxed),
+            self.activity.down.load(Ordering::Relaxed),
+            self.close_reason.get().copied().unwrap_or("closed"),
+        );
+    }
+}
+
+/// Outbound sockets of one association, bound lazily per address family.
+#[derive(Default)]
+struct OutboundSockets {
+    v4: Option<Arc<UdpSocket>>,
+    v6: Option<Arc<UdpSocket>>,
 }
 
 pub struct FragmentedPacket {
@@ -22,22 +180,47 @@ pub struct FragmentedPacket {
     received: Vec<Option<Bytes>>,
 }
 
-impl Default for UdpSession {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
 impl UdpSession {
-    pub fn new() -> Self {
+    pub fn new(session: &SessionGuard) -> Self {
+        ACTIVE_ASSOCIATIONS.fetch_add(1, Ordering::Relaxed);
+
         Self {
             inner: Arc::new(UdpSessionInner {
                 pakets: RwLock::new(HashMap::new()),
+                recent: parking_lot::Mutex::new(DedupWindow::default()),
                 address: RwLock::new(None),
+                sockets: Mutex::new(OutboundSockets::default()),
+                next_pkt_id: Arc::new(AtomicU16::new(0)),
+                activity: Arc::new(Activity::new()),
+                traffic: Arc::clone(session.traffic()),
+                cancel: CancellationToken::new(),
+                flow: Flow::udp(session),
+                close_reason: OnceLock::new(),
             }),
         }
     }
 
+    /// Whether a complete packet with this id was already relayed, as
+    /// happens when the client retransmits a datagram it thought lost.
+    pub fn is_duplicate(&self, pkt_id: u16) -> bool {
+        if self.inner.recent.lock().insert(pkt_id) {
+            return false;
+        }
+        DUPLICATE_PACKETS.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Whether the packet this fragment belongs to was already relayed.
+    /// Checked before reassembly, as a retransmitted fragment would
+    /// otherwise start a packet that never completes.
+    pub fn is_relayed(&self, pkt_id: u16) -> bool {
+        if !self.inner.recent.lock().contains(pkt_id) {
+            return false;
+        }
+        DUPLICATE_PACKETS.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
     pub fn get_address(&self) -> Option<Arc<Address>> {
         self.inner.address.read().as_ref().map(Arc::clone)
     }
@@ -46,40 +229,82 @@ impl UdpSession {
         *self.inner.address.write() = Some(addr);
     }
 
-    pub async fn send_and_recv(
+    /// Relays a datagram to `remote_addr` over the association's outbound
+    /// socket, binding it on first use. Replies are pushed back to the client
+    /// by the socket's receive task as they arrive.
+    pub async fn send_to(
         &self,
-        remote_addr: std::net::SocketAddr,
+        connection: &Arc<Connection>,
+        assoc_id: u16,
+        remote_addr: SocketAddr,
         data: &[u8],
-    ) -> anyhow::Result<Vec<u8>> {
-        let bind_addr = match remote_addr {
-            std::net::SocketAddr::V4(_) => "0.0.0.0:0",
-            std::net::SocketAddr::V6(_) => "[::]:0",
-        };
+    ) -> anyhow::Result<()> {
+        self.inner.activity.touch();
+        let socket = self.socket_for(connection, assoc_id, remote_addr).await?;
 
-        let socket = UdpSocket::bind(bind_addr).await?;
+        if let Err(e) = socket.send_to(data, remote_addr).await {
+            return Err(unreachable_or(&socket, e, remote_addr));
+        }
+        self.inner.traffic.add_up(data.len());
+        self.inner
+            .activity
+            .up
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        shaper::throttle(data.len()).await;
 
-        socket.send_to(data, remote_addr).await?;
+        Ok(())
+    }
 
-        let mut buf = vec![0u8; 4096];
+    async fn socket_for(
+        &self,
+        connection: &Arc<Connection>,
+        assoc_id: u16,
+        remote_addr: SocketAddr,
+    ) -> anyhow::Result<Arc<UdpSocket>> {
+        let mut sockets = self.inner.sockets.lock().await;
 
-        loop {
-            let (n, _) = tokio::time::timeout(
-                std::time::Duration::from_secs(3),
-                socket.recv_from(&mut buf),
-            )
-            .await??;
+        let (slot, bind_addr) = match remote_addr {
+            SocketAddr::V4(_) => (&mut sockets.v4, "0.0.0.0:0"),
+            SocketAddr::V6(_) => (&mut sockets.v6, "[::]:0"),
+        };
 
-            if n == buf.len() && buf.len() < 65535 {
-                buf.resize(buf.len() * 2, 0);
-                continue;
-            }
+        if let Some(socket) = slot {
+            return Ok(Arc::clone(socket));
+        }
 
-            buf.truncate(n);
-            return Ok(buf);
+        let socket = Arc::new(UdpSocket::bind(bind_addr).await?);
+        if let Err(e) = net_udp::enable_icmp_errors(&socket) {
+            debug!("Failed to enable ICMP error reporting: {}", e);
         }
+
+        tokio::spawn(recv_loop(
+            Arc::clone(&socket),
+            Arc::clone(connection),
+            assoc_id,
+            Arc::clone(&self.inner.next_pkt_id),
+            Arc::clone(&self.inner.activity),
+            Arc::clone(&self.inner.traffic),
+            self.inner.cancel.clone(),
+        ));
+
+        *slot = Some(Arc::clone(&socket));
+
+        Ok(socket)
+    }
+
+    /// How long the association has seen no traffic in either direction.
+    pub fn idle(&self) -> Duration {
+        self.inner.activity.idle()
     }
 
-    pub async fn close_socket(&self) {}
+    /// Closes the association's sockets; `reason` goes to the access log.
+    pub async fn close_socket(&self, reason: &'static str) {
+        let _ = self.inner.close_reason.set(reason);
+        self.inner.cancel.cancel();
+        let mut sockets = self.inner.sockets.lock().await;
+        sockets.v4 = None;
+        sockets.v6 = None;
+    }
 
     pub fn accept(&self, packet: Packet) -> Option<u16> {
         if !matches!(*packet.address, Address::None) {
@@ -154,3 +379,299 @@ impl UdpSession {
         }
     }
 }
+
+/// Most UDP replies coalesced into one batch of datagrams.
+const MAX_BATCH: usize = 32;
+
+/// Replies an association may have in flight on their own streams, when
+/// the client takes no datagrams. Past it the socket is not read.
+const MAX_STREAM_WRITES: usize = 64;
+
+/// Encoded response fragments sharing one buffer, so a batch costs a single
+/// allocation and each frame is a zero-copy slice of it.
+struct DatagramBatch {
+    buf: BytesMut,
+    /// Packet id and length of each frame.
+    frames: Vec<(u16, usize)>,
+    /// Largest datagram the path takes right now; None when the client
+    /// does not accept datagrams and replies go over unidirectional streams.
+    max_size: Option<usize>,
+    sources: usize,
+    bytes: usize,
+}
+
+impl DatagramBatch {
+    fn new(connection: &Connection) -> Self {
+        Self {
+            buf: BytesMut::new(),
+            frames: Vec::new(),
+            max_size: connection.max_datagram_size(),
+            sources: 0,
+            bytes: 0,
+        }
+    }
+
+    fn push(
+        &mut self,
+        payload: &[u8],
+        from: SocketAddr,
+        assoc_id: u16,
+        next_pkt_id: &AtomicU16,
+        traffic: &Traffic,
+    ) {
+        let pkt_id = next_pkt_id.fetch_add(1, Ordering::Relaxed);
+        let address = Arc::new(Address::Socket(from));
+        let max_size = self.max_size.unwrap_or(usize::MAX);
+
+        let Some(packets) = Packet::get_packets_from(payload, assoc_id, pkt_id, &address, max_size)
+        else {
+            debug!(
+                "associate(ID:{}) packet of {} bytes does not fit in {} byte datagrams, dropped",
+                assoc_id,
+                payload.len(),
+                max_size
+            );
+            traffic.add_datagram_dropped();
+            return;
+        };
+
+        for packet in packets {
+            let start = self.buf.len();
+            self.buf.reserve(packet.estimate_size());
+            packet.write_to_buf(&mut self.buf);
+            self.frames.push((pkt_id, self.buf.len() - start));
+        }
+
+        self.sources += 1;
+        self.bytes += payload.len();
+    }
+
+    async fn send(
+        self,
+        connection: &Connection,
+        assoc_id: u16,
+        traffic: &Traffic,
+        stream_writes: &Arc<Semaphore>,
+    ) -> anyhow::Result<()> {
+        let mut buf = self.buf.freeze();
+        // A packet missing a fragment cannot be reassembled, so the rest of
+        // it is not sent either.
+        let mut dropped = None;
+
+        for (pkt_id, len) in self.frames {
+            let frame = buf.split_to(len);
+
+            if self.max_size.is_none() {
+                // Each reply takes a stream of its own; opening them one
+                // after another would hold up the socket for a round trip
+                // whenever the client is out of stream credit.
+                let permit = Arc::clone(stream_writes).acquire_owned().await?;
+                let connection = connection.clone();
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    let written = async {
+                        let mut stream = connection.open_uni().await?;
+                        stream.write_all(&frame).await?;
+                        stream.finish()?;
+                        anyhow::Ok(())
+                    };
+                    if let Err(e) = written.await {
+                        debug!(
+                            "associate(ID:{}) failed to send packet {} over a stream: {}",
+                            assoc_id, pkt_id, e
+                        );
+                    }
+                });
+                continue;
+            }
+
+            if dropped == Some(pkt_id) {
+                continue;
+            }
+            match connection.send_datagram(frame) {
+                Ok(()) => {}
+                // The path MTU shrank since the batch was sized.
+                Err(SendDatagramError::TooLarge) => {
+                    debug!(
+                        "associate(ID:{}) fragment of {} bytes too large for a datagram, packet {} dropped",
+                        assoc_id, len, pkt_id
+                    );
+                    traffic.add_datagram_dropped();
+                    dropped = Some(pkt_id);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if tracing::enabled!(tracing::Level::DEBUG) {
+            debug!(
+                "associate(ID:{}) relayed {} packet(s), {} bytes{}",
+                assoc_id,
+                self.sources,
+                self.bytes,
+                if self.max_size.is_none() {
+                    " over streams"
+                } else {
+                    ""
+                }
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Pushes replies from `socket` back to the client until the association is
+/// dissociated or the connection goes away.
+async fn recv_loop(
+    socket: Arc<UdpSocket>,
+    connection: Arc<Connection>,
+    assoc_id: u16,
+    next_pkt_id: Arc<AtomicU16>,
+    activity: Arc<Activity>,
+    traffic: Arc<Traffic>,
+    cancel: CancellationToken,
+) {
+    let mut buf = vec![0u8; 65535];
+    let stream_writes = Arc::new(Semaphore::new(MAX_STREAM_WRITES));
+
+    loop {
+        let (n, from) = tokio::select! {
+            res = socket.recv_from(&mut buf) => match res {
+                Ok(r) => r,
+                Err(e) if net_udp::is_unreachable(&e) => {
+                    let targets = net_udp::take_unreachable(&socket);
+                    if tracing::enabled!(tracing::Level::DEBUG) {
+                        debug!(
+                            "associate(ID:{}) destination unreachable: {:?}",
+                            assoc_id, targets
+                        );
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    debug!("associate(ID:{}) UDP receive failed: {}", assoc_id, e);
+                    break;
+                }
+            },
+            _ = cancel.cancelled() => break,
+            _ = connection.closed() => break,
+        };
+
+        activity.touch();
+
+        if !udp_guard::admit_packet() {
+            continue;
+        }
+
+        let mut batch = DatagramBatch::new(&connection);
+        batch.push(&buf[..n], from, assoc_id, &next_pkt_id, &traffic);
+
+        // Drain replies already queued on the socket so a burst goes to
+        // quinn in one go and leaves in as few (GSO) transmits as possible.
+        while batch.sources < MAX_BATCH {
+            match socket.try_recv_from(&mut buf) {
+                Ok((n, from)) => {
+                    if udp_guard::admit_packet() {
+                        batch.push(&buf[..n], from, assoc_id, &next_pkt_id, &traffic);
+                    }
+                }
+                Err(e) if net_udp::is_unreachable(&e) => {
+                    net_udp::take_unreachable(&socket);
+                }
+                Err(_) => break,
+            }
+        }
+
+        traffic.add_down(batch.bytes);
+        activity
+            .down
+            .fetch_add(batch.bytes as u64, Ordering::Relaxed);
+        shaper::throttle(batch.bytes).await;
+
+        if let Err(e) = batch
+            .send(&connection, assoc_id, &traffic, &stream_writes)
+            .await
+        {
+            debug!(
+                "Failed to send data to client: {}: {}",
+                connection.remote_address(),
+                e
+            );
+            break;
+        }
+    }
+
+    if tracing::enabled!(tracing::Level::DEBUG) {
+        debug!("associate(ID:{}) receive task exited", assoc_id);
+    }
+}
+
+fn unreachable_or(
+    socket: &UdpSocket,
+    err: std::io::Error,
+    remote_addr: SocketAddr,
+) -> anyhow::Error {
+    if net_udp::is_unreachable(&err) {
+        net_udp::take_unreachable(socket);
+        return anyhow::Error::new(err).context(format!("Destination {} unreachable", remote_addr));
+    }
+
+    anyhow::Error::new(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::registry::registry;
+
+    #[tokio::test(start_paused = true)]
+    async fn idles_from_the_last_traffic() {
+        let guard = registry().register("TUIC", Arc::from("tuic"), "127.0.0.1:1".parse().unwrap());
+        let session = UdpSession::new(&guard);
+        assert_eq!(session.idle(), Duration::ZERO);
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_eq!(session.idle(), Duration::from_secs(30));
+
+        session.inner.activity.touch();
+        assert_eq!(session.idle(), Duration::ZERO);
+
+        tokio::time::advance(Duration::from_secs(5)).await;
+        assert_eq!(session.idle(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn retransmitted_fragments_of_relayed_packets_are_not_kept() {
+        let guard = registry().register("TUIC", Arc::from("tuic"), "127.0.0.1:1".parse().unwrap());
+        let session = UdpSession::new(&guard);
+        let address = Arc::new(Address::Socket("127.0.0.1:53".parse().unwrap()));
+        let fragments = || Packet::get_packets_from(&[7; 100], 1, 42, &address, 64).unwrap();
+
+        assert!(!session.is_relayed(42));
+        let mut completed = None;
+        for fragment in fragments() {
+            completed = session.accept(fragment);
+        }
+        assert_eq!(completed, Some(42));
+        assert_eq!(session.take_fragmented_packet(42).unwrap().len(), 100);
+        assert!(!session.is_duplicate(42));
+
+        // The client sends the packet again, thinking it lost.
+        assert!(fragments().iter().all(|f| session.is_relayed(f.pkt_id)));
+        assert!(session.inner.pakets.read().is_empty());
+    }
+
+    #[test]
+    fn window_remembers_recent_ids_only() {
+        let mut window = DedupWindow::default();
+        assert!(!window.contains(5));
+        assert!(window.insert(5));
+        assert!(window.contains(5));
+        assert!(!window.contains(6));
+
+        assert!(window.insert(5 + DEDUP_WINDOW));
+        assert!(!window.contains(5));
+        assert!(window.contains(5 + DEDUP_WINDOW));
+    }
+}
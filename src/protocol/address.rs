@@ -1,15 +1,17 @@
-use anyhow::{Context, Result, anyhow, bail};
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+use anyhow::{Context, anyhow};
+use anyhow::{Result, bail};
 use std::{
     fmt,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
-use tokio::{
-    io::{AsyncRead, AsyncReadExt},
-    net::lookup_host,
-};
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 use crate::net::util::is_local_addr;
+use crate::resolver;
 
+#[cfg(any(feature = "tuic", feature = "trojan"))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum AddressType {
@@ -18,6 +20,7 @@ pub enum AddressType {
     IPv6 = 0x04,
 }
 
+#[cfg(any(feature = "tuic", feature = "trojan"))]
 impl AddressType {
     pub fn from_u8(value: u8) -> Result<Self> {
         match value {
@@ -36,6 +39,23 @@ pub enum Address {
 }
 
 impl Address {
+    /// The requested host name, if the client asked for one.
+    pub fn domain(&self) -> Option<&str> {
+        match self {
+            Address::Domain(domain, _) => Some(domain),
+            _ => None,
+        }
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Socket(sa) => sa.port(),
+            Address::Domain(_, port) => *port,
+        }
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
     pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
         let addr_type_byte = reader
             .read_u8()
@@ -82,27 +102,62 @@ impl Address {
         Ok(address)
     }
 
-    pub async fn to_socket_addrs(&self) -> Result<SocketAddr> {
-        let mut sa = match self {
-            Address::Socket(sa) => Ok(*sa),
+    /// Appends the address in the SOCKS5 form `read_from` parses.
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub fn write_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Address::Socket(SocketAddr::V4(sa)) => {
+                buf.push(AddressType::IPv4 as u8);
+                buf.extend_from_slice(&sa.ip().octets());
+                buf.extend_from_slice(&sa.port().to_be_bytes());
+            }
+            Address::Socket(SocketAddr::V6(sa)) => {
+                buf.push(AddressType::IPv6 as u8);
+                buf.extend_from_slice(&sa.ip().octets());
+                buf.extend_from_slice(&sa.port().to_be_bytes());
+            }
             Address::Domain(domain, port) => {
-                let mut addrs = lookup_host((domain.as_str(), *port)).await?;
-                addrs.next().ok_or_else(|| anyhow!("no addresses found"))
+                buf.push(AddressType::DomainName as u8);
+                buf.push(domain.len() as u8);
+                buf.extend_from_slice(domain.as_bytes());
+                buf.extend_from_slice(&port.to_be_bytes());
             }
-        }?;
-
-        if is_local_addr(&sa) {
-            sa = match sa.ip() {
-                std::net::IpAddr::V4(_) => {
-                    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), sa.port())
-                }
-                std::net::IpAddr::V6(_) => {
-                    SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), sa.port())
-                }
-            };
         }
+    }
+
+    #[cfg(any(feature = "tuic", feature = "trojan"))]
+    pub async fn to_socket_addrs(&self) -> Result<SocketAddr> {
+        self.to_all_socket_addrs()
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("no addresses found"))
+    }
+
+    /// Every address the target resolves to, in resolver order.
+    pub async fn to_all_socket_addrs(&self) -> Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = match self {
+            Address::Socket(sa) => vec![*sa],
+            Address::Domain(domain, port) => resolver::lookup(domain, *port).await?,
+        };
+
+        if addrs.is_empty() {
+            bail!("no addresses found");
+        }
+
+        Ok(addrs.into_iter().map(localize).collect())
+    }
+}
+
+/// Maps this host's own addresses to loopback.
+fn localize(sa: SocketAddr) -> SocketAddr {
+    if !is_local_addr(&sa) {
+        return sa;
+    }
 
-        Ok(sa)
+    match sa.ip() {
+        IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), sa.port()),
+        IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), sa.port()),
     }
 }
 
@@ -1,2 +1,7 @@
+pub mod address;
+#[cfg(feature = "snell")]
+pub mod snell;
+#[cfg(feature = "trojan")]
 pub mod trojan;
+#[cfg(feature = "tuic")]
 pub mod tuic;
@@ -0,0 +1,387 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use bytes::{Buf, BufMut, BytesMut};
+use chacha20poly1305::aead::AeadInPlace;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce, Tag};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+pub const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const LENGTH_LEN: usize = 2;
+
+/// Snell limits a single AEAD chunk to 14 bits of payload.
+const MAX_PAYLOAD_LEN: usize = 0x3FFF;
+
+/// Derives a per-direction session key from the PSK and the peer's salt,
+/// using the argon2id parameters of the reference implementation.
+fn derive_key(psk: &[u8], salt: &[u8]) -> io::Result<ChaCha20Poly1305> {
+    let key = session_key(psk, salt)?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn session_key(psk: &[u8], salt: &[u8]) -> io::Result<[u8; KEY_LEN]> {
+    let params = Params::new(8, 3, 1, Some(KEY_LEN))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let mut key = [0u8; KEY_LEN];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(psk, salt, &mut key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    Ok(key)
+}
+
+struct AeadState {
+    cipher: ChaCha20Poly1305,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl AeadState {
+    fn new(cipher: ChaCha20Poly1305) -> Self {
+        Self {
+            cipher,
+            nonce: [0u8; NONCE_LEN],
+        }
+    }
+
+    fn increment_nonce(&mut self) {
+        for byte in self.nonce.iter_mut() {
+            let (value, overflow) = byte.overflowing_add(1);
+            *byte = value;
+            if !overflow {
+                break;
+            }
+        }
+    }
+
+    fn seal(&mut self, buf: &mut [u8]) -> io::Result<Tag> {
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(Nonce::from_slice(&self.nonce), b"", buf)
+            .map_err(|_| io::Error::other("Failed to encrypt snell chunk"))?;
+        self.increment_nonce();
+        Ok(tag)
+    }
+
+    fn open(&mut self, buf: &mut [u8], tag: &[u8]) -> io::Result<()> {
+        self.cipher
+            .decrypt_in_place_detached(
+                Nonce::from_slice(&self.nonce),
+                b"",
+                buf,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Snell chunk auth failed"))?;
+        self.increment_nonce();
+        Ok(())
+    }
+}
+
+/// A stream speaking the Snell AEAD framing: a random salt followed by
+/// chunks of `[encrypted length + tag][encrypted payload + tag]` in each
+/// direction. A zero-length chunk ends the peer's half of the session.
+pub struct SnellStream<S> {
+    inner: S,
+    psk: Vec<u8>,
+
+    reader: Option<AeadState>,
+    raw: BytesMut,
+    pending_len: Option<usize>,
+    plain: BytesMut,
+    read_eof: bool,
+
+    writer: Option<AeadState>,
+    out: BytesMut,
+    /// Whether the zero-length chunk ending our half has been queued.
+    write_eof: bool,
+}
+
+impl<S> SnellStream<S> {
+    pub fn new(inner: S, psk: &[u8]) -> Self {
+        Self {
+            inner,
+            psk: psk.to_vec(),
+            reader: None,
+            raw: BytesMut::with_capacity(4096),
+            pending_len: None,
+            plain: BytesMut::new(),
+            read_eof: false,
+            writer: None,
+            out: BytesMut::with_capacity(4096),
+            write_eof: false,
+        }
+    }
+
+    /// Decrypts as many complete chunks as are buffered. Returns false when
+    /// more ciphertext is needed.
+    fn decode(&mut self) -> io::Result<bool> {
+        if self.reader.is_none() {
+            if self.raw.len() < SALT_LEN {
+                return Ok(false);
+            }
+            let salt = self.raw.split_to(SALT_LEN);
+            self.reader = Some(AeadState::new(derive_key(&self.psk, &salt)?));
+        }
+
+        let Some(reader) = self.reader.as_mut() else {
+            return Ok(false);
+        };
+
+        let len = match self.pending_len {
+            Some(len) => len,
+            None => {
+                if self.raw.len() < LENGTH_LEN + TAG_LEN {
+                    return Ok(false);
+                }
+                let mut chunk = self.raw.split_to(LENGTH_LEN + TAG_LEN);
+                let (len_buf, tag) = chunk.split_at_mut(LENGTH_LEN);
+                reader.open(len_buf, tag)?;
+                let len = (u16::from_be_bytes([len_buf[0], len_buf[1]]) as usize) & MAX_PAYLOAD_LEN;
+                self.pending_len = Some(len);
+                len
+            }
+        };
+
+        if self.raw.len() < len + TAG_LEN {
+            return Ok(false);
+        }
+
+        let mut chunk = self.raw.split_to(len + TAG_LEN);
+        let (payload, tag) = chunk.split_at_mut(len);
+        reader.open(payload, tag)?;
+        self.pending_len = None;
+
+        if len == 0 {
+            self.read_eof = true;
+        } else {
+            self.plain.extend_from_slice(payload);
+        }
+
+        Ok(true)
+    }
+
+    fn encode(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.writer.is_none() {
+            let mut salt = [0u8; SALT_LEN];
+            rand::fill(&mut salt);
+            self.writer = Some(AeadState::new(derive_key(&self.psk, &salt)?));
+            self.out.put_slice(&salt);
+        }
+
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+
+        let mut len_buf = (data.len() as u16).to_be_bytes();
+        let tag = writer.seal(&mut len_buf)?;
+        self.out.put_slice(&len_buf);
+        self.out.put_slice(&tag);
+
+        let start = self.out.len();
+        self.out.put_slice(data);
+        let tag = writer.seal(&mut self.out[start..])?;
+        self.out.put_slice(&tag);
+
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> SnellStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.out.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.out.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for SnellStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if !this.plain.is_empty() {
+                let n = usize::min(this.plain.len(), buf.remaining());
+                buf.put_slice(&this.plain.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.read_eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.decode()? {
+                continue;
+            }
+
+            let mut tmp = [0u8; 8192];
+            let mut tmp_buf = ReadBuf::new(&mut tmp);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut tmp_buf))?;
+
+            if tmp_buf.filled().is_empty() {
+                if this.raw.is_empty() && this.pending_len.is_none() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            this.raw.extend_from_slice(tmp_buf.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for SnellStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        ready!(this.poll_drain(cx))?;
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let n = usize::min(buf.len(), MAX_PAYLOAD_LEN);
+        this.encode(&buf[..n])?;
+
+        // The chunk is queued; a pending drain is finished by the next call.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    /// Ends our half with a zero-length chunk, so the peer can tell a
+    /// clean close from a cut connection.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.write_eof {
+            ready!(this.poll_drain(cx))?;
+            this.encode(&[])?;
+            this.write_eof = true;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const PSK: &[u8] = b"snell-test-psk";
+
+    /// Salt 00..0f, a chunk carrying "hello snell" and the end chunk,
+    /// sealed with an independent argon2id and ChaCha20-Poly1305.
+    const REFERENCE: &str = "000102030405060708090a0b0c0d0e0f\
+        ec6eeaf3ef6a47d821d2f8cf418081eb4740\
+        5c66405f77789a4a439a5f336da4bd6be6a0c63b945c6175735d1f\
+        95e2e8ad4435080d56d0db3c5b9bd0d6a5c0\
+        74d5d79cef3b9561b9c91b1493fd17dc";
+
+    fn reference() -> Vec<u8> {
+        hex::decode(REFERENCE).unwrap()
+    }
+
+    #[test]
+    fn derives_reference_key() {
+        let salt: Vec<u8> = (0..SALT_LEN as u8).collect();
+        assert_eq!(
+            hex::encode(session_key(PSK, &salt).unwrap()),
+            "406046e7579ca361d0d05c643d498e6ec2dfedf6251c3dcb64e278a1dc821767"
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_reference_chunks() {
+        let wire = reference();
+        let mut stream = SnellStream::new(&wire[..], PSK);
+        let mut plain = Vec::new();
+        stream.read_to_end(&mut plain).await.unwrap();
+        assert_eq!(plain, b"hello snell");
+        assert!(stream.read_eof);
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_psk() {
+        let wire = reference();
+        let mut stream = SnellStream::new(&wire[..], b"other-psk");
+        let err = stream.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_chunk() {
+        let mut wire = reference();
+        wire[SALT_LEN + LENGTH_LEN + TAG_LEN] ^= 1;
+        let mut stream = SnellStream::new(&wire[..], PSK);
+        let err = stream.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn cut_chunk_is_unexpected_eof() {
+        let wire = reference();
+        let cut = &wire[..SALT_LEN + LENGTH_LEN + TAG_LEN + 4];
+        let mut stream = SnellStream::new(cut, PSK);
+        let err = stream.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn round_trips_and_ends_with_end_chunk() {
+        // Over two chunks' worth, so the payload is split.
+        let data: Vec<u8> = (0..2 * MAX_PAYLOAD_LEN + 100).map(|i| i as u8).collect();
+        let (client, server) = tokio::io::duplex(1 << 16);
+
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            let mut client = SnellStream::new(client, PSK);
+            client.write_all(&sent).await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+
+        let mut server = SnellStream::new(server, PSK);
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        writer.await.unwrap();
+
+        assert_eq!(received, data);
+        assert!(server.read_eof, "no end chunk was sent");
+    }
+
+    #[tokio::test]
+    async fn each_stream_picks_its_own_salt() {
+        let mut salts = Vec::new();
+        for _ in 0..2 {
+            let mut wire = Vec::new();
+            let mut stream = SnellStream::new(&mut wire, PSK);
+            stream.write_all(b"x").await.unwrap();
+            stream.shutdown().await.unwrap();
+            salts.push(wire[..SALT_LEN].to_vec());
+        }
+        assert_ne!(salts[0], salts[1]);
+    }
+}
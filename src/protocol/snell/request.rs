@@ -0,0 +1,121 @@
+use anyhow::{Context, Result, bail};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::protocol::address::Address;
+
+const VERSION: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CommandType {
+    Ping = 0x00,
+    Connect = 0x01,
+    ConnectV2 = 0x05,
+    Udp = 0x06,
+}
+
+impl CommandType {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0x00 => Ok(CommandType::Ping),
+            0x01 => Ok(CommandType::Connect),
+            0x05 => Ok(CommandType::ConnectV2),
+            0x06 => Ok(CommandType::Udp),
+            _ => bail!("Invalid snell command: 0x{:02x}", value),
+        }
+    }
+}
+
+impl fmt::Display for CommandType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandType::Ping => write!(f, "PING"),
+            CommandType::Connect => write!(f, "CONNECT"),
+            CommandType::ConnectV2 => write!(f, "CONNECT_V2"),
+            CommandType::Udp => write!(f, "UDP"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ResponseType {
+    Tunnel = 0x00,
+    Pong = 0x01,
+    Error = 0x02,
+}
+
+#[derive(Debug, Clone)]
+pub struct SnellRequest {
+    pub command: CommandType,
+    pub address: Option<Address>,
+}
+
+impl SnellRequest {
+    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
+        let version = reader.read_u8().await.context("Failed to read version")?;
+        if version != VERSION {
+            bail!("Unsupported snell header version: 0x{:02x}", version);
+        }
+
+        let command =
+            CommandType::from_u8(reader.read_u8().await.context("Failed to read command")?)?;
+
+        let id_len = reader
+            .read_u8()
+            .await
+            .context("Failed to read client id length")?;
+        // The client id is only used by snell for connection reuse bookkeeping.
+        let mut client_id = vec![0u8; id_len as usize];
+        reader
+            .read_exact(&mut client_id)
+            .await
+            .context("Failed to read client id")?;
+
+        let address = match command {
+            CommandType::Connect | CommandType::ConnectV2 => {
+                let host_len = reader
+                    .read_u8()
+                    .await
+                    .context("Failed to read host length")?;
+                let mut host = vec![0u8; host_len as usize];
+                reader
+                    .read_exact(&mut host)
+                    .await
+                    .context("Failed to read host")?;
+                let host = String::from_utf8(host).context("Invalid host encoding")?;
+                let port = reader.read_u16().await.context("Failed to read port")?;
+
+                Some(match host.parse::<IpAddr>() {
+                    Ok(ip) => Address::Socket(SocketAddr::new(ip, port)),
+                    Err(_) => Address::Domain(host, port),
+                })
+            }
+            CommandType::Ping | CommandType::Udp => None,
+        };
+
+        Ok(SnellRequest { command, address })
+    }
+}
+
+impl fmt::Display for SnellRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.address {
+            Some(address) => write!(f, "SnellRequest({} -> {})", self.command, address),
+            None => write!(f, "SnellRequest({})", self.command),
+        }
+    }
+}
+
+/// Encodes an error response: type, error code, then a length-prefixed message.
+pub fn error_response(code: u8, message: &str) -> Vec<u8> {
+    let message = &message.as_bytes()[..usize::min(message.len(), u8::MAX as usize)];
+    let mut buf = Vec::with_capacity(3 + message.len());
+    buf.push(ResponseType::Error as u8);
+    buf.push(code);
+    buf.push(message.len() as u8);
+    buf.extend_from_slice(message);
+    buf
+}
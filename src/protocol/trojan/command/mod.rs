@@ -1,9 +1,9 @@
 use anyhow::{Context, Result, bail};
 use std::fmt;
+use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
-use super::address::Address;
-use crate::authenticate::trojan::TrojanAuthenticationManager;
+use crate::protocol::address::Address;
 
 const CRLF: &[u8] = b"\r\n";
 const PASSWORD_HASH_LENGTH: usize = 56;
@@ -38,12 +38,17 @@ impl fmt::Display for CommandType {
 pub struct TrojanRequest {
     pub command: CommandType,
     pub address: Address,
+    /// Identity of the user whose password hash authenticated the request.
+    pub user: Arc<str>,
 }
 
 impl TrojanRequest {
+    /// Reads a request, resolving its password hash to a user with
+    /// `identify`. `None` means the stream is not a request from a known
+    /// user, and belongs to the fallback.
     pub async fn read_from<R: AsyncRead + Unpin>(
         reader: &mut R,
-        auth_manager: &TrojanAuthenticationManager,
+        identify: impl FnOnce(&str) -> Option<Arc<str>>,
     ) -> Result<Option<Self>> {
         let mut hash_buf = [0u8; PASSWORD_HASH_LENGTH];
         match reader.read_exact(&mut hash_buf).await {
@@ -56,8 +61,10 @@ impl TrojanRequest {
             }
         }
 
-        let received_hash =
-            String::from_utf8(hash_buf.to_vec()).context("Invalid password hash encoding")?;
+        // Anything that is not a hex hash is someone else's protocol.
+        let Ok(received_hash) = String::from_utf8(hash_buf.to_vec()) else {
+            return Ok(None);
+        };
 
         let mut crlf = [0u8; 2];
         match reader.read_exact(&mut crlf).await {
@@ -70,9 +77,9 @@ impl TrojanRequest {
             return Ok(None);
         }
 
-        if !auth_manager.verify_password_hash(&received_hash) {
+        let Some(user) = identify(&received_hash) else {
             return Ok(None);
-        }
+        };
 
         let cmd_byte = match reader.read_u8().await {
             Ok(b) => b,
@@ -95,7 +102,11 @@ impl TrojanRequest {
             return Ok(None);
         }
 
-        Ok(Some(TrojanRequest { command, address }))
+        Ok(Some(TrojanRequest {
+            command,
+            address,
+            user,
+        }))
     }
 }
 
@@ -1,2 +1 @@
-pub mod address;
 pub mod command;
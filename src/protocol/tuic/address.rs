@@ -11,9 +11,26 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::debug;
 
 use crate::net::util::is_local_addr;
+use crate::resolver;
 
 type Port = u16;
 
+/// Maps this host's own addresses to loopback.
+fn localize(addr: SocketAddr) -> SocketAddr {
+    if !is_local_addr(&addr) {
+        return addr;
+    }
+
+    let local = match addr {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port()),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port()),
+    };
+    if tracing::enabled!(tracing::Level::DEBUG) {
+        debug!("Using local address for socket: {:?}", local);
+    }
+    local
+}
+
 #[derive(Debug)]
 pub enum Address {
     Socket(SocketAddr),
@@ -22,6 +39,34 @@ pub enum Address {
 }
 
 impl Address {
+    /// The requested host name, if the client asked for one.
+    pub fn domain(&self) -> Option<&str> {
+        match self {
+            Address::Domain(domain, _) => Some(domain),
+            _ => None,
+        }
+    }
+
+    pub fn port(&self) -> Option<Port> {
+        match self {
+            Address::Socket(sa) => Some(sa.port()),
+            Address::Domain(_, port) => Some(*port),
+            Address::None => None,
+        }
+    }
+
+    /// The same target in the form outbounds take.
+    pub fn to_address(&self) -> Option<crate::protocol::address::Address> {
+        match self {
+            Address::Socket(sa) => Some(crate::protocol::address::Address::Socket(*sa)),
+            Address::Domain(domain, port) => Some(crate::protocol::address::Address::Domain(
+                domain.clone(),
+                *port,
+            )),
+            Address::None => None,
+        }
+    }
+
     pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
         match self {
             Address::Socket(socket_addr) => match socket_addr {
@@ -50,32 +95,7 @@ impl Address {
     }
 
     pub async fn to_socket_address(&self) -> Option<SocketAddr> {
-        let socket_addr = match self {
-            Address::Socket(socket_addr) => Some(*socket_addr),
-            Address::Domain(domain, port) => (self.resolve(domain, port).await).ok(),
-            Address::None => None,
-        };
-
-        let socket_addr = if let Some(addr) = socket_addr {
-            if is_local_addr(&addr) {
-                let local = match addr {
-                    SocketAddr::V4(_) => {
-                        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
-                    }
-                    SocketAddr::V6(_) => {
-                        SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())
-                    }
-                };
-                if tracing::enabled!(tracing::Level::DEBUG) {
-                    debug!("Using local address for socket: {:?}", local);
-                }
-                Some(local)
-            } else {
-                Some(addr)
-            }
-        } else {
-            None
-        };
+        let socket_addr = self.to_socket_addresses().await?.into_iter().next();
 
         if tracing::enabled!(tracing::Level::DEBUG) {
             debug!("Resolved address to {:?}", socket_addr);
@@ -83,15 +103,15 @@ impl Address {
         socket_addr
     }
 
-    async fn resolve(&self, domain: &str, port: &Port) -> Result<SocketAddr> {
-        let query_host = format!("{}:{}", domain, port);
-        let mut addr_itr = tokio::net::lookup_host(&query_host).await?;
-
-        let addr = addr_itr
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("Failed to resolve address: {}", domain))?;
+    /// Every address the target resolves to, in resolver order.
+    pub async fn to_socket_addresses(&self) -> Option<Vec<SocketAddr>> {
+        let addrs = match self {
+            Address::Socket(socket_addr) => vec![*socket_addr],
+            Address::Domain(domain, port) => resolver::lookup(domain, *port).await.ok()?,
+            Address::None => return None,
+        };
 
-        Ok(addr)
+        Some(addrs.into_iter().map(localize).collect())
     }
 
     pub async fn read_from<R>(read: &mut R) -> Result<Self>
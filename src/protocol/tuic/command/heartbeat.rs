@@ -1,8 +1,10 @@
 use anyhow::Result;
+use bytes::BufMut;
 use core::fmt;
 
 use tokio::io::AsyncRead;
 
+use crate::protocol::tuic::command::CommandType;
 use crate::protocol::tuic::header::Header;
 
 #[derive(Debug)]
@@ -24,3 +26,68 @@ impl fmt::Display for Heartbeat {
         write!(f, "Command: Heartbeat {}", &self.header)
     }
 }
+
+/// Extension tag for a downstream bandwidth estimate appended to a
+/// server-sent heartbeat. Clients that do not know the extension read the
+/// plain heartbeat and ignore the trailing bytes.
+const EXT_DOWNSTREAM_BANDWIDTH: u8 = 0x01;
+
+/// Server-sent heartbeat carrying the achievable downstream bandwidth, in
+/// bytes per second, as estimated by the server.
+#[derive(Debug)]
+pub struct BandwidthHeartbeat {
+    header: Header,
+    bytes_per_sec: u64,
+}
+
+impl BandwidthHeartbeat {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            header: Header::new(CommandType::Heartbeat),
+            bytes_per_sec,
+        }
+    }
+
+    pub fn estimate_size(&self) -> usize {
+        2 + 1 + 8
+    }
+
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+        self.header.write_to(buf);
+        buf.put_u8(EXT_DOWNSTREAM_BANDWIDTH);
+        buf.put_u64(self.bytes_per_sec);
+    }
+}
+
+/// Extension tag for a shutdown notice: the client should open new
+/// connections elsewhere and let streams in flight on this one finish.
+const EXT_GOING_AWAY: u8 = 0x02;
+
+/// Server-sent heartbeat announcing that the server is going away.
+#[derive(Debug)]
+pub struct GoingAwayHeartbeat {
+    header: Header,
+}
+
+impl GoingAwayHeartbeat {
+    pub fn new() -> Self {
+        Self {
+            header: Header::new(CommandType::Heartbeat),
+        }
+    }
+
+    pub fn estimate_size(&self) -> usize {
+        2 + 1
+    }
+
+    pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
+        self.header.write_to(buf);
+        buf.put_u8(EXT_GOING_AWAY);
+    }
+}
+
+impl Default for GoingAwayHeartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
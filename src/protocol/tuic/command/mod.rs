@@ -3,6 +3,7 @@ pub mod connect;
 pub mod dissociate;
 pub mod heartbeat;
 pub mod packet;
+pub mod reverse;
 
 use anyhow::{Context, Result};
 use bytes::BufMut;
@@ -13,7 +14,7 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 use crate::protocol::tuic::{
     command::{
         authenticate::Authenticate, connect::Connect, dissociate::Dissociate, heartbeat::Heartbeat,
-        packet::Packet,
+        packet::Packet, reverse::Reverse,
     },
     header::Header,
 };
@@ -25,6 +26,7 @@ pub enum Command {
     Packet(Packet),
     Heartbeat(Heartbeat),
     Dissociate(Dissociate),
+    Reverse(Reverse),
 }
 
 impl Command {
@@ -57,6 +59,10 @@ impl Command {
                 .await
                 .map(Command::Heartbeat)
                 .context("Failed to parse Heartbeat command"),
+            CommandType::Reverse => Reverse::read_from(header, &mut read)
+                .await
+                .map(Command::Reverse)
+                .context("Failed to parse Reverse command"),
         }
     }
 }
@@ -69,6 +75,7 @@ impl fmt::Display for Command {
             Command::Packet(p) => write!(f, "{}", p),
             Command::Heartbeat(_) => write!(f, "Heartbeat"),
             Command::Dissociate(_) => write!(f, "Dissociate"),
+            Command::Reverse(r) => write!(f, "{}", r),
         }
     }
 }
@@ -81,6 +88,8 @@ pub enum CommandType {
     Packet = 0x02,
     Dissociate = 0x03,
     Heartbeat = 0x04,
+    /// iway extension; see [`Reverse`].
+    Reverse = 0x80,
 }
 
 impl CommandType {
@@ -104,6 +113,7 @@ impl CommandType {
             CommandType::Packet => 0x02,
             CommandType::Dissociate => 0x03,
             CommandType::Heartbeat => 0x04,
+            CommandType::Reverse => 0x80,
         };
         w.put_u8(v);
     }
@@ -115,6 +125,7 @@ impl CommandType {
             CommandType::Packet => "Packet",
             CommandType::Dissociate => "Dissociate",
             CommandType::Heartbeat => "Heartbeat",
+            CommandType::Reverse => "Reverse",
         }
     }
 }
@@ -129,6 +140,7 @@ impl TryFrom<u8> for CommandType {
             0x02 => Ok(CommandType::Packet),
             0x03 => Ok(CommandType::Dissociate),
             0x04 => Ok(CommandType::Heartbeat),
+            0x80 => Ok(CommandType::Reverse),
             _ => Err(CommandTypeError::UnknownCommandType(value)),
         }
     }
@@ -9,7 +9,8 @@ use tokio::io::{AsyncRead, AsyncReadExt};
 
 use super::CommandType;
 
-const MAX_PAYLOAD_PER_PACKET: usize = 1200;
+/// Header, association id, packet id, fragment total, fragment id and size.
+const FIXED_SIZE: usize = 10;
 
 #[derive(Debug)]
 pub struct Packet {
@@ -24,17 +25,29 @@ pub struct Packet {
 }
 
 impl Packet {
+    /// Splits `full_payload` into fragments whose encoded size fits in
+    /// `max_size` bytes, e.g. the connection's current max datagram size.
+    /// Returns None if that needs more fragments than TUIC can number.
     pub fn get_packets_from(
         full_payload: &[u8],
         assoc_id: u16,
         pkt_id: u16,
         address: &Arc<Address>,
-    ) -> Vec<Packet> {
-        let total_len = full_payload.len();
-        let frag_total = total_len.div_ceil(MAX_PAYLOAD_PER_PACKET) as u8;
+        max_size: usize,
+    ) -> Option<Vec<Packet>> {
+        let first_cap = max_size
+            .checked_sub(FIXED_SIZE + address_size(address))
+            .filter(|cap| *cap > 0)?;
+        let rest_cap = max_size - FIXED_SIZE - address_size(&Address::None);
+
+        let (first, rest) = full_payload.split_at(usize::min(first_cap, full_payload.len()));
+        let frag_total = u8::try_from(1 + rest.len().div_ceil(rest_cap)).ok()?;
 
         let mut packets = Vec::with_capacity(frag_total as usize);
-        for (frag_id, chunk) in full_payload.chunks(MAX_PAYLOAD_PER_PACKET).enumerate() {
+        for (frag_id, chunk) in std::iter::once(first)
+            .chain(rest.chunks(rest_cap))
+            .enumerate()
+        {
             packets.push(Packet {
                 header: Header::new(CommandType::Packet),
                 assoc_id,
@@ -51,7 +64,7 @@ impl Packet {
             });
         }
 
-        packets
+        Some(packets)
     }
 
     pub fn write_to_buf<B: BufMut>(&self, buf: &mut B) {
@@ -115,16 +128,18 @@ impl Packet {
     }
 
     pub fn estimate_size(&self) -> usize {
-        let base_size = 10;
-        let addr_size = match &*self.address {
-            Address::Socket(socket_addr) => match socket_addr {
-                SocketAddr::V4(_) => 1 + 4 + 2,
-                SocketAddr::V6(_) => 1 + 16 + 2,
-            },
-            Address::Domain(domain, _) => 1 + 1 + domain.as_bytes().len() + 2,
-            Address::None => 1,
-        };
-        base_size + addr_size + self.payload.len()
+        FIXED_SIZE + address_size(&self.address) + self.payload.len()
+    }
+}
+
+fn address_size(address: &Address) -> usize {
+    match address {
+        Address::Socket(socket_addr) => match socket_addr {
+            SocketAddr::V4(_) => 1 + 4 + 2,
+            SocketAddr::V6(_) => 1 + 16 + 2,
+        },
+        Address::Domain(domain, _) => 1 + 1 + domain.len() + 2,
+        Address::None => 1,
     }
 }
 
@@ -0,0 +1,68 @@
+use anyhow::{Context, Result, bail};
+use bytes::BufMut;
+use core::fmt;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::protocol::tuic::address::Address;
+use crate::protocol::tuic::command::CommandType;
+use crate::protocol::tuic::header::Header;
+
+/// Reply to a registration: the tunnel is now served by this client.
+pub const REVERSE_OK: u8 = 0x00;
+/// Reply to a registration: no tunnel of that name, or not for this user.
+pub const REVERSE_NOT_ALLOWED: u8 = 0x01;
+/// Reply to a registration: another client serves the tunnel already.
+pub const REVERSE_TAKEN: u8 = 0x02;
+
+/// iway extension, outside the commands of TUIC v5. Sent by a client on a
+/// bidirectional stream, it registers the client to serve the reverse
+/// tunnel `name`; the server answers with one status byte and keeps the
+/// registration for as long as the stream stays open. Sent by the server
+/// on a stream it opens, followed by the visitor's address, it hands the
+/// client one connection to the tunnel.
+#[derive(Debug)]
+pub struct Reverse {
+    header: Header,
+    name: String,
+}
+
+impl Reverse {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub async fn read_from<R>(header: Header, read: &mut R) -> Result<Self>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let len = read
+            .read_u8()
+            .await
+            .context("Failed to read tunnel name length from stream!")?;
+        if len == 0 {
+            bail!("Empty tunnel name");
+        }
+        let mut name = vec![0u8; len as usize];
+        read.read_exact(&mut name)
+            .await
+            .context("Failed to read tunnel name from stream!")?;
+        let name = String::from_utf8(name).context("Tunnel name is not UTF-8")?;
+        Ok(Self { header, name })
+    }
+
+    /// Writes the server's hand-over of a connection from `visitor` to the
+    /// tunnel `name`.
+    pub fn write_accept<B: BufMut>(buf: &mut B, name: &str, visitor: &Address) {
+        Header::new(CommandType::Reverse).write_to(buf);
+        buf.put_u8(name.len() as u8);
+        buf.put_slice(name.as_bytes());
+        visitor.write_to_buf(buf);
+    }
+}
+
+impl fmt::Display for Reverse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "header:{} tunnel:{}", &self.header, &self.name)
+    }
+}
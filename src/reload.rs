@@ -0,0 +1,213 @@
+//! Applying edits to the config file without a restart, on SIGHUP and when
+//! the modification time of the file, or of one it includes, changes.
+//!
+//! Users, policies, the port policy, routing rules and quotas are
+//! swapped in place and listeners move only when their address changed;
+//! established connections are left alone. Everything else in the file is read at
+//! startup and takes a restart.
+//!
+//! Certificate and key files are watched the same way, and read again on
+//! SIGHUP: renewed certificates are swapped into the running TLS and QUIC
+//! configs, so new handshakes get them while listeners, endpoints and
+//! connections stay up.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Result, bail};
+use arc_swap::ArcSwap;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::policy;
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+use crate::router;
+use crate::server::ServerManager;
+
+/// How often the config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The config file and the config last applied from it.
+pub struct ConfigHandle {
+    path: PathBuf,
+    current: ArcSwap<Config>,
+}
+
+impl ConfigHandle {
+    pub fn new(path: impl Into<PathBuf>, config: Arc<Config>) -> Self {
+        Self {
+            path: path.into(),
+            current: ArcSwap::new(config),
+        }
+    }
+
+    /// The config last applied.
+    pub fn load(&self) -> Arc<Config> {
+        self.current.load_full()
+    }
+
+    /// Re-reads the file and applies it to `servers`. A file that does not
+    /// parse or validate, or whose outbounds, rule sets or DNS servers fail
+    /// to load, is refused as a whole and the running config stays in
+    /// place. A listener that fails to move keeps its old address.
+    pub async fn reload(&self, servers: &ServerManager) -> Result<()> {
+        let config = Arc::new(Config::from_file(&self.path)?);
+
+        let problems = config.validate();
+        if !problems.is_empty() {
+            for problem in &problems {
+                error!("Invalid config: {}", problem);
+            }
+            bail!("{} problem(s) found", problems.len());
+        }
+
+        if served(&self.load()) != served(&config) {
+            warn!(
+                "Servers added to or removed from {} start or stop on the next restart",
+                self.path.display()
+            );
+        }
+
+        // Everything that can fail is built before anything is swapped, so
+        // a refused file leaves every subsystem on the running config.
+        #[cfg(any(feature = "tuic", feature = "trojan"))]
+        let routes = router::prepare(config.router(), config.outbounds(), config.identities())?;
+        let ports = policy::ports::prepare(config.port_policy())?;
+        let resolver = crate::resolver::prepare(config.dns())?;
+
+        #[cfg(any(feature = "tuic", feature = "trojan"))]
+        routes.install();
+        ports.install();
+        resolver.install();
+        #[cfg(feature = "trojan")]
+        crate::security::fingerprint::init(config.fingerprints());
+        #[cfg(feature = "trojan")]
+        crate::server::tickets::init(config.tls());
+        policy::init(config.policies());
+        #[cfg(any(feature = "tuic", feature = "trojan"))]
+        policy::quota::init(&config);
+        self.current.store(Arc::clone(&config));
+        servers.reload(&config).await?;
+
+        info!("Reloaded {}", self.path.display());
+        Ok(())
+    }
+}
+
+/// Reloads `handle` on SIGHUP and whenever its file is modified, until
+/// shutdown.
+pub fn spawn(
+    handle: Arc<ConfigHandle>,
+    servers: Arc<ServerManager>,
+    mut shutdown_rx: watch::Receiver<()>,
+) {
+    let mut hangups = Hangups::install();
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(WATCH_INTERVAL);
+        let mut last_modified = modified(&handle);
+        let mut last_certificates = certificates_modified(&handle);
+
+        loop {
+            let (config_changed, certificates_changed) = tokio::select! {
+                _ = hangups.recv() => {
+                    info!("Received SIGHUP signal, reloading {}", handle.path.display());
+                    (true, true)
+                }
+                _ = ticker.tick() => {
+                    let config_changed = modified(&handle) != last_modified;
+                    let certificates_changed = certificates_modified(&handle) != last_certificates;
+                    if config_changed {
+                        info!("{} changed, reloading", handle.path.display());
+                    } else if certificates_changed {
+                        info!("Certificate files changed, reloading certificates");
+                    }
+                    (config_changed, certificates_changed)
+                }
+                _ = shutdown_rx.changed() => break,
+            };
+
+            if config_changed && let Err(e) = handle.reload(&servers).await {
+                error!("Failed to reload {}: {:#}", handle.path.display(), e);
+            }
+            if certificates_changed {
+                let _ = servers.reload_certificates(None).await;
+            }
+            // Taken after a SIGHUP as well, and after a reload that picked
+            // up new includes, so the poll does not reload the same edit a
+            // second time.
+            last_modified = modified(&handle);
+            last_certificates = certificates_modified(&handle);
+        }
+    });
+}
+
+/// The sections and inbound tags `config` runs servers for.
+fn served(config: &Config) -> Vec<&str> {
+    let sections = [
+        ("tuic", config.tuic().enabled()),
+        ("trojan", config.trojan().enabled()),
+        ("snell", config.snell().enabled()),
+    ];
+
+    sections
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .chain(config.inbounds().iter().map(|inbound| inbound.tag()))
+        .collect()
+}
+
+/// When the config file and each file it included were last modified.
+fn modified(handle: &ConfigHandle) -> Vec<Option<SystemTime>> {
+    let config = handle.load();
+    std::iter::once(handle.path.as_path())
+        .chain(config.sources().iter().map(PathBuf::as_path))
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// When each certificate and key file of the applied config was last
+/// modified.
+fn certificates_modified(handle: &ConfigHandle) -> Vec<Option<SystemTime>> {
+    let config = handle.load();
+    config
+        .certificate_files()
+        .into_iter()
+        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// SIGHUP deliveries, where the platform has them.
+struct Hangups {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl Hangups {
+    fn install() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{SignalKind, signal};
+
+            let signal = signal(SignalKind::hangup())
+                .inspect_err(|e| error!("Failed to install SIGHUP handler: {}", e))
+                .ok();
+            Self { signal }
+        }
+
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = self.signal.as_mut() {
+            signal.recv().await;
+            return;
+        }
+
+        std::future::pending::<()>().await
+    }
+}
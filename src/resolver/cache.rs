@@ -0,0 +1,75 @@
+//! Answers kept for as long as their records say, so a busy UDP
+//! association or a site opening many connections asks once.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+/// Most names kept; past it expired answers are dropped, then all.
+const MAX_ENTRIES: usize = 4096;
+
+/// Longest an answer is kept, whatever its TTL, so a changed record is
+/// picked up within the hour.
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+/// The addresses of a name and when they expire.
+type Entry = (Arc<[IpAddr]>, Instant);
+
+#[derive(Default)]
+pub struct Cache {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl Cache {
+    /// The addresses of `name`, unless they expired.
+    pub fn get(&self, name: &str) -> Option<Arc<[IpAddr]>> {
+        let entries = self.entries.lock();
+        let (ips, expires) = entries.get(name)?;
+        (*expires > Instant::now()).then(|| Arc::clone(ips))
+    }
+
+    /// Keeps `ips` for `name` for `ttl`; answers that may not be kept are
+    /// not.
+    pub fn insert(&self, name: &str, ips: &[IpAddr], ttl: Duration) {
+        if ttl.is_zero() || ips.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        if entries.len() >= MAX_ENTRIES && !entries.contains_key(name) {
+            entries.retain(|_, (_, expires)| *expires > now);
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear();
+            }
+        }
+        entries.insert(name.to_string(), (ips.into(), now + ttl.min(MAX_TTL)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_answers_for_their_ttl() {
+        let cache = Cache::default();
+        let ips: [IpAddr; 1] = ["192.0.2.1".parse().unwrap()];
+        cache.insert("example.com", &ips, Duration::from_secs(60));
+        cache.insert("uncacheable.example", &ips, Duration::ZERO);
+
+        assert_eq!(cache.get("example.com").as_deref(), Some(&ips[..]));
+        assert!(cache.get("uncacheable.example").is_none());
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(cache.get("example.com").is_none());
+
+        // However long the TTL, an answer is asked for again hourly.
+        cache.insert("example.com", &ips, Duration::from_secs(86400));
+        tokio::time::advance(MAX_TTL).await;
+        assert!(cache.get("example.com").is_none());
+    }
+}
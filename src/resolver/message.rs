@@ -0,0 +1,165 @@
+//! The DNS wire format (RFC 1035), as far as A and AAAA lookups need it.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use anyhow::{Context, Result, bail};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+
+const HEADER_LEN: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// A recursive query `id` for the `qtype` records of `name`.
+pub fn query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > 253 {
+        bail!("{:?} is not a domain name", name);
+    }
+
+    let mut message = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("{:?} is not a domain name", name);
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&1u16.to_be_bytes());
+    Ok(message)
+}
+
+/// The id of a message, to pair responses with queries.
+pub fn id(message: &[u8]) -> Option<u16> {
+    message
+        .get(..2)
+        .map(|id| u16::from_be_bytes([id[0], id[1]]))
+}
+
+/// Whether the server cut the response short to fit a UDP datagram.
+pub fn truncated(message: &[u8]) -> bool {
+    message.len() >= 4 && u16::from_be_bytes([message[2], message[3]]) & FLAG_TRUNCATED != 0
+}
+
+/// The A and AAAA records in the answer section of a response, and the
+/// shortest time to live among them in seconds.
+pub fn addresses(message: &[u8]) -> Result<(Vec<IpAddr>, u32)> {
+    let header = message
+        .get(..HEADER_LEN)
+        .context("Truncated DNS response")?;
+    let field = |at: usize| u16::from_be_bytes([header[at], header[at + 1]]);
+    let flags = field(2);
+    if flags & FLAG_RESPONSE == 0 {
+        bail!("Not a DNS response");
+    }
+    match flags & 0x000f {
+        0 => {}
+        RCODE_NXDOMAIN => bail!("No such domain"),
+        rcode => bail!("DNS server failed with rcode {}", rcode),
+    }
+
+    let mut at = HEADER_LEN;
+    for _ in 0..field(4) {
+        at = skip_name(message, at)? + 4;
+    }
+
+    let mut addresses = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..field(6) {
+        at = skip_name(message, at)?;
+        let record = message.get(at..at + 10).context("Truncated DNS record")?;
+        let rtype = u16::from_be_bytes([record[0], record[1]]);
+        let len = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let data = message
+            .get(at + 10..at + 10 + len)
+            .context("Truncated DNS record")?;
+        let address = match (rtype, data.len()) {
+            (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = data.try_into().unwrap_or_default();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => {
+                at += 10 + len;
+                continue;
+            }
+        };
+        addresses.push(address);
+        ttl = ttl.min(u32::from_be_bytes([
+            record[4], record[5], record[6], record[7],
+        ]));
+        at += 10 + len;
+    }
+    Ok((addresses, ttl))
+}
+
+/// A response to `query` with `ips` as records of the type asked for,
+/// each to live `ttl` seconds.
+#[cfg(test)]
+pub fn response(query: &[u8], ips: &[IpAddr], ttl: u32) -> Vec<u8> {
+    let qtype = u16::from_be_bytes([query[query.len() - 4], query[query.len() - 3]]);
+    let records: Vec<Vec<u8>> = ips
+        .iter()
+        .filter_map(|ip| match (ip, qtype) {
+            (IpAddr::V4(ip), TYPE_A) => Some(ip.octets().to_vec()),
+            (IpAddr::V6(ip), TYPE_AAAA) => Some(ip.octets().to_vec()),
+            _ => None,
+        })
+        .collect();
+
+    let mut message = query[..2].to_vec();
+    message.extend_from_slice(&(FLAG_RESPONSE | FLAG_RECURSION_DESIRED | 0x0080).to_be_bytes());
+    message.extend_from_slice(&[0, 1]);
+    message.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    message.extend_from_slice(&[0, 0, 0, 0]);
+    message.extend_from_slice(&query[HEADER_LEN..]);
+    for data in records {
+        // The name is a pointer to the one in the question.
+        message.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        message.extend_from_slice(&qtype.to_be_bytes());
+        message.extend_from_slice(&1u16.to_be_bytes());
+        message.extend_from_slice(&ttl.to_be_bytes());
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(&data);
+    }
+    message
+}
+
+/// The offset just past the name at `at`, which may end in a pointer.
+fn skip_name(message: &[u8], mut at: usize) -> Result<usize> {
+    loop {
+        let len = *message.get(at).context("Truncated DNS name")?;
+        match len {
+            0 => return Ok(at + 1),
+            len if len & 0xc0 == 0xc0 => return Ok(at + 2),
+            len => at += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_addresses_and_the_shortest_ttl() {
+        let query = query(7, "example.com.", TYPE_A).unwrap();
+        let ips: [IpAddr; 2] = ["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+        let response = response(&query, &ips, 300);
+        assert_eq!(id(&response), Some(7));
+        // Records of the other type are not in the answer.
+        assert_eq!(addresses(&response).unwrap(), (vec![ips[0]], 300));
+
+        let mut nxdomain = response;
+        nxdomain[3] |= RCODE_NXDOMAIN as u8;
+        assert!(addresses(&nxdomain).is_err());
+    }
+}
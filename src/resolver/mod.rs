@@ -0,0 +1,168 @@
+//! Name resolution for every outbound connection and UDP packet.
+//!
+//! Destinations, proxy upstreams and the REALITY target are all looked up
+//! here rather than each with the system resolver. `[dns]` can send the
+//! queries to its own servers, plain or encrypted (DNS over TLS or HTTPS),
+//! and pick other servers for some domains, such as an internal zone only
+//! a LAN server knows. Servers are asked in order until one answers within
+//! the timeout, and answers are kept for their TTL.
+
+mod cache;
+mod message;
+mod upstream;
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use arc_swap::ArcSwap;
+use once_cell::sync::Lazy;
+use tracing::{debug, info};
+
+use crate::config::DnsConfig;
+use cache::Cache;
+pub use upstream::Upstream;
+
+static RESOLVER: Lazy<ArcSwap<Resolver>> = Lazy::new(ArcSwap::default);
+
+struct Resolver {
+    servers: Vec<Upstream>,
+    /// Lowercased domains and the servers asked for them and their
+    /// subdomains.
+    rules: Vec<(Vec<String>, Vec<Upstream>)>,
+    timeout: Duration,
+    cache: Cache,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self {
+            servers: vec![Upstream::System],
+            rules: Vec::new(),
+            timeout: DnsConfig::default().timeout(),
+            cache: Cache::default(),
+        }
+    }
+}
+
+impl Resolver {
+    /// The servers to ask about `name`, lowercased.
+    fn servers_for(&self, name: &str) -> &[Upstream] {
+        self.rules
+            .iter()
+            .find(|(domains, _)| {
+                domains.iter().any(|domain| {
+                    name.strip_suffix(domain.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+                })
+            })
+            .map_or(&self.servers, |(_, servers)| servers)
+    }
+}
+
+/// Loads `[dns]`, replacing the servers of an earlier call.
+pub fn init(config: &DnsConfig) -> Result<()> {
+    prepare(config).map(Prepared::install)
+}
+
+/// Servers read by [`prepare`] that are not asked anything until
+/// installed.
+pub struct Prepared(Resolver);
+
+impl Prepared {
+    /// Resolves with these servers from now on, replacing those in place.
+    pub fn install(self) {
+        let resolver = self.0;
+        if !matches!(resolver.servers[..], [Upstream::System]) || !resolver.rules.is_empty() {
+            let names: Vec<String> = resolver.servers.iter().map(Upstream::to_string).collect();
+            info!(
+                "[DNS] Resolving with {}, {} domain rule(s)",
+                names.join(", "),
+                resolver.rules.len()
+            );
+        }
+        RESOLVER.store(Arc::new(resolver));
+    }
+}
+
+/// Reads `[dns]` without resolving anything with it yet.
+pub fn prepare(config: &DnsConfig) -> Result<Prepared> {
+    let parse = |specs: &[String]| {
+        specs
+            .iter()
+            .map(|spec| Upstream::parse(spec))
+            .collect::<Result<Vec<_>>>()
+    };
+    let mut servers = parse(config.servers())?;
+    if servers.is_empty() {
+        servers.push(Upstream::System);
+    }
+    let rules = config
+        .rules()
+        .iter()
+        .map(|rule| {
+            let domains = rule
+                .domains()
+                .iter()
+                .map(|domain| domain.trim_matches('.').to_ascii_lowercase())
+                .collect();
+            Ok((domains, parse(rule.servers())?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Prepared(Resolver {
+        servers,
+        rules,
+        timeout: config.timeout(),
+        cache: Cache::default(),
+    }))
+}
+
+/// Every address `host` resolves to, with `port`. IP addresses are taken
+/// as they are.
+pub async fn lookup(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    let name = host.trim_end_matches('.').to_ascii_lowercase();
+    let resolver = RESOLVER.load();
+    let with_port = |ips: &[IpAddr]| ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect();
+    if let Some(ips) = resolver.cache.get(&name) {
+        return Ok(with_port(&ips));
+    }
+
+    let mut last_error = None;
+    for server in resolver.servers_for(&name) {
+        match server.lookup(&name, resolver.timeout).await {
+            Ok((ips, ttl)) if !ips.is_empty() => {
+                resolver.cache.insert(&name, &ips, ttl);
+                return Ok(with_port(&ips));
+            }
+            Ok(_) => last_error = Some(anyhow!("no addresses found")),
+            Err(e) => {
+                debug!("[DNS] {} failed to resolve {}: {:#}", server, name, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| anyhow!("no server to ask"))
+        .context(format!("Failed to resolve {}", host)))
+}
+
+/// [`lookup`] for a `host:port` string.
+pub async fn lookup_host(target: &str) -> Result<Vec<SocketAddr>> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    let (host, port) = target
+        .rsplit_once(':')
+        .with_context(|| format!("{:?} has no port", target))?;
+    let Ok(port) = port.parse() else {
+        bail!("{:?} has a bad port", target);
+    };
+    lookup(host, port).await
+}
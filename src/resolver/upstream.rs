@@ -0,0 +1,377 @@
+//! The servers a name can be looked up with and how each is asked.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+use super::message;
+#[cfg(feature = "trojan")]
+use crate::net::http::Client;
+use crate::net::idle::Idle;
+use crate::net::tls;
+
+/// Largest response taken over UDP; longer ones come truncated and are
+/// asked again over TCP.
+const MAX_UDP_RESPONSE: usize = 1232;
+
+/// How long answers of the system resolver, which gives no TTLs, are
+/// kept.
+const SYSTEM_TTL: Duration = Duration::from_secs(30);
+
+/// Content type of DNS over HTTPS messages (RFC 8484).
+#[cfg(feature = "trojan")]
+const DNS_MESSAGE: &str = "application/dns-message";
+
+#[derive(Debug, Clone)]
+pub enum Upstream {
+    /// The operating system's resolver.
+    System,
+    Udp(SocketAddr),
+    Tcp {
+        addr: SocketAddr,
+        idle: Arc<Idle<TcpStream>>,
+    },
+    /// DNS over TLS to `addr`, whose certificate must name `host`.
+    Tls {
+        addr: String,
+        host: String,
+        idle: Arc<Idle<tls::Stream>>,
+    },
+    /// DNS over HTTPS, POSTing to the URL.
+    #[cfg(feature = "trojan")]
+    Https(Arc<Client>),
+}
+
+impl Upstream {
+    /// Parses `system` or a `udp://`, `tcp://`, `tls://` or `https://` URL.
+    pub fn parse(spec: &str) -> Result<Self> {
+        if spec == "system" {
+            return Ok(Upstream::System);
+        }
+        if spec.starts_with("https://") {
+            #[cfg(feature = "trojan")]
+            return Client::new(spec).map(|client| Upstream::Https(Arc::new(client)));
+            #[cfg(not(feature = "trojan"))]
+            bail!("https:// needs a build with the trojan feature");
+        }
+
+        let Some((scheme, rest)) = spec.split_once("://") else {
+            bail!("{:?} is neither \"system\" nor a URL", spec);
+        };
+        let (host, port) = split_host_port(rest.trim_end_matches('/'))
+            .with_context(|| format!("{:?} has a bad host or port", spec))?;
+        match scheme {
+            "udp" | "tcp" => {
+                let ip: IpAddr = host
+                    .parse()
+                    .with_context(|| format!("{:?} is not an IP address", host))?;
+                let addr = SocketAddr::new(ip, port.unwrap_or(53));
+                Ok(if scheme == "udp" {
+                    Upstream::Udp(addr)
+                } else {
+                    Upstream::Tcp {
+                        addr,
+                        idle: Arc::default(),
+                    }
+                })
+            }
+            "tls" => {
+                if !cfg!(feature = "trojan") {
+                    bail!("tls:// needs a build with the trojan feature");
+                }
+                let port = port.unwrap_or(853);
+                let addr = match host.parse::<IpAddr>() {
+                    Ok(ip) => SocketAddr::new(ip, port).to_string(),
+                    Err(_) => format!("{}:{}", host, port),
+                };
+                Ok(Upstream::Tls {
+                    addr,
+                    host: host.to_string(),
+                    idle: Arc::default(),
+                })
+            }
+            _ => bail!("{:?} is not one of udp, tcp, tls or https", scheme),
+        }
+    }
+
+    /// Asks for the A and AAAA records of `name`, and how long the answer
+    /// may be kept. Servers other than the system's list IPv4 addresses
+    /// first.
+    pub async fn lookup(&self, name: &str, timeout: Duration) -> Result<(Vec<IpAddr>, Duration)> {
+        if let Upstream::System = self {
+            let addrs = tokio::time::timeout(timeout, tokio::net::lookup_host((name, 0)))
+                .await
+                .context("Timed out")??;
+            return Ok((addrs.map(|addr| addr.ip()).collect(), SYSTEM_TTL));
+        }
+
+        let queries = [message::TYPE_A, message::TYPE_AAAA]
+            .into_iter()
+            .map(|qtype| message::query(rand::random(), name, qtype))
+            .collect::<Result<Vec<_>>>()?;
+        let responses = tokio::time::timeout(timeout, self.exchange(&queries, timeout))
+            .await
+            .context("Timed out")??;
+
+        let mut addresses = Vec::new();
+        let mut ttl = u32::MAX;
+        let mut error = None;
+        for response in responses {
+            match message::addresses(&response) {
+                Ok((found, found_ttl)) => {
+                    addresses.extend(found);
+                    ttl = ttl.min(found_ttl);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) if addresses.is_empty() => Err(e),
+            _ => Ok((addresses, Duration::from_secs(ttl.into()))),
+        }
+    }
+
+    /// Sends `queries` and returns the responses in the same order.
+    #[cfg_attr(not(feature = "trojan"), allow(unused_variables))]
+    async fn exchange(&self, queries: &[Vec<u8>], timeout: Duration) -> Result<Vec<Vec<u8>>> {
+        match self {
+            Upstream::System => unreachable!("the system resolver takes no queries"),
+            Upstream::Udp(addr) => {
+                let responses = exchange_udp(*addr, queries).await?;
+                if responses
+                    .iter()
+                    .any(|response| message::truncated(response))
+                {
+                    let mut stream = TcpStream::connect(addr).await?;
+                    return exchange_stream(&mut stream, queries).await;
+                }
+                Ok(responses)
+            }
+            Upstream::Tcp { addr, idle } => {
+                exchange_kept(idle, queries, || async {
+                    Ok(TcpStream::connect(addr).await?)
+                })
+                .await
+            }
+            Upstream::Tls { addr, host, idle } => {
+                exchange_kept(idle, queries, || async {
+                    let stream = TcpStream::connect(addr.as_str())
+                        .await
+                        .with_context(|| format!("Failed to connect to {}", addr))?;
+                    tls::connect(stream, host).await
+                })
+                .await
+            }
+            #[cfg(feature = "trojan")]
+            Upstream::Https(client) => {
+                // One request per connection at a time, so the queries go
+                // in parallel.
+                let requests: Vec<_> = queries
+                    .iter()
+                    .map(|query| {
+                        tokio::spawn(exchange_https(Arc::clone(client), query.clone(), timeout))
+                    })
+                    .collect();
+                let mut responses = Vec::new();
+                for request in requests {
+                    responses.push(request.await??);
+                }
+                Ok(responses)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Upstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Upstream::System => f.write_str("system"),
+            Upstream::Udp(addr) => write!(f, "udp://{}", addr),
+            Upstream::Tcp { addr, .. } => write!(f, "tcp://{}", addr),
+            Upstream::Tls { addr, .. } => write!(f, "tls://{}", addr),
+            #[cfg(feature = "trojan")]
+            Upstream::Https(client) => f.write_str(client.url()),
+        }
+    }
+}
+
+/// Splits `host[:port]`, with IPv6 addresses in brackets.
+fn split_host_port(authority: &str) -> Option<(&str, Option<u16>)> {
+    if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']')?;
+        return match rest.strip_prefix(':') {
+            Some(port) => Some((host, Some(port.parse().ok()?))),
+            None if rest.is_empty() => Some((host, None)),
+            None => None,
+        };
+    }
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, Some(port.parse().ok()?)),
+        None => (authority, None),
+    };
+    (!host.is_empty()).then_some((host, port))
+}
+
+/// POSTs `query` to a DNS over HTTPS server.
+#[cfg(feature = "trojan")]
+async fn exchange_https(client: Arc<Client>, query: Vec<u8>, timeout: Duration) -> Result<Vec<u8>> {
+    let response = client.post(DNS_MESSAGE, &query, timeout).await?;
+    if response.status() != 200 {
+        bail!("{} replied {}", client.url(), response.status());
+    }
+    Ok(response.body().to_vec())
+}
+
+/// [`exchange_stream`] over a connection from `idle`, or one `connect`
+/// opens if there is none or the server closed it, which is kept open for
+/// the next lookup.
+async fn exchange_kept<S, F, C>(
+    idle: &Idle<S>,
+    queries: &[Vec<u8>],
+    connect: C,
+) -> Result<Vec<Vec<u8>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Future<Output = Result<S>>,
+    C: FnOnce() -> F,
+{
+    if let Some(mut stream) = idle.take()
+        && let Ok(responses) = exchange_stream(&mut stream, queries).await
+    {
+        idle.put(stream);
+        return Ok(responses);
+    }
+    let mut stream = connect().await?;
+    let responses = exchange_stream(&mut stream, queries).await?;
+    idle.put(stream);
+    Ok(responses)
+}
+
+/// Sends every query in one datagram each, and waits for the answers.
+async fn exchange_udp(addr: SocketAddr, queries: &[Vec<u8>]) -> Result<Vec<Vec<u8>>> {
+    let local: SocketAddr = if addr.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    for query in queries {
+        socket.send(query).await?;
+    }
+
+    let mut responses: Vec<Option<Vec<u8>>> = vec![None; queries.len()];
+    let mut buf = [0u8; MAX_UDP_RESPONSE];
+    while responses.iter().any(Option::is_none) {
+        let len = socket.recv(&mut buf).await?;
+        let response = &buf[..len];
+        // Stray or spoofed datagrams carry ids nothing asked for.
+        if let Some(i) = queries
+            .iter()
+            .position(|query| message::id(query) == message::id(response))
+        {
+            responses[i] = Some(response.to_vec());
+        }
+    }
+    Ok(responses.into_iter().flatten().collect())
+}
+
+/// Sends every query length-prefixed on one stream, as TCP and TLS
+/// upstreams take them, and reads the answers, which may come in any order.
+async fn exchange_stream<S>(stream: &mut S, queries: &[Vec<u8>]) -> Result<Vec<Vec<u8>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Vec::new();
+    for query in queries {
+        framed.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        framed.extend_from_slice(query);
+    }
+    stream.write_all(&framed).await?;
+
+    let mut responses: Vec<Option<Vec<u8>>> = vec![None; queries.len()];
+    while responses.iter().any(Option::is_none) {
+        let len = stream.read_u16().await? as usize;
+        let mut response = vec![0u8; len];
+        stream.read_exact(&mut response).await?;
+        if let Some(i) = queries
+            .iter()
+            .position(|query| message::id(query) == message::id(&response))
+        {
+            responses[i] = Some(response);
+        }
+    }
+    Ok(responses.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A TCP DNS server answering every A query with 192.0.2.1, closing
+    /// connections after `queries` of them. Returns its address and how
+    /// many connections it took.
+    async fn serve(queries: usize) -> (SocketAddr, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    for _ in 0..queries {
+                        let Ok(len) = stream.read_u16().await else {
+                            return;
+                        };
+                        let mut query = vec![0u8; len as usize];
+                        stream.read_exact(&mut query).await.unwrap();
+                        let ip = "192.0.2.1".parse().unwrap();
+                        let response = message::response(&query, &[ip], 60);
+                        stream.write_u16(response.len() as u16).await.unwrap();
+                        stream.write_all(&response).await.unwrap();
+                    }
+                });
+            }
+        });
+        (addr, accepted)
+    }
+
+    async fn lookup_thrice(upstream: &Upstream) {
+        for _ in 0..3 {
+            let (ips, ttl) = upstream
+                .lookup("example.com", Duration::from_secs(5))
+                .await
+                .unwrap();
+            assert_eq!(ips, ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+            assert_eq!(ttl, Duration::from_secs(60));
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_connections_open_between_lookups() {
+        let (addr, accepted) = serve(usize::MAX).await;
+        let upstream = Upstream::parse(&format!("tcp://{}", addr)).unwrap();
+        lookup_thrice(&upstream).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn reconnects_when_the_server_closed_the_connection() {
+        // Each lookup asks for A and AAAA.
+        let (addr, accepted) = serve(2).await;
+        let upstream = Upstream::parse(&format!("tcp://{}", addr)).unwrap();
+        lookup_thrice(&upstream).await;
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,141 @@
+//! Reading v2ray `geosite.dat` files: a protobuf `GeoSiteList` of
+//! `GeoSite { country_code = 1; repeated Domain domain = 2; }` entries,
+//! each `Domain { Type type = 1; string value = 2; ... }`. Only the few
+//! fields needed are decoded, by hand, to spare a protobuf dependency.
+
+use anyhow::{Result, bail};
+
+use crate::router::rule_set::Kind;
+
+/// Wire types of the protobuf encoding.
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LENGTH_DELIMITED: u64 = 2;
+const FIXED32: u64 = 5;
+
+/// The domains of the category `code`, matched case-insensitively.
+pub fn entries(data: &[u8], code: &str) -> Result<Vec<(Kind, String)>> {
+    let mut entries = Vec::new();
+    let mut found = false;
+
+    let mut list = Fields::new(data);
+    while let Some((field, value)) = list.next_field()? {
+        let (1, Value::Bytes(site)) = (field, value) else {
+            continue;
+        };
+        if !site_code(site)?.eq_ignore_ascii_case(code) {
+            continue;
+        }
+        found = true;
+
+        let mut site = Fields::new(site);
+        while let Some((field, value)) = site.next_field()? {
+            if let (2, Value::Bytes(domain)) = (field, value)
+                && let Some(entry) = domain_entry(domain)?
+            {
+                entries.push(entry);
+            }
+        }
+    }
+
+    if !found {
+        bail!("no category {:?}", code);
+    }
+    Ok(entries)
+}
+
+fn site_code(site: &[u8]) -> Result<&str> {
+    let mut fields = Fields::new(site);
+    while let Some((field, value)) = fields.next_field()? {
+        if let (1, Value::Bytes(code)) = (field, value) {
+            return Ok(std::str::from_utf8(code)?);
+        }
+    }
+    Ok("")
+}
+
+fn domain_entry(domain: &[u8]) -> Result<Option<(Kind, String)>> {
+    // Type defaults to 0, Plain, when the field is left out.
+    let mut kind = 0;
+    let mut value = "";
+    let mut fields = Fields::new(domain);
+    while let Some((field, field_value)) = fields.next_field()? {
+        match (field, field_value) {
+            (1, Value::Varint(v)) => kind = v,
+            (2, Value::Bytes(bytes)) => value = std::str::from_utf8(bytes)?,
+            _ => {}
+        }
+    }
+
+    let kind = match kind {
+        0 => Kind::Keyword,
+        1 => Kind::Regex,
+        2 => Kind::Suffix,
+        3 => Kind::Full,
+        _ => return Ok(None),
+    };
+    Ok((!value.is_empty()).then(|| (kind, value.to_string())))
+}
+
+enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// The fields of one protobuf message.
+struct Fields<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u64, Value<'a>)>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            VARINT => Value::Varint(self.varint()?),
+            LENGTH_DELIMITED => {
+                let len = usize::try_from(self.varint()?)?;
+                Value::Bytes(self.take(len)?)
+            }
+            FIXED64 => {
+                self.take(8)?;
+                Value::Fixed
+            }
+            FIXED32 => {
+                self.take(4)?;
+                Value::Fixed
+            }
+            wire_type => bail!("unsupported wire type {}", wire_type),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for (i, byte) in self.buf.iter().enumerate().take(10) {
+            value |= u64::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.buf = &self.buf[i + 1..];
+                return Ok(value);
+            }
+        }
+        bail!("truncated varint")
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.buf.len() {
+            bail!("truncated field");
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+}
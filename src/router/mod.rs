@@ -0,0 +1,371 @@
+//! Rule-based routing of outbound connects.
+//!
+//! `[[router.rules]]` are tried in order against the target of each Trojan
+//! and TUIC connect (the host the client named, the addresses it resolved
+//! to and the port) and against who asked for it (user, the groups its
+//! `[[users]]` entry puts it in, and inbound protocol). Hosts can also be looked up in `[[router.rule_sets]]`,
+//! domain lists and `geosite.dat` categories loaded by [`rule_set`].
+//!
+//! The first rule that matches picks the outbound: `direct`, `block`, or
+//! a named `[[outbounds]]` upstream (Trojan, SOCKS5 or HTTP). Without a
+//! match, `router.default` decides, and without that the inbound does as
+//! it always has.
+
+pub mod geosite;
+pub mod rule_set;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use tracing::{debug, info};
+
+use crate::config::{IdentityConfig, OutboundConfig, OutboundKind, RouteRuleConfig, RouterConfig};
+use crate::control::registry::SessionGuard;
+use crate::net::sockopt::{Bind, TcpOptions};
+use crate::net::util::parse_port_range;
+use crate::outbound::group::GroupOutbound;
+use crate::outbound::http::HttpOutbound;
+use crate::outbound::socks5::Socks5Outbound;
+#[cfg(feature = "trojan")]
+use crate::outbound::trojan::TrojanOutbound;
+use crate::outbound::{DirectOutbound, Outbound};
+use crate::router::rule_set::RuleSet;
+
+static ROUTER: Lazy<ArcSwap<Router>> = Lazy::new(ArcSwap::default);
+
+#[derive(Default)]
+struct Router {
+    rules: Vec<(Rule, Route)>,
+    default: Option<Route>,
+    /// `[[users]]` groups by user name.
+    groups: HashMap<String, Vec<String>>,
+}
+
+/// Where a connect goes.
+#[derive(Clone)]
+pub enum Route {
+    /// Dialed from this host.
+    Direct,
+    /// Refused.
+    Block,
+    /// Through a named `[[outbounds]]` entry.
+    Upstream(Arc<dyn Outbound>),
+}
+
+/// A `[[router.rules]]` entry, compiled.
+pub struct Rule {
+    domain_suffix: Vec<String>,
+    domain_keyword: Vec<String>,
+    domain_regex: Vec<Regex>,
+    /// Filled in by [`prepare`] once the sets are loaded.
+    rule_sets: Vec<Arc<RuleSet>>,
+    ip_cidr: Vec<IpNet>,
+    ports: Vec<RangeInclusive<u16>>,
+    users: Vec<String>,
+    user_groups: Vec<String>,
+    protocols: Vec<String>,
+    outbound: String,
+}
+
+impl Rule {
+    pub fn compile(config: &RouteRuleConfig) -> Result<Self> {
+        let lowercase = |values: &[String]| -> Vec<String> {
+            values
+                .iter()
+                .map(|value| value.trim().trim_matches('.').to_ascii_lowercase())
+                .collect()
+        };
+
+        let domain_regex = config
+            .domain_regex()
+            .iter()
+            .map(|pattern| {
+                Regex::new(pattern).with_context(|| format!("Bad domain_regex {:?}", pattern))
+            })
+            .collect::<Result<_>>()?;
+
+        let ip_cidr = config
+            .ip_cidr()
+            .iter()
+            .map(|value| {
+                crate::security::banlist::parse_net(value)
+                    .with_context(|| format!("Bad ip_cidr {:?}", value))
+            })
+            .collect::<Result<_>>()?;
+
+        let ports = config
+            .port()
+            .iter()
+            .map(|spec| parse_port_range(spec))
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            domain_suffix: lowercase(config.domain_suffix()),
+            domain_keyword: lowercase(config.domain_keyword()),
+            domain_regex,
+            rule_sets: Vec::new(),
+            ip_cidr,
+            ports,
+            users: config.user().to_vec(),
+            user_groups: config.user_group().to_vec(),
+            protocols: lowercase(config.protocol()),
+            outbound: config.outbound().to_string(),
+        })
+    }
+
+    fn matches(&self, request: &Request) -> bool {
+        let domain = request.domain;
+        (self.domain_suffix.is_empty()
+            || domain.is_some_and(|domain| {
+                self.domain_suffix.iter().any(|suffix| {
+                    domain
+                        .strip_suffix(suffix.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+                })
+            }))
+            && (self.domain_keyword.is_empty()
+                || domain.is_some_and(|domain| {
+                    self.domain_keyword
+                        .iter()
+                        .any(|keyword| domain.contains(keyword.as_str()))
+                }))
+            && (self.domain_regex.is_empty()
+                || domain.is_some_and(|domain| {
+                    self.domain_regex.iter().any(|regex| regex.is_match(domain))
+                }))
+            && (self.rule_sets.is_empty()
+                || domain
+                    .is_some_and(|domain| self.rule_sets.iter().any(|set| set.contains(domain))))
+            && (self.ip_cidr.is_empty()
+                || request.addrs.iter().any(|addr| {
+                    let ip = addr.ip().to_canonical();
+                    self.ip_cidr.iter().any(|net| net.contains(&ip))
+                }))
+            && (self.ports.is_empty()
+                || self.ports.iter().any(|ports| ports.contains(&request.port)))
+            && (self.users.is_empty()
+                || request
+                    .user
+                    .as_deref()
+                    .is_some_and(|user| self.users.iter().any(|name| name == user)))
+            && (self.user_groups.is_empty()
+                || request
+                    .groups
+                    .iter()
+                    .any(|group| self.user_groups.contains(group)))
+            && (self.protocols.is_empty()
+                || self
+                    .protocols
+                    .iter()
+                    .any(|protocol| protocol.eq_ignore_ascii_case(request.protocol)))
+    }
+}
+
+/// What a connect is routed on.
+struct Request<'a> {
+    protocol: &'a str,
+    user: Option<String>,
+    /// Groups of `user`.
+    groups: &'a [String],
+    /// Lowercase, without a trailing dot.
+    domain: Option<&'a str>,
+    addrs: &'a [SocketAddr],
+    port: u16,
+}
+
+/// A router compiled by [`prepare`] that routes nothing until installed.
+pub struct Prepared {
+    router: Router,
+    /// Outbounds built, or `None` without a `[router]`.
+    outbounds: Option<usize>,
+}
+
+impl Prepared {
+    /// Routes connects from now on, replacing the router in place.
+    pub fn install(self) {
+        if let Some(outbounds) = self.outbounds {
+            info!(
+                "[Router] Loaded {} rule(s) and {} outbound(s)",
+                self.router.rules.len(),
+                outbounds
+            );
+        }
+        ROUTER.store(Arc::new(self.router));
+    }
+}
+
+/// Compiles the router and builds its outbounds, replacing those of an
+/// earlier call. On error the routes in place are kept.
+pub fn init(
+    config: Option<&RouterConfig>,
+    outbounds: &[OutboundConfig],
+    identities: &[IdentityConfig],
+) -> Result<()> {
+    prepare(config, outbounds, identities).map(Prepared::install)
+}
+
+/// Compiles the router and builds its outbounds without routing anything
+/// through them yet.
+pub fn prepare(
+    config: Option<&RouterConfig>,
+    outbounds: &[OutboundConfig],
+    identities: &[IdentityConfig],
+) -> Result<Prepared> {
+    let Some(config) = config else {
+        return Ok(Prepared {
+            router: Router::default(),
+            outbounds: None,
+        });
+    };
+
+    let mut routes = HashMap::from([
+        ("direct".to_string(), Route::Direct),
+        ("block".to_string(), Route::Block),
+    ]);
+    // Groups last, once the outbounds they balance over are built.
+    let (groups, singles): (Vec<_>, Vec<_>) = outbounds
+        .iter()
+        .partition(|outbound| matches!(outbound.kind(), OutboundKind::Group(_)));
+    for outbound in singles.into_iter().chain(groups) {
+        let route = build(outbound, &routes)
+            .with_context(|| format!("Failed to set up outbound {:?}", outbound.name()))?;
+        routes.insert(outbound.name().to_string(), route);
+    }
+    let lookup = |name: &str| {
+        routes
+            .get(name)
+            .cloned()
+            .with_context(|| format!("No outbound named {:?}", name))
+    };
+
+    let mut sets = HashMap::new();
+    for set in config.rule_sets() {
+        let loaded = RuleSet::load(set)
+            .with_context(|| format!("Failed to load rule set {:?}", set.name()))?;
+        sets.insert(set.name(), Arc::new(loaded));
+    }
+
+    let mut rules = Vec::with_capacity(config.rules().len());
+    for (i, rule_config) in config.rules().iter().enumerate() {
+        let mut rule =
+            Rule::compile(rule_config).with_context(|| format!("router.rules[{}]", i))?;
+        for name in rule_config.rule_set() {
+            let set = sets
+                .get(name.as_str())
+                .with_context(|| format!("No rule set named {:?}", name))?;
+            rule.rule_sets.push(Arc::clone(set));
+        }
+        let route = lookup(&rule.outbound)?;
+        rules.push((rule, route));
+    }
+    let default = config.default_outbound().map(lookup).transpose()?;
+
+    let groups = identities
+        .iter()
+        .filter(|identity| !identity.groups().is_empty())
+        .map(|identity| (identity.name().to_string(), identity.groups().to_vec()))
+        .collect();
+
+    Ok(Prepared {
+        router: Router {
+            rules,
+            default,
+            groups,
+        },
+        outbounds: Some(outbounds.len()),
+    })
+}
+
+/// Builds one outbound; a group's members are looked up in `built`.
+fn build(outbound: &OutboundConfig, built: &HashMap<String, Route>) -> Result<Route> {
+    let bind = Bind::from_config(outbound.bind())?.map(Arc::new);
+    let name = outbound.name();
+    let outbound: Arc<dyn Outbound> = match outbound.kind() {
+        OutboundKind::Direct => {
+            Arc::new(DirectOutbound::new(TcpOptions::default()).with_bind(bind))
+        }
+        #[cfg(feature = "trojan")]
+        OutboundKind::Trojan(upstream) => Arc::new(TrojanOutbound::from_config(upstream, bind)?),
+        #[cfg(not(feature = "trojan"))]
+        OutboundKind::Trojan(_) => {
+            anyhow::bail!("Trojan upstreams need a build with the trojan feature")
+        }
+        OutboundKind::Socks5(upstream) => Arc::new(Socks5Outbound::from_config(upstream, bind)?),
+        OutboundKind::Http(upstream) => Arc::new(HttpOutbound::from_config(upstream, bind)?),
+        OutboundKind::Group(group) => {
+            let members = group
+                .members()
+                .iter()
+                .map(|member| match built.get(member) {
+                    Some(Route::Upstream(outbound)) => Ok((member.clone(), Arc::clone(outbound))),
+                    _ => anyhow::bail!("No outbound named {:?} to balance over", member),
+                })
+                .collect::<Result<_>>()?;
+            Arc::new(GroupOutbound::new(name, group, members))
+        }
+    };
+    Ok(Route::Upstream(outbound))
+}
+
+/// Picks the route for the session's connect to `port` at `domain`, when
+/// the client named one, resolved to `addrs`. `None` when no rule matches
+/// and no default is set.
+pub fn route(
+    session: &SessionGuard,
+    domain: Option<&str>,
+    port: u16,
+    addrs: &[SocketAddr],
+) -> Option<Route> {
+    let router = ROUTER.load();
+    if router.rules.is_empty() {
+        return router.default.clone();
+    }
+
+    let domain = domain.map(|domain| domain.trim_end_matches('.').to_ascii_lowercase());
+    let user = session.user();
+    let groups = user
+        .as_deref()
+        .and_then(|user| router.groups.get(user))
+        .map_or(&[][..], Vec::as_slice);
+    let request = Request {
+        protocol: session.protocol(),
+        user,
+        groups,
+        domain: domain.as_deref(),
+        addrs,
+        port,
+    };
+
+    match router
+        .rules
+        .iter()
+        .enumerate()
+        .find(|(_, (rule, _))| rule.matches(&request))
+    {
+        Some((i, (rule, route))) => {
+            debug!(
+                "[Router] Rule {} matched {} port {} for user {}, going {}",
+                i,
+                request.domain.map_or_else(
+                    || addrs
+                        .first()
+                        .map(|addr| addr.ip().to_string())
+                        .unwrap_or_default(),
+                    str::to_string
+                ),
+                port,
+                request.user.as_deref().unwrap_or("-"),
+                rule.outbound
+            );
+            Some(route.clone())
+        }
+        None => router.default.clone(),
+    }
+}
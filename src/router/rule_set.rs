@@ -0,0 +1,202 @@
+//! Named sets of domains for routing rules, read from v2ray `geosite.dat`
+//! files or plain domain lists.
+//!
+//! Suffix and full-name entries go into a trie keyed by label from the
+//! right, so a lookup costs one step per label of the host however many
+//! domains the set holds; labels are interned, since lists of millions
+//! repeat `com` and `www` a great deal. Keywords and regular expressions
+//! are tried one after another.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use regex::RegexSet;
+use tracing::{info, warn};
+
+use crate::config::RuleSetConfig;
+use crate::router::geosite;
+
+/// How an entry matches a host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// The domain and everything below it.
+    Suffix,
+    /// The domain only.
+    Full,
+    /// Hosts containing it.
+    Keyword,
+    /// Hosts matching it as a regular expression.
+    Regex,
+}
+
+/// Domains of one `[[router.rule_sets]]` entry.
+#[derive(Default)]
+pub struct RuleSet {
+    trie: DomainTrie,
+    keywords: Vec<String>,
+    regexes: Option<RegexSet>,
+    len: usize,
+}
+
+impl RuleSet {
+    /// Reads the file the entry names.
+    pub fn load(config: &RuleSetConfig) -> Result<Self> {
+        let path = Path::new(config.path());
+        let entries = match config.code() {
+            Some(code) => {
+                let data =
+                    std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+                geosite::entries(&data, code)
+                    .with_context(|| format!("Bad geosite file {:?}", path))?
+            }
+            None => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {:?}", path))?;
+                parse_list(&text)
+            }
+        };
+        if entries.is_empty() {
+            bail!("{:?} has no domains for rule set {:?}", path, config.name());
+        }
+
+        let set = Self::from_entries(entries)?;
+        info!(
+            "[Router] Rule set {:?} holds {} entries from {}",
+            config.name(),
+            set.len,
+            config.path()
+        );
+        Ok(set)
+    }
+
+    fn from_entries(entries: Vec<(Kind, String)>) -> Result<Self> {
+        let mut set = Self {
+            len: entries.len(),
+            ..Default::default()
+        };
+        let mut patterns = Vec::new();
+        for (kind, value) in entries {
+            let domain = || value.trim_end_matches('.').to_ascii_lowercase();
+            match kind {
+                Kind::Suffix => set.trie.insert(&domain(), SUFFIX),
+                Kind::Full => set.trie.insert(&domain(), FULL),
+                Kind::Keyword => set.keywords.push(domain()),
+                Kind::Regex => patterns.push(value),
+            }
+        }
+        if !patterns.is_empty() {
+            set.regexes = Some(RegexSet::new(&patterns).context("Bad regexp entry")?);
+        }
+        Ok(set)
+    }
+
+    /// Whether `host`, lowercase and without a trailing dot, is in the set.
+    pub fn contains(&self, host: &str) -> bool {
+        self.trie.contains(host)
+            || self
+                .keywords
+                .iter()
+                .any(|keyword| host.contains(keyword.as_str()))
+            || self
+                .regexes
+                .as_ref()
+                .is_some_and(|regexes| regexes.is_match(host))
+    }
+}
+
+/// Parses a domain list in the format of v2fly's domain-list-community:
+/// `domain:`, `full:`, `keyword:` or `regexp:` and a value per line, `#`
+/// starting a comment and ` @attribute`s after the value ignored.
+fn parse_list(text: &str) -> Vec<(Kind, String)> {
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let Some(entry) = line.split_whitespace().next() else {
+            continue;
+        };
+
+        let (kind, value) = match entry.split_once(':') {
+            Some(("domain", value)) => (Kind::Suffix, value),
+            Some(("full", value)) => (Kind::Full, value),
+            Some(("keyword", value)) => (Kind::Keyword, value),
+            Some(("regexp", value)) => (Kind::Regex, value),
+            Some((prefix, _)) => {
+                warn!(
+                    "[Router] Skipping {:?}: {}: is not supported",
+                    entry, prefix
+                );
+                continue;
+            }
+            None => (
+                Kind::Suffix,
+                entry.trim_start_matches("*.").trim_start_matches('.'),
+            ),
+        };
+        if !value.is_empty() {
+            entries.push((kind, value.to_string()));
+        }
+    }
+    entries
+}
+
+/// The node matches its domain and everything below it.
+const SUFFIX: u8 = 1;
+/// The node matches its domain only.
+const FULL: u8 = 2;
+
+/// Domains by label, from the top-level one down. Node 0 is the root;
+/// each edge is a (parent, label) pair in one map, which takes far less
+/// memory than a map per node.
+#[derive(Default)]
+struct DomainTrie {
+    labels: HashMap<Box<str>, u32>,
+    edges: HashMap<(u32, u32), u32>,
+    flags: Vec<u8>,
+}
+
+impl DomainTrie {
+    fn insert(&mut self, domain: &str, flag: u8) {
+        if self.flags.is_empty() {
+            self.flags.push(0);
+        }
+
+        let mut node = 0;
+        for label in domain.rsplit('.') {
+            let label = match self.labels.get(label) {
+                Some(&id) => id,
+                None => {
+                    let id = self.labels.len() as u32;
+                    self.labels.insert(label.into(), id);
+                    id
+                }
+            };
+            let next = self.flags.len() as u32;
+            node = *self.edges.entry((node, label)).or_insert(next);
+            if node == next {
+                self.flags.push(0);
+            }
+        }
+        self.flags[node as usize] |= flag;
+    }
+
+    fn contains(&self, host: &str) -> bool {
+        let mut node = 0;
+        let mut labels = host.rsplit('.').peekable();
+        while let Some(label) = labels.next() {
+            let Some(&label) = self.labels.get(label) else {
+                return false;
+            };
+            let Some(&next) = self.edges.get(&(node, label)) else {
+                return false;
+            };
+            node = next;
+
+            let flags = self.flags[node as usize];
+            if flags & SUFFIX != 0 || (flags & FULL != 0 && labels.peek().is_none()) {
+                return true;
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,73 @@
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::Local;
+use cron::Schedule;
+use tokio::sync::watch::Receiver;
+use tracing::{error, info};
+
+use crate::config::{ScheduleConfig, ScheduledAction};
+use crate::control::stats_summary;
+use crate::server::ServerManager;
+
+/// Spawns one task per `[[schedule]]` entry. Entries with an invalid cron
+/// expression are reported and skipped.
+pub fn spawn(entries: &[ScheduleConfig], servers: Arc<ServerManager>, shutdown_rx: Receiver<()>) {
+    for entry in entries {
+        let schedule = match Schedule::from_str(entry.cron()) {
+            Ok(schedule) => schedule,
+            Err(e) => {
+                error!(
+                    "[Scheduler] Invalid cron expression {:?}: {}",
+                    entry.cron(),
+                    e
+                );
+                continue;
+            }
+        };
+
+        info!(
+            "[Scheduler] {:?} on {} at {:?}",
+            entry.action(),
+            entry.server().unwrap_or("all servers"),
+            entry.cron()
+        );
+
+        tokio::spawn(run(
+            schedule,
+            entry.clone(),
+            Arc::clone(&servers),
+            shutdown_rx.clone(),
+        ));
+    }
+}
+
+async fn run(
+    schedule: Schedule,
+    entry: ScheduleConfig,
+    servers: Arc<ServerManager>,
+    mut shutdown_rx: Receiver<()>,
+) {
+    while let Some(next) = schedule.upcoming(Local).next() {
+        let delay = (next - Local::now()).to_std().unwrap_or_default();
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown_rx.changed() => break,
+        }
+
+        info!("[Scheduler] Running {:?}", entry.action());
+
+        match entry.action() {
+            ScheduledAction::Restart => {
+                let _ = servers.restart(entry.server()).await;
+            }
+            ScheduledAction::ReloadCerts => {
+                let _ = servers.reload_certificates(entry.server()).await;
+            }
+            ScheduledAction::StatsRollup => {
+                info!("[Stats] {}", stats_summary());
+            }
+        }
+    }
+}
@@ -0,0 +1,381 @@
+//! Temporary bans of source addresses that keep failing.
+//!
+//! Failed Trojan and TUIC authentication and failed TLS handshakes are
+//! counted per address off the event bus. An address that fails
+//! `max_failures` times within `window` is refused at the accept loops,
+//! before any TLS work, until its ban runs out. IPv6 clients are counted
+//! and banned by /64, since one host usually holds the whole network.
+//! Clients without an address of their own, such as those on a Unix
+//! socket, are never counted.
+//!
+//! Bans live in memory and end with the process, unless `path` names a
+//! JSON file: it is read at startup and rewritten whenever a ban is
+//! issued or lifted, with each ban's end as a Unix timestamp.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use dashmap::DashMap;
+use ipnet::IpNet;
+use once_cell::sync::Lazy;
+#[cfg(feature = "control")]
+use tokio::sync::Notify;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch::Receiver;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::config::BanlistConfig;
+use crate::events::{self, Event};
+
+/// The address clients without one of their own, such as those handed
+/// over on a Unix socket, are registered under. Their failures are not
+/// counted, or a few stalled local clients would get loopback banned.
+pub const UNADDRESSED: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Banned networks and when their bans end.
+static BANNED: Lazy<DashMap<IpNet, Instant>> = Lazy::new(DashMap::new);
+
+/// Tells the failure counter an operator lifted a ban, so the store
+/// forgets it too.
+#[cfg(feature = "control")]
+static LIFTED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Whether connections from `ip` are refused right now.
+pub fn is_banned(ip: IpAddr) -> bool {
+    if BANNED.is_empty() {
+        return false;
+    }
+    BANNED
+        .get(&network(ip))
+        .is_some_and(|until| *until > Instant::now())
+}
+
+/// Current bans as a table, for `iway ctl bans`.
+#[cfg(feature = "control")]
+pub fn render() -> String {
+    let now = Instant::now();
+    let mut bans: Vec<_> = BANNED
+        .iter()
+        .filter(|entry| *entry.value() > now)
+        .map(|entry| (*entry.key(), entry.value().duration_since(now)))
+        .collect();
+    bans.sort();
+
+    let mut out = format!("{:<44} {:>10}\n", "ADDRESS", "LEFT");
+    for (net, left) in bans {
+        out.push_str(&format!("{:<44} {:>9}s\n", label(net), left.as_secs()));
+    }
+    out
+}
+
+/// Lifts the ban on `ip`. Returns false if it was not banned.
+#[cfg(feature = "control")]
+pub fn unban(ip: IpAddr) -> bool {
+    let lifted = BANNED.remove(&network(ip)).is_some();
+    if lifted {
+        info!("[Banlist] Ban on {} lifted by operator", label(network(ip)));
+        LIFTED.notify_one();
+    }
+    lifted
+}
+
+/// Parses an `exempt` entry: an address or a network in CIDR notation.
+pub fn parse_net(value: &str) -> Option<IpNet> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+}
+
+/// Restores the stored bans and starts counting failures, if `[banlist]`
+/// is configured. A store that cannot be read is left alone and bans are
+/// not persisted, rather than overwriting it with an empty list.
+pub fn spawn(config: Option<&BanlistConfig>, shutdown_rx: Receiver<()>) {
+    let Some(config) = config else {
+        return;
+    };
+
+    let store = config
+        .path()
+        .map(PathBuf::from)
+        .and_then(|path| match load(&path) {
+            Ok(stored) => {
+                let restored = restore(stored);
+                info!(
+                    "[Banlist] Restored {} ban(s) from {}",
+                    restored,
+                    path.display()
+                );
+                Some(path)
+            }
+            Err(e) => {
+                warn!("[Banlist] Not persisting bans: {:#}", e);
+                None
+            }
+        });
+
+    info!(
+        "[Banlist] Banning addresses for {:?} after {} failure(s) within {:?}",
+        config.ban_duration(),
+        config.max_failures(),
+        config.window()
+    );
+
+    let exempt: Vec<IpNet> = config
+        .exempt()
+        .iter()
+        .filter_map(|value| parse_net(value))
+        .collect();
+    tokio::spawn(run(config.clone(), exempt, store, shutdown_rx));
+}
+
+async fn run(
+    config: BanlistConfig,
+    exempt: Vec<IpNet>,
+    store: Option<PathBuf>,
+    mut shutdown_rx: Receiver<()>,
+) {
+    let mut events = events::subscribe();
+    let mut sweep = tokio::time::interval(config.window());
+    // First failure of the current window and failures since, per network.
+    let mut failures: HashMap<IpNet, (Instant, u32)> = HashMap::new();
+
+    loop {
+        let (ip, what) = tokio::select! {
+            event = events.recv() => match event {
+                Ok(Event::AuthFailed { peer_addr, .. } | Event::HandshakeFailed { peer_addr, .. })
+                    if peer_addr == UNADDRESSED =>
+                {
+                    continue;
+                }
+                Ok(Event::AuthFailed { protocol, peer_addr }) => {
+                    (peer_addr.ip(), format!("{} authentication", protocol))
+                }
+                Ok(Event::HandshakeFailed { protocol, peer_addr }) => {
+                    (peer_addr.ip(), format!("{} handshake", protocol))
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("[Banlist] {} event(s) missed", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = sweep.tick() => {
+                let now = Instant::now();
+                failures.retain(|_, (since, _)| now.duration_since(*since) < config.window());
+                BANNED.retain(|net, until| {
+                    let active = *until > now;
+                    if !active {
+                        info!("[Banlist] Ban on {} expired", label(*net));
+                    }
+                    active
+                });
+                continue;
+            }
+            _ = lifted() => {
+                persist(store.as_deref()).await;
+                continue;
+            }
+            _ = shutdown_rx.changed() => break,
+        };
+
+        let ip = ip.to_canonical();
+        if exempt.iter().any(|net| net.contains(&ip)) {
+            continue;
+        }
+
+        let net = network(ip);
+        let now = Instant::now();
+        let (since, count) = failures.entry(net).or_insert((now, 0));
+        if now.duration_since(*since) >= config.window() {
+            *since = now;
+            *count = 0;
+        }
+        *count += 1;
+        if *count < config.max_failures() {
+            continue;
+        }
+
+        failures.remove(&net);
+        BANNED.insert(net, now + config.ban_duration());
+        let reason = format!(
+            "{} failure(s) within {:?}, the last in {}",
+            config.max_failures(),
+            config.window(),
+            what
+        );
+        warn!(
+            "[Banlist] Banning {} for {:?}: {}",
+            label(net),
+            config.ban_duration(),
+            reason
+        );
+        events::publish(Event::BanIssued {
+            ip,
+            reason: Arc::from(reason),
+            duration: Some(config.ban_duration()),
+        });
+        persist(store.as_deref()).await;
+    }
+}
+
+/// Resolves when an operator lifted a ban.
+async fn lifted() {
+    #[cfg(feature = "control")]
+    LIFTED.notified().await;
+    #[cfg(not(feature = "control"))]
+    std::future::pending::<()>().await;
+}
+
+/// Bans by network, each with its end in seconds since the Unix epoch.
+fn load(path: &Path) -> Result<HashMap<String, u64>> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Bans the stored networks for what is left of their bans. Returns how
+/// many were still in force.
+fn restore(stored: HashMap<String, u64>) -> usize {
+    let (now, wall) = (Instant::now(), SystemTime::now());
+    let mut restored = 0;
+    for (net, ends) in stored {
+        let Some(net) = parse_net(&net) else {
+            warn!("[Banlist] Ignoring stored ban on {:?}", net);
+            continue;
+        };
+        let Ok(left) = (UNIX_EPOCH + Duration::from_secs(ends)).duration_since(wall) else {
+            continue;
+        };
+        BANNED.insert(net, now + left);
+        restored += 1;
+    }
+    restored
+}
+
+async fn persist(store: Option<&Path>) {
+    let Some(path) = store else {
+        return;
+    };
+    match save(path).await {
+        Ok(bans) => debug!("[Banlist] Saved {} ban(s)", bans),
+        Err(e) => warn!("[Banlist] Failed to save bans: {:#}", e),
+    }
+}
+
+/// Replaces the store with the bans in force, through a temporary file so
+/// a crash mid-write leaves the previous one intact.
+async fn save(path: &Path) -> Result<usize> {
+    let (now, wall) = (Instant::now(), SystemTime::now());
+    let bans: BTreeMap<String, u64> = BANNED
+        .iter()
+        .filter(|entry| *entry.value() > now)
+        .map(|entry| {
+            let ends = wall + entry.value().duration_since(now);
+            let ends = ends.duration_since(UNIX_EPOCH).unwrap_or_default();
+            (entry.key().to_string(), ends.as_secs())
+        })
+        .collect();
+    let content = serde_json::to_vec_pretty(&bans)?;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, content)
+        .await
+        .with_context(|| format!("Failed to write {:?}", tmp))?;
+    tokio::fs::rename(&tmp, path)
+        .await
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+
+    Ok(bans.len())
+}
+
+/// What a ban on `ip` covers: the address itself, or its /64 for IPv6.
+fn network(ip: IpAddr) -> IpNet {
+    match ip.to_canonical() {
+        ip @ IpAddr::V4(_) => IpNet::from(ip),
+        IpAddr::V6(ip) => IpNet::new(IpAddr::V6(ip), 64)
+            .expect("64 is a valid IPv6 prefix")
+            .trunc(),
+    }
+}
+
+/// Single addresses without their /32.
+fn label(net: IpNet) -> String {
+    if net.prefix_len() == net.max_prefix_len() {
+        net.addr().to_string()
+    } else {
+        net.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn does_not_count_unaddressed_clients() {
+        let config: BanlistConfig = toml::from_str("max_failures = 1").unwrap();
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+        let mut events = events::subscribe();
+        spawn(Some(&config), shutdown_rx);
+        // Let the task subscribe before anything is published.
+        tokio::task::yield_now().await;
+
+        events::publish(Event::AuthFailed {
+            protocol: "Trojan",
+            peer_addr: UNADDRESSED,
+        });
+        events::publish(Event::HandshakeFailed {
+            protocol: "Trojan",
+            peer_addr: UNADDRESSED,
+        });
+        // Failures are handled in order, so once this one is banned the
+        // ones before it have been seen.
+        let marker: SocketAddr = "192.0.2.44:40000".parse().unwrap();
+        events::publish(Event::AuthFailed {
+            protocol: "Trojan",
+            peer_addr: marker,
+        });
+
+        loop {
+            match events.recv().await.unwrap() {
+                Event::BanIssued { ip, .. } if ip == marker.ip() => break,
+                Event::BanIssued { ip, .. } => assert_ne!(ip, UNADDRESSED.ip()),
+                _ => {}
+            }
+        }
+        assert!(!is_banned(UNADDRESSED.ip()));
+    }
+
+    #[tokio::test]
+    async fn stored_bans_outlast_a_restart() {
+        let dir = std::env::temp_dir().join(format!("iway-banlist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bans.json");
+
+        let banned: IpAddr = "192.0.2.77".parse().unwrap();
+        BANNED.insert(network(banned), Instant::now() + Duration::from_secs(600));
+        save(&path).await.unwrap();
+        BANNED.remove(&network(banned));
+        assert!(!is_banned(banned));
+
+        let mut stored = load(&path).unwrap();
+        // Ended while the process was down.
+        stored.insert(String::from("192.0.2.78"), 1);
+        restore(stored);
+        assert!(is_banned(banned));
+        assert!(!is_banned("192.0.2.78".parse().unwrap()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,433 @@
+//! TLS ClientHello fingerprints of scanners and bots.
+//!
+//! Scanners and crawlers probing for proxies mostly run off-the-shelf TLS
+//! stacks whose ClientHellos are easy to tell from a browser's. The JA3
+//! and JA4 fingerprints of a Trojan client's ClientHello are matched
+//! against `[fingerprints]` before the handshake, and matching connections
+//! are reset or handed to the fallback as if they had never reached the
+//! proxy.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use arc_swap::ArcSwap;
+use md5::Md5;
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::config::{FingerprintAction, FingerprintConfig};
+
+static FINGERPRINTS: Lazy<ArcSwap<Fingerprints>> = Lazy::new(ArcSwap::default);
+static MATCHED: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default)]
+struct Fingerprints {
+    ja3: HashSet<String>,
+    ja4: HashSet<String>,
+    action: FingerprintAction,
+}
+
+/// A ClientHello `[fingerprints]` lists.
+pub struct Match {
+    pub fingerprint: String,
+    pub action: FingerprintAction,
+}
+
+/// Loads `[fingerprints]`, replacing the lists of an earlier call.
+pub fn init(config: &FingerprintConfig) {
+    let lower = |list: &[String]| list.iter().map(|f| f.to_ascii_lowercase()).collect();
+    let fingerprints = Fingerprints {
+        ja3: lower(config.ja3()),
+        ja4: lower(config.ja4()),
+        action: config.action(),
+    };
+
+    if !fingerprints.ja3.is_empty() || !fingerprints.ja4.is_empty() {
+        info!(
+            "[Fingerprints] Matching {} JA3 and {} JA4 fingerprint(s), action {:?}",
+            fingerprints.ja3.len(),
+            fingerprints.ja4.len(),
+            fingerprints.action
+        );
+    }
+
+    FINGERPRINTS.store(Arc::new(fingerprints));
+}
+
+/// Whether any fingerprints are listed, so ClientHellos need a look.
+pub fn enabled() -> bool {
+    let fingerprints = FINGERPRINTS.load();
+    !fingerprints.ja3.is_empty() || !fingerprints.ja4.is_empty()
+}
+
+/// ClientHellos matched since startup.
+#[cfg(feature = "control")]
+pub fn matched() -> u64 {
+    MATCHED.load(Ordering::Relaxed)
+}
+
+/// Matches the ClientHello at the start of `raw`, the TLS records read
+/// from a client, against the lists.
+pub fn check(raw: &[u8]) -> Option<Match> {
+    let fingerprints = FINGERPRINTS.load();
+    if fingerprints.ja3.is_empty() && fingerprints.ja4.is_empty() {
+        return None;
+    }
+    let hello = ClientHello::parse(&handshake_message(raw)?)?;
+
+    let fingerprint = [
+        (&fingerprints.ja3, ja3(&hello)),
+        (&fingerprints.ja4, ja4(&hello)),
+    ]
+    .into_iter()
+    .find_map(|(list, fingerprint)| list.contains(&fingerprint).then_some(fingerprint))?;
+
+    MATCHED.fetch_add(1, Ordering::Relaxed);
+    Some(Match {
+        fingerprint,
+        action: fingerprints.action,
+    })
+}
+
+/// The first handshake message carried by the records in `raw`.
+fn handshake_message(mut raw: &[u8]) -> Option<Vec<u8>> {
+    let mut message = Vec::new();
+    while raw.len() >= 5 && raw[0] == 0x16 {
+        let len = u16::from_be_bytes([raw[3], raw[4]]) as usize;
+        let fragment = raw.get(5..5 + len)?;
+        message.extend_from_slice(fragment);
+        raw = &raw[5 + len..];
+
+        if message.len() >= 4 {
+            let len = u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+            if message.len() >= 4 + len {
+                message.truncate(4 + len);
+                return (message[0] == 0x01).then_some(message);
+            }
+        }
+    }
+    None
+}
+
+/// GREASE values (RFC 8701) are random by design and left out of both
+/// fingerprints.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+#[derive(Default)]
+struct ClientHello {
+    version: u16,
+    ciphers: Vec<u16>,
+    /// Extension types in the order sent.
+    extensions: Vec<u16>,
+    groups: Vec<u16>,
+    point_formats: Vec<u8>,
+    signature_algorithms: Vec<u16>,
+    supported_versions: Vec<u16>,
+    alpn: Option<Vec<u8>>,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+fn u16s(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .collect()
+}
+
+impl ClientHello {
+    /// Parses a ClientHello handshake message, header included.
+    fn parse(message: &[u8]) -> Option<Self> {
+        let mut r = Reader(message.get(4..)?);
+        let mut hello = ClientHello {
+            version: r.u16()?,
+            ..Default::default()
+        };
+        r.take(32)?;
+        r.vec8()?;
+        hello.ciphers = u16s(r.vec16()?);
+        r.vec8()?;
+
+        let mut extensions = Reader(r.vec16().unwrap_or_default());
+        while !extensions.0.is_empty() {
+            let kind = extensions.u16()?;
+            let mut data = Reader(extensions.vec16()?);
+            hello.extensions.push(kind);
+            match kind {
+                0x000a => hello.groups = u16s(data.vec16()?),
+                0x000b => hello.point_formats = data.vec8()?.to_vec(),
+                0x000d => hello.signature_algorithms = u16s(data.vec16()?),
+                0x002b => hello.supported_versions = u16s(data.vec8()?),
+                0x0010 => {
+                    let mut protocols = Reader(data.vec16()?);
+                    hello.alpn = protocols.vec8().map(<[u8]>::to_vec);
+                }
+                _ => {}
+            }
+        }
+        Some(hello)
+    }
+}
+
+/// The JA3 hash: MD5 of version, ciphers, extensions, groups and point
+/// formats, in the order sent.
+fn ja3(hello: &ClientHello) -> String {
+    let join = |values: &mut dyn Iterator<Item = u16>| {
+        values
+            .filter(|v| !is_grease(*v))
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join("-")
+    };
+    let text = format!(
+        "{},{},{},{},{}",
+        hello.version,
+        join(&mut hello.ciphers.iter().copied()),
+        join(&mut hello.extensions.iter().copied()),
+        join(&mut hello.groups.iter().copied()),
+        join(&mut hello.point_formats.iter().map(|&f| u16::from(f))),
+    );
+    hex::encode(Md5::digest(text.as_bytes()))
+}
+
+/// The JA4 fingerprint of a ClientHello received over TCP.
+fn ja4(hello: &ClientHello) -> String {
+    let version = hello
+        .supported_versions
+        .iter()
+        .copied()
+        .filter(|v| !is_grease(*v))
+        .max()
+        .unwrap_or(hello.version);
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        _ => "00",
+    };
+    let sni = if hello.extensions.contains(&0x0000) {
+        'd'
+    } else {
+        'i'
+    };
+
+    let mut ciphers: Vec<u16> = hello
+        .ciphers
+        .iter()
+        .copied()
+        .filter(|c| !is_grease(*c))
+        .collect();
+    let extensions: Vec<u16> = hello
+        .extensions
+        .iter()
+        .copied()
+        .filter(|e| !is_grease(*e))
+        .collect();
+
+    let alpn = match hello.alpn.as_deref() {
+        Some([first, .., last])
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() =>
+        {
+            format!("{}{}", *first as char, *last as char)
+        }
+        Some([only]) if only.is_ascii_alphanumeric() => {
+            format!("{}{}", *only as char, *only as char)
+        }
+        Some(protocol) if !protocol.is_empty() => {
+            let hex = hex::encode(protocol);
+            format!("{}{}", &hex[..1], &hex[hex.len() - 1..])
+        }
+        _ => String::from("00"),
+    };
+
+    let head = format!(
+        "t{}{}{:02}{:02}{}",
+        version,
+        sni,
+        ciphers.len().min(99),
+        extensions.len().min(99),
+        alpn
+    );
+
+    ciphers.sort_unstable();
+    let mut sorted: Vec<u16> = extensions
+        .into_iter()
+        .filter(|e| *e != 0x0000 && *e != 0x0010)
+        .collect();
+    sorted.sort_unstable();
+
+    let list = |values: &[u16]| {
+        values
+            .iter()
+            .map(|v| format!("{:04x}", v))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let truncated_hash = |text: String| {
+        if text.is_empty() {
+            return String::from("000000000000");
+        }
+        hex::encode(Sha256::digest(text.as_bytes()))[..12].to_string()
+    };
+
+    let cipher_hash = truncated_hash(list(&ciphers));
+    let mut extension_text = list(&sorted);
+    if !extension_text.is_empty() && !hello.signature_algorithms.is_empty() {
+        extension_text.push('_');
+        extension_text.push_str(&list(&hello.signature_algorithms));
+    }
+    let extension_hash = truncated_hash(extension_text);
+
+    format!("{}_{}_{}", head, cipher_hash, extension_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// ClientHello sent by OpenSSL 3.5 `s_client -servername example.com
+    /// -alpn h2,http/1.1 -groups X25519:P-256`, record header included.
+    const OPENSSL_TLS13: &str = "\
+        1603010145010001410303673fe69f420070a2438f71230c0dbebc676699234854\
+        afc5c401d8e88580798020062748823603abd7e238f8eafbf10e62abc8672ea72a\
+        f0126c04827ff3e6cddf003c130213031301c02cc030009fcca9cca8ccaac02bc0\
+        2f009ec024c028006bc023c0270067c00ac0140039c009c0130033009d009c003d\
+        003c0035002f010000bcff0100010000000010000e00000b6578616d706c652e63\
+        6f6d000b000403000102000a00060004001d0017002300000010000e000c026832\
+        08687474702f312e310016000000170000000d0036003409050906090404030503\
+        060308070808081a081b081c0809080a080b080408050806040105010601030303\
+        010302040205020602002b00050403040303002d00020101003300260024001d00\
+        2034cb8b686adadedaf140b44d1ce6ab39c85028d2f95d03705c3f0a662194d00e";
+
+    /// ClientHello sent by OpenSSL 3.5 `s_client -noservername -tls1_2
+    /// -groups P-256 -cipher ECDHE-RSA-AES128-GCM-SHA256:AES256-SHA`.
+    const OPENSSL_TLS12: &str = "\
+        16030100800100007c03039f43897cc1f48e962a1a223b2b143c5686f87284658a\
+        783e4041293dcc73bd6c000004c02f00350100004fff01000100000b0004030001\
+        02000a000400020017002300000016000000170000000d002a0028040305030603\
+        080708080809080a080b080408050806040105010601030303010302040205020602";
+
+    fn hello(raw: &str) -> ClientHello {
+        let raw = hex::decode(raw).unwrap();
+        ClientHello::parse(&handshake_message(&raw).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn fingerprints_a_tls13_client_hello() {
+        // JA3 string:
+        // 771,4866-4867-4865-49196-...-53-47,65281-0-11-10-35-16-22-23-13-43-45-51,29-23,0-1-2
+        let hello = hello(OPENSSL_TLS13);
+        assert_eq!(ja3(&hello), "3cccd0410cd2a6eb87bab586a6f51e73");
+        assert_eq!(ja4(&hello), "t13d3012h2_1d37bd780c83_8e6e362c5eac");
+    }
+
+    #[test]
+    fn fingerprints_a_tls12_client_hello_without_sni_or_alpn() {
+        // JA3 string: 771,49199-53,65281-11-10-35-22-23-13,23,0-1-2
+        let hello = hello(OPENSSL_TLS12);
+        assert_eq!(ja3(&hello), "529a72907459c96588270691372a6080");
+        assert_eq!(ja4(&hello), "t12i020700_1584f0a67932_36cef8aed422");
+    }
+
+    #[test]
+    fn reassembles_a_client_hello_split_over_records() {
+        let raw = hex::decode(OPENSSL_TLS13).unwrap();
+        let (head, body) = raw[5..].split_at(100);
+        let mut split = Vec::new();
+        for fragment in [head, body] {
+            split.extend_from_slice(&[0x16, 0x03, 0x01]);
+            split.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            split.extend_from_slice(fragment);
+        }
+        assert_eq!(handshake_message(&split).as_deref(), Some(&raw[5..]));
+
+        // Short of the last record, there is no message yet.
+        assert!(handshake_message(&split[..split.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn leaves_grease_out() {
+        let mut hello = hello(OPENSSL_TLS13);
+        let (ja3_before, ja4_before) = (ja3(&hello), ja4(&hello));
+        hello.ciphers.insert(0, 0x1a1a);
+        hello.extensions.insert(0, 0x2a2a);
+        hello.extensions.push(0xfafa);
+        hello.groups.insert(0, 0x3a3a);
+        hello.supported_versions.insert(0, 0x4a4a);
+        assert_eq!(ja3(&hello), ja3_before);
+        assert_eq!(ja4(&hello), ja4_before);
+
+        assert!(is_grease(0x0a0a) && is_grease(0xdada));
+        assert!(!is_grease(0x0a1a) && !is_grease(0x1301));
+    }
+
+    #[test]
+    fn writes_other_alpn_values_in_the_ja4_prefix() {
+        let mut hello = hello(OPENSSL_TLS13);
+        let prefix = |hello: &ClientHello| ja4(hello)[..10].to_string();
+
+        hello.alpn = Some(b"http/1.1".to_vec());
+        assert_eq!(prefix(&hello), "t13d3012h1");
+        hello.alpn = Some(b"x".to_vec());
+        assert_eq!(prefix(&hello), "t13d3012xx");
+        // Non-alphanumeric ends give the ends of the hex instead.
+        hello.alpn = Some(vec![0xab, b'x', 0x01]);
+        assert_eq!(prefix(&hello), "t13d3012a1");
+        hello.alpn = Some(Vec::new());
+        assert_eq!(prefix(&hello), "t13d301200");
+    }
+
+    #[test]
+    fn matches_listed_fingerprints() {
+        let raw = hex::decode(OPENSSL_TLS13).unwrap();
+        let config: FingerprintConfig = toml::from_str(
+            r#"ja4 = ["T13D3012H2_1D37BD780C83_8E6E362C5EAC"]
+action = "fallback""#,
+        )
+        .unwrap();
+        init(&config);
+
+        let matched = check(&raw).unwrap();
+        assert_eq!(matched.fingerprint, "t13d3012h2_1d37bd780c83_8e6e362c5eac");
+        assert_eq!(matched.action, FingerprintAction::Fallback);
+        assert!(check(&hex::decode(OPENSSL_TLS12).unwrap()).is_none());
+
+        init(&FingerprintConfig::default());
+        assert!(check(&raw).is_none());
+    }
+}
@@ -0,0 +1,3 @@
+pub mod banlist;
+#[cfg(feature = "trojan")]
+pub mod fingerprint;
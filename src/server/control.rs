@@ -0,0 +1,287 @@
+use std::time::Instant;
+
+use super::{Server, ServerStatus, wait_shutdown};
+use crate::control::handle_command;
+use crate::control::registry::registry;
+
+use anyhow::{Error, Result};
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::watch::Receiver;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+/// Longest command line accepted on the control channel.
+const MAX_LINE_LEN: u64 = 1024;
+
+pub struct ControlServer {
+    name: &'static str,
+    path: String,
+    status: ServerStatus,
+    shutdown_rx: Option<Receiver<()>>,
+    stop_token: Option<CancellationToken>,
+}
+
+impl ControlServer {
+    pub fn new_with_config(
+        config: std::sync::Arc<crate::config::Config>,
+        shutdown_rx: Option<Receiver<()>>,
+    ) -> Self {
+        // Create the registry now so the reported uptime starts at launch.
+        registry();
+
+        Self {
+            name: "Control",
+            path: config.control().path().to_string(),
+            status: ServerStatus::Initializing(Instant::now()),
+            shutdown_rx,
+            stop_token: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for ControlServer {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn init(&mut self) -> Result<Instant, Error> {
+        let instant = Instant::now();
+
+        info!("[Control] Initializing control channel at {}", self.path);
+
+        self.status = ServerStatus::Initializing(instant);
+
+        Ok(instant)
+    }
+
+    async fn start(&mut self) -> Result<Instant, Error> {
+        let instant = Instant::now();
+
+        let path = self.path.clone();
+        let shutdown_rx = self.shutdown_rx.clone();
+        let stop_token = CancellationToken::new();
+        self.stop_token = Some(stop_token.clone());
+
+        let listener = listen(&path)?;
+
+        info!("[Control] Listening on {}", path);
+
+        tokio::spawn(async move {
+            if let Err(e) = accept_loop(listener, &path, shutdown_rx, stop_token).await {
+                error!("[Control] Accept loop exited with error: {}", e);
+            }
+        });
+
+        self.status = ServerStatus::Running(instant);
+
+        Ok(instant)
+    }
+
+    async fn stop(&mut self) -> Result<Instant, Error> {
+        let instant = Instant::now();
+
+        if let Some(stop_token) = self.stop_token.take() {
+            stop_token.cancel();
+        }
+
+        #[cfg(unix)]
+        let _ = std::fs::remove_file(&self.path);
+
+        self.status = ServerStatus::Stopped(instant);
+
+        info!("[Control] Server stopped");
+
+        Ok(instant)
+    }
+
+    /// Stays up while the proxies drain, so their progress can be watched.
+    async fn stop_accepting(&mut self) -> Result<Instant, Error> {
+        Ok(Instant::now())
+    }
+
+    async fn close(&mut self) -> Result<Instant, Error> {
+        self.stop().await
+    }
+
+    async fn status(&mut self) -> Result<&ServerStatus, Error> {
+        Ok(&self.status)
+    }
+}
+
+#[cfg(unix)]
+fn listen(path: &str) -> Result<tokio::net::UnixListener> {
+    use anyhow::{Context, bail};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt};
+
+    // A socket left behind by an unclean exit would make bind fail, but
+    // anything else at the path is not ours to delete.
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            bail!("{} exists and is not a socket", path);
+        }
+        Ok(_) if std::os::unix::net::UnixStream::connect(path).is_ok() => {
+            bail!("{} is in use by another process", path);
+        }
+        Ok(_) => std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path))?,
+        Err(_) => {
+            if let Some(dir) = std::path::Path::new(path).parent()
+                && !dir.as_os_str().is_empty()
+                && !dir.exists()
+            {
+                std::fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(dir)
+                    .with_context(|| format!("Failed to create {}", dir.display()))?;
+            }
+        }
+    }
+
+    // The socket is created owner-only rather than restricted after bind,
+    // which would leave a moment for any local user to connect. The umask
+    // is process-wide, but meanwhile it can only make other files stricter.
+    let previous = unsafe { libc::umask(0o177) };
+    let bound = tokio::net::UnixListener::bind(path);
+    unsafe { libc::umask(previous) };
+
+    bound.with_context(|| format!("Failed to bind control socket {}", path))
+}
+
+#[cfg(unix)]
+async fn accept_loop(
+    listener: tokio::net::UnixListener,
+    _path: &str,
+    mut shutdown_rx: Option<Receiver<()>>,
+    stop_token: CancellationToken,
+) -> Result<(), Error> {
+    loop {
+        tokio::select! {
+            res = listener.accept() => {
+                match res {
+                    Ok((stream, _)) => {
+                        tokio::spawn(handle_client(stream));
+                    }
+                    Err(e) => {
+                        error!("[Control] Failed to accept connection: {}", e);
+                    }
+                }
+            }
+            _ = wait_shutdown(&mut shutdown_rx) => {
+                info!("[Control] Shutdown signal received, stopping accept loop");
+                break;
+            }
+            _ = stop_token.cancelled() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn listen(path: &str) -> Result<tokio::net::windows::named_pipe::NamedPipeServer> {
+    use anyhow::Context;
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    ServerOptions::new()
+        .first_pipe_instance(true)
+        .reject_remote_clients(true)
+        .create(path)
+        .with_context(|| format!("Failed to create control pipe {}", path))
+}
+
+#[cfg(windows)]
+async fn accept_loop(
+    mut server: tokio::net::windows::named_pipe::NamedPipeServer,
+    path: &str,
+    mut shutdown_rx: Option<Receiver<()>>,
+    stop_token: CancellationToken,
+) -> Result<(), Error> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    loop {
+        tokio::select! {
+            res = server.connect() => {
+                // Create the next instance before handing this one off so a
+                // client never finds the pipe missing.
+                let next = ServerOptions::new()
+                    .reject_remote_clients(true)
+                    .create(path)?;
+                let connected = std::mem::replace(&mut server, next);
+                match res {
+                    Ok(()) => {
+                        tokio::spawn(handle_client(connected));
+                    }
+                    // One client failing leaves the pipe to the next.
+                    Err(e) => error!("[Control] Failed to accept connection: {}", e),
+                }
+            }
+            _ = wait_shutdown(&mut shutdown_rx) => {
+                info!("[Control] Shutdown signal received, stopping accept loop");
+                break;
+            }
+            _ = stop_token.cancelled() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_client<S>(stream: S)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    if let Err(e) = (&mut reader).take(MAX_LINE_LEN).read_line(&mut line).await {
+        debug!("[Control] Failed to read command: {}", e);
+        return;
+    }
+
+    info!("[Control] Command: {}", line.trim());
+    let reply = handle_command(&line);
+
+    let mut stream = reader.into_inner();
+    if let Err(e) = stream.write_all(reply.as_bytes()).await {
+        debug!("[Control] Failed to write reply: {}", e);
+        return;
+    }
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn binds_only_over_stale_sockets() {
+        let dir = std::env::temp_dir().join(format!("iway-control-{}", std::process::id()));
+        let path = dir.join("run").join("iway.sock");
+        let path = path.to_str().unwrap();
+
+        // The missing directory is created, owner-only.
+        let listener = listen(path).unwrap();
+        assert!(listen(path).is_err(), "a live socket was replaced");
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // Left behind by an unclean exit.
+        drop(listener);
+        listen(path).unwrap();
+
+        let file = dir.join("config.toml");
+        std::fs::write(&file, "keep me").unwrap();
+        assert!(listen(file.to_str().unwrap()).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep me");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,16 +1,56 @@
-use std::{collections::HashMap, sync::Arc, time::Instant};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::Error;
+use crate::config::ShutdownConfig;
+use crate::control::registry::registry;
+use anyhow::{Error, bail};
 use async_trait::async_trait;
+#[cfg(feature = "control")]
+use control::ControlServer;
+#[cfg(feature = "snell")]
+use snell::SnellServer;
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub use tls::provision_self_signed;
 use tokio::sync::{Mutex, watch::Receiver};
-use tracing::{error, info};
-use trojan::TrojanServer;
-use tuic::TuicServer;
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+#[cfg(feature = "trojan")]
+pub use trojan::TrojanServer;
+#[cfg(feature = "tuic")]
+pub use tuic::TuicServer;
 
+// For embedding; the binary goes through the ServerManager instead.
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub use tls::CertSource;
+#[cfg(feature = "trojan")]
+pub use trojan::TrojanServerBuilder;
+#[cfg(feature = "tuic")]
+pub use tuic::TuicServerBuilder;
+
+#[cfg(feature = "control")]
+mod control;
+#[cfg(feature = "trojan")]
+mod reality;
+#[cfg(any(feature = "tuic", feature = "trojan"))]
 mod resolver;
-mod tls;
+#[cfg(feature = "snell")]
+mod snell;
+#[cfg(feature = "trojan")]
+mod sni;
+#[cfg(feature = "trojan")]
+pub mod tickets;
+#[cfg(any(feature = "tuic", feature = "trojan"))]
+pub(crate) mod tls;
+#[cfg(feature = "trojan")]
 mod trojan;
+#[cfg(feature = "trojan")]
 pub mod trojan_fallback;
+#[cfg(feature = "tuic")]
 mod tuic;
 
 #[async_trait]
@@ -23,6 +63,55 @@ pub trait Server: Send + Sync {
 
     async fn stop(&mut self) -> Result<Instant, Error>;
 
+    /// First shutdown stage: refuse new connections, leaving established
+    /// ones to finish.
+    async fn stop_accepting(&mut self) -> Result<Instant, Error> {
+        self.stop().await
+    }
+
+    /// Tells clients of established connections to reconnect elsewhere,
+    /// for protocols that have a way to say so.
+    async fn announce_going_away(&mut self) -> Result<Instant, Error> {
+        Ok(Instant::now())
+    }
+
+    /// Waits at most `timeout` for established connections to finish, for
+    /// servers with a drain limit of their own.
+    async fn drain(&mut self, _timeout: Duration) -> Result<Instant, Error> {
+        Ok(Instant::now())
+    }
+
+    /// Last shutdown stage, once connections have drained: release what
+    /// is still held open.
+    async fn close(&mut self) -> Result<Instant, Error> {
+        Ok(Instant::now())
+    }
+
+    /// Stops and starts the server again, picking up certificates and
+    /// listener settings anew. Established connections are left alone.
+    async fn restart(&mut self) -> Result<Instant, Error> {
+        self.stop().await?;
+        self.init().await?;
+        self.start().await
+    }
+
+    /// Moves the listener to `addr`, if that is a new address. Connections
+    /// accepted on the old address are served until they close.
+    async fn rebind(&mut self, _addr: SocketAddr) -> Result<Instant, Error> {
+        bail!("{} cannot move its listener", self.name())
+    }
+
+    /// Re-reads the certificate files without interrupting the listener.
+    async fn reload_certificates(&mut self) -> Result<Instant, Error> {
+        Ok(Instant::now())
+    }
+
+    /// Applies what can change under a running server from `config`, such
+    /// as its users. Listeners and established connections are untouched.
+    async fn reload(&mut self, _config: Arc<crate::config::Config>) -> Result<Instant, Error> {
+        Ok(Instant::now())
+    }
+
     async fn status(&mut self) -> Result<&ServerStatus, Error>;
 }
 
@@ -30,6 +119,74 @@ pub struct ServerManager {
     servers: HashMap<String, Arc<Mutex<dyn Server>>>,
 }
 
+/// Servers by the feature that brings them in, and whether this build has it.
+const FEATURES: [(&str, bool); 4] = [
+    ("tuic", cfg!(feature = "tuic")),
+    ("trojan", cfg!(feature = "trojan")),
+    ("snell", cfg!(feature = "snell")),
+    ("control", cfg!(feature = "control")),
+];
+
+/// Whether this build includes the server for `protocol`.
+fn built(protocol: &str) -> bool {
+    FEATURES
+        .iter()
+        .any(|(feature, built)| *feature == protocol && *built)
+}
+
+/// Complains about sections the config enables but this build left out.
+fn warn_unsupported(config: &crate::config::Config) {
+    let sections = [
+        ("tuic", config.tuic().enabled()),
+        ("trojan", config.trojan().enabled()),
+        ("snell", config.snell().enabled()),
+        ("control", config.control().enabled()),
+    ];
+    let inbounds = config
+        .inbounds()
+        .iter()
+        .map(|inbound| (inbound.protocol(), true));
+
+    for (name, enabled) in sections.into_iter().chain(inbounds) {
+        if enabled && !built(name) {
+            warn!(
+                "[{}] is enabled, but this build does not include the {} feature",
+                name, name
+            );
+        }
+    }
+}
+
+/// Builds the server for the `protocol` section of `config`.
+#[cfg_attr(
+    not(any(feature = "tuic", feature = "trojan", feature = "snell")),
+    allow(unused_variables)
+)]
+fn build_server(
+    protocol: &str,
+    config: std::sync::Arc<crate::config::Config>,
+    shutdown_rx: Option<Receiver<()>>,
+) -> Result<Arc<Mutex<dyn Server>>, Error> {
+    match protocol {
+        #[cfg(feature = "tuic")]
+        "tuic" => Ok(Arc::new(Mutex::new(TuicServer::new_with_config(
+            config,
+            shutdown_rx,
+        )?))),
+        #[cfg(feature = "trojan")]
+        "trojan" => Ok(Arc::new(Mutex::new(TrojanServer::new_with_config(
+            config,
+            shutdown_rx,
+        )?))),
+        #[cfg(feature = "snell")]
+        "snell" => Ok(Arc::new(Mutex::new(SnellServer::new_with_config(
+            config,
+            shutdown_rx,
+        )?))),
+        _ => bail!("This build does not include the {} feature", protocol),
+    }
+}
+
 impl ServerManager {
     pub fn new_with_config(
         config: std::sync::Arc<crate::config::Config>,
@@ -37,39 +194,70 @@ impl ServerManager {
     ) -> Self {
         let mut servers: HashMap<String, Arc<Mutex<dyn Server>>> = HashMap::new();
 
-        if config.tuic().enabled() {
-            let tuic_server = match TuicServer::new_with_config(
+        warn_unsupported(&config);
+
+        let sections = [
+            ("Tuic", "tuic", config.tuic().enabled()),
+            ("Trojan", "trojan", config.trojan().enabled()),
+            ("Snell", "snell", config.snell().enabled()),
+        ];
+        for (name, protocol, enabled) in sections {
+            if !enabled || !built(protocol) {
+                continue;
+            }
+            match build_server(
+                protocol,
                 std::sync::Arc::clone(&config),
                 shutdown_rx.clone(),
             ) {
-                Ok(server) => server,
-                Err(e) => {
-                    error!("Failed to create TuicServer: {}", e);
-                    return Self { servers };
+                Ok(server) => {
+                    servers.insert(String::from(name), server);
                 }
-            };
+                // The others are still served, as with a broken inbound.
+                Err(e) => error!("Failed to create {}Server: {}", name, e),
+            }
+        }
 
-            const TUIC_SERVER_NAME: &str = "Tuic";
-            servers.insert(
-                String::from(TUIC_SERVER_NAME),
-                Arc::new(Mutex::new(tuic_server)),
-            );
+        // Each entry is served under its tag, so `iway ctl` can address it.
+        for inbound in config.inbounds() {
+            let name = inbound.tag();
+            if servers.keys().any(|taken| taken.eq_ignore_ascii_case(name)) {
+                error!(
+                    "Inbound tag {:?} is already taken, skipping this {} inbound",
+                    name,
+                    inbound.protocol()
+                );
+                continue;
+            }
+            if !built(inbound.protocol()) {
+                continue;
+            }
+
+            let scoped = std::sync::Arc::new(config.for_inbound(inbound));
+            match build_server(inbound.protocol(), scoped, shutdown_rx.clone()) {
+                Ok(server) => {
+                    servers.insert(String::from(name), server);
+                }
+                Err(e) => error!(
+                    "Failed to create {} inbound {}: {}",
+                    inbound.protocol(),
+                    name,
+                    e
+                ),
+            }
         }
 
-        if config.trojan().enabled() {
-            let trojan_server =
-                match TrojanServer::new_with_config(std::sync::Arc::clone(&config), shutdown_rx) {
-                    Ok(server) => server,
-                    Err(e) => {
-                        error!("Failed to create TrojanServer: {}", e);
-                        return Self { servers };
-                    }
-                };
+        #[cfg(feature = "control")]
+        if config.control().enabled() {
+            // Not tied to the shutdown signal: the control socket keeps
+            // answering while connections drain and is closed last.
+            let control_server =
+                ControlServer::new_with_config(std::sync::Arc::clone(&config), None);
 
-            const TROJAN_SERVER_NAME: &str = "Trojan";
+            const CONTROL_SERVER_NAME: &str = "Control";
             servers.insert(
-                String::from(TROJAN_SERVER_NAME),
-                Arc::new(Mutex::new(trojan_server)),
+                String::from(CONTROL_SERVER_NAME),
+                Arc::new(Mutex::new(control_server)),
             );
         }
 
@@ -121,29 +309,288 @@ impl ServerManager {
         Ok(Instant::now())
     }
 
-    pub async fn stop(&self) -> Result<Instant, Error> {
-        for (name, server) in self.servers.iter() {
-            let server = Arc::clone(server);
+    /// Every server by name, with its status label and how long it has had
+    /// it, or `None` for a server busy past `STATUS_TIMEOUT` (in a restart,
+    /// say).
+    pub async fn statuses(&self) -> Vec<(String, Option<(&'static str, Duration)>)> {
+        let mut statuses = Vec::with_capacity(self.servers.len());
+        for (name, server) in &self.servers {
+            let status = tokio::time::timeout(STATUS_TIMEOUT, async {
+                let mut server = server.lock().await;
+                server
+                    .status()
+                    .await
+                    .ok()
+                    .map(|status| (status.label(), status.since().elapsed()))
+            })
+            .await
+            .ok()
+            .flatten();
+            statuses.push((name.clone(), status));
+        }
+        statuses.sort_by(|a, b| a.0.cmp(&b.0));
+        statuses
+    }
+
+    /// Servers whose name matches `name` case-insensitively, or all of them.
+    fn matching(&self, name: Option<&str>) -> Vec<(&String, Arc<Mutex<dyn Server>>)> {
+        self.servers
+            .iter()
+            .filter(|(server_name, _)| name.is_none_or(|n| server_name.eq_ignore_ascii_case(n)))
+            .map(|(server_name, server)| (server_name, Arc::clone(server)))
+            .collect()
+    }
+
+    pub async fn restart(&self, name: Option<&str>) -> Result<Instant, Error> {
+        for (name, server) in self.matching(name) {
+            let mut server = server.lock().await;
+            match server.restart().await {
+                Ok(_) => info!("Server {} restarted successfully", name),
+                Err(e) => error!("Failed to restart server {}: {}", name, e),
+            }
+        }
+
+        Ok(Instant::now())
+    }
+
+    /// Moves every protocol listener whose address changed in `config`.
+    pub async fn rebind(&self, config: &crate::config::Config) -> Result<Instant, Error> {
+        let addrs = [
+            ("Tuic", config.tuic().server_addr()),
+            ("Trojan", config.trojan().server_addr()),
+            ("Snell", config.snell().server_addr()),
+        ];
+        let inbounds = config
+            .inbounds()
+            .iter()
+            .map(|inbound| (inbound.tag(), inbound.server_addr()));
+
+        for (name, addr) in addrs.into_iter().chain(inbounds) {
+            let Some(server) = self.servers.get(name) else {
+                continue;
+            };
+
+            let addr = match addr.parse::<SocketAddr>() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    error!("Invalid address {:?} for server {}: {}", addr, name, e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = server.lock().await.rebind(addr).await {
+                error!("Failed to move server {} to {}: {:#}", name, addr, e);
+            }
+        }
+
+        Ok(Instant::now())
+    }
+
+    /// Applies an edited `config` to the running servers: listeners move
+    /// when their address changed and users are swapped in place. Servers
+    /// the edit adds or removes take a restart.
+    pub async fn reload(
+        &self,
+        config: &std::sync::Arc<crate::config::Config>,
+    ) -> Result<Instant, Error> {
+        self.rebind(config).await?;
+
+        let sections = ["Tuic", "Trojan", "Snell"].map(|name| (name, Arc::clone(config)));
+        let inbounds = config
+            .inbounds()
+            .iter()
+            .map(|inbound| (inbound.tag(), Arc::new(config.for_inbound(inbound))));
+
+        for (name, scoped) in sections.into_iter().chain(inbounds) {
+            let Some(server) = self.servers.get(name) else {
+                continue;
+            };
+            if let Err(e) = server.lock().await.reload(scoped).await {
+                error!("Failed to reload server {}: {:#}", name, e);
+            }
+        }
+
+        Ok(Instant::now())
+    }
+
+    pub async fn reload_certificates(&self, name: Option<&str>) -> Result<Instant, Error> {
+        for (name, server) in self.matching(name) {
+            let mut server = server.lock().await;
+            if let Err(e) = server.reload_certificates().await {
+                error!("Failed to reload certificates of server {}: {}", name, e);
+            }
+        }
+
+        Ok(Instant::now())
+    }
+
+    /// Shuts the servers down in stages: stop accepting, tell clients to
+    /// migrate (if a notice is configured), drain the established
+    /// connections, run `flush` (pending stats), then close what is left.
+    /// Each stage gets its own timeout and moves on when it runs out.
+    pub async fn shutdown(
+        &self,
+        timeouts: &ShutdownConfig,
+        flush: impl Future<Output = ()>,
+    ) -> Result<Instant, Error> {
+        info!("[Shutdown] Stage 1/5: stopping listeners");
+        self.run_stage(Stage::StopAccepting, timeouts.accept_timeout())
+            .await;
+
+        if let Some(notice) = timeouts.migration_notice() {
+            info!(
+                "[Shutdown] Stage 2/5: asking clients to reconnect elsewhere, waiting {:?}",
+                notice
+            );
+            self.run_stage(Stage::AnnounceGoingAway, timeouts.accept_timeout())
+                .await;
+            tokio::time::sleep(notice).await;
+        } else {
+            info!("[Shutdown] Stage 2/5: no migration notice configured");
+        }
+
+        info!("[Shutdown] Stage 3/5: draining connections");
+        // Servers may close theirs sooner; the registry waits for the rest.
+        tokio::join!(
+            self.run_stage(
+                Stage::Drain(timeouts.drain_timeout()),
+                timeouts.drain_timeout() + KICK_GRACE
+            ),
+            drain(timeouts.drain_timeout())
+        );
+
+        info!("[Shutdown] Stage 4/5: flushing stats");
+        if tokio::time::timeout(timeouts.flush_timeout(), flush)
+            .await
+            .is_err()
+        {
+            warn!(
+                "[Shutdown] Flushing did not finish within {:?}",
+                timeouts.flush_timeout()
+            );
+        }
+
+        info!("[Shutdown] Stage 5/5: closing endpoints");
+        self.run_stage(Stage::Close, timeouts.close_timeout()).await;
+
+        Ok(Instant::now())
+    }
+
+    /// Runs `stage` on every server at once, giving up on those that take
+    /// longer than `timeout`.
+    async fn run_stage(&self, stage: Stage, timeout: Duration) {
+        let mut tasks = JoinSet::new();
+
+        for (name, server) in &self.servers {
             let name = name.clone();
-            let _handle = tokio::spawn({
-                async move {
+            let server = Arc::clone(server);
+            tasks.spawn(async move {
+                let result = tokio::time::timeout(timeout, async {
                     let mut server = server.lock().await;
-                    match server.stop().await {
-                        Ok(instant) => {
-                            info!("Server {} stopped successfully", &name);
-                            Ok(instant)
-                        }
-                        Err(e) => {
-                            error!("Failed to stop server {}: {}", &name, e);
-                            Err(e)
-                        }
+                    match stage {
+                        Stage::StopAccepting => server.stop_accepting().await,
+                        Stage::AnnounceGoingAway => server.announce_going_away().await,
+                        Stage::Drain(timeout) => server.drain(timeout).await,
+                        Stage::Close => server.close().await,
                     }
+                })
+                .await;
+                (name, result)
+            });
+        }
+
+        while let Some(joined) = tasks.join_next().await {
+            match joined {
+                Ok((name, Ok(Ok(_)))) => info!("[Shutdown] {}: {} done", name, stage),
+                Ok((name, Ok(Err(e)))) => {
+                    error!("[Shutdown] {}: {} failed: {}", name, stage, e)
+                }
+                Ok((name, Err(_))) => warn!(
+                    "[Shutdown] {}: {} did not finish within {:?}",
+                    name, stage, timeout
+                ),
+                Err(e) => error!("[Shutdown] {} task panicked: {}", stage, e),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Stage {
+    StopAccepting,
+    AnnounceGoingAway,
+    Drain(Duration),
+    Close,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::StopAccepting => "stop accepting",
+            Stage::AnnounceGoingAway => "migration notice",
+            Stage::Drain(_) => "drain",
+            Stage::Close => "close",
+        })
+    }
+}
+
+/// How long `statuses` waits for a server that is busy.
+const STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const DRAIN_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+const KICK_GRACE: Duration = Duration::from_secs(1);
+
+/// Waits for the open sessions to end, logging how many are left every
+/// few seconds, and kicks whatever is still open after `timeout`.
+async fn drain(timeout: Duration) {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut next_report = tokio::time::Instant::now();
+
+    loop {
+        let open = registry().session_count();
+        if open == 0 {
+            info!("[Shutdown] All connections closed");
+            return;
+        }
+
+        let now = tokio::time::Instant::now();
+        if now >= deadline {
+            let kicked = registry().kick_all();
+            warn!(
+                "[Shutdown] Kicking {} connection(s) still open after {:?}",
+                kicked, timeout
+            );
+            // Let the kicked sessions unregister, so their records make it
+            // into the flush.
+            let _ = tokio::time::timeout(KICK_GRACE, async {
+                while registry().session_count() > 0 {
+                    tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
                 }
             })
             .await;
+            return;
+        }
+        if now >= next_report {
+            info!(
+                "[Shutdown] Waiting for {} connection(s), {:?} left",
+                open,
+                deadline - now
+            );
+            next_report = now + DRAIN_REPORT_INTERVAL;
         }
 
-        Ok(Instant::now())
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// Resolves when the shared shutdown signal fires; never, without one.
+async fn wait_shutdown(shutdown_rx: &mut Option<Receiver<()>>) {
+    match shutdown_rx.as_mut() {
+        Some(rx) => {
+            let _ = rx.changed().await;
+        }
+        None => std::future::pending::<()>().await,
     }
 }
 
@@ -153,3 +600,57 @@ pub enum ServerStatus {
     Running(Instant),
     Stopped(Instant),
 }
+
+impl ServerStatus {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ServerStatus::Initializing(_) => "initializing",
+            ServerStatus::Running(_) => "running",
+            ServerStatus::Stopped(_) => "stopped",
+        }
+    }
+
+    /// When the server entered this status.
+    pub fn since(&self) -> Instant {
+        match self {
+            ServerStatus::Initializing(since)
+            | ServerStatus::Running(since)
+            | ServerStatus::Stopped(since) => *since,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "trojan"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_the_servers_after_a_broken_one() {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+[trojan]
+enabled = true
+server_addr = "not an address"
+
+[[inbounds]]
+type = "trojan"
+tag = "broken"
+server_addr = "also not an address"
+
+[[inbounds]]
+type = "trojan"
+tag = "alt"
+server_addr = "127.0.0.1:0"
+
+[control]
+enabled = false
+"#,
+        )
+        .unwrap();
+
+        let manager = ServerManager::new_with_config(Arc::new(config), None);
+        let mut names: Vec<_> = manager.servers.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(names, ["alt"]);
+    }
+}
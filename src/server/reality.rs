@@ -0,0 +1,601 @@
+//! REALITY: the listener borrows the identity of another TLS site.
+//!
+//! A client holding the server's X25519 public key hides an encrypted
+//! token in the ClientHello session ID, keyed by its own key share. One
+//! whose token checks out gets a TLS 1.3 handshake with a throwaway
+//! certificate it can recognise through the shared key; everybody else,
+//! probers included, is spliced to the real site and sees its certificate.
+//! Unlike Xray, the record sizes of the real site's handshake are not
+//! mimicked.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow, bail};
+use aws_lc_rs::aead::{AES_256_GCM, Aad, LessSafeKey, Nonce, UnboundKey};
+use aws_lc_rs::agreement::{self, PrivateKey, UnparsedPublicKey, X25519};
+use aws_lc_rs::rand::SystemRandom;
+use aws_lc_rs::signature::{Ed25519KeyPair, KeyPair};
+use aws_lc_rs::{error, hkdf, hmac};
+use base64ct::{Base64UrlUnpadded, Encoding};
+use rustls::crypto::ring::sign::any_eddsa_type;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::sign::{CertifiedKey, Signer, SigningKey, SingleCertAndKey};
+use rustls::{ServerConfig, SignatureAlgorithm, SignatureScheme};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::config::RealityConfig;
+use crate::net::tcp as net_tcp;
+use crate::resolver;
+use crate::server::tls::{CertSource, name_matches, normalize_name};
+
+const HANDSHAKE_RECORD: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const KEY_SHARE_EXTENSION: u16 = 51;
+const X25519_GROUP: u16 = 0x001d;
+/// Where the session ID starts in a ClientHello handshake message: type,
+/// length, legacy version, random and the session ID length come first.
+const SESSION_ID_OFFSET: usize = 4 + 2 + 32 + 1;
+const ED25519_OID: &[u8] = &[0x2b, 0x65, 0x70];
+
+pub struct Reality {
+    private_key: PrivateKey,
+    dest: String,
+    /// Lowercased names; a leading `*.` matches any subdomain.
+    server_names: Vec<String>,
+    short_ids: HashSet<[u8; 8]>,
+    max_time_diff: Option<Duration>,
+    /// Ed25519 key the throwaway certificates carry.
+    signing_key: Arc<dyn SigningKey>,
+    /// PKCS#8 form of the same key.
+    signing_key_der: Vec<u8>,
+    certificate_key: Vec<u8>,
+    /// Certificate for `certificate_key` whose signature is swapped for
+    /// each client's proof.
+    certificate: Vec<u8>,
+}
+
+impl Reality {
+    pub fn from_config(config: &RealityConfig) -> Result<Self> {
+        let private_key = PrivateKey::from_private_key(&X25519, &decode_key(config.private_key())?)
+            .map_err(|e| anyhow!("Bad REALITY private key: {}", e))?;
+
+        if config.server_names().is_empty() {
+            bail!("REALITY needs server_names");
+        }
+        if config.short_ids().is_empty() {
+            bail!("REALITY needs short_ids (\"\" allows the all-zero ID)");
+        }
+        let short_ids = config
+            .short_ids()
+            .iter()
+            .map(|id| decode_short_id(id))
+            .collect::<Result<HashSet<_>>>()?;
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|_| anyhow!("Failed to generate the REALITY certificate key"))?;
+        let certificate_key = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+            .map_err(|e| anyhow!("Failed to load the REALITY certificate key: {}", e))?
+            .public_key()
+            .as_ref()
+            .to_vec();
+        let signing_key = any_eddsa_type(&PrivatePkcs8KeyDer::from(pkcs8.as_ref()))
+            .context("Failed to load the REALITY certificate key")?;
+
+        Ok(Self {
+            private_key,
+            dest: config.dest().to_string(),
+            server_names: config
+                .server_names()
+                .iter()
+                .map(|name| normalize_name(name))
+                .collect(),
+            short_ids,
+            max_time_diff: config.max_time_diff(),
+            signing_key: Arc::new(AlwaysEd25519(signing_key)),
+            signing_key_der: pkcs8.as_ref().to_vec(),
+            certificate: certificate_template(&certificate_key),
+            certificate_key,
+        })
+    }
+
+    /// The public half of the X25519 key, as clients are configured with.
+    pub fn public_key(&self) -> String {
+        self.private_key
+            .compute_public_key()
+            .map(|key| Base64UrlUnpadded::encode_string(key.as_ref()))
+            .unwrap_or_default()
+    }
+
+    pub fn dest(&self) -> &str {
+        &self.dest
+    }
+
+    /// Stands in for the listener's certificate files, which REALITY has
+    /// no use for: clients that fail authentication never reach it.
+    pub fn cert_source(&self) -> CertSource {
+        CertSource::Der(vec![(
+            vec![CertificateDer::from(self.certificate.clone())],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.signing_key_der.clone())),
+        )])
+    }
+
+    /// Checks the token in the ClientHello `raw` holds (records included)
+    /// and returns the key shared with the client if it is valid.
+    pub fn authenticate(&self, raw: &[u8], sni: Option<&str>) -> Option<[u8; 32]> {
+        let sni = normalize_name(sni?);
+        if !self
+            .server_names
+            .iter()
+            .any(|name| name_matches(name, &sni))
+        {
+            return None;
+        }
+
+        let message = handshake_message(raw)?;
+        let hello = parse_client_hello(&message)?;
+        if hello.session_id.len() != 32 {
+            return None;
+        }
+
+        let auth_key = agreement::agree(
+            &self.private_key,
+            UnparsedPublicKey::new(&X25519, hello.x25519_share?),
+            error::Unspecified,
+            |shared| {
+                let mut auth_key = [0u8; 32];
+                hkdf::Salt::new(hkdf::HKDF_SHA256, &hello.random[..20])
+                    .extract(shared)
+                    .expand(&[b"REALITY"], hkdf::HKDF_SHA256)?
+                    .fill(&mut auth_key)?;
+                Ok(auth_key)
+            },
+        )
+        .ok()?;
+
+        // The token is sealed over the ClientHello with its session ID
+        // zeroed.
+        let mut aad = message.clone();
+        aad[SESSION_ID_OFFSET..SESSION_ID_OFFSET + 32].fill(0);
+        let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &auth_key).ok()?);
+        let nonce = Nonce::try_assume_unique_for_key(&hello.random[20..]).ok()?;
+        let mut token = hello.session_id.to_vec();
+        let plain = key.open_in_place(nonce, Aad::from(&aad), &mut token).ok()?;
+
+        // Client version (3 bytes), reserved, Unix time, short ID.
+        let time = u32::from_be_bytes(plain[4..8].try_into().ok()?);
+        let short_id: [u8; 8] = plain[8..16].try_into().ok()?;
+        if !self.short_ids.contains(&short_id) {
+            return None;
+        }
+        if let Some(max) = self.max_time_diff {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+            if now.abs_diff(u64::from(time)) > max.as_secs() {
+                return None;
+            }
+        }
+
+        Some(auth_key)
+    }
+
+    /// TLS config presenting a certificate whose signature is the proof a
+    /// client holding `auth_key` expects: HMAC-SHA512 of the certificate's
+    /// public key. Cipher suites and ALPN follow `base`.
+    pub fn tls_config(
+        &self,
+        auth_key: &[u8; 32],
+        base: &ServerConfig,
+    ) -> Result<Arc<ServerConfig>> {
+        let proof = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA512, auth_key),
+            &self.certificate_key,
+        );
+        let mut certificate = self.certificate.clone();
+        let at = certificate.len() - 64;
+        certificate[at..].copy_from_slice(proof.as_ref());
+
+        let certified = CertifiedKey::new(
+            vec![CertificateDer::from(certificate)],
+            Arc::clone(&self.signing_key),
+        );
+
+        let mut config = ServerConfig::builder_with_provider(Arc::clone(base.crypto_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .context("Failed to set TLS protocol versions")?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(SingleCertAndKey::from(certified)));
+        config.alpn_protocols = base.alpn_protocols.clone();
+
+        Ok(Arc::new(config))
+    }
+
+    /// Hands the connection to the real site, replaying what was read.
+    pub async fn forward(&self, mut stream: TcpStream, replay: Vec<u8>) -> Result<()> {
+        let addrs = resolver::lookup_host(&self.dest)
+            .await
+            .with_context(|| format!("Failed to resolve REALITY target {}", self.dest))?;
+        let mut upstream = net_tcp::connect_any(&addrs).await?;
+        upstream.write_all(&replay).await?;
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+        Ok(())
+    }
+}
+
+/// Signs with Ed25519 whatever the client offered. REALITY clients accept
+/// it, though browser fingerprints leave Ed25519 out of their list.
+#[derive(Debug)]
+struct AlwaysEd25519(Arc<dyn SigningKey>);
+
+impl SigningKey for AlwaysEd25519 {
+    fn choose_scheme(&self, _offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        self.0.choose_scheme(&[SignatureScheme::ED25519])
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::ED25519
+    }
+}
+
+/// Base64url (with or without padding) or hex.
+fn decode_key(key: &str) -> Result<[u8; 32]> {
+    let key = key.trim().trim_end_matches('=');
+    let bytes = if key.len() == 64 {
+        hex::decode(key).context("Bad hex in REALITY private key")?
+    } else {
+        Base64UrlUnpadded::decode_vec(key)
+            .map_err(|_| anyhow!("Bad base64 in REALITY private key"))?
+    };
+    bytes
+        .try_into()
+        .map_err(|_| anyhow!("REALITY private key must be 32 bytes"))
+}
+
+/// Short IDs are left-aligned in 8 bytes and zero-padded.
+fn decode_short_id(id: &str) -> Result<[u8; 8]> {
+    if id.len() > 16 || !id.len().is_multiple_of(2) {
+        bail!(
+            "REALITY short_id {:?} must be an even number of hex digits, at most 16",
+            id
+        );
+    }
+    let bytes = hex::decode(id).with_context(|| format!("Bad REALITY short_id {:?}", id))?;
+    let mut short_id = [0u8; 8];
+    short_id[..bytes.len()].copy_from_slice(&bytes);
+    Ok(short_id)
+}
+
+/// The handshake message carried by the TLS records in `raw`.
+fn handshake_message(raw: &[u8]) -> Option<Vec<u8>> {
+    let mut message = Vec::new();
+    let mut rest = raw;
+
+    while rest.len() >= 5 {
+        if rest[0] != HANDSHAKE_RECORD {
+            return None;
+        }
+        let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        message.extend_from_slice(rest.get(5..5 + len)?);
+        rest = &rest[5 + len..];
+
+        if message.len() >= 4 {
+            let full = 4 + u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+            if message.len() >= full {
+                message.truncate(full);
+                return Some(message);
+            }
+        }
+    }
+
+    None
+}
+
+struct ClientHelloFields<'a> {
+    random: &'a [u8],
+    session_id: &'a [u8],
+    x25519_share: Option<&'a [u8]>,
+}
+
+fn parse_client_hello(message: &[u8]) -> Option<ClientHelloFields<'_>> {
+    if *message.first()? != CLIENT_HELLO {
+        return None;
+    }
+    let mut body = Reader(message.get(4..)?);
+
+    body.take(2)?;
+    let random = body.take(32)?;
+    let session_id_len = body.u8()? as usize;
+    let session_id = body.take(session_id_len)?;
+    let cipher_suites_len = body.u16()? as usize;
+    body.take(cipher_suites_len)?;
+    let compression_len = body.u8()? as usize;
+    body.take(compression_len)?;
+    let extensions_len = body.u16()? as usize;
+    let mut extensions = Reader(body.take(extensions_len)?);
+
+    let mut x25519_share = None;
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let data = extensions.take(len)?;
+        if kind != KEY_SHARE_EXTENSION {
+            continue;
+        }
+
+        let mut shares = Reader(data);
+        let shares_len = shares.u16()? as usize;
+        let mut shares = Reader(shares.take(shares_len)?);
+        while !shares.0.is_empty() {
+            let group = shares.u16()?;
+            let len = shares.u16()? as usize;
+            let key = shares.take(len)?;
+            if group == X25519_GROUP && key.len() == 32 {
+                x25519_share = Some(key);
+            }
+        }
+    }
+
+    Some(ClientHelloFields {
+        random,
+        session_id,
+        x25519_share,
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+/// A minimal X.509 v3 certificate for an Ed25519 `public_key`, with empty
+/// names and a zeroed 64-byte signature at the very end.
+fn certificate_template(public_key: &[u8]) -> Vec<u8> {
+    let algorithm = der(0x30, &der(0x06, ED25519_OID));
+
+    let mut spki_key = vec![0u8];
+    spki_key.extend_from_slice(public_key);
+    let spki = der(0x30, &[algorithm.clone(), der(0x03, &spki_key)].concat());
+
+    let validity = der(
+        0x30,
+        &[der(0x17, b"000101000000Z"), der(0x17, b"491231235959Z")].concat(),
+    );
+
+    let tbs = der(
+        0x30,
+        &[
+            der(0xa0, &der(0x02, &[2])),
+            der(0x02, &[0]),
+            algorithm.clone(),
+            der(0x30, &[]),
+            validity,
+            der(0x30, &[]),
+            spki,
+        ]
+        .concat(),
+    );
+
+    der(0x30, &[tbs, algorithm, der(0x03, &[0u8; 65])].concat())
+}
+
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    match contents.len() {
+        len @ 0..=0x7f => out.push(len as u8),
+        len @ 0x80..=0xff => out.extend_from_slice(&[0x81, len as u8]),
+        len => {
+            out.push(0x82);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The RFC 7748 X25519 test keys: the server is Bob, the client Alice.
+    const SERVER_PRIVATE_KEY: &str =
+        "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb";
+    const SERVER_PUBLIC_KEY: &str = "3p7bfXt9wbTTW2HC7OQ1Nz-DQ8hbeGdNrfx-FG-IK08";
+
+    /// A ClientHello for example.com with random 00..1f and Alice's key
+    /// share, its session ID sealed the way Xray clients do it for version
+    /// 25.3.1, Unix time 1767225600 and short ID 0123456789abcdef.
+    /// Generated with Python's `cryptography`.
+    const CLIENT_HELLO: &str = "\
+        1603010094010000900303000102030405060708090a0b0c0d0e0f101112131415\
+        161718191a1b1c1d1e1f20151dc0d164dcba21361002d9a21507bf63142c6782a3\
+        159e8c52b88a26c5d182000213010100004500000010000e00000b6578616d706c\
+        652e636f6d002b0003020304003300260024001d00208520f0098930a754748b7d\
+        dcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a";
+
+    /// HKDF-SHA256 of the shared secret, salted with the first 20 bytes
+    /// of the random.
+    const AUTH_KEY: &str = "68e5a4d6fbfc0f93477d737fbdd45bd5f81578fbd172327b6db8e963e2ba4a3c";
+
+    /// Where the session ID starts in `CLIENT_HELLO`, record header
+    /// included.
+    const SESSION_ID: usize = 5 + SESSION_ID_OFFSET;
+
+    fn reality(extra: &str) -> Reality {
+        let config: RealityConfig = toml::from_str(&format!(
+            r#"dest = "example.com:443"
+server_names = ["example.com", "*.example.net"]
+private_key = "{}"
+short_ids = ["0123456789abcdef", "abcd"]
+{}"#,
+            SERVER_PRIVATE_KEY, extra
+        ))
+        .unwrap();
+        Reality::from_config(&config).unwrap()
+    }
+
+    fn auth_key() -> [u8; 32] {
+        hex::decode(AUTH_KEY).unwrap().try_into().unwrap()
+    }
+
+    /// `CLIENT_HELLO` resealed for another time and short ID.
+    fn client_hello(time: u64, short_id: &[u8]) -> Vec<u8> {
+        let mut raw = hex::decode(CLIENT_HELLO).unwrap();
+        raw[SESSION_ID..SESSION_ID + 32].fill(0);
+
+        let mut token = vec![25, 3, 1, 0];
+        token.extend_from_slice(&(time as u32).to_be_bytes());
+        token.extend_from_slice(short_id);
+        token.resize(16, 0);
+
+        let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, &auth_key()).unwrap());
+        let nonce = Nonce::try_assume_unique_for_key(&raw[5 + 6 + 20..5 + 6 + 32]).unwrap();
+        key.seal_in_place_append_tag(nonce, Aad::from(&raw[5..]), &mut token)
+            .unwrap();
+        raw[SESSION_ID..SESSION_ID + 32].copy_from_slice(&token);
+        raw
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn accepts_a_client_hello_sealed_as_xray_does() {
+        let reality = reality("");
+        assert_eq!(reality.public_key(), SERVER_PUBLIC_KEY);
+
+        let raw = hex::decode(CLIENT_HELLO).unwrap();
+        assert_eq!(
+            reality.authenticate(&raw, Some("example.com")),
+            Some(auth_key())
+        );
+        assert_eq!(
+            reality.authenticate(&raw, Some("Example.COM.")),
+            Some(auth_key())
+        );
+        assert_eq!(
+            reality.authenticate(&raw, Some("cdn.example.net")),
+            Some(auth_key())
+        );
+        assert_eq!(
+            client_hello(1767225600, &hex::decode("0123456789abcdef").unwrap()),
+            raw
+        );
+
+        // The same hello split over two records.
+        let (head, body) = raw[5..].split_at(50);
+        let mut split = Vec::new();
+        for fragment in [head, body] {
+            split.extend_from_slice(&[0x16, 0x03, 0x01]);
+            split.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            split.extend_from_slice(fragment);
+        }
+        assert_eq!(
+            reality.authenticate(&split, Some("example.com")),
+            Some(auth_key())
+        );
+    }
+
+    #[test]
+    fn rejects_names_it_does_not_serve() {
+        let reality = reality("");
+        let raw = hex::decode(CLIENT_HELLO).unwrap();
+        for sni in [
+            None,
+            Some("example.org"),
+            Some("www.example.com"),
+            Some("example.net"),
+        ] {
+            assert_eq!(reality.authenticate(&raw, sni), None, "{:?}", sni);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_short_ids() {
+        let reality = reality("");
+        let time = 1767225600;
+        let tampered = client_hello(time, &hex::decode("0123456789abcdee").unwrap());
+        assert_eq!(reality.authenticate(&tampered, Some("example.com")), None);
+        assert_eq!(
+            reality.authenticate(&client_hello(time, &[]), Some("example.com")),
+            None
+        );
+
+        // Shorter IDs are zero-padded on the right.
+        let short = client_hello(time, &[0xab, 0xcd]);
+        assert_eq!(
+            reality.authenticate(&short, Some("example.com")),
+            Some(auth_key())
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_hellos() {
+        let reality = reality("");
+        // A bit of the token, the cipher suite under the seal, and the
+        // client's key share.
+        for at in [SESSION_ID + 3, SESSION_ID + 32 + 3, 152] {
+            let mut raw = hex::decode(CLIENT_HELLO).unwrap();
+            raw[at] ^= 0x01;
+            assert_eq!(
+                reality.authenticate(&raw, Some("example.com")),
+                None,
+                "{}",
+                at
+            );
+        }
+
+        let raw = hex::decode(CLIENT_HELLO).unwrap();
+        assert_eq!(
+            reality.authenticate(&raw[..raw.len() - 1], Some("example.com")),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_max_time_diff() {
+        let reality = reality("max_time_diff = 60");
+        let short_id = hex::decode("0123456789abcdef").unwrap();
+        let check =
+            |time| reality.authenticate(&client_hello(time, &short_id), Some("example.com"));
+
+        assert_eq!(check(now()), Some(auth_key()));
+        assert_eq!(check(now() - 30), Some(auth_key()));
+        assert_eq!(check(now() - 3600), None);
+        assert_eq!(check(now() + 3600), None);
+        assert_eq!(check(1767225600), None);
+    }
+
+    #[test]
+    fn rejects_bad_keys_and_short_ids() {
+        assert!(decode_key(SERVER_PRIVATE_KEY).is_ok());
+        assert!(decode_key("3p7bfXt9wbTTW2HC7OQ1Nz-DQ8hbeGdNrfx-FG-IK08=").is_ok());
+        assert!(decode_key("3p7bfXt9wbTTW2HC7OQ1Nz-DQ8hbeGdNrfx-FG-IK0").is_err());
+
+        assert_eq!(decode_short_id("").unwrap(), [0; 8]);
+        assert_eq!(decode_short_id("ab").unwrap(), [0xab, 0, 0, 0, 0, 0, 0, 0]);
+        for id in ["abc", "0123456789abcdef01", "zz"] {
+            assert!(decode_short_id(id).is_err(), "{}", id);
+        }
+    }
+}
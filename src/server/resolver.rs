@@ -1,32 +1,55 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::sync::Arc;
+
+#[cfg(feature = "trojan")]
+use arc_swap::ArcSwap;
 
 use rustls::{
     server::{ClientHello, ResolvesServerCert},
     sign::CertifiedKey,
 };
 
+use crate::server::tls::CertSet;
+
+/// Serves the chain from the current [`CertSet`] behind an [`ArcSwap`],
+/// so one `ServerConfig` outlives certificate reloads.
+#[cfg(feature = "trojan")]
+#[derive(Debug)]
+pub struct SwappableCertResolver {
+    certs: Arc<ArcSwap<CertSet>>,
+}
+
+#[cfg(feature = "trojan")]
+impl SwappableCertResolver {
+    pub fn new(certs: Arc<ArcSwap<CertSet>>) -> Self {
+        Self { certs }
+    }
+}
+
+#[cfg(feature = "trojan")]
+impl ResolvesServerCert for SwappableCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.certs.load().select(&client_hello)
+    }
+}
+
+/// Serves the chain from a [`CertSet`] that matches the client's signature
+/// algorithms.
+#[cfg(feature = "tuic")]
 #[derive(Debug)]
-pub struct PeerAwareCertResolver {
-    cert: Arc<CertifiedKey>,
-    peer_addr: SocketAddr,
+pub struct CertSetResolver {
+    certs: Arc<CertSet>,
 }
 
-impl PeerAwareCertResolver {
-    pub fn new(cert: Arc<CertifiedKey>, peer_addr: SocketAddr) -> Self {
-        Self { cert, peer_addr }
+#[cfg(feature = "tuic")]
+impl CertSetResolver {
+    pub fn new(certs: Arc<CertSet>) -> Self {
+        Self { certs }
     }
 }
 
-impl ResolvesServerCert for PeerAwareCertResolver {
+#[cfg(feature = "tuic")]
+impl ResolvesServerCert for CertSetResolver {
     fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
-        if let Some(sni) = client_hello.server_name() {
-            tracing::debug!(
-                "[Trojan] Suspicious TLS handshake: IP used as SNI, peer={} sni={}",
-                self.peer_addr,
-                sni
-            );
-        }
-
-        Some(self.cert.clone())
+        self.certs.select(&client_hello)
     }
 }
@@ -0,0 +1,283 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::control::registry::registry;
+use crate::policy;
+use crate::policy::geoip::{self, Verdict};
+use crate::processor::snell::SnellConnectionProcessor;
+use crate::security::banlist;
+
+use super::{Server, ServerStatus, wait_shutdown};
+use crate::net::capabilities::adjust_bind_addr;
+use crate::net::proxy_protocol;
+use crate::net::relay::RelayLimits;
+
+use anyhow::{Context, Error, Result, bail};
+use async_trait::async_trait;
+use ipnet::IpNet;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch::Receiver;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info};
+
+pub struct SnellServer {
+    name: &'static str,
+    socket_addr: SocketAddr,
+    status: ServerStatus,
+    processor: Arc<SnellConnectionProcessor>,
+    shutdown_rx: Option<Receiver<()>>,
+    stop_token: Option<CancellationToken>,
+    tag: Arc<str>,
+    /// Load balancers whose PROXY headers are read, when the inbound
+    /// expects them.
+    proxy_protocol: Option<Arc<[IpNet]>>,
+}
+
+impl SnellServer {
+    pub fn new_with_config(
+        config: std::sync::Arc<crate::config::Config>,
+        shutdown_rx: Option<Receiver<()>>,
+    ) -> Result<Self, Error> {
+        let socket_addr = config
+            .snell()
+            .server_addr()
+            .parse()
+            .map(adjust_bind_addr)
+            .with_context(|| "Failed to parse server address")?;
+
+        if config.snell().psk().is_empty() {
+            bail!("Snell requires a non-empty psk");
+        }
+        let proxy_protocol = if config.snell().proxy_protocol() {
+            let trusted = config.snell().proxy_protocol_from();
+            if trusted.is_empty() {
+                bail!("Snell PROXY protocol needs proxy_protocol_from");
+            }
+            Some(Arc::from(trusted))
+        } else {
+            None
+        };
+
+        let processor = Arc::new(SnellConnectionProcessor::new(
+            config.snell().psk(),
+            config.snell().handshake_timeout(),
+            config.relay_buffer_size(),
+            RelayLimits::new(config.relay_idle_timeout(), config.relay_max_lifetime()),
+        ));
+
+        Ok(Self {
+            name: "Snell",
+            socket_addr,
+            status: ServerStatus::Initializing(Instant::now()),
+            processor,
+            shutdown_rx,
+            stop_token: None,
+            tag: Arc::from(config.snell().tag()),
+            proxy_protocol,
+        })
+    }
+}
+
+impl SnellServer {
+    fn spawn_accept_loop(&mut self, listener: TcpListener) {
+        let processor = Arc::clone(&self.processor);
+        let tag = Arc::clone(&self.tag);
+        let proxy_protocol = self.proxy_protocol.clone();
+        let shutdown_rx = self.shutdown_rx.clone();
+        let stop_token = CancellationToken::new();
+        self.stop_token = Some(stop_token.clone());
+
+        tokio::spawn(async move {
+            if let Err(e) = accept_loop(
+                listener,
+                processor,
+                tag,
+                proxy_protocol,
+                shutdown_rx,
+                stop_token,
+            )
+            .await
+            {
+                error!("[Snell] Accept loop exited with error: {}", e);
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Server for SnellServer {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn init(&mut self) -> Result<Instant, Error> {
+        let instant = Instant::now();
+
+        info!("[Snell] Initializing server at {}", self.socket_addr);
+
+        self.status = ServerStatus::Initializing(instant);
+
+        Ok(instant)
+    }
+
+    async fn start(&mut self) -> Result<Instant, Error> {
+        let instant = Instant::now();
+
+        let listener = TcpListener::bind(self.socket_addr)
+            .await
+            .with_context(|| format!("Failed to bind to {}", self.socket_addr))?;
+
+        info!("[Snell] Listening on {}", self.socket_addr);
+
+        self.spawn_accept_loop(listener);
+
+        self.status = ServerStatus::Running(instant);
+
+        Ok(instant)
+    }
+
+    async fn rebind(&mut self, addr: SocketAddr) -> Result<Instant, Error> {
+        let addr = adjust_bind_addr(addr);
+        if addr == self.socket_addr {
+            return Ok(Instant::now());
+        }
+
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to bind to {}", addr))?;
+
+        // Accepted connections run in their own tasks and outlive the loop.
+        if let Some(stop_token) = self.stop_token.take() {
+            stop_token.cancel();
+        }
+
+        info!("[Snell] Moved from {} to {}", self.socket_addr, addr);
+        self.socket_addr = addr;
+
+        if matches!(self.status, ServerStatus::Running(_)) {
+            self.spawn_accept_loop(listener);
+        }
+
+        Ok(Instant::now())
+    }
+
+    async fn stop(&mut self) -> Result<Instant, Error> {
+        let instant = Instant::now();
+
+        if let Some(stop_token) = self.stop_token.take() {
+            stop_token.cancel();
+        }
+
+        self.status = ServerStatus::Stopped(instant);
+
+        info!("[Snell] Server stopped");
+
+        Ok(instant)
+    }
+
+    async fn status(&mut self) -> Result<&ServerStatus, Error> {
+        Ok(&self.status)
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    processor: Arc<SnellConnectionProcessor>,
+    tag: Arc<str>,
+    proxy_protocol: Option<Arc<[IpNet]>>,
+    mut shutdown_rx: Option<Receiver<()>>,
+    stop_token: CancellationToken,
+) -> Result<(), Error> {
+    loop {
+        tokio::select! {
+            res = listener.accept() => {
+                match res {
+                    Ok((tcp_stream, peer_addr)) => {
+                        if banlist::is_banned(peer_addr.ip()) {
+                            debug!("[Snell] Dropping {}: banned", peer_addr);
+                            continue;
+                        }
+                        debug!("[Snell] Accepted connection from {}", peer_addr);
+                        tokio::spawn(serve(tcp_stream, peer_addr, proxy_protocol.clone(), Arc::clone(&processor), Arc::clone(&tag)));
+                    }
+                    Err(e) => {
+                        error!("[Snell] Failed to accept connection: {}", e);
+                    }
+                }
+            }
+            _ = wait_shutdown(&mut shutdown_rx) => {
+                info!("[Snell] Shutdown signal received, stopping accept loop");
+                break;
+            }
+            _ = stop_token.cancelled() => {
+                info!("[Snell] Server stopped, closing listener");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Takes the client address from the PROXY header when one is expected
+/// from this peer, then admits the client by GeoIP and the inbound's
+/// connection limit.
+async fn serve(
+    mut tcp_stream: TcpStream,
+    mut peer_addr: SocketAddr,
+    proxy_protocol: Option<Arc<[IpNet]>>,
+    processor: Arc<SnellConnectionProcessor>,
+    tag: Arc<str>,
+) {
+    let proxied =
+        proxy_protocol.is_some_and(|trusted| proxy_protocol::trusts(&trusted, peer_addr.ip()));
+    if proxied {
+        match proxy_protocol::read_header(&mut tcp_stream).await {
+            Ok(Some(header)) => {
+                debug!("[Snell] {} is proxying for {}", peer_addr, header.source);
+                peer_addr = header.source;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                debug!("[Snell] Bad PROXY header from {}: {}", peer_addr, e);
+                return;
+            }
+        }
+    }
+
+    if proxied && banlist::is_banned(peer_addr.ip()) {
+        debug!("[Snell] Dropping {}: banned", peer_addr);
+        return;
+    }
+    if geoip::check(peer_addr.ip()) != Verdict::Allow {
+        debug!("[Snell] Rejected {} by GeoIP", peer_addr);
+        return;
+    }
+    if !policy::admits(&tag) {
+        debug!("[Snell] Inbound {} is full, dropping {}", tag, peer_addr);
+        return;
+    }
+
+    handle_connection(tcp_stream, peer_addr, processor, tag).await;
+}
+
+async fn handle_connection(
+    tcp_stream: TcpStream,
+    peer_addr: SocketAddr,
+    processor: Arc<SnellConnectionProcessor>,
+    tag: Arc<str>,
+) {
+    let session = registry().register("Snell", tag, peer_addr);
+
+    tokio::select! {
+        res = processor.process_connection(tcp_stream, peer_addr, &session) => {
+            if let Err(e) = res {
+                debug!("[Snell] Connection processing error: {:#}", e);
+            }
+        }
+        _ = session.kicked() => {
+            info!("[Snell] Connection from {} kicked", peer_addr);
+        }
+    }
+}
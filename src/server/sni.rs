@@ -0,0 +1,84 @@
+use std::net::SocketAddr;
+
+use anyhow::{Result, bail};
+use rustls::server::{Accepted, Acceptor};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+use crate::server::tls::{name_matches, normalize_name};
+
+/// Largest ClientHello we wait for; rustls gives up well before this.
+const MAX_CLIENT_HELLO: usize = 64 * 1024;
+
+/// Splits a TLS listener between the proxy and another TLS server by the
+/// SNI in the ClientHello, before any TLS is terminated.
+#[derive(Debug)]
+pub struct SniRouter {
+    /// Lowercased names; a leading `*.` matches any subdomain.
+    names: Vec<String>,
+    backend: SocketAddr,
+}
+
+impl SniRouter {
+    pub fn new(names: &[String], backend: SocketAddr) -> Self {
+        Self {
+            names: names.iter().map(|name| normalize_name(name)).collect(),
+            backend,
+        }
+    }
+
+    /// Whether the proxy terminates a connection that asked for `sni`.
+    pub fn serves(&self, sni: Option<&str>) -> bool {
+        let Some(sni) = sni else {
+            return false;
+        };
+        let sni = normalize_name(sni);
+
+        self.names.iter().any(|name| name_matches(name, &sni))
+    }
+
+    pub fn backend(&self) -> SocketAddr {
+        self.backend
+    }
+}
+
+/// Reads from `stream` until rustls has parsed a complete ClientHello.
+/// Returns it with every byte read so far, or `None` for a client that
+/// does not speak TLS.
+pub async fn read_client_hello(stream: &mut TcpStream) -> Result<(Option<Accepted>, Vec<u8>)> {
+    let mut acceptor = Acceptor::default();
+    let mut raw = Vec::new();
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            bail!("Connection closed before the ClientHello");
+        }
+        raw.extend_from_slice(&buf[..n]);
+
+        let mut chunk = &buf[..n];
+        while !chunk.is_empty() {
+            acceptor.read_tls(&mut chunk)?;
+        }
+
+        match acceptor.accept() {
+            Ok(Some(accepted)) => return Ok((Some(accepted), raw)),
+            Ok(None) if raw.len() < MAX_CLIENT_HELLO => continue,
+            Ok(None) => return Ok((None, raw)),
+            Err((e, _)) => {
+                debug!("[Trojan] Not a TLS ClientHello: {}", e);
+                return Ok((None, raw));
+            }
+        }
+    }
+}
+
+/// Hands the connection to `backend`, replaying what was already read.
+pub async fn splice(mut stream: TcpStream, backend: SocketAddr, replay: Vec<u8>) -> Result<()> {
+    let mut upstream = crate::net::tcp::connect(backend).await?;
+    upstream.write_all(&replay).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut upstream).await?;
+    Ok(())
+}
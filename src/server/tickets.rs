@@ -0,0 +1,201 @@
+//! Stateless TLS session tickets for the Trojan listeners.
+//!
+//! Every Trojan listener, inbounds included, seals its tickets with the
+//! same ChaCha20-Poly1305 key, so a client resumes wherever it reconnects.
+//! The key is replaced every `ticket_rotation` seconds of `[tls]`; tickets
+//! sealed with the one before stay good until the next rotation drops it,
+//! which bounds how long a leaked key exposes past sessions.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use ring::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, NONCE_LEN, Nonce, UnboundKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::HandshakeKind;
+use rustls::server::ProducesTickets;
+use tokio::time::Instant;
+use tracing::{debug, error};
+
+use crate::config::{MAX_TICKET_ROTATION, TlsConfig};
+
+const KEY_NAME_LEN: usize = 16;
+
+static TICKETS: Lazy<Arc<Tickets>> = Lazy::new(|| Arc::new(Tickets::default()));
+static ROTATION: AtomicU64 = AtomicU64::new(6 * 60 * 60);
+
+static FULL: AtomicU64 = AtomicU64::new(0);
+static RESUMED: AtomicU64 = AtomicU64::new(0);
+
+/// Sets how often the ticket key is replaced, from `[tls]`.
+pub fn init(config: &TlsConfig) {
+    ROTATION.store(config.ticket_rotation().as_secs(), Ordering::Relaxed);
+}
+
+/// The ticketer shared by every listener.
+pub fn ticketer() -> Arc<dyn ProducesTickets> {
+    TICKETS.clone()
+}
+
+/// Counts a completed handshake as full or resumed.
+pub fn record(kind: Option<HandshakeKind>) {
+    match kind {
+        Some(HandshakeKind::Resumed) => RESUMED.fetch_add(1, Ordering::Relaxed),
+        Some(_) => FULL.fetch_add(1, Ordering::Relaxed),
+        None => return,
+    };
+}
+
+/// Handshakes since startup and the share of them resumed.
+#[cfg(feature = "control")]
+pub fn status() -> String {
+    let full = FULL.load(Ordering::Relaxed);
+    let resumed = RESUMED.load(Ordering::Relaxed);
+    let total = full + resumed;
+    let rate = if total == 0 {
+        0.0
+    } else {
+        resumed as f64 * 100.0 / total as f64
+    };
+    format!(
+        "TLS handshakes: {} full, {} resumed ({:.1}% resumed)\n",
+        full, resumed, rate
+    )
+}
+
+fn rotation() -> Duration {
+    Duration::from_secs(ROTATION.load(Ordering::Relaxed))
+}
+
+struct TicketKey {
+    name: [u8; KEY_NAME_LEN],
+    key: LessSafeKey,
+}
+
+impl TicketKey {
+    fn generate() -> Option<Self> {
+        let rng = SystemRandom::new();
+        let mut name = [0u8; KEY_NAME_LEN];
+        let mut key = [0u8; 32];
+        rng.fill(&mut name).ok()?;
+        rng.fill(&mut key).ok()?;
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key).ok()?;
+        Some(Self {
+            name,
+            key: LessSafeKey::new(key),
+        })
+    }
+
+    /// Seals `plain` as key name, nonce, then ciphertext and tag.
+    fn seal(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+
+        let mut ticket = Vec::with_capacity(KEY_NAME_LEN + NONCE_LEN + plain.len() + 16);
+        ticket.extend_from_slice(&self.name);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(plain);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(self.name),
+                &mut ticket[KEY_NAME_LEN + NONCE_LEN..],
+            )
+            .ok()?;
+        ticket.extend_from_slice(tag.as_ref());
+        Some(ticket)
+    }
+
+    fn open(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        let (name, rest) = ticket.split_at_checked(KEY_NAME_LEN)?;
+        if name != self.name {
+            return None;
+        }
+        let (nonce, sealed) = rest.split_at_checked(NONCE_LEN)?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+
+        let mut plain = sealed.to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce, Aad::from(self.name), &mut plain)
+            .ok()?
+            .len();
+        plain.truncate(len);
+        Some(plain)
+    }
+}
+
+#[derive(Default)]
+struct Keys {
+    current: Option<TicketKey>,
+    previous: Option<TicketKey>,
+    rotated_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Tickets {
+    keys: RwLock<Keys>,
+}
+
+impl Tickets {
+    /// Replaces the current key once it is older than the rotation
+    /// interval, keeping it to open the tickets it sealed.
+    fn rotate(&self) {
+        let rotation = rotation();
+        let due = |keys: &Keys| {
+            keys.rotated_at
+                .is_none_or(|rotated_at| rotated_at.elapsed() >= rotation)
+        };
+        if !due(&self.keys.read()) {
+            return;
+        }
+
+        let mut keys = self.keys.write();
+        if !due(&keys) {
+            return;
+        }
+        let Some(fresh) = TicketKey::generate() else {
+            error!("[TLS] Failed to generate a session ticket key");
+            return;
+        };
+        keys.previous = keys.current.take();
+        keys.current = Some(fresh);
+        keys.rotated_at = Some(Instant::now());
+        debug!("[TLS] Rotated the session ticket key");
+    }
+}
+
+impl std::fmt::Debug for Tickets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tickets").finish_non_exhaustive()
+    }
+}
+
+impl ProducesTickets for Tickets {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    /// A ticket is good for at least one interval after it is sealed, but
+    /// never longer than TLS 1.3 lets a client keep it.
+    fn lifetime(&self) -> u32 {
+        rotation().as_secs().min(MAX_TICKET_ROTATION) as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.rotate();
+        self.keys.read().current.as_ref()?.seal(plain)
+    }
+
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        self.rotate();
+        let keys = self.keys.read();
+        [&keys.current, &keys.previous]
+            .into_iter()
+            .flatten()
+            .find_map(|key| key.open(ticket))
+    }
+}
@@ -1,16 +1,34 @@
-use std::io::BufReader;
-use std::net::SocketAddr;
-use std::path::Path;
+use std::fmt;
+use std::io::{BufReader, Write as _};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{Context, Result};
-use rustls::crypto;
+#[cfg(feature = "trojan")]
+use arc_swap::ArcSwap;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rustls::SignatureAlgorithm;
 use rustls::crypto::ring::sign::any_supported_type;
+use rustls::crypto::{CryptoProvider, ring};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::ClientHello;
+#[cfg(feature = "trojan")]
+use rustls::server::WebPkiClientVerifier;
+#[cfg(feature = "trojan")]
+use rustls::server::danger::ClientCertVerifier;
 use rustls::sign::CertifiedKey;
-use rustls::{CipherSuite, ServerConfig};
-use tokio_rustls::TlsAcceptor;
+#[cfg(feature = "trojan")]
+use rustls::{RootCertStore, ServerConfig};
+use sha2::{Digest, Sha256};
+use tracing::{error, info};
 
-use crate::server::resolver::PeerAwareCertResolver;
+#[cfg(feature = "trojan")]
+use crate::config::ClientAuth;
+use crate::config::{CertificateConfig, Config, TlsConfig};
+#[cfg(feature = "trojan")]
+use crate::server::resolver::SwappableCertResolver;
+#[cfg(feature = "trojan")]
+use crate::server::tickets;
 
 pub fn load_certs(path: &Path) -> Result<Vec<rustls::pki_types::CertificateDer<'static>>> {
     let file = std::fs::File::open(path)
@@ -32,17 +50,11 @@ pub fn load_key(path: &Path) -> Result<rustls::pki_types::PrivateKeyDer<'static>
     let file = std::fs::File::open(path)
         .with_context(|| format!("Failed to open private key file: {:?}", path))?;
 
+    // PKCS#8, PKCS#1 (RSA) and SEC1 (EC) keys are all accepted.
     let mut reader = BufReader::new(file);
-    let keys: Vec<_> = rustls_pemfile::pkcs8_private_keys(&mut reader)
-        .collect::<std::result::Result<Vec<_>, _>>()
-        .with_context(|| format!("Failed to parse private keys from file: {:?}", path))?;
-
-    let key = keys
-        .into_iter()
-        .next()
-        .ok_or_else(|| anyhow::anyhow!("No private keys found in file"))?;
-
-    Ok(key.into())
+    rustls_pemfile::private_key(&mut reader)
+        .with_context(|| format!("Failed to parse private keys from file: {:?}", path))?
+        .ok_or_else(|| anyhow::anyhow!("No private keys found in file"))
 }
 
 pub fn build_certified_key(
@@ -54,27 +66,476 @@ pub fn build_certified_key(
     Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
 }
 
-pub fn build_tls_acceptor(
-    base_cert: Arc<CertifiedKey>,
-    peer_addr: SocketAddr,
-) -> Result<TlsAcceptor> {
-    let resolver = Arc::new(PeerAwareCertResolver::new(base_cert, peer_addr));
+/// Loads the certificate chain and key at the given paths and checks that
+/// the key is the one the leaf certificate was issued for.
+pub fn check_key_pair(cert_path: &Path, key_path: &Path) -> Result<()> {
+    load_key_pair(cert_path, key_path).map(|_| ())
+}
+
+/// Loads a chain and its key, refusing a key the leaf was not issued for,
+/// as happens when a reload catches a renewal between the two files.
+fn load_key_pair(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>> {
+    let key = build_certified_key(load_certs(cert_path)?, load_key(key_path)?)?;
+    match key.keys_match() {
+        Err(rustls::Error::InconsistentKeys(rustls::InconsistentKeys::KeyMismatch)) => {
+            anyhow::bail!("{:?} is not the key of {:?}", key_path, cert_path)
+        }
+        _ => Ok(key),
+    }
+}
+
+/// Where a listener gets its certificate chains from.
+pub enum CertSource {
+    /// PEM files, read again on every certificate reload.
+    Files {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+        additional: Vec<CertificateConfig>,
+    },
+    /// Chains handed over in DER form, for embedding without files on disk.
+    Der(Vec<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>),
+}
+
+impl CertSource {
+    pub fn files(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self::Files {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            additional: Vec::new(),
+        }
+    }
+
+    pub fn load(&self) -> Result<CertSet> {
+        match self {
+            Self::Files {
+                cert_path,
+                key_path,
+                additional,
+            } => CertSet::load((cert_path, key_path), additional),
+            Self::Der(chains) => CertSet::from_der(
+                chains
+                    .iter()
+                    .map(|(certs, key)| (certs.clone(), key.clone_key())),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for CertSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Files { cert_path, .. } => write!(f, "{:?}", cert_path),
+            Self::Der(chains) => write!(f, "{} in-memory chain(s)", chains.len()),
+        }
+    }
+}
+
+/// The certificate chains a listener can serve, ordered by preference.
+#[derive(Debug)]
+pub struct CertSet {
+    chains: Vec<Chain>,
+}
+
+#[derive(Debug)]
+struct Chain {
+    /// SNIs this chain is reserved for; empty for a chain that serves any.
+    names: Vec<String>,
+    key: Arc<CertifiedKey>,
+}
+
+impl CertSet {
+    /// Loads the primary chain plus any additional ones.
+    pub fn load(primary: (&Path, &Path), additional: &[CertificateConfig]) -> Result<Self> {
+        let mut chains = vec![Chain {
+            names: Vec::new(),
+            key: load_key_pair(primary.0, primary.1)?,
+        }];
+
+        for extra in additional {
+            let cert_path = Path::new(extra.cert_path());
+            let key_path = Path::new(extra.key_path());
+            chains.push(Chain {
+                names: extra
+                    .server_names()
+                    .iter()
+                    .map(|name| normalize_name(name))
+                    .collect(),
+                key: load_key_pair(cert_path, key_path)?,
+            });
+        }
+
+        Self::from_chains(chains)
+    }
+
+    /// Builds the set from chains already in memory.
+    pub fn from_der<I>(chains: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    {
+        let chains = chains
+            .into_iter()
+            .map(|(certs, key)| {
+                Ok(Chain {
+                    names: Vec::new(),
+                    key: build_certified_key(certs, key)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Self::from_chains(chains)
+    }
+
+    fn from_chains(mut chains: Vec<Chain>) -> Result<Self> {
+        if chains.is_empty() {
+            anyhow::bail!("No certificate chains given");
+        }
+
+        // ECDSA first: smaller and faster, and every modern client takes it.
+        chains.sort_by_key(|chain| algorithm_preference(chain.key.key.algorithm()));
+
+        Ok(Self { chains })
+    }
+
+    /// Picks the chains reserved for the client's SNI, or those serving any
+    /// name when none is, and among them the most preferred one the client
+    /// can verify. Falls back to the most preferred chain overall.
+    pub fn select(&self, client_hello: &ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let schemes = client_hello.signature_schemes();
+        let sni = client_hello.server_name().map(normalize_name);
+
+        let named: Vec<&Chain> = match sni.as_deref() {
+            Some(sni) => self
+                .chains
+                .iter()
+                .filter(|chain| chain.names.iter().any(|name| name_matches(name, sni)))
+                .collect(),
+            None => Vec::new(),
+        };
+        let candidates = if named.is_empty() {
+            self.chains
+                .iter()
+                .filter(|chain| chain.names.is_empty())
+                .collect()
+        } else {
+            named
+        };
+
+        candidates
+            .iter()
+            .find(|chain| chain.key.key.choose_scheme(schemes).is_some())
+            .or_else(|| candidates.first())
+            .map(|chain| &chain.key)
+            .or_else(|| self.chains.first().map(|chain| &chain.key))
+            .cloned()
+    }
+}
+
+/// Lowercases a host name and drops a trailing dot.
+pub fn normalize_name(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Whether the normalized `sni` matches `pattern`; a leading `*.` matches
+/// any subdomain.
+pub fn name_matches(pattern: &str, sni: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => sni
+            .strip_suffix(domain)
+            .is_some_and(|rest| rest.len() > 1 && rest.ends_with('.')),
+        None => pattern == sni,
+    }
+}
+
+fn algorithm_preference(algorithm: SignatureAlgorithm) -> u8 {
+    match algorithm {
+        SignatureAlgorithm::ECDSA => 0,
+        SignatureAlgorithm::ED25519 => 1,
+        SignatureAlgorithm::RSA => 2,
+        _ => 3,
+    }
+}
+
+/// Verifier for client certificates chaining to the CAs in `ca_path`, or
+/// `None` when `mode` asks for none.
+#[cfg(feature = "trojan")]
+pub fn build_client_verifier(
+    mode: ClientAuth,
+    ca_path: Option<&Path>,
+) -> Result<Option<Arc<dyn ClientCertVerifier>>> {
+    if mode == ClientAuth::None {
+        return Ok(None);
+    }
+    let Some(ca_path) = ca_path else {
+        anyhow::bail!("client_auth needs client_ca_path");
+    };
 
-    let mut provider = crypto::ring::default_provider();
-    provider.cipher_suites.retain(|suite| {
-        matches!(
-            suite.suite(),
-            CipherSuite::TLS13_AES_256_GCM_SHA384 | CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
-        )
-    });
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots
+            .add(cert)
+            .with_context(|| format!("Bad client CA certificate in {:?}", ca_path))?;
+    }
+
+    let builder = WebPkiClientVerifier::builder_with_provider(
+        Arc::new(roots),
+        Arc::new(ring::default_provider()),
+    );
+    let builder = match mode {
+        ClientAuth::Optional => builder.allow_unauthenticated(),
+        _ => builder,
+    };
+
+    Ok(Some(builder.build().context(
+        "Failed to build the client certificate verifier",
+    )?))
+}
+
+/// Subject common name of a DER certificate. Only walks as far into the
+/// structure as the subject, so no full X.509 parser is needed.
+#[cfg(feature = "trojan")]
+pub fn subject_common_name(cert: &CertificateDer<'_>) -> Option<String> {
+    const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+
+    let (_, certificate, _) = der_element(cert.as_ref())?;
+    let (_, mut tbs, _) = der_element(certificate)?;
+
+    // Optional [0] version, then serial, signature algorithm, issuer and
+    // validity ahead of the subject.
+    let (tag, _, rest) = der_element(tbs)?;
+    if tag == 0xa0 {
+        tbs = rest;
+    }
+    for _ in 0..4 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (_, mut subject, _) = der_element(tbs)?;
+
+    while !subject.is_empty() {
+        let (_, mut rdn, rest) = der_element(subject)?;
+        subject = rest;
+        while !rdn.is_empty() {
+            let (_, attribute, rest) = der_element(rdn)?;
+            rdn = rest;
+            let (_, oid, value) = der_element(attribute)?;
+            if oid == COMMON_NAME {
+                let (_, value, _) = der_element(value)?;
+                return String::from_utf8(value.to_vec()).ok();
+            }
+        }
+    }
+
+    None
+}
+
+/// When the first certificate of the PEM file at `path` expires.
+pub fn expiry(path: &Path) -> Result<DateTime<Utc>> {
+    load_certs(path)?
+        .first()
+        .and_then(not_after)
+        .with_context(|| format!("No readable expiry date in {:?}", path))
+}
+
+/// When a DER certificate stops being valid (its notAfter), read the same
+/// way as the subject.
+pub fn not_after(cert: &CertificateDer<'_>) -> Option<DateTime<Utc>> {
+    let (_, certificate, _) = der_element(cert.as_ref())?;
+    let (_, mut tbs, _) = der_element(certificate)?;
+
+    let (tag, _, rest) = der_element(tbs)?;
+    if tag == 0xa0 {
+        tbs = rest;
+    }
+    for _ in 0..3 {
+        tbs = der_element(tbs)?.2;
+    }
+    let (_, validity, _) = der_element(tbs)?;
+    let (_, _, rest) = der_element(validity)?;
+    let (tag, time, _) = der_element(rest)?;
+
+    let time = std::str::from_utf8(time).ok()?;
+    // UTCTime has a two-digit year: 50 to 99 are the 1900s.
+    let time = match tag {
+        0x17 if time.get(..2)? >= "50" => format!("19{}", time),
+        0x17 => format!("20{}", time),
+        0x18 => time.to_string(),
+        _ => return None,
+    };
+    NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ")
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+/// Splits the first DER element off `input` as (tag, contents, rest).
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || input.len() < count {
+            return None;
+        }
+        let (bytes, rest) = input.split_at(count);
+        input = rest;
+        bytes
+            .iter()
+            .fold(0usize, |len, byte| len << 8 | *byte as usize)
+    };
+
+    if input.len() < len {
+        return None;
+    }
+    let (contents, rest) = input.split_at(len);
+    Some((tag, contents, rest))
+}
+
+/// The ring provider cut down to the cipher suites and key exchange
+/// groups `[tls]` lists, in its order.
+pub fn crypto_provider(config: &TlsConfig) -> Result<CryptoProvider> {
+    let cipher_suites = config
+        .cipher_suites()
+        .iter()
+        .map(|name| {
+            ring::ALL_CIPHER_SUITES
+                .iter()
+                .find(|suite| {
+                    suite.tls13().is_some()
+                        && format!("{:?}", suite.suite()).eq_ignore_ascii_case(name)
+                })
+                .copied()
+                .with_context(|| format!("Unknown TLS 1.3 cipher suite {:?}", name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let kx_groups = config
+        .key_exchange_groups()
+        .iter()
+        .map(|name| {
+            ring::ALL_KX_GROUPS
+                .iter()
+                .find(|group| format!("{:?}", group.name()).eq_ignore_ascii_case(name))
+                .copied()
+                .with_context(|| format!("Unknown key exchange group {:?}", name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if cipher_suites.is_empty() {
+        anyhow::bail!("No cipher suite to offer");
+    }
+    if kx_groups.is_empty() {
+        anyhow::bail!("No key exchange group to offer");
+    }
+
+    Ok(CryptoProvider {
+        cipher_suites,
+        kx_groups,
+        ..ring::default_provider()
+    })
+}
+
+/// ALPN protocol names as rustls takes them.
+pub fn alpn_protocols(names: &[String]) -> Vec<Vec<u8>> {
+    names.iter().map(|name| name.as_bytes().to_vec()).collect()
+}
+
+#[cfg(feature = "trojan")]
+pub fn build_tls_config(
+    certs: Arc<ArcSwap<CertSet>>,
+    tls: &TlsConfig,
+    alpn: &[Vec<u8>],
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+) -> Result<Arc<ServerConfig>> {
+    let resolver = Arc::new(SwappableCertResolver::new(certs));
 
     static TLS_PROTOCOL_VERSIONS: &[&rustls::SupportedProtocolVersion] = &[&rustls::version::TLS13];
 
-    let config = ServerConfig::builder_with_provider(Arc::new(provider))
+    let builder = ServerConfig::builder_with_provider(Arc::new(crypto_provider(tls)?))
         .with_protocol_versions(TLS_PROTOCOL_VERSIONS)
-        .with_context(|| "Failed to set TLS protocol versions!")?
-        .with_no_client_auth()
-        .with_cert_resolver(resolver);
+        .with_context(|| "Failed to set TLS protocol versions!")?;
+    let mut config = match client_verifier {
+        Some(verifier) => builder.with_client_cert_verifier(verifier),
+        None => builder.with_no_client_auth(),
+    }
+    .with_cert_resolver(resolver);
+    config.alpn_protocols = alpn.to_vec();
+    if tls.session_tickets() {
+        config.ticketer = tickets::ticketer();
+    }
+
+    Ok(Arc::new(config))
+}
+
+/// Generates a certificate for `[self_signed]` over each primary
+/// certificate and key pair whose certificate does not exist yet, and
+/// logs its SHA-256 fingerprint for clients to pin.
+pub fn provision_self_signed(config: &Config) {
+    let Some(self_signed) = config.self_signed() else {
+        return;
+    };
+    for (cert_path, key_path) in config.primary_key_pairs() {
+        let (cert_path, key_path) = (Path::new(cert_path), Path::new(key_path));
+        if cert_path.is_file() {
+            continue;
+        }
+        match generate_self_signed(self_signed.server_names(), cert_path, key_path) {
+            Ok(fingerprint) => info!(
+                "[TLS] Generated a self-signed certificate {:?} for {:?}, SHA-256 fingerprint {}",
+                cert_path,
+                self_signed.server_names(),
+                fingerprint
+            ),
+            Err(e) => error!(
+                "[TLS] Failed to generate a self-signed certificate {:?}: {:#}",
+                cert_path, e
+            ),
+        }
+    }
+}
+
+/// Writes a new P-256 key and a certificate for `names` signed with it,
+/// returning the hex SHA-256 of the certificate.
+fn generate_self_signed(names: &[String], cert_path: &Path, key_path: &Path) -> Result<String> {
+    let key = rcgen::KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)?;
+    let mut params = rcgen::CertificateParams::new(names.to_vec())?;
+    if let Some(name) = names.first() {
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name.as_str());
+    }
+    let cert = params.self_signed(&key)?;
+
+    write_private(key_path, key.serialize_pem().as_bytes())
+        .with_context(|| format!("Failed to save key to {:?}", key_path))?;
+    write_file(cert_path, cert.pem().as_bytes())
+        .with_context(|| format!("Failed to save certificate to {:?}", cert_path))?;
+
+    Ok(hex::encode(Sha256::digest(cert.der())))
+}
+
+/// Writes `contents` to `path` through a temporary file, so readers never
+/// see half a file.
+pub fn write_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    write_atomic(path, contents, 0o644)
+}
+
+/// Like [`write_file`], readable by the owner only.
+pub fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    write_atomic(path, contents, 0o600)
+}
+
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn write_atomic(path: &Path, contents: &[u8], mode: u32) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
 
-    Ok(TlsAcceptor::from(Arc::new(config)))
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    let mut file = options.open(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
 }
@@ -1,34 +1,85 @@
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use crate::acme;
 use crate::authenticate::trojan::TrojanAuthenticationManager;
+use crate::config::{
+    FingerprintAction, Profile, ProxyProtocolVersion, SniffConfig, TlsConfig, TrojanConfig,
+    TrojanUdpConfig, UdpNatMode, UdpSessionConfig,
+};
+use crate::control::registry::registry;
+use crate::events::{self, Event};
+use crate::outbound::Outbound;
+use crate::outbound::trojan::TrojanOutbound;
+use crate::policy;
+use crate::policy::geoip::{self, Verdict};
 use crate::processor::trojan::{RuntimeContext, TrojanConnectionProcessor};
-use crate::server::tls::{build_certified_key, build_tls_acceptor, load_certs, load_key};
+use crate::security::{banlist, fingerprint};
+use crate::server::reality::Reality;
+use crate::server::sni::{self, SniRouter};
+use crate::server::tls::{CertSet, CertSource, build_client_verifier, build_tls_config};
+use crate::server::trojan_fallback::{self, FallbackHandler, FallbackRoute};
 
-use super::{Server, ServerStatus};
+use super::{Server, ServerStatus, wait_shutdown};
+use crate::net::capabilities::adjust_bind_addr;
+use crate::net::proxy_protocol;
+use crate::net::relay::RelayLimits;
+use crate::net::sockopt::TcpOptions;
+use crate::net::util::{listen_addrs, parse_listen};
 
-use anyhow::{Context, Error, Result};
+use anyhow::{Context, Error, Result, bail};
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
+use ipnet::IpNet;
+use rustls::ServerConfig;
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{Acceptor, ClientHello};
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
 use tokio::sync::watch::Receiver;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::LazyConfigAcceptor;
+use tokio_rustls::server::{StartHandshake, TlsStream};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info};
 
-use rustls::sign::CertifiedKey;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 pub struct TrojanServer {
     name: &'static str,
     socket_addr: std::net::SocketAddr,
-    listener: Option<TcpListener>,
+    /// Served on the host of `socket_addr` as well.
+    extra_ports: Vec<u16>,
     status: ServerStatus,
     processor: Arc<TrojanConnectionProcessor>,
-    #[allow(dead_code)]
     fallback_addr: std::net::SocketAddr,
     #[allow(dead_code)]
     shutdown_rx: Option<Receiver<()>>,
-    cert_path: std::path::PathBuf,
-    key_path: std::path::PathBuf,
+    certs: CertSource,
+    cert_key: Option<Arc<ArcSwap<CertSet>>>,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    /// Built once at start and shared by every port; certificate reloads
+    /// go through `cert_key`.
+    inbound: Option<Arc<Inbound>>,
+    /// Stops the accept loop of each bound address.
+    accept_loops: HashMap<SocketAddr, CancellationToken>,
+    /// Unix sockets taking plaintext Trojan; they stay put on rebind.
+    unix_paths: Vec<PathBuf>,
+    unix_loops: Vec<CancellationToken>,
+    tag: Arc<str>,
+    sni_router: Option<Arc<SniRouter>>,
+    reality: Option<Arc<Reality>>,
+    proxy_protocol: bool,
+    proxy_protocol_from: Arc<[IpNet]>,
+    fallback_proxy_protocol: Option<ProxyProtocolVersion>,
+    tls: TlsConfig,
+    alpn: Vec<Vec<u8>>,
+    handshake_timeout: Duration,
+    max_handshakes: Option<usize>,
+    socket_options: TcpOptions,
 }
 
 impl TrojanServer {
@@ -40,36 +91,507 @@ impl TrojanServer {
             .trojan()
             .server_addr()
             .parse()
+            .map(adjust_bind_addr)
             .with_context(|| "Failed to parse server address")?;
 
-        let passwords: Vec<String> = config
+        let fallback_addr: std::net::SocketAddr = config.trojan().fallback_addr().parse()?;
+        let fallback_routes = config
             .trojan()
-            .users()
+            .fallbacks()
             .iter()
-            .map(|u| u.password().to_string())
-            .collect();
+            .map(FallbackRoute::from_config)
+            .collect::<Result<Vec<_>>>()?;
 
-        let auth = Arc::new(TrojanAuthenticationManager::new(passwords));
+        let (listen_ports, unix_paths) = parse_listen(config.trojan().listen())?;
 
-        let fallback_addr: std::net::SocketAddr = config.trojan().fallback_addr().parse()?;
+        let mut builder = TrojanServerBuilder::new(socket)
+            .listen_ports(listen_ports)
+            .listen_unix(unix_paths)
+            .users(trojan_users(&config))
+            .fallback_addr(fallback_addr)
+            .fallback_routes(fallback_routes)
+            .relay_buffer_size(config.relay_buffer_size())
+            .relay_limits(RelayLimits::new(
+                config.relay_idle_timeout(),
+                config.relay_max_lifetime(),
+            ))
+            .udp_nat(
+                config.trojan().udp_nat(),
+                config.trojan().udp_nat_max_mappings(),
+                config.udp_session().session_timeout(),
+            )
+            .udp_limits(config.trojan().udp().clone())
+            .sniff(config.trojan().sniff().clone())
+            .socket_options(TcpOptions::from_config(config.trojan().socket()))
+            .tag(config.trojan().tag())
+            .tls(config.tls().clone())
+            .handshake_timeout(config.trojan().handshake_timeout())
+            .max_handshakes(config.trojan().max_handshakes())
+            .proxy_protocol(config.trojan().proxy_protocol())
+            .proxy_protocol_from(config.trojan().proxy_protocol_from())
+            .fallback_proxy_protocol(config.trojan().fallback_proxy_protocol());
+
+        match config.trojan().reality() {
+            Some(reality) => {
+                let reality = Reality::from_config(reality).context("Failed to set up REALITY")?;
+                info!(
+                    "[Trojan] REALITY enabled, borrowing {}, public key {}",
+                    reality.dest(),
+                    reality.public_key()
+                );
+                builder = builder.reality(Arc::new(reality));
+            }
+            None => {
+                builder = builder.certs(CertSource::Files {
+                    cert_path: PathBuf::from(config.trojan().cert_path()),
+                    key_path: PathBuf::from(config.trojan().key_path()),
+                    additional: config.trojan().certificates().to_vec(),
+                });
+            }
+        }
+
+        let client_ca_path = config.trojan().client_ca_path().map(Path::new);
+        if let Some(verifier) =
+            build_client_verifier(config.trojan().client_auth(), client_ca_path)?
+        {
+            builder =
+                builder.client_auth(verifier, config.trojan().client_cert_replaces_password());
+        }
+
+        if let Some(upstream) = config.trojan().upstream() {
+            let upstream = TrojanOutbound::from_config(upstream, None)
+                .context("Failed to set up the Trojan upstream")?;
+            builder = builder.upstream(Arc::new(upstream));
+        }
+
+        if let Some(backend) = config.trojan().sni_backend() {
+            if config.trojan().server_names().is_empty() {
+                bail!("Trojan sni_backend requires server_names");
+            }
+            let backend = backend
+                .parse()
+                .with_context(|| format!("Failed to parse sni_backend {}", backend))?;
+            builder = builder.sni_backend(config.trojan().server_names(), backend);
+        }
+
+        if let Some(shutdown_rx) = shutdown_rx {
+            builder = builder.shutdown(shutdown_rx);
+        }
+
+        builder.build()
+    }
+}
+
+/// Assembles a [`TrojanServer`] in code, without a config file.
+///
+/// Users, certificates and the connection processor are given directly;
+/// anything left unset takes the same default as an empty `[trojan]`
+/// section. A processor passed to [`processor`](Self::processor) replaces
+/// the one built from the users and relay settings.
+pub struct TrojanServerBuilder {
+    socket_addr: SocketAddr,
+    listen_ports: Vec<u16>,
+    unix_paths: Vec<PathBuf>,
+    users: Vec<(String, String)>,
+    certs: Option<CertSource>,
+    fallback_addr: SocketAddr,
+    fallback_routes: Vec<FallbackRoute>,
+    relay_buffer_size: usize,
+    relay_limits: RelayLimits,
+    udp_nat: (UdpNatMode, usize, Duration),
+    udp_limits: TrojanUdpConfig,
+    sniff: SniffConfig,
+    socket_options: TcpOptions,
+    processor: Option<Arc<TrojanConnectionProcessor>>,
+    sni_router: Option<Arc<SniRouter>>,
+    reality: Option<Arc<Reality>>,
+    proxy_protocol: bool,
+    proxy_protocol_from: Vec<IpNet>,
+    fallback_proxy_protocol: Option<ProxyProtocolVersion>,
+    tls: TlsConfig,
+    handshake_timeout: Duration,
+    max_handshakes: Option<usize>,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    client_cert_replaces_password: bool,
+    upstream: Option<Arc<dyn Outbound>>,
+    tag: Arc<str>,
+    shutdown_rx: Option<Receiver<()>>,
+}
+
+impl TrojanServerBuilder {
+    pub fn new(socket_addr: SocketAddr) -> Self {
+        let defaults = TrojanConfig::default();
+        let udp_session = UdpSessionConfig::default();
+
+        Self {
+            socket_addr,
+            listen_ports: Vec::new(),
+            unix_paths: Vec::new(),
+            users: Vec::new(),
+            certs: None,
+            fallback_addr: defaults
+                .fallback_addr()
+                .parse()
+                .expect("default fallback address is valid"),
+            fallback_routes: Vec::new(),
+            relay_buffer_size: Profile::default().defaults().relay_buffer_size,
+            relay_limits: RelayLimits::default(),
+            udp_nat: (
+                defaults.udp_nat(),
+                defaults.udp_nat_max_mappings(),
+                udp_session.session_timeout(),
+            ),
+            udp_limits: defaults.udp().clone(),
+            sniff: defaults.sniff().clone(),
+            socket_options: TcpOptions::from_config(defaults.socket()),
+            processor: None,
+            sni_router: None,
+            reality: None,
+            proxy_protocol: false,
+            proxy_protocol_from: Vec::new(),
+            fallback_proxy_protocol: None,
+            tls: TlsConfig::default(),
+            handshake_timeout: defaults.handshake_timeout(),
+            max_handshakes: defaults.max_handshakes(),
+            client_verifier: None,
+            client_cert_replaces_password: defaults.client_cert_replaces_password(),
+            upstream: None,
+            tag: Arc::from(defaults.tag()),
+            shutdown_rx: None,
+        }
+    }
+
+    /// More ports served on the same host as the socket address.
+    pub fn listen_ports(mut self, ports: Vec<u16>) -> Self {
+        self.listen_ports = ports;
+        self
+    }
+
+    /// Unix sockets a TLS terminator in front hands decrypted connections
+    /// to, each carrying plaintext Trojan.
+    pub fn listen_unix(mut self, paths: Vec<PathBuf>) -> Self {
+        self.unix_paths = paths;
+        self
+    }
+
+    /// Adds a user identified by `identity` that logs in with `password`.
+    pub fn user(mut self, identity: impl Into<String>, password: impl Into<String>) -> Self {
+        self.users.push((identity.into(), password.into()));
+        self
+    }
+
+    pub fn users<I>(mut self, users: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        self.users.extend(users);
+        self
+    }
+
+    pub fn certs(mut self, certs: CertSource) -> Self {
+        self.certs = Some(certs);
+        self
+    }
 
-        let processor =
-            Arc::new(TrojanConnectionProcessor::new(auth).with_fallback_addr(fallback_addr));
+    pub fn fallback_addr(mut self, fallback_addr: SocketAddr) -> Self {
+        self.fallback_addr = fallback_addr;
+        self
+    }
+
+    /// Fallback servers picked by ALPN and request path ahead of the
+    /// fallback address. ALPN protocols they name are offered to clients.
+    pub fn fallback_routes(mut self, routes: Vec<FallbackRoute>) -> Self {
+        self.fallback_routes = routes;
+        self
+    }
+
+    pub fn relay_buffer_size(mut self, relay_buffer_size: usize) -> Self {
+        self.relay_buffer_size = relay_buffer_size;
+        self
+    }
+
+    pub fn relay_limits(mut self, relay_limits: RelayLimits) -> Self {
+        self.relay_limits = relay_limits;
+        self
+    }
+
+    pub fn udp_nat(
+        mut self,
+        mode: UdpNatMode,
+        max_mappings: usize,
+        idle_timeout: Duration,
+    ) -> Self {
+        self.udp_nat = (mode, max_mappings, idle_timeout);
+        self
+    }
+
+    /// Frame size, packet rate and byte caps for each UDP association.
+    pub fn udp_limits(mut self, limits: TrojanUdpConfig) -> Self {
+        self.udp_limits = limits;
+        self
+    }
+
+    pub fn sniff(mut self, sniff: SniffConfig) -> Self {
+        self.sniff = sniff;
+        self
+    }
+
+    /// Options set on accepted sockets, the listeners and the connections
+    /// opened for clients.
+    pub fn socket_options(mut self, options: TcpOptions) -> Self {
+        self.socket_options = options;
+        self
+    }
+
+    pub fn processor(mut self, processor: Arc<TrojanConnectionProcessor>) -> Self {
+        self.processor = Some(processor);
+        self
+    }
+
+    /// Serves only `server_names` and splices every other SNI to `backend`.
+    pub fn sni_backend(mut self, server_names: &[String], backend: SocketAddr) -> Self {
+        self.sni_router = Some(Arc::new(SniRouter::new(server_names, backend)));
+        self
+    }
+
+    /// Runs the listener in REALITY mode: only clients holding its key get
+    /// a handshake, everyone else is spliced to the borrowed site. Stands
+    /// in for [`certs`](Self::certs) and takes precedence over
+    /// [`sni_backend`](Self::sni_backend).
+    pub fn reality(mut self, reality: Arc<Reality>) -> Self {
+        self.reality = Some(reality);
+        self
+    }
+
+    /// Expects a PROXY protocol header on every connection from
+    /// [`proxy_protocol_from`](Self::proxy_protocol_from).
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Load balancers whose PROXY headers are believed; other TCP peers
+    /// are served under their own address.
+    pub fn proxy_protocol_from(mut self, trusted: Vec<IpNet>) -> Self {
+        self.proxy_protocol_from = trusted;
+        self
+    }
+
+    /// Sends a PROXY protocol header to the fallback and SNI backend.
+    pub fn fallback_proxy_protocol(mut self, version: Option<ProxyProtocolVersion>) -> Self {
+        self.fallback_proxy_protocol = version;
+        self
+    }
+
+    /// Cipher suites, key exchange groups and ALPN protocols offered.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Caps the TLS handshakes in flight; connections over it are closed
+    /// as soon as they are accepted.
+    pub fn max_handshakes(mut self, max: Option<usize>) -> Self {
+        self.max_handshakes = max;
+        self
+    }
+
+    /// Asks clients for a certificate checked by `verifier`; the subject CN
+    /// of a verified one becomes the user identity. With
+    /// `replaces_password` such clients skip the password check.
+    pub fn client_auth(
+        mut self,
+        verifier: Arc<dyn ClientCertVerifier>,
+        replaces_password: bool,
+    ) -> Self {
+        self.client_verifier = Some(verifier);
+        self.client_cert_replaces_password = replaces_password;
+        self
+    }
 
-        Ok(Self {
+    /// Forwards CONNECT requests to another Trojan server.
+    pub fn upstream(mut self, upstream: Arc<dyn Outbound>) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tag = Arc::from(tag);
+        self
+    }
+
+    pub fn shutdown(mut self, shutdown_rx: Receiver<()>) -> Self {
+        self.shutdown_rx = Some(shutdown_rx);
+        self
+    }
+
+    pub fn build(self) -> Result<TrojanServer, Error> {
+        let certs = match (self.certs, &self.reality) {
+            (Some(certs), _) => certs,
+            (None, Some(reality)) => reality.cert_source(),
+            (None, None) => bail!("Trojan server needs certificates"),
+        };
+        if cfg!(not(unix)) && !self.unix_paths.is_empty() {
+            bail!("Unix socket listeners are not supported on this platform");
+        }
+        if self.proxy_protocol && self.proxy_protocol_from.is_empty() {
+            bail!("PROXY protocol needs the load balancers to trust it from");
+        }
+
+        let processor = match self.processor {
+            Some(processor) => processor,
+            None => {
+                let (mode, max_mappings, idle_timeout) = self.udp_nat;
+                let auth = Arc::new(TrojanAuthenticationManager::new(self.users));
+                Arc::new(
+                    TrojanConnectionProcessor::new(auth)
+                        .with_fallback_addr(self.fallback_addr)
+                        .with_fallback_proxy_protocol(self.fallback_proxy_protocol)
+                        .with_fallback_routes(self.fallback_routes.clone())
+                        .with_relay_buffer_size(self.relay_buffer_size)
+                        .with_relay_limits(self.relay_limits)
+                        .with_udp_nat(mode, max_mappings, idle_timeout)
+                        .with_udp_limits(self.udp_limits)
+                        .with_sniff(self.sniff)
+                        .with_socket_options(self.socket_options)
+                        .with_request_timeout(self.handshake_timeout)
+                        .with_client_cert_replaces_password(self.client_cert_replaces_password)
+                        .with_upstream(self.upstream),
+                )
+            }
+        };
+
+        Ok(TrojanServer {
             name: "Trojan",
-            socket_addr: socket,
-            listener: None,
+            socket_addr: self.socket_addr,
+            extra_ports: self.listen_ports,
             status: ServerStatus::Initializing(Instant::now()),
             processor,
-            fallback_addr,
-            shutdown_rx,
-            cert_path: PathBuf::from(config.trojan().cert_path()),
-            key_path: PathBuf::from(config.trojan().key_path()),
+            fallback_addr: self.fallback_addr,
+            shutdown_rx: self.shutdown_rx,
+            certs,
+            cert_key: None,
+            client_verifier: self.client_verifier,
+            inbound: None,
+            accept_loops: HashMap::new(),
+            unix_paths: self.unix_paths,
+            unix_loops: Vec::new(),
+            tag: self.tag,
+            sni_router: self.sni_router,
+            reality: self.reality,
+            proxy_protocol: self.proxy_protocol,
+            proxy_protocol_from: Arc::from(self.proxy_protocol_from),
+            fallback_proxy_protocol: self.fallback_proxy_protocol,
+            alpn: trojan_fallback::offered_alpn(self.tls.alpn().trojan(), &self.fallback_routes),
+            tls: self.tls,
+            handshake_timeout: self.handshake_timeout,
+            max_handshakes: self.max_handshakes,
+            socket_options: self.socket_options,
         })
     }
 }
 
+impl TrojanServer {
+    fn load_cert_set(&self) -> Result<CertSet> {
+        self.certs.load()
+    }
+
+    fn build_inbound(&self, tls_config: Arc<ServerConfig>) -> Inbound {
+        Inbound {
+            tls_config,
+            processor: Arc::clone(&self.processor),
+            tag: Arc::clone(&self.tag),
+            fallbacks: Fallbacks {
+                addr: self.fallback_addr,
+                sni: self.sni_router.clone(),
+                proxy_protocol: self.fallback_proxy_protocol,
+            },
+            reality: self.reality.clone(),
+            proxy_protocol: self.proxy_protocol,
+            proxy_protocol_from: Arc::clone(&self.proxy_protocol_from),
+            handshake_timeout: self.handshake_timeout,
+            handshakes: self.max_handshakes.map(|max| Arc::new(Semaphore::new(max))),
+            socket_options: self.socket_options,
+        }
+    }
+
+    /// `primary` and the same host on every extra port.
+    fn listen_addrs(&self, primary: SocketAddr) -> Vec<SocketAddr> {
+        listen_addrs(primary, &self.extra_ports)
+    }
+
+    /// Binds each of `addrs` that is not served yet; fails without keeping
+    /// any if one of them cannot be bound.
+    async fn bind_new(&self, addrs: &[SocketAddr]) -> Result<Vec<(SocketAddr, TcpListener)>> {
+        let mut bound = Vec::new();
+        for addr in addrs {
+            if self.accept_loops.contains_key(addr) {
+                continue;
+            }
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to bind to {}", addr))?;
+            self.socket_options.apply_listener(&listener);
+            bound.push((*addr, listener));
+        }
+        Ok(bound)
+    }
+
+    fn spawn_accept_loop(
+        &mut self,
+        addr: SocketAddr,
+        listener: TcpListener,
+        inbound: Arc<Inbound>,
+    ) {
+        let stop_token = self.spawn_listener(Listener::Tcp(listener), inbound);
+        self.accept_loops.insert(addr, stop_token);
+    }
+
+    /// Binds every Unix socket, replacing stale socket files left behind
+    /// by an earlier run.
+    #[cfg(unix)]
+    fn bind_unix(&mut self, inbound: &Arc<Inbound>) -> Result<()> {
+        use std::os::unix::fs::FileTypeExt;
+
+        for path in self.unix_paths.clone() {
+            if std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Failed to remove stale socket {}", path.display()))?;
+            }
+            let listener = UnixListener::bind(&path)
+                .with_context(|| format!("Failed to bind to {}", path.display()))?;
+            info!("[Trojan] Listening on unix://{}", path.display());
+
+            let stop_token = self.spawn_listener(Listener::Unix(listener), Arc::clone(inbound));
+            self.unix_loops.push(stop_token);
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn bind_unix(&mut self, _inbound: &Arc<Inbound>) -> Result<()> {
+        Ok(())
+    }
+
+    fn spawn_listener(&self, listener: Listener, inbound: Arc<Inbound>) -> CancellationToken {
+        let shutdown_rx = self.shutdown_rx.clone();
+        let stop_token = CancellationToken::new();
+
+        let token = stop_token.clone();
+        tokio::spawn(async move {
+            if let Err(e) = accept_loop(listener, inbound, shutdown_rx, token).await {
+                error!("[Trojan] Accept loop exited with error: {}", e);
+            }
+        });
+        stop_token
+    }
+}
+
 #[async_trait]
 impl Server for TrojanServer {
     fn name(&self) -> &'static str {
@@ -93,35 +615,68 @@ impl Server for TrojanServer {
 
         info!("[Trojan] Starting server at {}", self.socket_addr);
 
-        let certs = load_certs(&self.cert_path)?;
-        let key = load_key(&self.key_path)?;
+        let cert_key = Arc::new(ArcSwap::from_pointee(self.load_cert_set()?));
+        let tls_config = build_tls_config(
+            Arc::clone(&cert_key),
+            &self.tls,
+            &self.alpn,
+            self.client_verifier.clone(),
+        )?;
+        self.cert_key = Some(cert_key);
+        let inbound = Arc::new(self.build_inbound(tls_config));
+        self.inbound = Some(Arc::clone(&inbound));
 
-        let cert_key = build_certified_key(certs, key)?;
+        let addrs = self.listen_addrs(self.socket_addr);
+        for (addr, listener) in self.bind_new(&addrs).await? {
+            info!("[Trojan] Listening on {}", addr);
+            self.spawn_accept_loop(addr, listener, Arc::clone(&inbound));
+        }
+        self.bind_unix(&inbound)?;
 
-        let listener = TcpListener::bind(self.socket_addr)
-            .await
-            .with_context(|| format!("Failed to bind to {}", self.socket_addr))?;
+        self.status = ServerStatus::Running(instant);
+
+        info!("[Trojan] Server started");
+
+        Ok(instant)
+    }
 
-        info!("[Trojan] Listening on {}", self.socket_addr);
+    async fn rebind(&mut self, addr: std::net::SocketAddr) -> Result<Instant, Error> {
+        let addr = adjust_bind_addr(addr);
+        if addr == self.socket_addr {
+            return Ok(Instant::now());
+        }
 
-        self.listener = Some(listener);
+        // Ports that stay put keep their listeners.
+        let addrs = self.listen_addrs(addr);
+        let bound = self.bind_new(&addrs).await?;
 
-        if let Some(listener) = self.listener.take() {
-            let processor = Arc::clone(&self.processor);
-            let shutdown_rx = self.shutdown_rx.take();
+        // Accepted connections run in their own tasks and outlive the loop.
+        self.accept_loops.retain(|addr, stop_token| {
+            let keep = addrs.contains(addr);
+            if !keep {
+                stop_token.cancel();
+            }
+            keep
+        });
 
-            tokio::spawn(async move {
-                if let Err(e) = accept_loop(listener, cert_key, processor, shutdown_rx).await {
-                    error!("[Trojan] Accept loop exited with error: {}", e);
-                }
-            });
+        info!("[Trojan] Moved from {} to {}", self.socket_addr, addr);
+        self.socket_addr = addr;
+
+        if let Some(inbound) = self.inbound.clone() {
+            for (addr, listener) in bound {
+                self.spawn_accept_loop(addr, listener, Arc::clone(&inbound));
+            }
         }
 
-        self.status = ServerStatus::Running(instant);
+        Ok(Instant::now())
+    }
 
-        info!("[Trojan] Server started");
+    async fn reload(&mut self, config: Arc<crate::config::Config>) -> Result<Instant, Error> {
+        let users = trojan_users(&config);
+        info!("[Trojan] Reloaded {} user(s)", users.len());
+        self.processor.replace_users(users);
 
-        Ok(instant)
+        Ok(Instant::now())
     }
 
     async fn stop(&mut self) -> Result<Instant, Error> {
@@ -129,7 +684,15 @@ impl Server for TrojanServer {
 
         info!("[Trojan] Stopping server");
 
-        self.listener = None;
+        for (_, stop_token) in self.accept_loops.drain() {
+            stop_token.cancel();
+        }
+        for stop_token in self.unix_loops.drain(..) {
+            stop_token.cancel();
+        }
+        for path in &self.unix_paths {
+            let _ = std::fs::remove_file(path);
+        }
 
         self.status = ServerStatus::Stopped(instant);
 
@@ -138,52 +701,159 @@ impl Server for TrojanServer {
         Ok(instant)
     }
 
+    async fn reload_certificates(&mut self) -> Result<Instant, Error> {
+        let Some(cert_key) = &self.cert_key else {
+            bail!("[Trojan] Server is not started");
+        };
+
+        cert_key.store(Arc::new(self.load_cert_set()?));
+
+        info!("[Trojan] Certificates reloaded from {}", self.certs);
+
+        Ok(Instant::now())
+    }
+
     async fn status(&mut self) -> Result<&ServerStatus, Error> {
         Ok(&self.status)
     }
 }
 
-async fn accept_loop(
-    listener: TcpListener,
-    cert_key: Arc<CertifiedKey>,
+/// Where connections the proxy does not serve itself are sent.
+struct Fallbacks {
+    /// Plain TCP server for clients sent away by GeoIP.
+    addr: SocketAddr,
+    /// TLS server for SNIs that are not the proxy's.
+    sni: Option<Arc<SniRouter>>,
+    /// PROXY protocol header written ahead of the replayed bytes.
+    proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+impl Fallbacks {
+    /// What a fallback connection starts with: the PROXY header, if one is
+    /// configured, then `replay`.
+    fn preamble(
+        &self,
+        client_addr: SocketAddr,
+        local_addr: SocketAddr,
+        replay: Vec<u8>,
+    ) -> Vec<u8> {
+        match self.proxy_protocol {
+            Some(version) => {
+                let mut preamble = proxy_protocol::encode(version, client_addr, local_addr);
+                preamble.extend_from_slice(&replay);
+                preamble
+            }
+            None => replay,
+        }
+    }
+}
+
+/// What every connection accepted by the server shares, whichever port it
+/// came in on.
+struct Inbound {
+    tls_config: Arc<ServerConfig>,
     processor: Arc<TrojanConnectionProcessor>,
+    tag: Arc<str>,
+    fallbacks: Fallbacks,
+    reality: Option<Arc<Reality>>,
+    /// Whether connections start with a PROXY protocol header.
+    proxy_protocol: bool,
+    /// TCP peers whose PROXY headers are believed. Unix socket clients
+    /// always are.
+    proxy_protocol_from: Arc<[IpNet]>,
+    /// How long a client has from connecting to finishing the handshake.
+    handshake_timeout: Duration,
+    /// Bounds the handshakes in flight at once.
+    handshakes: Option<Arc<Semaphore>>,
+    socket_options: TcpOptions,
+}
+
+/// A socket clients are accepted on.
+enum Listener {
+    Tcp(TcpListener),
+    /// Plaintext Trojan from a TLS terminator on the same host.
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+enum Incoming {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    async fn accept(&self) -> std::io::Result<Incoming> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, peer_addr)| Incoming::Tcp(stream, peer_addr)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, _)| Incoming::Unix(stream)),
+        }
+    }
+}
+
+/// The `(identity, password)` pairs of everyone `config` lets use Trojan.
+fn trojan_users(config: &crate::config::Config) -> Vec<(String, String)> {
+    config
+        .trojan_credentials()
+        .iter()
+        .map(|c| (c.identity.to_string(), c.password.to_string()))
+        .collect()
+}
+
+async fn accept_loop(
+    listener: Listener,
+    inbound: Arc<Inbound>,
     mut shutdown_rx: Option<Receiver<()>>,
+    stop_token: CancellationToken,
 ) -> Result<(), Error> {
     loop {
-        let accept_fut = listener.accept();
-
-        if let Some(ref mut rx) = shutdown_rx {
-            tokio::select! {
-                biased;
-                res = accept_fut => {
-                    match res {
-                        Ok((tcp_stream, peer_addr)) => {
-                            debug!("[Trojan] Accepted connection from {}", peer_addr);
-                            let key = Arc::clone(&cert_key);
-                            let proc = Arc::clone(&processor);
-                            tokio::spawn(handle_connection(tcp_stream, peer_addr, key, proc));
-                        }
-                        Err(e) => {
-                            error!("[Trojan] Failed to accept connection: {}", e);
+        tokio::select! {
+            biased;
+            res = listener.accept() => {
+                match res {
+                    #[cfg(unix)]
+                    Ok(Incoming::Unix(stream)) => {
+                        tokio::spawn(serve_unix(stream, Arc::clone(&inbound)));
+                    }
+                    Ok(Incoming::Tcp(tcp_stream, peer_addr)) => {
+                        if banlist::is_banned(peer_addr.ip()) {
+                            debug!("[Trojan] Dropping {}: banned", peer_addr);
+                            continue;
                         }
+                        inbound.socket_options.apply(&tcp_stream);
+                        debug!("[Trojan] Accepted connection from {}", peer_addr);
+                        // Over the cap, the socket is closed right here.
+                        let permit = match &inbound.handshakes {
+                            Some(handshakes) => match Arc::clone(handshakes).try_acquire_owned() {
+                                Ok(permit) => Some(permit),
+                                Err(_) => {
+                                    debug!("[Trojan] Too many handshakes in flight, dropping {}", peer_addr);
+                                    continue;
+                                }
+                            },
+                            None => None,
+                        };
+                        tokio::spawn(serve(tcp_stream, peer_addr, Arc::clone(&inbound), permit));
+                    }
+                    Err(e) => {
+                        error!("[Trojan] Failed to accept connection: {}", e);
                     }
-                }
-                _ = rx.changed() => {
-                    info!("[Trojan] Shutdown signal received, stopping accept loop");
-                    break;
                 }
             }
-        } else {
-            match accept_fut.await {
-                Ok((tcp_stream, peer_addr)) => {
-                    debug!("[Trojan] Accepted connection from {}", peer_addr);
-                    let key = Arc::clone(&cert_key);
-                    let proc = Arc::clone(&processor);
-                    tokio::spawn(handle_connection(tcp_stream, peer_addr, key, proc));
-                }
-                Err(e) => {
-                    error!("[Trojan] Failed to accept connection: {}", e);
-                }
+            _ = wait_shutdown(&mut shutdown_rx) => {
+                info!("[Trojan] Shutdown signal received, stopping accept loop");
+                break;
+            }
+            _ = stop_token.cancelled() => {
+                info!("[Trojan] Server stopped, closing listener");
+                break;
             }
         }
     }
@@ -191,38 +861,463 @@ async fn accept_loop(
     Ok(())
 }
 
-async fn handle_connection(
-    tcp_stream: TcpStream,
+/// Works out who the client is, from the PROXY header when there is one,
+/// and admits them by GeoIP and the inbound's connection limit.
+async fn serve(
+    mut tcp_stream: TcpStream,
     peer_addr: SocketAddr,
-    cert_key: Arc<CertifiedKey>,
-    processor: Arc<TrojanConnectionProcessor>,
+    inbound: Arc<Inbound>,
+    permit: Option<OwnedSemaphorePermit>,
 ) {
-    let tls_acceptor = build_tls_acceptor(cert_key, peer_addr);
-
-    let tls_acceptor = match tls_acceptor {
-        Ok(a) => a,
+    let mut client_addr = peer_addr;
+    let mut local_addr = match tcp_stream.local_addr() {
+        Ok(addr) => addr,
         Err(e) => {
-            debug!("[Trojan] TLS acceptor not initialized {}", e);
+            debug!("[Trojan] Connection from {} lost: {}", peer_addr, e);
             return;
         }
     };
 
-    let tcp_stream = tcp_stream;
+    // Anyone else could name any address, to slip past bans and GeoIP or
+    // to get someone else banned.
+    let proxied = inbound.proxy_protocol
+        && proxy_protocol::trusts(&inbound.proxy_protocol_from, peer_addr.ip());
+    if proxied {
+        match proxy_protocol::read_header(&mut tcp_stream).await {
+            Ok(Some(header)) => {
+                debug!("[Trojan] {} is proxying for {}", peer_addr, header.source);
+                client_addr = header.source;
+                local_addr = header.destination;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                debug!("[Trojan] Bad PROXY header from {}: {}", peer_addr, e);
+                return;
+            }
+        }
+    }
 
-    match tls_acceptor.accept(tcp_stream).await {
-        Ok(tls_stream) => {
-            debug!("[Trojan] TLS handshake completed with {}", peer_addr);
-            let context = Arc::new(RuntimeContext::new(peer_addr));
+    if proxied && banlist::is_banned(client_addr.ip()) {
+        debug!("[Trojan] Dropping {}: banned", client_addr);
+        return;
+    }
+    match geoip::check(client_addr.ip()) {
+        Verdict::Allow => {}
+        Verdict::Reject => {
+            debug!("[Trojan] Rejected {} by GeoIP", client_addr);
+            return;
+        }
+        Verdict::Fallback => {
+            debug!("[Trojan] Sending {} to fallback by GeoIP", client_addr);
+            let preamble = inbound
+                .fallbacks
+                .preamble(client_addr, local_addr, Vec::new());
+            let _ = FallbackHandler::handle_fallback(tcp_stream, inbound.fallbacks.addr, preamble)
+                .await;
+            return;
+        }
+    }
+    if !policy::admits(&inbound.tag) {
+        debug!(
+            "[Trojan] Inbound {} is full, dropping {}",
+            inbound.tag, client_addr
+        );
+        return;
+    }
+
+    handle_connection(tcp_stream, client_addr, local_addr, &inbound, permit).await;
+}
+
+/// Serves plaintext Trojan handed over on a Unix socket. The client
+/// address comes from the PROXY header when the inbound expects one; without
+/// it every client shows up as `banlist::UNADDRESSED`, which failures are
+/// not counted against.
+#[cfg(unix)]
+async fn serve_unix(mut stream: UnixStream, inbound: Arc<Inbound>) {
+    let (mut client_addr, mut local_addr) = (banlist::UNADDRESSED, banlist::UNADDRESSED);
+
+    if inbound.proxy_protocol {
+        match proxy_protocol::read_header(&mut stream).await {
+            Ok(Some(header)) => {
+                client_addr = header.source;
+                local_addr = header.destination;
+            }
+            Ok(None) => {}
+            Err(e) => {
+                debug!("[Trojan] Bad PROXY header on Unix socket: {}", e);
+                return;
+            }
+        }
+    }
+    debug!("[Trojan] Accepted {} on Unix socket", client_addr);
+
+    if inbound.proxy_protocol && banlist::is_banned(client_addr.ip()) {
+        debug!("[Trojan] Dropping {}: banned", client_addr);
+        return;
+    }
+    match geoip::check(client_addr.ip()) {
+        Verdict::Allow => {}
+        Verdict::Reject => {
+            debug!("[Trojan] Rejected {} by GeoIP", client_addr);
+            return;
+        }
+        Verdict::Fallback => {
+            debug!("[Trojan] Sending {} to fallback by GeoIP", client_addr);
+            let preamble = inbound
+                .fallbacks
+                .preamble(client_addr, local_addr, Vec::new());
+            let _ =
+                FallbackHandler::handle_fallback(stream, inbound.fallbacks.addr, preamble).await;
+            return;
+        }
+    }
+    if !policy::admits(&inbound.tag) {
+        debug!(
+            "[Trojan] Inbound {} is full, dropping {}",
+            inbound.tag, client_addr
+        );
+        return;
+    }
 
-            if let Err(e) = processor.process_connection_tls(tls_stream, context).await {
+    let session = Arc::new(registry().register("Trojan", Arc::clone(&inbound.tag), client_addr));
+    let context = Arc::new(RuntimeContext::new(
+        client_addr,
+        local_addr,
+        Arc::clone(&session),
+    ));
+
+    tokio::select! {
+        res = inbound.processor.process_connection_plain(stream, context) => {
+            if let Err(e) = res {
                 debug!("[Trojan] Connection processing error: {}", e);
             }
         }
-        Err(e) => {
+        _ = session.kicked() => {
+            info!("[Trojan] Connection from {} kicked", client_addr);
+        }
+    }
+}
+
+/// How the TLS side of a connection was settled.
+enum Handshake {
+    /// The proxy terminated TLS.
+    Done(Box<TlsStream<TcpStream>>),
+    /// The SNI belongs to the backend; `Vec` holds the bytes already read.
+    Passthrough(TcpStream, Vec<u8>),
+    /// Not a REALITY client; the borrowed site gets the connection.
+    Borrowed(TcpStream, Vec<u8>),
+    /// The ClientHello matched `[fingerprints]`.
+    Scanner(TcpStream, Vec<u8>, fingerprint::Match),
+    /// An ACME TLS-ALPN-01 validation, over once the handshake is.
+    Validation,
+}
+
+fn log_client_hello(peer_addr: SocketAddr, client_hello: &ClientHello<'_>) {
+    match client_hello.server_name() {
+        Some(sni) => debug!("[Trojan] ClientHello from {} for {}", peer_addr, sni),
+        None => debug!("[Trojan] ClientHello from {} without SNI", peer_addr),
+    }
+}
+
+async fn handshake(
+    mut tcp_stream: TcpStream,
+    peer_addr: SocketAddr,
+    inbound: &Inbound,
+) -> Result<Handshake> {
+    let tls_config = Arc::clone(&inbound.tls_config);
+
+    if inbound.reality.is_none() && inbound.fallbacks.sni.is_none() && !fingerprint::enabled() {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), tcp_stream).await?;
+        log_client_hello(peer_addr, &start.client_hello());
+        if let Some(config) = acme::challenge_config(&start.client_hello()) {
+            start.into_stream(config).await?;
+            return Ok(Handshake::Validation);
+        }
+        let tls_stream = start.into_stream(tls_config).await?;
+        return Ok(Handshake::Done(Box::new(tls_stream)));
+    }
+
+    let (accepted, raw) = sni::read_client_hello(&mut tcp_stream)
+        .await
+        .context("Failed to read ClientHello")?;
+    if let Some(accepted) = &accepted {
+        log_client_hello(peer_addr, &accepted.client_hello());
+    }
+    if let Some(matched) = fingerprint::check(&raw) {
+        return Ok(Handshake::Scanner(tcp_stream, raw, matched));
+    }
+    let accepted = match accepted {
+        Some(accepted) => match acme::challenge_config(&accepted.client_hello()) {
+            Some(config) => {
+                StartHandshake::from_parts(accepted, tcp_stream)
+                    .into_stream(config)
+                    .await?;
+                return Ok(Handshake::Validation);
+            }
+            None => Some(accepted),
+        },
+        None => None,
+    };
+
+    if let Some(reality) = &inbound.reality {
+        let Some(accepted) = accepted else {
+            return Ok(Handshake::Borrowed(tcp_stream, raw));
+        };
+
+        let sni = accepted.client_hello().server_name().map(str::to_owned);
+        let Some(auth_key) = reality.authenticate(&raw, sni.as_deref()) else {
+            return Ok(Handshake::Borrowed(tcp_stream, raw));
+        };
+        let tls_config = reality.tls_config(&auth_key, &tls_config)?;
+        let tls_stream = StartHandshake::from_parts(accepted, tcp_stream)
+            .into_stream(tls_config)
+            .await?;
+        return Ok(Handshake::Done(Box::new(tls_stream)));
+    }
+
+    match (accepted, &inbound.fallbacks.sni) {
+        (Some(accepted), Some(router)) if !router.serves(accepted.client_hello().server_name()) => {
+            Ok(Handshake::Passthrough(tcp_stream, raw))
+        }
+        (Some(accepted), _) => {
+            let tls_stream = StartHandshake::from_parts(accepted, tcp_stream)
+                .into_stream(tls_config)
+                .await?;
+            Ok(Handshake::Done(Box::new(tls_stream)))
+        }
+        (None, Some(_)) => Ok(Handshake::Passthrough(tcp_stream, raw)),
+        (None, None) => bail!("Not a TLS ClientHello"),
+    }
+}
+
+async fn handle_connection(
+    tcp_stream: TcpStream,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    inbound: &Inbound,
+    permit: Option<OwnedSemaphorePermit>,
+) {
+    let handshake = tokio::time::timeout(
+        inbound.handshake_timeout,
+        handshake(tcp_stream, peer_addr, inbound),
+    )
+    .await;
+    drop(permit);
+
+    let tls_stream = match handshake {
+        Ok(Ok(Handshake::Done(tls_stream))) => *tls_stream,
+        Ok(Ok(Handshake::Passthrough(tcp_stream, raw))) => {
+            let Some(router) = &inbound.fallbacks.sni else {
+                return;
+            };
+            debug!(
+                "[Trojan] Passing {} through to {} by SNI",
+                peer_addr,
+                router.backend()
+            );
+            let preamble = inbound.fallbacks.preamble(peer_addr, local_addr, raw);
+            if let Err(e) = sni::splice(tcp_stream, router.backend(), preamble).await {
+                debug!("[Trojan] SNI passthrough for {} ended: {}", peer_addr, e);
+            }
+            return;
+        }
+        Ok(Ok(Handshake::Borrowed(tcp_stream, raw))) => {
+            let Some(reality) = &inbound.reality else {
+                return;
+            };
+            debug!(
+                "[Trojan] {} is not a REALITY client, passing it to {}",
+                peer_addr,
+                reality.dest()
+            );
+            if let Err(e) = reality.forward(tcp_stream, raw).await {
+                debug!(
+                    "[Trojan] REALITY passthrough for {} ended: {}",
+                    peer_addr, e
+                );
+            }
+            return;
+        }
+        Ok(Ok(Handshake::Validation)) => {
+            debug!("[Trojan] Answered ACME validation from {}", peer_addr);
+            return;
+        }
+        Ok(Ok(Handshake::Scanner(tcp_stream, raw, matched))) => {
+            info!(
+                "[Trojan] {} matched scanner fingerprint {}, {:?}",
+                peer_addr, matched.fingerprint, matched.action
+            );
+            match matched.action {
+                FingerprintAction::Reset => {
+                    let _ = socket2::SockRef::from(&tcp_stream).set_linger(Some(Duration::ZERO));
+                }
+                FingerprintAction::Fallback => {
+                    let preamble = inbound.fallbacks.preamble(peer_addr, local_addr, raw);
+                    let _ = FallbackHandler::handle_fallback(
+                        tcp_stream,
+                        inbound.fallbacks.addr,
+                        preamble,
+                    )
+                    .await;
+                }
+            }
+            return;
+        }
+        Ok(Err(e)) => {
             debug!(
                 "[Trojan] TLS handshake failed with client IP: {}, Error: {}",
                 peer_addr, e
             );
+            events::publish(Event::HandshakeFailed {
+                protocol: "Trojan",
+                peer_addr,
+            });
+            return;
+        }
+        Err(_) => {
+            debug!(
+                "[Trojan] TLS handshake with {} timed out after {:?}",
+                peer_addr, inbound.handshake_timeout
+            );
+            // Stalling is the cheapest way to hold a slot, so it counts.
+            events::publish(Event::HandshakeFailed {
+                protocol: "Trojan",
+                peer_addr,
+            });
+            return;
+        }
+    };
+
+    debug!("[Trojan] TLS handshake completed with {}", peer_addr);
+    let session = Arc::new(registry().register("Trojan", Arc::clone(&inbound.tag), peer_addr));
+    let context = Arc::new(RuntimeContext::new(
+        peer_addr,
+        local_addr,
+        Arc::clone(&session),
+    ));
+
+    tokio::select! {
+        res = inbound.processor.process_connection_tls(tls_stream, context) => {
+            if let Err(e) = res {
+                debug!("[Trojan] Connection processing error: {}", e);
+            }
+        }
+        _ = session.kicked() => {
+            info!("[Trojan] Connection from {} kicked", peer_addr);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::config::BanlistConfig;
+
+    #[tokio::test(start_paused = true)]
+    async fn bans_clients_that_stall_the_handshake() {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+        let tls_config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.cert.der().clone()], key.into())
+                .unwrap();
+        let server = TrojanServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .certs(CertSource::Der(Vec::new()))
+            .handshake_timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+        let inbound = server.build_inbound(Arc::new(tls_config));
+
+        let config: BanlistConfig = toml::from_str("max_failures = 1").unwrap();
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+        let mut events = events::subscribe();
+        banlist::spawn(Some(&config), shutdown_rx);
+
+        // A client that connects and never sends its ClientHello, from an
+        // address no other test uses.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        let peer_addr: SocketAddr = "192.0.2.27:40000".parse().unwrap();
+        assert!(!banlist::is_banned(peer_addr.ip()));
+
+        let started = tokio::time::Instant::now();
+        handle_connection(stream, peer_addr, local_addr, &inbound, None).await;
+        assert!(started.elapsed() >= Duration::from_secs(5));
+
+        let banned = async {
+            loop {
+                if let Event::BanIssued { ip, .. } = events.recv().await.unwrap()
+                    && ip == peer_addr.ip()
+                {
+                    break;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), banned)
+            .await
+            .expect("no ban issued");
+        assert!(banlist::is_banned(peer_addr.ip()));
+    }
+
+    #[tokio::test]
+    async fn builder_serves_from_der_certificates() {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut server = TrojanServerBuilder::new(SocketAddr::from(([127, 0, 0, 1], port)))
+            .user("alice", "secret")
+            .certs(CertSource::Der(vec![(
+                vec![cert.cert.der().clone()],
+                key.into(),
+            )]))
+            .build()
+            .unwrap();
+        server.init().await.unwrap();
+        server.start().await.unwrap();
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+
+        // The client trusts the certificate only, so it reached this server.
+        let dir = std::env::temp_dir().join(format!("iway-trojan-der-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let ca_path = dir.join("ca.pem");
+        std::fs::write(&ca_path, cert.cert.pem()).unwrap();
+        let upstream: crate::config::TrojanUpstreamConfig = toml::from_str(&format!(
+            "server = \"127.0.0.1:{}\"\npassword = \"secret\"\nsni = \"localhost\"\nca_path = {:?}",
+            port, ca_path
+        ))
+        .unwrap();
+        let client = TrojanOutbound::from_config(&upstream, None).unwrap();
+
+        let mut stream = client
+            .connect(&crate::protocol::address::Address::Socket(target), &[])
+            .await
+            .unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+
+        server.stop().await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,34 +1,155 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use std::net::SocketAddr;
-use tokio::io::AsyncWriteExt;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tracing::{debug, warn};
 
-#[allow(dead_code)]
+use crate::config::{FallbackConfig, ProxyProtocolVersion};
+use crate::server::tls::alpn_protocols;
+
+/// Longest request line read to match fallback paths against.
+const MAX_REQUEST_LINE: usize = 2048;
+
+/// How long to wait for the rest of a request line.
+const REQUEST_LINE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A fallback server for clients that negotiated `alpn` and whose HTTP
+/// request path starts with `path`; an unset field matches anything.
+#[derive(Debug, Clone)]
+pub struct FallbackRoute {
+    alpn: Option<Vec<u8>>,
+    path: Option<String>,
+    dest: SocketAddr,
+    proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+impl FallbackRoute {
+    pub fn from_config(config: &FallbackConfig) -> Result<Self> {
+        Ok(Self {
+            alpn: config.alpn().map(|alpn| alpn.as_bytes().to_vec()),
+            path: config.path().map(str::to_string),
+            dest: config
+                .dest()
+                .parse()
+                .with_context(|| format!("Failed to parse fallback dest {}", config.dest()))?,
+            proxy_protocol: config.proxy_protocol(),
+        })
+    }
+
+    pub fn dest(&self) -> SocketAddr {
+        self.dest
+    }
+
+    pub fn proxy_protocol(&self) -> Option<ProxyProtocolVersion> {
+        self.proxy_protocol
+    }
+
+    pub fn alpn(&self) -> Option<&[u8]> {
+        self.alpn.as_deref()
+    }
+
+    pub fn needs_path(&self) -> bool {
+        self.path.is_some()
+    }
+
+    fn matches(&self, alpn: Option<&[u8]>, path: Option<&str>) -> bool {
+        let alpn_ok = self.alpn.as_deref().is_none_or(|want| alpn == Some(want));
+        let path_ok = match (&self.path, path) {
+            (None, _) => true,
+            (Some(want), Some(path)) => path.starts_with(want.as_str()),
+            (Some(_), None) => false,
+        };
+        alpn_ok && path_ok
+    }
+}
+
+/// Picks the first route matching the negotiated ALPN and the request the
+/// client sent.
+pub fn select_route<'a>(
+    routes: &'a [FallbackRoute],
+    alpn: Option<&[u8]>,
+    request: &[u8],
+) -> Option<&'a FallbackRoute> {
+    let path = request_path(request);
+    routes.iter().find(|route| route.matches(alpn, path))
+}
+
+/// ALPN protocols to offer: `configured` ones, then those the routes name
+/// so they can tell clients apart, and with those "http/1.1" so clients
+/// offering only that still complete the handshake.
+pub fn offered_alpn(configured: &[String], routes: &[FallbackRoute]) -> Vec<Vec<u8>> {
+    let mut offered = alpn_protocols(configured);
+    let routed = routes.iter().any(|route| route.alpn().is_some());
+    for alpn in routes.iter().filter_map(FallbackRoute::alpn) {
+        if !offered.iter().any(|known| known == alpn) {
+            offered.push(alpn.to_vec());
+        }
+    }
+    if routed && !offered.iter().any(|alpn| alpn == b"http/1.1") {
+        offered.push(b"http/1.1".to_vec());
+    }
+    offered
+}
+
+/// Reads on into `request` until it holds a whole first line, so a path
+/// longer than what authentication consumed can still be matched.
+pub async fn read_request_line<S>(stream: &mut S, request: &mut Vec<u8>)
+where
+    S: AsyncRead + Unpin,
+{
+    let _ = tokio::time::timeout(REQUEST_LINE_TIMEOUT, async {
+        let mut buf = [0u8; 512];
+        while !request.windows(2).any(|w| w == b"\r\n") && request.len() < MAX_REQUEST_LINE {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+    })
+    .await;
+}
+
+/// The path of an HTTP/1.x request line, e.g. "/index.html" out of
+/// "GET /index.html HTTP/1.1".
+fn request_path(request: &[u8]) -> Option<&str> {
+    let end = request
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .unwrap_or(request.len());
+    let line = std::str::from_utf8(&request[..end]).ok()?;
+    let mut parts = line.split(' ');
+    let (_method, path, version) = (parts.next()?, parts.next()?, parts.next()?);
+    version.starts_with("HTTP/1.").then_some(path)
+}
+
 pub struct FallbackHandler;
 
 impl FallbackHandler {
-    #[allow(dead_code)]
-    pub async fn handle_fallback(
-        mut client_stream: TcpStream,
+    /// Relays the client to the fallback server, first replaying `replay`:
+    /// whatever was already read from the client before deciding it is not
+    /// a Trojan request.
+    pub async fn handle_fallback<S>(
+        mut client_stream: S,
         fallback_addr: SocketAddr,
-    ) -> Result<()> {
+        replay: Vec<u8>,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         match crate::net::tcp::connect(fallback_addr).await {
-            Ok(fallback_stream) => {
-                let (mut client_read, mut client_write) = client_stream.split();
-                let (mut fallback_read, mut fallback_write) = fallback_stream.into_split();
-
-                tokio::select! {
-                    result = tokio::io::copy(&mut client_read, &mut fallback_write) => {
-                        if let Err(e) = result {
-                            debug!("[Trojan] Client to fallback copy error: {}", e);
-                        }
-                    }
-                    result = tokio::io::copy(&mut fallback_read, &mut client_write) => {
-                        if let Err(e) = result {
-                            debug!("[Trojan] Fallback to client copy error: {}", e);
-                        }
-                    }
+            Ok(mut fallback_stream) => {
+                if let Err(e) = fallback_stream.write_all(&replay).await {
+                    debug!("[Trojan] Failed to replay request to fallback: {}", e);
+                    return Ok(());
+                }
+
+                // A prober that half-closes after its request must still
+                // get the whole response, as it would from the site itself.
+                if let Err(e) =
+                    tokio::io::copy_bidirectional(&mut client_stream, &mut fallback_stream).await
+                {
+                    debug!("[Trojan] Fallback relay error: {}", e);
                 }
 
                 debug!("[Trojan] Fallback connection closed");
@@ -1,57 +1,133 @@
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
-use std::{net::SocketAddr, path::Path, time::Instant};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
-use crate::processor::tuic::TuicConnectionProcessor;
+use crate::config::{Config, CongestionControl, ProfileDefaults, TlsConfig, TuicTransportConfig};
+use crate::control::registry::registry;
+use crate::events::{self, Event};
+use crate::policy;
+use crate::policy::geoip::{self, Verdict};
 use crate::processor::tuic::context::RuntimeContext;
 use crate::processor::tuic::notifier::OneShotNotifier;
+use crate::processor::tuic::{SERVER_GOING_AWAY_ERROR_CODE, TuicConnectionProcessor, masquerade};
+use crate::protocol::tuic::command::heartbeat::GoingAwayHeartbeat;
+use crate::security::banlist;
+use crate::server::resolver::CertSetResolver;
+use crate::server::tls::{CertSource, alpn_protocols, crypto_provider};
 
-use super::{Server, ServerStatus};
+use super::{Server, ServerStatus, wait_shutdown};
+use crate::net::capabilities::{adjust_bind_addr, capabilities};
+use crate::net::udp as net_udp;
+use crate::net::util::{listen_addrs, parse_ports};
 
 use anyhow::{Context, Error, Result, anyhow, bail};
 use async_trait::async_trait;
-use quinn::congestion::BbrConfig;
+use bytes::BytesMut;
+use quinn::congestion::{BbrConfig, ControllerFactory, CubicConfig, NewRenoConfig};
 use quinn::crypto::rustls::QuicServerConfig;
-use quinn::{Endpoint, ServerConfig, TransportConfig, VarInt};
-use rustls::CipherSuite;
-use rustls::crypto;
+use quinn::{
+    Connecting, Connection, Endpoint, EndpointConfig, ServerConfig, TransportConfig, VarInt,
+    ZeroRttAccepted,
+};
 use rustls::crypto::aws_lc_rs::cipher_suite::TLS13_AES_128_GCM_SHA256;
-use rustls::pki_types::pem::PemObject;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use tokio::sync::watch::Receiver;
-use tracing::{debug, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
 
-fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
-    let certs = CertificateDer::pem_file_iter(path)
-        .with_context(|| format!("Failed to read certificate file: {:?}", path))?
-        .collect::<std::result::Result<Vec<CertificateDer<'static>>, _>>()
-        .with_context(|| "Failed to parse certificates!")?;
+fn congestion_controller(
+    transport: &TuicTransportConfig,
+) -> Arc<dyn ControllerFactory + Send + Sync + 'static> {
+    let initial_window = transport.initial_window();
 
-    if certs.is_empty() {
-        bail!("No certificates found in file");
+    match transport.congestion_control() {
+        CongestionControl::Bbr => {
+            let mut config = BbrConfig::default();
+            if let Some(window) = initial_window {
+                config.initial_window(window);
+            }
+            Arc::new(config)
+        }
+        CongestionControl::Cubic => {
+            let mut config = CubicConfig::default();
+            if let Some(window) = initial_window {
+                config.initial_window(window);
+            }
+            Arc::new(config)
+        }
+        CongestionControl::NewReno => {
+            let mut config = NewRenoConfig::default();
+            if let Some(window) = initial_window {
+                config.initial_window(window);
+            }
+            Arc::new(config)
+        }
     }
+}
 
-    Ok(certs)
+/// Completes an incoming connection. With 0-RTT enabled the connection is
+/// handed out before the handshake finishes, so commands carried in early
+/// data are processed right away; the returned future resolves once the
+/// handshake is complete.
+async fn establish(
+    connecting: Connecting,
+    zero_rtt: bool,
+) -> Result<(Connection, Option<ZeroRttAccepted>), quinn::ConnectionError> {
+    if zero_rtt {
+        match connecting.into_0rtt() {
+            Ok((connection, accepted)) => return Ok((connection, Some(accepted))),
+            Err(connecting) => return connecting.await.map(|connection| (connection, None)),
+        }
+    }
+
+    connecting.await.map(|connection| (connection, None))
 }
 
-fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
-    let key = PrivateKeyDer::from_pem_file(path)
-        .with_context(|| format!("Failed to read private key file: {:?}", path))?;
-    Ok(key)
+/// The `(uuid, password, identity)` entries of everyone `config` lets use
+/// TUIC. Entries whose UUID does not parse are left out.
+fn tuic_users(config: &crate::config::Config) -> Vec<(uuid::Uuid, Arc<[u8]>, Arc<str>)> {
+    config
+        .tuic_credentials()
+        .iter()
+        .filter_map(|c| {
+            uuid::Uuid::parse_str(c.uuid)
+                .ok()
+                .map(|id| (id, Arc::from(c.password.as_bytes()), Arc::from(c.identity)))
+        })
+        .collect()
 }
 
 pub static TLS_PROTOCOL_VERSIONS: &[&rustls::SupportedProtocolVersion] = &[&rustls::version::TLS13];
 
+/// Reason every connection still open at the end of the drain is closed
+/// with; clients that know it reconnect right away, preferably to another
+/// node.
+const GOING_AWAY_REASON: &[u8] = b"server restarting, reconnect";
+
 pub struct TuicServer {
     name: &'static str,
     socket: SocketAddr,
-    ep: Option<Endpoint>,
+    /// Served on the host of `socket` as well.
+    extra_ports: Vec<u16>,
+    endpoints: Vec<Endpoint>,
+    listeners: usize,
     status: ServerStatus,
     processor: Arc<TuicConnectionProcessor>,
-    cert_path: PathBuf,
-    key_path: PathBuf,
+    certs: CertSource,
     shutdown_rx: Option<Receiver<()>>,
+    tuning: ProfileDefaults,
+    transport: TuicTransportConfig,
+    zero_rtt: bool,
+    tls: TlsConfig,
+    tag: Arc<str>,
+    drain_timeout: Duration,
+    going_away_error_code: u32,
+    /// Cancelled to send every established connection the migration
+    /// notice; they are closed only by `close`.
+    going_away: CancellationToken,
 }
 
 impl TuicServer {
@@ -63,63 +139,47 @@ impl TuicServer {
             .tuic()
             .server_addr()
             .parse()
+            .map(adjust_bind_addr)
             .with_context(|| "Failed to parse server adress with error")?;
 
-        let user_entries = config
-            .tuic()
-            .users()
-            .iter()
-            .filter_map(|u| {
-                uuid::Uuid::parse_str(u.uuid())
-                    .ok()
-                    .map(|id| (id, Arc::from(u.password().as_bytes())))
-            })
-            .collect::<Vec<_>>();
-
-        let processor = Arc::new(TuicConnectionProcessor::new(user_entries));
+        let user_entries = tuic_users(&config);
 
-        Ok(Self {
-            name: "TUIC v5",
-            socket,
-            ep: None,
-            status: ServerStatus::Initializing(Instant::now()),
-            processor,
+        let certs = CertSource::Files {
             cert_path: PathBuf::from(config.tuic().cert_path()),
             key_path: PathBuf::from(config.tuic().key_path()),
-            shutdown_rx,
-        })
-    }
-}
+            additional: config.tuic().certificates().to_vec(),
+        };
 
-#[async_trait]
-impl Server for TuicServer {
-    fn name(&self) -> &'static str {
-        self.name
-    }
+        let mut builder = TuicServerBuilder::new(socket)
+            .config(config)
+            .users(user_entries)
+            .certs(certs);
 
-    async fn init(&mut self) -> Result<Instant, Error> {
-        let certs = load_certs(&self.cert_path)?;
-        let key = load_key(&self.key_path)?;
+        if let Some(shutdown_rx) = shutdown_rx {
+            builder = builder.shutdown(shutdown_rx);
+        }
 
-        let mut provider = crypto::ring::default_provider();
+        builder.build()
+    }
 
-        provider.cipher_suites.retain(|suite| {
-            matches!(
-                suite.suite(),
-                CipherSuite::TLS13_AES_256_GCM_SHA384 | CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
-            )
-        });
+    /// Builds the QUIC server config from the certificates and the
+    /// transport settings, so it can be swapped into a running endpoint.
+    fn build_server_config(&self) -> Result<ServerConfig> {
+        let certs = Arc::new(self.certs.load()?);
+
+        let provider = crypto_provider(&self.tls)?;
 
         let mut rustls_config = rustls::ServerConfig::builder_with_provider(Arc::new(provider))
             .with_protocol_versions(TLS_PROTOCOL_VERSIONS)
             .with_context(|| "Failed to set TLS protocol versions!")?
             .with_no_client_auth()
-            .with_single_cert(certs, key)
-            .with_context(|| "Failed to configure TLS certificate!")?;
+            .with_cert_resolver(Arc::new(CertSetResolver::new(certs)));
 
-        rustls_config.alpn_protocols = vec![b"h3".to_vec()];
-        rustls_config.max_early_data_size = u32::MAX;
-        rustls_config.send_half_rtt_data = true;
+        rustls_config.alpn_protocols = alpn_protocols(self.tls.alpn().tuic());
+        if self.zero_rtt {
+            rustls_config.max_early_data_size = u32::MAX;
+            rustls_config.send_half_rtt_data = true;
+        }
 
         let quic_server_config = QuicServerConfig::with_initial(
             Arc::new(rustls_config),
@@ -134,28 +194,486 @@ impl Server for TuicServer {
         let mut config = ServerConfig::with_crypto(Arc::new(quic_server_config));
 
         let transport_config = {
+            let transport = &self.transport;
             let mut tc = TransportConfig::default();
 
-            tc.max_concurrent_bidi_streams(1024u32.into())
-                .max_concurrent_uni_streams(1024u32.into())
-                .stream_receive_window(VarInt::from_u32(1 << 21))
-                .receive_window(VarInt::from_u32(1 << 22))
-                .send_window(1 << 22)
-                .keep_alive_interval(Some(Duration::from_secs(10)))
-                .congestion_controller_factory(Arc::new(BbrConfig::default()))
-                .max_idle_timeout(Some(
-                    Duration::from_secs(30)
-                        .try_into()
-                        .with_context(|| "Invalid idle timeout!")?,
-                ));
+            tc.max_concurrent_bidi_streams(
+                transport
+                    .max_concurrent_bidi_streams()
+                    .unwrap_or(self.tuning.tuic_max_concurrent_streams)
+                    .into(),
+            )
+            .max_concurrent_uni_streams(
+                transport
+                    .max_concurrent_uni_streams()
+                    .unwrap_or(self.tuning.tuic_max_concurrent_streams)
+                    .into(),
+            )
+            .stream_receive_window(VarInt::from_u32(
+                transport
+                    .stream_receive_window()
+                    .unwrap_or(self.tuning.tuic_stream_receive_window),
+            ))
+            .receive_window(VarInt::from_u32(
+                transport
+                    .receive_window()
+                    .unwrap_or(self.tuning.tuic_receive_window),
+            ))
+            .send_window(
+                transport
+                    .send_window()
+                    .unwrap_or(self.tuning.tuic_send_window),
+            )
+            .keep_alive_interval(transport.keep_alive_interval())
+            .congestion_controller_factory(congestion_controller(transport))
+            .max_idle_timeout(Some(
+                transport
+                    .max_idle_timeout()
+                    .try_into()
+                    .with_context(|| "Invalid idle timeout!")?,
+            ));
+
+            if let Some(mtu) = transport.initial_mtu() {
+                tc.initial_mtu(mtu);
+            }
             tc
         };
 
         config.transport_config(Arc::new(transport_config));
 
-        let ep = Endpoint::server(config, self.socket)?;
+        Ok(config)
+    }
+
+    /// `primary` and the same host on every extra port.
+    fn listen_addrs(&self, primary: SocketAddr) -> Vec<SocketAddr> {
+        listen_addrs(primary, &self.extra_ports)
+    }
+
+    /// Binds the configured number of endpoints on each of `addrs`.
+    fn bind_all(&self, addrs: &[SocketAddr]) -> Result<Vec<Endpoint>> {
+        let config = self.build_server_config()?;
+
+        let mut endpoints = Vec::new();
+        for addr in addrs {
+            endpoints.extend(self.bind_endpoints(*addr, &config)?);
+        }
+        Ok(endpoints)
+    }
+
+    /// Binds the configured number of endpoints on `addr`. More than one
+    /// share the port through SO_REUSEPORT, and the kernel spreads clients
+    /// across them by flow.
+    fn bind_endpoints(&self, addr: SocketAddr, config: &ServerConfig) -> Result<Vec<Endpoint>> {
+        if self.listeners <= 1 || !capabilities().reuse_port {
+            if self.listeners > 1 {
+                warn!("SO_REUSEPORT is unavailable, TUIC uses a single listener");
+            }
+            let ep = Endpoint::server(config.clone(), addr)
+                .with_context(|| format!("Failed to bind to {}", addr))?;
+            return Ok(vec![ep]);
+        }
+
+        let runtime = quinn::default_runtime().context("No async runtime found")?;
+
+        (0..self.listeners)
+            .map(|_| {
+                let socket = net_udp::bind_reuse_port(addr)
+                    .with_context(|| format!("Failed to bind to {}", addr))?;
+                Ok(Endpoint::new(
+                    EndpointConfig::default(),
+                    Some(config.clone()),
+                    socket,
+                    Arc::clone(&runtime),
+                )?)
+            })
+            .collect()
+    }
+
+    /// Serves connections arriving on `ep` until it closes or the shutdown
+    /// signal fires.
+    fn spawn_accept_loop(&self, ep: Endpoint) {
+        let tuic_processor = Arc::clone(&self.processor);
+        let zero_rtt = self.zero_rtt;
+        let tag = Arc::clone(&self.tag);
+        let mut shutdown_rx = self.shutdown_rx.clone();
+        let going_away = self.going_away.clone();
+        let going_away_error_code = self.going_away_error_code;
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    incoming = ep.accept() => {
+                        let incoming = match incoming {
+                            Some(conn) => conn,
+                            None => {
+                                debug!("Endpoint incoming stream closed!");
+                                break;
+                            }
+                        };
+
+                        if banlist::is_banned(incoming.remote_address().ip()) {
+                            debug!("Refusing {}: banned", incoming.remote_address());
+                            incoming.refuse();
+                            continue;
+                        }
+
+                        // QUIC has nothing to fall back to before the handshake.
+                        if geoip::check(incoming.remote_address().ip()) != Verdict::Allow {
+                            debug!("Rejected {} by GeoIP", incoming.remote_address());
+                            incoming.refuse();
+                            continue;
+                        }
+
+                        if !policy::admits(&tag) {
+                            debug!("Inbound {} is full, refusing {}", tag, incoming.remote_address());
+                            incoming.refuse();
+                            continue;
+                        }
+
+                        let tuic_processor = Arc::clone(&tuic_processor);
+                        let tag = Arc::clone(&tag);
+                        let going_away = going_away.clone();
+                        let peer_addr = incoming.remote_address();
+                        tokio::spawn(async move {
+                            match incoming.accept() {
+                                Ok(connecting) => match establish(connecting, zero_rtt).await {
+                                    Ok((connection, handshake)) => {
+                                        let session = Arc::new(registry().register("TUIC", tag, connection.remote_address()));
+                                        let context = Arc::new(RuntimeContext::new(OneShotNotifier::default(), Arc::clone(&session)));
+                                        if let Some(handshake) = handshake {
+                                            context.track_handshake(handshake);
+                                        }
+
+                                        debug!("New connection connected (ID: {})", &connection.stable_id());
+
+                                        let recevied_processor = Arc::clone(&tuic_processor);
+                                        let recevied_conn = Arc::new(connection.clone());
+                                        let recevied_context = Arc::clone(&context);
+
+                                        let conn_for_uni = Arc::clone(&recevied_conn);
+                                        let conn_for_bid = Arc::clone(&recevied_conn);
+                                        let conn_for_dat = Arc::clone(&recevied_conn);
+
+                                        let t_uni = tokio::spawn(async move {
+                                            let _ = recevied_processor
+                                                .process_uni(recevied_context, conn_for_uni)
+                                                .await;
+                                        });
+
+                                        let bidirectional_processor = Arc::clone(&tuic_processor);
+                                        let bidiraction_context = Arc::clone(&context);
+                                        let t_bid = tokio::spawn(async move {
+                                             let _ = bidirectional_processor
+                                                                .process_bidirectional(bidiraction_context, conn_for_bid)
+                                                                .await;
+                                        });
+
+                                        let datagram_processor = Arc::clone(&tuic_processor);
+                                        let datagram_ontext = Arc::clone(&context);
+                                        let t_dat = tokio::spawn(async move {
+                                            let _ = datagram_processor
+                                                            .process_datagram(datagram_ontext, conn_for_dat)
+                                                            .await;
+                                        });
+
+                                        let deadline_processor = Arc::clone(&tuic_processor);
+                                        let deadline_context = Arc::clone(&context);
+                                        let conn_for_deadline = Arc::clone(&recevied_conn);
+                                        let t_auth = tokio::spawn(async move {
+                                            let _ = deadline_processor
+                                                            .process_auth_deadline(deadline_context, conn_for_deadline)
+                                                            .await;
+                                        });
+
+                                        let bandwidth_processor = Arc::clone(&tuic_processor);
+                                        let bandwidth_context = Arc::clone(&context);
+                                        let conn_for_bw = Arc::clone(&recevied_conn);
+                                        let t_bw = tokio::spawn(async move {
+                                            let _ = bandwidth_processor
+                                                            .process_bandwidth_reports(bandwidth_context, conn_for_bw)
+                                                            .await;
+                                        });
+
+                                        let stats_processor = Arc::clone(&tuic_processor);
+                                        let stats_context = Arc::clone(&context);
+                                        let conn_for_stats = Arc::clone(&recevied_conn);
+                                        let t_stats = tokio::spawn(async move {
+                                            let _ = stats_processor
+                                                            .process_path_stats(stats_context, conn_for_stats)
+                                                            .await;
+                                        });
+
+                                        let gc_processor = Arc::clone(&tuic_processor);
+                                        let gc_context = Arc::clone(&context);
+                                        let conn_for_gc = Arc::clone(&recevied_conn);
+                                        let t_gc = tokio::spawn(async move {
+                                            let _ = gc_processor
+                                                            .process_idle_sessions(gc_context, conn_for_gc)
+                                                            .await;
+                                        });
+
+                                        let tasks = async {
+                                            let _ = tokio::join!(t_uni, t_bid, t_dat, t_bw, t_stats, t_gc, t_auth);
+                                        };
+                                        hold(&connection, &context, &going_away, going_away_error_code, tasks).await;
+                                        debug!("The connection (ID:{}) was closed!", &connection.stable_id());
+                                    }
+                                    Err(e) => {
+                                        debug!("Connecting await failed: {}", e);
+                                        // Lost packets are not the client's doing.
+                                        if e != quinn::ConnectionError::TimedOut {
+                                            events::publish(Event::HandshakeFailed {
+                                                protocol: "TUIC",
+                                                peer_addr,
+                                            });
+                                        }
+                                    }
+                                },
+                                Err(e) => {
+                                    debug!("Incoming.accept() failed: {}", e);
+                                }
+                            }
+                        });
+                    }
+                    // Servers built without a shutdown signal run until
+                    // stopped.
+                    _ = wait_shutdown(&mut shutdown_rx) => {
+                        info!("TUIC server received shutdown signal, breaking main loop");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Serves `connection` until `tasks` end or it is kicked. The migration
+/// notice leaves streams in flight alone: authenticated clients get a
+/// going-away heartbeat as an early hint and are closed with
+/// `GOING_AWAY_REASON` once the drain is over, while connections that never
+/// authenticated are closed with it at once.
+async fn hold(
+    connection: &Connection,
+    context: &RuntimeContext,
+    going_away: &CancellationToken,
+    going_away_error_code: u32,
+    tasks: impl Future<Output = ()>,
+) {
+    tokio::pin!(tasks);
+    let mut announced = false;
+
+    loop {
+        tokio::select! {
+            _ = &mut tasks => return,
+            _ = context.session().kicked() => {
+                info!("Connection (ID: {}) kicked", connection.stable_id());
+                connection.close(0u32.into(), b"kicked");
+                return;
+            }
+            _ = going_away.cancelled(), if !announced => {
+                announced = true;
+                if !context.auth_state().is_authenticated() {
+                    connection.close(going_away_error_code.into(), GOING_AWAY_REASON);
+                    return;
+                }
+
+                let notice = GoingAwayHeartbeat::new();
+                let mut buf = BytesMut::with_capacity(notice.estimate_size());
+                notice.write_to_buf(&mut buf);
+                if let Err(e) = connection.send_datagram(buf.freeze()) {
+                    debug!(
+                        "Failed to send the migration notice to {}: {}",
+                        connection.remote_address(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Closes every connection left on `endpoints` with `error_code` and
+/// `GOING_AWAY_REASON`, so stock clients see why and reconnect.
+async fn close_endpoints(endpoints: &[Endpoint], error_code: u32) {
+    for ep in endpoints {
+        ep.close(error_code.into(), GOING_AWAY_REASON);
+    }
+    // Give the CONNECTION_CLOSE frames a moment to go out.
+    let _ = tokio::time::timeout(Duration::from_secs(1), async {
+        for ep in endpoints {
+            ep.wait_idle().await;
+        }
+    })
+    .await;
+}
+
+/// Assembles a [`TuicServer`] in code, without a config file.
+///
+/// Protocol settings the processor needs (timeouts, masquerade, UDP
+/// relaying) come from [`config`](Self::config), which defaults to an
+/// empty configuration; users, certificates and transport tuning can be
+/// given directly and take precedence over it.
+pub struct TuicServerBuilder {
+    socket: SocketAddr,
+    listen_ports: Option<Vec<u16>>,
+    config: Arc<Config>,
+    users: Vec<(Uuid, Arc<[u8]>, Arc<str>)>,
+    certs: Option<CertSource>,
+    transport: Option<TuicTransportConfig>,
+    tuning: Option<ProfileDefaults>,
+    zero_rtt: Option<bool>,
+    tls: Option<TlsConfig>,
+    listeners: Option<usize>,
+    processor: Option<Arc<TuicConnectionProcessor>>,
+    tag: Option<Arc<str>>,
+    shutdown_rx: Option<Receiver<()>>,
+}
+
+impl TuicServerBuilder {
+    pub fn new(socket: SocketAddr) -> Self {
+        Self {
+            socket,
+            listen_ports: None,
+            config: Arc::new(Config::default()),
+            users: Vec::new(),
+            certs: None,
+            transport: None,
+            tuning: None,
+            zero_rtt: None,
+            tls: None,
+            listeners: None,
+            processor: None,
+            tag: None,
+            shutdown_rx: None,
+        }
+    }
+
+    /// Settings for everything not set on the builder itself.
+    pub fn config(mut self, config: Arc<Config>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// More ports served on the same host as the socket address.
+    pub fn listen_ports(mut self, ports: Vec<u16>) -> Self {
+        self.listen_ports = Some(ports);
+        self
+    }
+
+    /// Adds a user; `identity` names it in logs and statistics.
+    pub fn user(mut self, uuid: Uuid, password: &[u8], identity: &str) -> Self {
+        self.users
+            .push((uuid, Arc::from(password), Arc::from(identity)));
+        self
+    }
+
+    pub fn users<I>(mut self, users: I) -> Self
+    where
+        I: IntoIterator<Item = (Uuid, Arc<[u8]>, Arc<str>)>,
+    {
+        self.users.extend(users);
+        self
+    }
+
+    pub fn certs(mut self, certs: CertSource) -> Self {
+        self.certs = Some(certs);
+        self
+    }
+
+    pub fn transport(mut self, transport: TuicTransportConfig) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Window and stream limits used where the transport leaves them unset.
+    pub fn tuning(mut self, tuning: ProfileDefaults) -> Self {
+        self.tuning = Some(tuning);
+        self
+    }
+
+    pub fn zero_rtt(mut self, zero_rtt: bool) -> Self {
+        self.zero_rtt = Some(zero_rtt);
+        self
+    }
+
+    /// Cipher suites, key exchange groups and ALPN protocols offered.
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn listeners(mut self, listeners: usize) -> Self {
+        self.listeners = Some(listeners);
+        self
+    }
+
+    pub fn processor(mut self, processor: Arc<TuicConnectionProcessor>) -> Self {
+        self.processor = Some(processor);
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tag = Some(Arc::from(tag));
+        self
+    }
+
+    pub fn shutdown(mut self, shutdown_rx: Receiver<()>) -> Self {
+        self.shutdown_rx = Some(shutdown_rx);
+        self
+    }
+
+    pub fn build(self) -> Result<TuicServer, Error> {
+        let Some(certs) = self.certs else {
+            bail!("TUIC server needs certificates");
+        };
+
+        let config = self.config;
+        let tuic = config.tuic();
 
-        self.ep = Some(ep);
+        let processor = match self.processor {
+            Some(processor) => processor,
+            None => Arc::new(TuicConnectionProcessor::new(self.users, &config)),
+        };
+
+        let extra_ports = match self.listen_ports {
+            Some(ports) => ports,
+            None => parse_ports(tuic.listen())?,
+        };
+
+        Ok(TuicServer {
+            name: "TUIC v5",
+            socket: self.socket,
+            extra_ports,
+            endpoints: Vec::new(),
+            listeners: self.listeners.unwrap_or_else(|| tuic.listeners()),
+            status: ServerStatus::Initializing(Instant::now()),
+            processor,
+            certs,
+            shutdown_rx: self.shutdown_rx,
+            tuning: self.tuning.unwrap_or_else(|| config.profile().defaults()),
+            transport: self.transport.unwrap_or_else(|| tuic.transport().clone()),
+            zero_rtt: self.zero_rtt.unwrap_or_else(|| tuic.zero_rtt()),
+            tls: self.tls.unwrap_or_else(|| config.tls().clone()),
+            tag: self.tag.unwrap_or_else(|| Arc::from(tuic.tag())),
+            drain_timeout: tuic.drain_timeout(),
+            going_away_error_code: if tuic.masquerade().enabled() {
+                masquerade::H3_NO_ERROR
+            } else {
+                SERVER_GOING_AWAY_ERROR_CODE
+            },
+            going_away: CancellationToken::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl Server for TuicServer {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn init(&mut self) -> Result<Instant, Error> {
+        self.endpoints = self.bind_all(&self.listen_addrs(self.socket))?;
         self.status = ServerStatus::Running(Instant::now());
         Ok(Instant::now())
     }
@@ -168,95 +686,30 @@ impl Server for TuicServer {
                 bail!("Server is still initializing");
             }
             ServerStatus::Running(_) => {
-                // Spawn the accept loop so start() returns promptly (consistent with Trojan)
-                let ep_clone = if let Some(ep) = &self.ep {
-                    let addr = ep
-                        .local_addr()
-                        .with_context(|| "Failed to get local address")?;
-                    info!("Starting TUIC server on {}", addr);
-                    ep.clone()
-                } else {
+                if self.endpoints.is_empty() {
                     bail!("Need to initialize EndPoint first, call init() method",);
-                };
-
-                let tuic_processor = Arc::clone(&self.processor);
-                let mut shutdown_rx = self.shutdown_rx.as_mut().cloned();
-
-                tokio::spawn(async move {
-                    loop {
-                        tokio::select! {
-                            incoming = ep_clone.accept() => {
-                                let incoming = match incoming {
-                                    Some(conn) => conn,
-                                    None => {
-                                        debug!("Endpoint incoming stream closed!");
-                                        continue;
-                                    }
-                                };
-
-                                let tuic_processor = Arc::clone(&tuic_processor);
-                                tokio::spawn(async move {
-                                    match incoming.accept() {
-                                        Ok(connecting) => match connecting.await {
-                                            Ok(connection) => {
-                                                let context = Arc::new(RuntimeContext::new(OneShotNotifier::default()));
-
-                                                debug!("New connection connected (ID: {})", &connection.stable_id());
-
-                                                let recevied_processor = Arc::clone(&tuic_processor);
-                                                let recevied_conn = Arc::new(connection.clone());
-                                                let recevied_context = Arc::clone(&context);
-
-                                                let conn_for_uni = Arc::clone(&recevied_conn);
-                                                let conn_for_bid = Arc::clone(&recevied_conn);
-                                                let conn_for_dat = Arc::clone(&recevied_conn);
-
-                                                let t_uni = tokio::spawn(async move {
-                                                    let _ = recevied_processor
-                                                        .process_uni(recevied_context, conn_for_uni)
-                                                        .await;
-                                                });
-
-                                                let bidirectional_processor = Arc::clone(&tuic_processor);
-                                                let bidiraction_context = Arc::clone(&context);
-                                                let t_bid = tokio::spawn(async move {
-                                                     let _ = bidirectional_processor
-                                                                        .process_bidirectional(bidiraction_context, conn_for_bid)
-                                                                        .await;
-                                                });
-
-                                                let datagram_processor = Arc::clone(&tuic_processor);
-                                                let datagram_ontext = Arc::clone(&context);
-                                                let t_dat = tokio::spawn(async move {
-                                                    let _ = datagram_processor
-                                                                    .process_datagram(datagram_ontext, conn_for_dat)
-                                                                    .await;
-                                                });
-
-                                                let _ = tokio::join!(t_uni, t_bid, t_dat);
-                                                debug!("The connection (ID:{}) was closed!", &connection.stable_id());
-                                            }
-                                            Err(e) => {
-                                                debug!("Connecting await failed: {}", e);
-                                            }
-                                        },
-                                        Err(e) => {
-                                            debug!("Incoming.accept() failed: {}", e);
-                                        }
-                                    }
-                                });
-                            }
-                            _ = async {
-                                if let Some(rx) = &mut shutdown_rx {
-                                    let _ = rx.changed().await;
-                                }
-                            } => {
-                                info!("TUIC server received shutdown signal, breaking main loop");
-                                break;
-                            }
+                }
+
+                let addrs = self
+                    .endpoints
+                    .iter()
+                    .filter_map(|ep| ep.local_addr().ok())
+                    .fold(Vec::new(), |mut addrs, addr| {
+                        if !addrs.contains(&addr) {
+                            addrs.push(addr);
                         }
-                    }
-                });
+                        addrs
+                    });
+                info!(
+                    "Starting TUIC server on {:?} ({} listener(s))",
+                    addrs,
+                    self.endpoints.len()
+                );
+
+                // Spawn the accept loops so start() returns promptly (consistent with Trojan)
+                for ep in self.endpoints.clone() {
+                    self.spawn_accept_loop(ep);
+                }
 
                 return Ok(Instant::now());
             }
@@ -266,16 +719,32 @@ impl Server for TuicServer {
         }
     }
 
+    async fn reload(&mut self, config: Arc<crate::config::Config>) -> Result<Instant, Error> {
+        let users = tuic_users(&config);
+        info!("Reloaded {} TUIC user(s)", users.len());
+        self.processor.replace_users(users);
+
+        Ok(Instant::now())
+    }
+
     async fn stop(&mut self) -> Result<Instant, Error> {
+        self.stop_accepting().await?;
+        self.drain(self.drain_timeout).await?;
+        self.close().await
+    }
+
+    async fn stop_accepting(&mut self) -> Result<Instant, Error> {
         match self.status {
             ServerStatus::Running(_) => {
                 info!("Stopping TUIC server that was running");
                 self.status = ServerStatus::Stopped(Instant::now());
 
-                if let Some(ep) = &self.ep {
-                    ep.close(0u32.into(), b"Server shutdown");
-                    info!("TUIC endpoint closed");
+                // Refuse new handshakes while established connections get a
+                // chance to finish their streams.
+                for ep in &self.endpoints {
+                    ep.set_server_config(None);
                 }
+
                 Ok(Instant::now())
             }
             ServerStatus::Initializing(_) => bail!("Cannot stop: server is still initializing",),
@@ -283,7 +752,339 @@ impl Server for TuicServer {
         }
     }
 
+    async fn announce_going_away(&mut self) -> Result<Instant, Error> {
+        let open: usize = self.endpoints.iter().map(Endpoint::open_connections).sum();
+        info!("Asking {} TUIC connection(s) to reconnect elsewhere", open);
+        self.going_away.cancel();
+        Ok(Instant::now())
+    }
+
+    async fn drain(&mut self, timeout: Duration) -> Result<Instant, Error> {
+        let timeout = timeout.min(self.drain_timeout);
+        let open = |endpoints: &[Endpoint]| -> usize {
+            endpoints.iter().map(Endpoint::open_connections).sum()
+        };
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        while open(&self.endpoints) > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let remaining = open(&self.endpoints);
+        if remaining > 0 {
+            info!(
+                "Closing {} TUIC connection(s) still open after {:?} of draining",
+                remaining, timeout
+            );
+            return self.close().await;
+        }
+
+        Ok(Instant::now())
+    }
+
+    async fn close(&mut self) -> Result<Instant, Error> {
+        if self.endpoints.is_empty() {
+            return Ok(Instant::now());
+        }
+
+        close_endpoints(&self.endpoints, self.going_away_error_code).await;
+        info!("TUIC endpoint closed");
+
+        Ok(Instant::now())
+    }
+
+    async fn rebind(&mut self, addr: SocketAddr) -> Result<Instant, Error> {
+        let addr = adjust_bind_addr(addr);
+        if addr == self.socket {
+            return Ok(Instant::now());
+        }
+
+        // Ports that stay put keep their endpoints.
+        let addrs = self.listen_addrs(addr);
+        let (kept, retired): (Vec<Endpoint>, Vec<Endpoint>) =
+            self.endpoints.iter().cloned().partition(|ep| {
+                ep.local_addr()
+                    .is_ok_and(|local_addr| addrs.contains(&local_addr))
+            });
+        let unbound: Vec<SocketAddr> = addrs
+            .iter()
+            .copied()
+            .filter(|addr| !kept.iter().any(|ep| ep.local_addr().ok() == Some(*addr)))
+            .collect();
+        let endpoints = self.bind_all(&unbound)?;
+
+        // The old sockets keep serving their connections, but take no new
+        // ones, and go away with the last of them.
+        for old in retired {
+            old.set_server_config(None);
+            tokio::spawn(async move {
+                let old_addr = old.local_addr();
+                old.wait_idle().await;
+                old.close(0u32.into(), b"");
+                if let Ok(old_addr) = old_addr {
+                    info!("TUIC listener on {} retired", old_addr);
+                }
+            });
+        }
+        self.endpoints = kept.into_iter().chain(endpoints.iter().cloned()).collect();
+
+        info!("TUIC server moved from {} to {}", self.socket, addr);
+        self.socket = addr;
+
+        if matches!(self.status, ServerStatus::Running(_)) {
+            for ep in endpoints {
+                self.spawn_accept_loop(ep);
+            }
+        }
+
+        Ok(Instant::now())
+    }
+
+    async fn restart(&mut self) -> Result<Instant, Error> {
+        // Swapping the server config keeps the endpoint and every established
+        // connection alive; only new handshakes see the new settings.
+        self.reload_certificates().await?;
+        info!("TUIC server restarted in place");
+        Ok(Instant::now())
+    }
+
+    async fn reload_certificates(&mut self) -> Result<Instant, Error> {
+        if self.endpoints.is_empty() {
+            bail!("Need to initialize EndPoint first, call init() method");
+        }
+
+        let config = self.build_server_config()?;
+        for ep in &self.endpoints {
+            ep.set_server_config(Some(config.clone()));
+        }
+        info!("TUIC certificates reloaded from {}", self.certs);
+
+        Ok(Instant::now())
+    }
+
     async fn status(&mut self) -> Result<&ServerStatus, Error> {
         Ok(&self.status)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::tuic::AUTH_TIMEOUT_ERROR_CODE;
+    use crate::processor::tuic::context::AuthState;
+
+    /// A server and a client endpoint on loopback, the client trusting a
+    /// fresh self-signed certificate for "localhost".
+    fn endpoints() -> (Endpoint, Endpoint) {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+        let server_config =
+            ServerConfig::with_single_cert(vec![cert.cert.der().clone()], key.into()).unwrap();
+        let server = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(
+            quinn::ClientConfig::with_root_certificates(Arc::new(roots)).unwrap(),
+        );
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn migration_notice_lets_open_streams_finish() {
+        let (server, client) = endpoints();
+        let server_addr = server.local_addr().unwrap();
+        let going_away = CancellationToken::new();
+
+        let notice = going_away.clone();
+        let served = tokio::spawn(async move {
+            let connection = server.accept().await.unwrap().await.unwrap();
+            let session =
+                registry().register("TUIC", Arc::from("tuic"), connection.remote_address());
+            let context = RuntimeContext::new(OneShotNotifier::default(), Arc::new(session));
+            context.auth_done(AuthState::Authenticated(Uuid::nil()));
+
+            // Echoes one stream, answering only after the notice is out.
+            let tasks = async {
+                let (mut send, mut recv) = connection.accept_bi().await.unwrap();
+                let request = recv.read_to_end(64).await.unwrap();
+                notice.cancel();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                send.write_all(&request).await.unwrap();
+                send.finish().unwrap();
+                let _ = send.stopped().await;
+            };
+            hold(
+                &connection,
+                &context,
+                &notice,
+                SERVER_GOING_AWAY_ERROR_CODE,
+                tasks,
+            )
+            .await;
+            connection
+        });
+
+        let connection = client
+            .connect(server_addr, "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        send.write_all(b"ping").await.unwrap();
+        send.finish().unwrap();
+
+        // Version 5, Heartbeat, then the going-away extension.
+        let notice = connection.read_datagram().await.unwrap();
+        assert_eq!(&notice[..], [0x05, 0x04, 0x02]);
+        assert_eq!(recv.read_to_end(64).await.unwrap(), b"ping");
+        assert!(connection.close_reason().is_none());
+
+        let served = served.await.unwrap();
+        assert!(served.close_reason().is_none());
+        assert!(going_away.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn close_tells_every_connection_to_reconnect() {
+        let (server, client) = endpoints();
+        let (_served, connection) = connect(&server, &client).await;
+
+        close_endpoints(&[server], SERVER_GOING_AWAY_ERROR_CODE).await;
+
+        match connection.closed().await {
+            quinn::ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(close.error_code, SERVER_GOING_AWAY_ERROR_CODE.into());
+                assert_eq!(&close.reason[..], GOING_AWAY_REASON);
+            }
+            other => panic!("closed with {:?}", other),
+        }
+    }
+
+    /// A connection from `client` to `server`, from both ends.
+    async fn connect(server: &Endpoint, client: &Endpoint) -> (Connection, Connection) {
+        let accepting = server.clone();
+        let accepted =
+            tokio::spawn(async move { accepting.accept().await.unwrap().await.unwrap() });
+        let connection = client
+            .connect(server.local_addr().unwrap(), "localhost")
+            .unwrap()
+            .await
+            .unwrap();
+        (accepted.await.unwrap(), connection)
+    }
+
+    fn processor(masquerade: bool) -> TuicConnectionProcessor {
+        let config: Config = toml::from_str(&format!(
+            "[tuic]\nauth_timeout = 3\n[tuic.masquerade]\nenabled = {}",
+            masquerade
+        ))
+        .unwrap();
+        TuicConnectionProcessor::new(Vec::new(), &config)
+    }
+
+    fn context(connection: &Connection) -> Arc<RuntimeContext> {
+        let session = registry().register("TUIC", Arc::from("tuic"), connection.remote_address());
+        Arc::new(RuntimeContext::new(
+            OneShotNotifier::default(),
+            Arc::new(session),
+        ))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_connections_that_never_authenticate() {
+        for (masquerade, code) in [
+            (false, AUTH_TIMEOUT_ERROR_CODE),
+            (true, masquerade::H3_NO_ERROR),
+        ] {
+            let (server, client) = endpoints();
+            let (served, connection) = connect(&server, &client).await;
+
+            let started = tokio::time::Instant::now();
+            processor(masquerade)
+                .process_auth_deadline(context(&served), Arc::new(served))
+                .await
+                .unwrap();
+            assert!(started.elapsed() >= Duration::from_secs(3));
+
+            match connection.closed().await {
+                quinn::ConnectionError::ApplicationClosed(close) => {
+                    assert_eq!(close.error_code, code.into(), "masquerade = {}", masquerade);
+                }
+                other => panic!("closed with {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_connections_that_authenticate_in_time() {
+        let (server, client) = endpoints();
+        let (served, connection) = connect(&server, &client).await;
+        let served = Arc::new(served);
+        let context = context(&served);
+
+        let deadline = tokio::spawn({
+            let (context, served) = (Arc::clone(&context), Arc::clone(&served));
+            async move {
+                processor(false)
+                    .process_auth_deadline(context, served)
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        context.auth_done(AuthState::Authenticated(Uuid::nil()));
+        deadline.await.unwrap().unwrap();
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert!(served.close_reason().is_none());
+        assert!(connection.close_reason().is_none());
+    }
+
+    #[tokio::test]
+    async fn builder_serves_from_der_certificates() {
+        let cert = rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut server = TuicServerBuilder::new(addr)
+            .user(Uuid::from_u128(1), b"secret", "alice")
+            .certs(CertSource::Der(vec![(
+                vec![cert.cert.der().clone()],
+                key.into(),
+            )]))
+            .listeners(1)
+            .build()
+            .unwrap();
+        server.init().await.unwrap();
+        server.start().await.unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let mut crypto = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_protocol_versions(TLS_PROTOCOL_VERSIONS)
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        crypto.alpn_protocols = vec![b"h3".to_vec()];
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(
+            quinn::crypto::rustls::QuicClientConfig::try_from(crypto).unwrap(),
+        )));
+
+        // The client trusts the certificate only, so it reached this server.
+        let connection = client.connect(addr, "localhost").unwrap().await.unwrap();
+        let handshake = connection.handshake_data().unwrap();
+        let handshake = handshake
+            .downcast::<quinn::crypto::rustls::HandshakeData>()
+            .unwrap();
+        assert_eq!(handshake.protocol.as_deref(), Some(&b"h3"[..]));
+
+        connection.close(0u32.into(), b"");
+        server.stop().await.unwrap();
+    }
+}